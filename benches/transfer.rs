@@ -0,0 +1,307 @@
+//! Loopback `RETR` and `STOR` throughput, with and without TLS, in
+//! stream mode and `MODE Z`.
+//!
+//! Transfers 1 GiB per iteration by default,
+//! set `FTPY_BENCH_BYTES` to change the size.
+
+use std::{
+    net::{SocketAddr, TcpListener as StdTcpListener},
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    runtime::Runtime,
+};
+use tokio_rustls::{
+    rustls::{crypto::ring, pki_types::ServerName, ClientConfig, RootCertStore},
+    TlsConnector,
+};
+
+use ftp_server::{
+    config::{AuthConfig, Config, TlsConfig},
+    FTPServer,
+};
+
+const DEFAULT_BYTES: u64 = 1 << 30;
+
+const CHUNK_SIZES: [usize; 3] = [4 << 10, 64 << 10, 1 << 20];
+
+/// What the client deflates into or inflates from at once
+const DEFLATE_BUFFER_SIZE: usize = 64 << 10;
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// Just enough of an FTP client to drive the transfers
+struct Client {
+    control: BufReader<Box<dyn Stream>>,
+    /// Transfers are deflated, selected with `MODE Z`
+    deflate: bool,
+}
+
+impl Client {
+    async fn connect(addr: SocketAddr, tls: Option<&TlsConnector>) -> Self {
+        let mut control = BufReader::new(TcpStream::connect(addr).await.unwrap());
+        read_reply(&mut control, "220").await;
+        let control: Box<dyn Stream> = match tls {
+            Some(connector) => {
+                control.write_all(b"AUTH TLS\r\n").await.unwrap();
+                read_reply(&mut control, "234").await;
+                let server_name = ServerName::try_from("localhost").unwrap();
+                Box::new(
+                    connector
+                        .connect(server_name, control.into_inner())
+                        .await
+                        .unwrap(),
+                )
+            }
+            None => Box::new(control.into_inner()),
+        };
+        let mut client = Self {
+            control: BufReader::new(control),
+            deflate: false,
+        };
+        client.command("USER bench", "331").await;
+        client.command("PASS bench", "230").await;
+        client
+    }
+
+    async fn mode(&mut self, deflate: bool) {
+        let mode = if deflate { "Z" } else { "S" };
+        self.command(&format!("MODE {mode}"), "200").await;
+        self.deflate = deflate;
+    }
+
+    async fn command(&mut self, command: &str, expected: &str) -> String {
+        self.control
+            .write_all(format!("{command}\r\n").as_bytes())
+            .await
+            .unwrap();
+        read_reply(&mut self.control, expected).await
+    }
+
+    async fn passive(&mut self) -> TcpStream {
+        let reply = self.command("PASV", "227").await;
+        let start = reply.find('(').unwrap() + 1;
+        let end = reply.find(')').unwrap();
+        let numbers = reply[start..end]
+            .split(',')
+            .map(|number| number.trim().parse::<u8>().unwrap())
+            .collect::<Vec<_>>();
+        let addr = SocketAddr::from((
+            [numbers[0], numbers[1], numbers[2], numbers[3]],
+            u16::from_be_bytes([numbers[4], numbers[5]]),
+        ));
+        let data = TcpStream::connect(addr).await.unwrap();
+        // The server accepts the data connection on its own task
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        data
+    }
+
+    async fn retr(&mut self, name: &str, chunk_size: usize) -> u64 {
+        let mut data = self.passive().await;
+        self.command(&format!("RETR {name}"), "125").await;
+        let mut buffer = vec![0; chunk_size];
+        let mut decompress = self.deflate.then(|| Decompress::new(true));
+        let mut inflated = vec![0; DEFLATE_BUFFER_SIZE];
+        let mut received = 0;
+        loop {
+            let read = data.read(&mut buffer).await.unwrap();
+            if read == 0 {
+                break;
+            }
+            match &mut decompress {
+                Some(decompress) => received += inflate(decompress, &buffer[..read], &mut inflated),
+                None => received += read as u64,
+            }
+        }
+        read_reply(&mut self.control, "226").await;
+        received
+    }
+
+    async fn stor(&mut self, name: &str, bytes: u64, chunk_size: usize) {
+        let mut data = self.passive().await;
+        self.command(&format!("STOR {name}"), "125").await;
+        let buffer = vec![0x5a; chunk_size];
+        let mut compress = self
+            .deflate
+            .then(|| Compress::new(Compression::default(), true));
+        let mut deflated = vec![0; DEFLATE_BUFFER_SIZE];
+        let mut sent = 0;
+        while sent < bytes {
+            let chunk = (bytes - sent).min(chunk_size as u64) as usize;
+            match &mut compress {
+                Some(compress) => {
+                    let flush = FlushCompress::None;
+                    send_deflated(&mut data, compress, &buffer[..chunk], flush, &mut deflated).await
+                }
+                None => data.write_all(&buffer[..chunk]).await.unwrap(),
+            }
+            sent += chunk as u64;
+        }
+        if let Some(compress) = &mut compress {
+            let flush = FlushCompress::Finish;
+            send_deflated(&mut data, compress, &[], flush, &mut deflated).await;
+        }
+        data.shutdown().await.unwrap();
+        read_reply(&mut self.control, "226").await;
+    }
+}
+
+/// Inflates `input`, returning how many bytes it held.
+fn inflate(decompress: &mut Decompress, mut input: &[u8], output: &mut [u8]) -> u64 {
+    let mut inflated = 0;
+    loop {
+        let (read, written) = (decompress.total_in(), decompress.total_out());
+        let status = decompress
+            .decompress(input, output, FlushDecompress::None)
+            .unwrap();
+        let produced = decompress.total_out() - written;
+        input = &input[(decompress.total_in() - read) as usize..];
+        inflated += produced;
+        // Until all of it is read and there's nothing left to write
+        if status == Status::StreamEnd || (input.is_empty() && produced < output.len() as u64) {
+            return inflated;
+        }
+    }
+}
+
+/// Deflates `input` and sends it, with everything left if `flush` finishes
+/// the stream.
+async fn send_deflated(
+    data: &mut TcpStream,
+    compress: &mut Compress,
+    mut input: &[u8],
+    flush: FlushCompress,
+    output: &mut [u8],
+) {
+    loop {
+        let (read, written) = (compress.total_in(), compress.total_out());
+        let status = compress.compress(input, output, flush).unwrap();
+        let produced = (compress.total_out() - written) as usize;
+        input = &input[(compress.total_in() - read) as usize..];
+        data.write_all(&output[..produced]).await.unwrap();
+        let done = match flush {
+            FlushCompress::Finish => status == Status::StreamEnd,
+            _ => input.is_empty() && produced < output.len(),
+        };
+        if done {
+            return;
+        }
+    }
+}
+
+async fn read_reply(control: &mut (impl AsyncBufReadExt + Unpin), expected: &str) -> String {
+    loop {
+        let mut line = String::new();
+        control.read_line(&mut line).await.unwrap();
+        assert!(!line.is_empty(), "Control connection closed");
+        if line.len() >= 4 && line.as_bytes()[3] == b' ' {
+            assert!(line.starts_with(expected), "Unexpected reply {line:?}");
+            return line;
+        }
+    }
+}
+
+/// Self-signed certificate for `localhost`, with a client trusting it
+fn tls(dir: &Path) -> (TlsConfig, TlsConnector) {
+    let certificate = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let certificate_path = dir.join("cert.pem");
+    let key_path = dir.join("key.pem");
+    std::fs::write(&certificate_path, certificate.serialize_pem().unwrap()).unwrap();
+    std::fs::write(&key_path, certificate.serialize_private_key_pem()).unwrap();
+
+    let mut roots = RootCertStore::empty();
+    roots
+        .add(certificate.serialize_der().unwrap().into())
+        .unwrap();
+    let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    let tls = TlsConfig {
+        certificate: Some(certificate_path),
+        key: Some(key_path),
+        ..TlsConfig::default()
+    };
+    (tls, TlsConnector::from(Arc::new(config)))
+}
+
+fn transfer(c: &mut Criterion) {
+    let bytes = std::env::var("FTPY_BENCH_BYTES")
+        .ok()
+        .and_then(|bytes| bytes.parse().ok())
+        .unwrap_or(DEFAULT_BYTES);
+
+    // The server serves its working directory
+    let root = tempfile::tempdir().unwrap();
+    std::env::set_current_dir(root.path()).unwrap();
+    std::fs::File::create("source.bin")
+        .unwrap()
+        .set_len(bytes)
+        .unwrap();
+
+    let (tls, connector) = tls(root.path());
+    let config = Config {
+        tls,
+        auth: AuthConfig {
+            allow_any: true,
+            ..AuthConfig::default()
+        },
+        ..Config::default()
+    };
+    let addr = StdTcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+
+    let runtime = Runtime::new().unwrap();
+    runtime.spawn(async move { FTPServer::from((addr, config)).listen().await });
+    std::thread::sleep(Duration::from_millis(100));
+
+    let mut group = c.benchmark_group("transfer");
+    group.sample_size(10).throughput(Throughput::Bytes(bytes));
+
+    let modes = [
+        ("plain", None, false),
+        ("tls", Some(&connector), false),
+        ("plain+z", None, true),
+        ("tls+z", Some(&connector), true),
+    ];
+    for (mode, connector, deflate) in modes {
+        let mut client = runtime.block_on(Client::connect(addr, connector));
+        runtime.block_on(client.mode(deflate));
+        for chunk_size in CHUNK_SIZES {
+            group.bench_with_input(
+                BenchmarkId::new(format!("retr/{mode}"), chunk_size),
+                &chunk_size,
+                |b, &chunk_size| {
+                    b.iter(|| {
+                        let received = runtime.block_on(client.retr("source.bin", chunk_size));
+                        assert_eq!(received, bytes);
+                    })
+                },
+            );
+            group.bench_with_input(
+                BenchmarkId::new(format!("stor/{mode}"), chunk_size),
+                &chunk_size,
+                |b, &chunk_size| {
+                    b.iter(|| runtime.block_on(client.stor("destination.bin", bytes, chunk_size)))
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, transfer);
+criterion_main!(benches);
@@ -1,21 +1,30 @@
+mod theme;
+
+pub use theme::*;
+
 use std::{
     io::{self, stdout},
+    panic,
     sync::mpsc,
     thread,
 };
 
 use crossterm::{
+    cursor::Show,
     event::{self, *},
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
 use miette::*;
 use ratatui::{prelude::*, widgets::*};
-use tracing::trace;
+use tracing::{error, trace};
 use tui_logger::*;
 
 pub struct App {
     mode: AppMode,
+    theme: Theme,
+    /// The effective configuration, shown next to the logs
+    summary: Vec<(&'static str, String)>,
 }
 
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
@@ -33,9 +42,21 @@ impl App {
     pub fn new() -> Self {
         Self {
             mode: AppMode::default(),
+            theme: Theme::default(),
+            summary: Vec::new(),
         }
     }
 
+    pub fn with_theme(mut self, theme: Theme) -> Self {
+        self.theme = theme;
+        self
+    }
+
+    pub fn with_summary(mut self, summary: Vec<(&'static str, String)>) -> Self {
+        self.summary = summary;
+        self
+    }
+
     pub fn start(&mut self, terminal: &mut Terminal<impl Backend>) -> Result<()> {
         let (tx, rx) = mpsc::channel();
         let event_tx = tx.clone();
@@ -90,7 +111,7 @@ impl Widget for &mut App {
         Block::default()
             .borders(Borders::ALL)
             .title("FTP Server")
-            .border_style(Color::Green)
+            .border_style(self.theme.border)
             .render(area, buf);
 
         let area = area.inner(&Margin {
@@ -101,14 +122,33 @@ impl Widget for &mut App {
         let [body, footer] =
             Layout::vertical([Constraint::Min(0), Constraint::Length(1)]).areas(area);
 
-        let [left, _right] =
+        let [left, right] =
             Layout::horizontal([Constraint::Fill(75), Constraint::Fill(25)]).areas(body);
 
-        TuiLoggerSmartWidget::default().render(left, buf);
+        self.theme
+            .logger(TuiLoggerSmartWidget::default())
+            .render(left, buf);
+
+        let rows = self.summary.iter().map(|(label, value)| {
+            Row::new([
+                Cell::from(*label).style(self.theme.info),
+                Cell::from(value.as_str()),
+            ])
+        });
+        Widget::render(
+            Table::new(rows, [Constraint::Length(15), Constraint::Fill(1)]).block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title("Configuration")
+                    .border_style(self.theme.border),
+            ),
+            right,
+            buf,
+        );
 
         Paragraph::new("Press 'q' to quit")
             .centered()
-            .style(Color::Gray)
+            .style(self.theme.footer)
             .render(footer, buf);
     }
 }
@@ -124,10 +164,41 @@ pub fn init_terminal() -> Result<Terminal<impl Backend>> {
 pub fn restore_terminal() -> Result<()> {
     trace!(target:"crossterm", "Restoring terminal");
     disable_raw_mode().into_diagnostic()?;
-    execute!(io::stdout(), LeaveAlternateScreen, DisableMouseCapture).into_diagnostic()?;
+    execute!(
+        io::stdout(),
+        LeaveAlternateScreen,
+        DisableMouseCapture,
+        Show
+    )
+    .into_diagnostic()?;
     Ok(())
 }
 
+/// Restores the terminal before the panic message is printed,
+/// otherwise it's lost in the alternate screen and the shell is left in raw mode.
+pub fn install_panic_hook() {
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let _ = restore_terminal();
+        hook(info);
+    }));
+}
+
+/// Restores the terminal when dropped,
+/// so it's also restored when returning early with an error.
+///
+/// Create it before [`init_terminal`] in case it fails halfway.
+#[derive(Debug, Default)]
+pub struct TerminalGuard;
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        if let Err(error) = restore_terminal() {
+            error!("Could not restore the terminal: {:?}", error);
+        }
+    }
+}
+
 pub fn input_thread(tx_event: mpsc::Sender<AppEvent>) -> Result<()> {
     trace!(target:"crossterm", "Starting input thread");
     while let Ok(event) = event::read() {
@@ -0,0 +1,124 @@
+//! Color themes of the interactive mode.
+//!
+//! The monochrome theme only uses text modifiers, and is picked
+//! automatically when `NO_COLOR` is set (see <https://no-color.org>).
+
+use clap::ValueEnum;
+use ratatui::prelude::*;
+use tui_logger::TuiLoggerSmartWidget;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ThemeName {
+    #[default]
+    Dark,
+    Light,
+    Mono,
+}
+
+impl ThemeName {
+    /// Returns `name` if set, otherwise the monochrome theme
+    /// if `NO_COLOR` is set and the dark one if it isn't.
+    pub fn resolve(name: Option<ThemeName>) -> Self {
+        name.unwrap_or_else(|| match std::env::var_os("NO_COLOR") {
+            Some(value) if !value.is_empty() => ThemeName::Mono,
+            _ => ThemeName::default(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Theme {
+    pub border: Style,
+    pub footer: Style,
+    pub highlight: Style,
+    pub error: Style,
+    pub warn: Style,
+    pub info: Style,
+    pub debug: Style,
+    pub trace: Style,
+    pub target_shown: Style,
+    pub target_hidden: Style,
+    pub target_off: Style,
+}
+
+impl Theme {
+    pub fn dark() -> Self {
+        Self {
+            border: Style::new().fg(Color::Green),
+            footer: Style::new().fg(Color::Gray),
+            highlight: Style::new().fg(Color::Black).bg(Color::Green),
+            error: Style::new().fg(Color::Red),
+            warn: Style::new().fg(Color::Yellow),
+            info: Style::new().fg(Color::Cyan),
+            debug: Style::new().fg(Color::Green),
+            trace: Style::new().fg(Color::Magenta),
+            target_shown: Style::new().fg(Color::Gray),
+            target_hidden: Style::new().fg(Color::DarkGray),
+            target_off: Style::new().fg(Color::DarkGray),
+        }
+    }
+
+    /// Darker foregrounds that stay readable on light backgrounds
+    pub fn light() -> Self {
+        Self {
+            border: Style::new().fg(Color::Blue),
+            footer: Style::new().fg(Color::DarkGray),
+            highlight: Style::new().fg(Color::White).bg(Color::Blue),
+            error: Style::new().fg(Color::Red).add_modifier(Modifier::BOLD),
+            warn: Style::new().fg(Color::Rgb(0x99, 0x66, 0x00)),
+            info: Style::new().fg(Color::Blue),
+            debug: Style::new().fg(Color::Rgb(0x00, 0x66, 0x00)),
+            trace: Style::new().fg(Color::Magenta),
+            target_shown: Style::new().fg(Color::Black),
+            target_hidden: Style::new().fg(Color::DarkGray),
+            target_off: Style::new().fg(Color::DarkGray),
+        }
+    }
+
+    pub fn mono() -> Self {
+        Self {
+            border: Style::new(),
+            footer: Style::new().add_modifier(Modifier::DIM),
+            highlight: Style::new().add_modifier(Modifier::REVERSED),
+            error: Style::new().add_modifier(Modifier::BOLD | Modifier::UNDERLINED),
+            warn: Style::new().add_modifier(Modifier::BOLD),
+            info: Style::new(),
+            debug: Style::new().add_modifier(Modifier::DIM),
+            trace: Style::new().add_modifier(Modifier::DIM | Modifier::ITALIC),
+            target_shown: Style::new(),
+            target_hidden: Style::new().add_modifier(Modifier::DIM),
+            target_off: Style::new().add_modifier(Modifier::DIM | Modifier::CROSSED_OUT),
+        }
+    }
+
+    /// Applies the theme to the log widget
+    pub fn logger<'b>(&self, widget: TuiLoggerSmartWidget<'b>) -> TuiLoggerSmartWidget<'b> {
+        widget
+            .border_style(self.border)
+            .highlight_style(self.highlight)
+            .style_error(self.error)
+            .style_warn(self.warn)
+            .style_info(self.info)
+            .style_debug(self.debug)
+            .style_trace(self.trace)
+            .style_show(self.target_shown)
+            .style_hide(self.target_hidden)
+            .style_off(self.target_off)
+    }
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self::from(ThemeName::default())
+    }
+}
+
+impl From<ThemeName> for Theme {
+    fn from(name: ThemeName) -> Self {
+        match name {
+            ThemeName::Dark => Self::dark(),
+            ThemeName::Light => Self::light(),
+            ThemeName::Mono => Self::mono(),
+        }
+    }
+}
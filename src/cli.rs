@@ -1,7 +1,18 @@
-use clap::{CommandFactory, Parser};
-use clap_help::Printer;
-use termimad::ansi;
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+};
 
+use clap::{CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
+use ftp_server::{config::Config, self_check, transcript};
+use miette::*;
+
+#[cfg(feature = "tui")]
+use crate::app::ThemeName;
+
+#[cfg(feature = "tui")]
 static INTRO: &str = "
 
 *ftpy* is an interactive FTPServer
@@ -13,18 +24,148 @@ static INTRO: &str = "
 #[derive(Parser, Debug)]
 #[command(name = "ftpy", author, version, about, disable_help_flag = true)]
 pub struct Args {
+    /// Without a subcommand the server is started
+    #[command(subcommand)]
+    pub command: Option<Commands>,
+
     /// Print help
     #[arg(long)]
     pub help: bool,
 
     /// Interactive mode, that is, the cool mode
+    #[cfg(feature = "tui")]
     #[arg(short, long)]
     pub interactive: bool,
 
     /// The port to listen on
-    #[cfg_attr(debug_assertions, arg(short, long, default_value = "2121"))]
-    #[cfg_attr(not(debug_assertions), arg(short, long, default_value = "21"))]
+    #[cfg_attr(
+        debug_assertions,
+        arg(short, long, global = true, default_value = "2121")
+    )]
+    #[cfg_attr(
+        not(debug_assertions),
+        arg(short, long, global = true, default_value = "21")
+    )]
     pub port: u16,
+
+    /// Color theme of the interactive mode, `mono` if NO_COLOR is set
+    #[cfg(feature = "tui")]
+    #[arg(long, value_enum)]
+    pub theme: Option<ThemeName>,
+
+    /// Path to the TOML configuration file
+    #[arg(short, long, global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Chroot into the served root before accepting connections,
+    /// like `process.chroot`
+    #[arg(long)]
+    pub chroot: bool,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Commands {
+    /// Print the completion script of a shell
+    Completions {
+        /// The shell to generate the script for
+        shell: Shell,
+    },
+
+    /// Print the man page
+    Man,
+
+    /// Replay a recorded transcript and compare the reply codes,
+    /// against a server with `--config` serving an empty directory
+    Replay {
+        /// The transcript to replay
+        transcript: PathBuf,
+    },
+
+    /// Check that a running server on `--port`, configured with `--config`,
+    /// is reachable in passive mode
+    SelfCheck {
+        /// The address clients connect to
+        #[arg(long, default_value = "127.0.0.1")]
+        host: IpAddr,
+
+        /// The user to log in as, the first configured one by default
+        #[arg(long, requires = "password")]
+        user: Option<String>,
+
+        /// The password of `--user`
+        #[arg(long, requires = "user")]
+        password: Option<String>,
+    },
+}
+
+impl Commands {
+    /// Writes the output of the subcommand to stdout
+    pub async fn run(&self, args: &Args) -> Result<()> {
+        let mut command = Args::command();
+        match self {
+            Commands::Completions { shell } => {
+                let name = command.get_name().to_string();
+                clap_complete::generate(*shell, &mut command, name, &mut io::stdout());
+                Ok(())
+            }
+            Commands::Man => clap_mangen::Man::new(command)
+                .render(&mut io::stdout())
+                .into_diagnostic(),
+            Commands::Replay { transcript } => {
+                let config = match &args.config {
+                    Some(path) => Config::load(path)?,
+                    None => Config::default(),
+                };
+                let report = transcript::replay(transcript, config).await?;
+                for exchange in &report.skipped {
+                    println!("line {}: skipped {}", exchange.line, exchange.command);
+                }
+                for (exchange, codes) in &report.mismatches {
+                    println!(
+                        "line {}: {} expected {:?}, got {:?}",
+                        exchange.line, exchange.command, exchange.replies, codes
+                    );
+                }
+                println!(
+                    "{} commands replayed, {} skipped, {} mismatched",
+                    report.replayed,
+                    report.skipped.len(),
+                    report.mismatches.len()
+                );
+                if !report.mismatches.is_empty() {
+                    bail!("The replies don't match the transcript");
+                }
+                Ok(())
+            }
+            Commands::SelfCheck {
+                host,
+                user,
+                password,
+            } => {
+                let config = match &args.config {
+                    Some(path) => Config::load(path)?,
+                    None => Config::default(),
+                };
+                let addr = SocketAddr::new(*host, args.port);
+                let credentials = user.clone().zip(password.clone());
+                let findings = self_check::self_check(addr, &config, credentials).await?;
+                let errors = findings
+                    .iter()
+                    .filter(|finding| {
+                        finding.severity().unwrap_or(Severity::Error) == Severity::Error
+                    })
+                    .count();
+                for finding in &findings {
+                    eprintln!("{:?}", finding);
+                }
+                if errors > 0 {
+                    bail!("{} of the checks of {} failed", errors, addr);
+                }
+                println!("{} is reachable in passive mode", addr);
+                Ok(())
+            }
+        }
+    }
 }
 
 /// Implements the `Args` struct and its associated methods.
@@ -47,11 +188,24 @@ impl Args {
         Some(args)
     }
 
+    /// Returns `true` if the interactive mode was requested,
+    /// always `false` without the `tui` feature.
+    pub fn interactive(&self) -> bool {
+        #[cfg(feature = "tui")]
+        return self.interactive;
+        #[cfg(not(feature = "tui"))]
+        return false;
+    }
+
     /// Prints the help message for the CLI.
     ///
     /// The help message is styled using the `clap-help` and
     /// the `termimad` crates.
+    #[cfg(feature = "tui")]
     pub fn print_help() {
+        use clap_help::Printer;
+        use termimad::ansi;
+
         let mut printer = Printer::new(Args::command())
             .with("introduction", INTRO)
             .with("options", clap_help::TEMPLATE_OPTIONS_MERGED_VALUE)
@@ -63,4 +217,10 @@ impl Args {
         skin.italic = termimad::CompoundStyle::with_fg(color);
         printer.print_help();
     }
+
+    /// Prints the plain help message of `clap`.
+    #[cfg(not(feature = "tui"))]
+    pub fn print_help() {
+        let _ = Args::command().print_help();
+    }
 }
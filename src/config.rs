@@ -0,0 +1,1923 @@
+//! Server configuration.
+//!
+//! The configuration is read from a TOML file passed with `--config`.
+//! Every section is optional and falls back to its defaults, and the paths
+//! of files in it are relative to it, see [`Config::load`].
+
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    net::{IpAddr, Ipv4Addr},
+    path::{Component, Path, PathBuf},
+};
+
+use miette::*;
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::auth::PasswordHash;
+use crate::checksum::HashAlgorithm;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The directory served, the working directory of the server if unset
+    pub root: Option<PathBuf>,
+
+    /// Where the files are kept, the local filesystem if unset
+    pub storage: StorageConfig,
+
+    /// Post-processing applied to uploaded files
+    pub uploads: UploadsConfig,
+
+    /// Accounts allowed to log in, if empty every login is refused
+    /// unless `auth.allow_any` is set
+    pub users: Vec<UserConfig>,
+
+    /// Where more accounts are loaded from
+    pub auth: AuthConfig,
+
+    /// Read-only logins as `anonymous` or `ftp` with any password
+    pub anonymous: AnonymousConfig,
+
+    /// Defaults of the users, which only exist in the configuration
+    pub virtual_users: VirtualUsersConfig,
+
+    /// Passive mode data connections
+    pub passive: PassiveConfig,
+
+    /// Data connection modes allowed unless overridden per user
+    pub data: DataConfig,
+
+    /// Explicit FTPS with `AUTH TLS`
+    pub tls: TlsConfig,
+
+    /// Commands switched off by the operator
+    pub commands: CommandsConfig,
+
+    /// Resources a session may use
+    pub limits: LimitsConfig,
+
+    /// Delays and bans after failed logins
+    pub throttle: ThrottleConfig,
+
+    /// Addresses clients may connect from
+    pub access: AccessConfig,
+
+    /// Countries clients may connect from
+    pub geoip: GeoIpConfig,
+
+    /// Names uploaded files and directories may have
+    pub filenames: FilenamesConfig,
+
+    /// Files no client may list or access
+    pub hidden: HiddenConfig,
+
+    /// Files tightening the settings of the directories holding them
+    pub overrides: OverridesConfig,
+
+    /// The user the server runs as
+    pub process: ProcessConfig,
+
+    /// How directory listings show files
+    pub listing: ListingConfig,
+
+    /// Server specific `SITE` commands
+    pub site: SiteConfig,
+
+    /// How client addresses appear in the logs
+    pub privacy: PrivacyConfig,
+
+    /// Transcripts of the control connections
+    pub record: RecordConfig,
+
+    /// Logins, uploads and refusals kept apart from the debug log
+    pub audit: AuditConfig,
+
+    /// Lets anyone in to a decoy, recording what they do
+    pub honeypot: HoneypotConfig,
+
+    /// What replies tell clients
+    pub replies: RepliesConfig,
+
+    /// Languages of the reply texts besides English, selected with `LANG`
+    pub lang: LangConfig,
+
+    /// Rewrites of the paths sent by clients, applied in order
+    pub rewrite: Vec<RewriteRule>,
+
+    /// Permissions of directories, applied from the least to the most
+    /// specific path
+    pub acl: Vec<AclRule>,
+
+    /// Virtual hosts selected with `HOST`
+    pub hosts: Vec<HostConfig>,
+
+    /// Directories shown side by side at the root instead of the served
+    /// one, each to the users it's shared with
+    pub shares: Vec<ShareConfig>,
+}
+
+impl Config {
+    /// Loads the configuration from the TOML file at `path`, with the
+    /// paths of files in it relative to its directory.
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Could not read config file {}", path.display()))?;
+        let mut config: Self = toml::from_str(&contents)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Invalid config file {}", path.display()))?;
+        config.rebase(path.parent().unwrap_or(Path::new("")));
+        if let Some(users_file) = config.auth.users_file.clone() {
+            let directory = users_file.parent().unwrap_or(Path::new(""));
+            let mut users = UsersFile::load(&users_file)?.users;
+            for user in &mut users {
+                // Homes are relative to the file they're in
+                user.home = user.home.take().map(|home| directory.join(home));
+                user.source = Some(users_file.clone());
+            }
+            config.add_users(users)?;
+        }
+        if let Some(htpasswd) = &config.auth.htpasswd {
+            config.add_users(load_htpasswd(htpasswd)?)?;
+        }
+        config.prepare()?;
+        Ok(config)
+    }
+
+    /// Checks the configuration and fills in what's derived from it: the
+    /// settings of virtual users and the order the ACL rules apply in.
+    ///
+    /// Done by [`Self::load`], and by the server for configurations built
+    /// in code. Preparing twice changes nothing.
+    pub fn prepare(&mut self) -> Result<()> {
+        for user in &mut self.users {
+            self.virtual_users.apply(user)?;
+            user.validate()?;
+        }
+        for (index, share) in self.shares.iter().enumerate() {
+            share.validate()?;
+            if self.shares[..index]
+                .iter()
+                .any(|other| other.name == share.name)
+            {
+                bail!("Share {:?} is configured more than once", share.name);
+            }
+        }
+        let quarantine = &self.uploads.quarantine;
+        if quarantine.as_os_str().is_empty()
+            || !quarantine
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)))
+        {
+            bail!(
+                help = "Name a directory beneath the root, like \".partial\"",
+                "Quarantine {:?} has to be beneath the root",
+                quarantine
+            );
+        }
+        self.acl.sort_by_key(|rule| rule.path.components().count());
+        Ok(())
+    }
+
+    /// Makes the paths of files read from `directory` relative to it, as
+    /// they're written relative to the config file.
+    ///
+    /// Upload mounts and the quarantine are beneath the root whatever
+    /// the config file, and hook commands are looked up like in a shell.
+    fn rebase(&mut self, directory: &Path) {
+        let rebase = |path: &mut PathBuf| *path = directory.join(&*path);
+        self.root.iter_mut().for_each(rebase);
+        self.auth.users_file.iter_mut().for_each(rebase);
+        self.auth.htpasswd.iter_mut().for_each(rebase);
+        self.auth.log.iter_mut().for_each(rebase);
+        self.anonymous.root.iter_mut().for_each(rebase);
+        for user in &mut self.users {
+            user.home.iter_mut().for_each(rebase);
+        }
+        if let Some(template) = &mut self.virtual_users.home {
+            if Path::new(template).is_relative() {
+                *template = directory.join(&*template).to_string_lossy().into_owned();
+            }
+        }
+        for host in &mut self.hosts {
+            rebase(&mut host.root);
+        }
+        for share in &mut self.shares {
+            rebase(&mut share.path);
+        }
+        self.tls.certificate.iter_mut().for_each(rebase);
+        self.tls.key.iter_mut().for_each(rebase);
+        self.tls.client_ca.iter_mut().for_each(rebase);
+        self.lang.catalogs.values_mut().for_each(rebase);
+        self.record.directory.iter_mut().for_each(rebase);
+        self.audit.log.iter_mut().for_each(rebase);
+        self.honeypot.log.iter_mut().for_each(rebase);
+        self.geoip.database.iter_mut().for_each(rebase);
+    }
+
+    /// Adds `users` to the configured ones, whose names must all differ.
+    pub fn add_users(&mut self, users: Vec<UserConfig>) -> Result<()> {
+        for user in users {
+            if self.users.iter().any(|other| other.name == user.name) {
+                bail!("User {:?} is configured more than once", user.name);
+            }
+            self.users.push(user);
+        }
+        Ok(())
+    }
+
+    /// The directory served, the current one if `root` is unset.
+    pub fn served_root(&self) -> Result<PathBuf> {
+        match &self.root {
+            Some(root) => Ok(root.clone()),
+            None => std::env::current_dir().into_diagnostic(),
+        }
+    }
+
+    /// Rewrites the served paths for a process chrooted into `root`,
+    /// which becomes `/`. Every one of them has to be inside it.
+    pub fn chroot(&mut self, root: &Path) -> Result<()> {
+        let cwd = std::env::current_dir().into_diagnostic()?;
+        let inside = |path: &Path| -> Result<PathBuf> {
+            let path = cwd.join(path);
+            match path.strip_prefix(root) {
+                Ok(relative) => Ok(Path::new("/").join(relative)),
+                Err(_) => Err(miette!(
+                    help = "Move it into the root, or don't chroot",
+                    "{:?} is outside of the chroot {:?}",
+                    path,
+                    root
+                )),
+            }
+        };
+        self.root = Some(PathBuf::from("/"));
+        if let Some(anonymous) = &self.anonymous.root {
+            self.anonymous.root = Some(inside(anonymous)?);
+        }
+        for host in &mut self.hosts {
+            host.root = inside(&host.root)?;
+        }
+        for share in &mut self.shares {
+            share.path = inside(&share.path)?;
+        }
+        for user in &mut self.users {
+            if let Some(home) = &user.home {
+                user.home = Some(inside(home)?);
+            }
+        }
+        for mount in &mut self.uploads.mounts {
+            // Relative mounts are relative to the root already
+            if mount.path.is_absolute() {
+                mount.path = inside(&mount.path)?;
+            }
+        }
+        if let Some(directory) = &self.record.directory {
+            self.record.directory = Some(inside(directory)?);
+        }
+        Ok(())
+    }
+
+    /// Rewrites the paths of the certificates inside `root` for a process
+    /// chrooted into it, to reload them from there on `SIGHUP`. Those
+    /// outside are kept as they are, and can't be reloaded.
+    pub fn chroot_tls(&mut self, root: &Path) -> Result<()> {
+        let cwd = std::env::current_dir().into_diagnostic()?;
+        let tls = &mut self.tls;
+        let paths = [&mut tls.certificate, &mut tls.key, &mut tls.client_ca];
+        for path in paths.into_iter().flatten() {
+            if let Ok(relative) = cwd.join(&*path).strip_prefix(root) {
+                *path = Path::new("/").join(relative);
+            }
+        }
+        Ok(())
+    }
+
+    /// The virtual host called `name`, compared case insensitively.
+    pub fn host(&self, name: &str) -> Option<&HostConfig> {
+        self.hosts
+            .iter()
+            .find(|host| host.name.eq_ignore_ascii_case(name))
+    }
+
+    /// Applies every rewrite rule to a path sent by a client.
+    pub fn rewrite_path<'a>(&self, path: &'a str) -> Cow<'a, str> {
+        let mut path = Cow::Borrowed(path);
+        for rule in &self.rewrite {
+            let rewritten = match rule.apply(&path) {
+                Cow::Owned(rewritten) => Some(rewritten),
+                Cow::Borrowed(_) => None,
+            };
+            if let Some(rewritten) = rewritten {
+                path = Cow::Owned(rewritten);
+            }
+        }
+        path
+    }
+}
+
+/// ```toml
+/// [[users]]
+/// name = "alice"
+/// password = "secret"
+/// account = "billing"
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserConfig {
+    pub name: String,
+
+    /// The password in plain text, unless `password_hash` is set
+    #[serde(default)]
+    pub password: Option<String>,
+
+    /// The hash of the password, with argon2id or bcrypt, or as `sha256:`
+    /// and its hexadecimal digest
+    #[serde(default)]
+    pub password_hash: Option<String>,
+
+    /// The initial working directory of the user's sessions
+    #[serde(default)]
+    pub home: Option<PathBuf>,
+
+    /// Bytes the files under `home` may take, unlimited if unset
+    #[serde(default)]
+    pub quota: Option<u64>,
+
+    /// What the user may do, everything if unset
+    #[serde(default)]
+    pub permissions: Option<Vec<Permission>>,
+
+    /// Groups the `acl` rules can refer to
+    #[serde(default)]
+    pub groups: Vec<String>,
+
+    /// The users file the user was loaded from, if not the config file
+    #[serde(skip)]
+    pub source: Option<PathBuf>,
+
+    /// Required with `ACCT` after the password if set
+    #[serde(default)]
+    pub account: Option<String>,
+
+    /// Overrides `data.active` for this user
+    #[serde(default)]
+    pub active: Option<bool>,
+
+    /// Overrides `data.passive` for this user
+    #[serde(default)]
+    pub passive: Option<bool>,
+
+    /// Overrides `data.fxp` for this user
+    #[serde(default)]
+    pub fxp: Option<bool>,
+
+    /// Allows administrative commands like `SITE DEBUG`
+    #[serde(default)]
+    pub admin: bool,
+
+    /// Virtual hosts the user can log in to, every one if empty
+    #[serde(default)]
+    pub hosts: Vec<String>,
+
+    /// Subject of the client certificate the user has to present, like
+    /// `O=Example, CN=alice`, as logged when a client presents it
+    #[serde(default)]
+    pub certificate: Option<String>,
+}
+
+impl UserConfig {
+    fn validate(&self) -> Result<()> {
+        if self.quota.is_some() && self.home.is_none() {
+            bail!(
+                help = "Set the `home` the quota applies to",
+                "User {:?} has a quota but no home",
+                self.name
+            );
+        }
+        match (&self.password, &self.password_hash) {
+            (Some(_), None) => Ok(()),
+            (None, Some(hash)) => PasswordHash::parse(hash)
+                .map(|_| ())
+                .wrap_err_with(|| format!("Invalid password hash of user {:?}", self.name)),
+            // Only logs in with `tls.certificate_login`
+            (None, None) if self.certificate.is_some() => Ok(()),
+            _ => bail!(
+                help = "Set either `password` or `password_hash`",
+                "User {:?} needs exactly one password",
+                self.name
+            ),
+        }
+    }
+}
+
+/// What a user may do, checked before running each command
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Permission {
+    /// See the files, with `LIST`, `NLST`, `MLSD`, `MLST`, `STAT` or `MDTM`
+    List,
+    /// Read the contents of files, with `RETR` or their checksums
+    Download,
+    /// Write files, with `STOR`, `APPE` or `MFMT`
+    Upload,
+    /// Replace existing files with `STOR`, or cut them short with a `STOR`
+    /// restarted before their end, besides `upload`
+    Delete,
+}
+
+impl Permission {
+    pub const ALL: [Permission; 4] = [Self::List, Self::Download, Self::Upload, Self::Delete];
+
+    /// The permissions of anonymous sessions
+    pub const READ_ONLY: [Permission; 2] = [Self::List, Self::Download];
+}
+
+impl std::fmt::Display for Permission {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::List => "list",
+            Self::Download => "download",
+            Self::Upload => "upload",
+            Self::Delete => "delete",
+        };
+        f.write_str(name)
+    }
+}
+
+/// ```toml
+/// [auth]
+/// users_file = "users.toml"
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// A TOML or JSON file with more `users`, relative to the config file
+    pub users_file: Option<PathBuf>,
+
+    /// An Apache htpasswd file with more users, relative to the config file
+    pub htpasswd: Option<PathBuf>,
+
+    /// Rewrites the plain text, SHA-256 and APR1 passwords of the users file
+    /// as argon2id hashes when their users log in
+    pub upgrade_hashes: bool,
+
+    /// Where every login is appended in a format for tools like fail2ban,
+    /// relative to the config file
+    pub log: Option<PathBuf>,
+
+    /// Lets anyone log in with any name and password while no users are
+    /// configured, instead of refusing every login
+    pub allow_any: bool,
+}
+
+/// Loads the users of an htpasswd file, with bcrypt or APR1 passwords.
+///
+/// The file is left to the tools that manage it, so its passwords are
+/// never upgraded.
+fn load_htpasswd(path: &Path) -> Result<Vec<UserConfig>> {
+    let contents = std::fs::read_to_string(path)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Could not read htpasswd file {}", path.display()))?;
+    let mut users = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = || format!("Invalid line {} of {}", number + 1, path.display());
+        let (name, hash) = line
+            .split_once(':')
+            .ok_or_else(|| miette!("Expected `user:hash`"))
+            .wrap_err_with(invalid)?;
+        PasswordHash::parse(hash).wrap_err_with(invalid)?;
+        users.push(UserConfig {
+            name: name.to_string(),
+            password: None,
+            password_hash: Some(hash.to_string()),
+            account: None,
+            home: None,
+            quota: None,
+            permissions: None,
+            groups: Vec::new(),
+            source: None,
+            active: None,
+            passive: None,
+            fxp: None,
+            admin: false,
+            hosts: Vec::new(),
+            certificate: None,
+        });
+    }
+    Ok(users)
+}
+
+/// ```toml
+/// [anonymous]
+/// enabled = true
+/// root = "/srv/mirror"
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AnonymousConfig {
+    pub enabled: bool,
+
+    /// The initial working directory of anonymous sessions
+    pub root: Option<PathBuf>,
+
+    /// Refuses every login but the anonymous ones
+    pub only: bool,
+}
+
+impl AnonymousConfig {
+    /// Returns `true` if `user` logs in anonymously.
+    pub fn matches(&self, user: &str) -> bool {
+        self.enabled
+            && ["anonymous", "ftp"]
+                .iter()
+                .any(|name| name.eq_ignore_ascii_case(user))
+    }
+}
+
+/// ```toml
+/// [virtual_users]
+/// home = "/srv/ftp/{user}"
+/// create_homes = true
+/// quota = 1073741824
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VirtualUsersConfig {
+    /// The home of users without one, where `{user}` is their name
+    pub home: Option<String>,
+
+    /// Creates the homes that don't exist yet when their users log in
+    pub create_homes: bool,
+
+    /// The quota of users without one
+    pub quota: Option<u64>,
+
+    /// The permissions of users without their own
+    pub permissions: Option<Vec<Permission>>,
+}
+
+impl VirtualUsersConfig {
+    /// Fills in the settings `user` doesn't have.
+    fn apply(&self, user: &mut UserConfig) -> Result<()> {
+        if let (None, Some(template)) = (&user.home, &self.home) {
+            // The name becomes a path component
+            if user.name.is_empty()
+                || user.name.contains('/')
+                || user.name == "."
+                || user.name == ".."
+            {
+                bail!("User {:?} can't have a home from the template", user.name);
+            }
+            user.home = Some(PathBuf::from(template.replace("{user}", &user.name)));
+        }
+        user.quota = user.quota.or(self.quota);
+        if user.permissions.is_none() {
+            user.permissions = self.permissions.clone();
+        }
+        Ok(())
+    }
+}
+
+/// The accounts of a users file, like the `[[users]]` of the config file
+/// in TOML or `{"users": [...]}` in JSON
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UsersFile {
+    users: Vec<UserConfig>,
+}
+
+impl UsersFile {
+    /// Loads the file at `path`, as JSON if its extension is `.json`.
+    fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Could not read users file {}", path.display()))?;
+        let users = match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") => serde_json::from_str(&contents).into_diagnostic(),
+            _ => toml::from_str(&contents).into_diagnostic(),
+        };
+        users.wrap_err_with(|| format!("Invalid users file {}", path.display()))
+    }
+
+    /// Replaces the password of the user called `name` in the file at
+    /// `path` with `hash`, keeping the rest of the file as it was.
+    pub fn set_password_hash(path: &Path, name: &str, hash: &str) -> Result<()> {
+        let contents = std::fs::read_to_string(path)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Could not read users file {}", path.display()))?;
+        let missing = || miette!("User {:?} is not in {}", name, path.display());
+        let contents = match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") => {
+                let mut document: serde_json::Value =
+                    serde_json::from_str(&contents).into_diagnostic()?;
+                let user = document
+                    .get_mut("users")
+                    .and_then(serde_json::Value::as_array_mut)
+                    .and_then(|users| users.iter_mut().find(|user| user["name"] == name))
+                    .and_then(serde_json::Value::as_object_mut)
+                    .ok_or_else(missing)?;
+                user.remove("password");
+                user.insert("password_hash".to_string(), hash.into());
+                serde_json::to_string_pretty(&document).into_diagnostic()? + "\n"
+            }
+            _ => {
+                let mut document: toml_edit::DocumentMut = contents.parse().into_diagnostic()?;
+                let user = document
+                    .get_mut("users")
+                    .and_then(toml_edit::Item::as_array_of_tables_mut)
+                    .and_then(|users| {
+                        users.iter_mut().find(|user| {
+                            user.get("name").and_then(|name| name.as_str()) == Some(name)
+                        })
+                    })
+                    .ok_or_else(missing)?;
+                user.remove("password");
+                user["password_hash"] = toml_edit::value(hash);
+                document.to_string()
+            }
+        };
+
+        // Replaced at once, so a crash can't leave half a file behind
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        let temporary = path.with_file_name(format!(".{}.tmp", file_name));
+        std::fs::write(&temporary, contents).into_diagnostic()?;
+        let permissions = std::fs::metadata(path).into_diagnostic()?.permissions();
+        std::fs::set_permissions(&temporary, permissions).into_diagnostic()?;
+        std::fs::rename(&temporary, path)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Could not replace users file {}", path.display()))
+    }
+}
+
+/// ```toml
+/// [[hosts]]
+/// name = "ftp.example.com"
+/// root = "/srv/example"
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HostConfig {
+    /// The name clients send with `HOST`
+    pub name: String,
+
+    /// The initial working directory of the sessions of the host
+    pub root: PathBuf,
+}
+
+/// ```toml
+/// [data]
+/// active = false
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DataConfig {
+    /// Allows `PORT`
+    pub active: bool,
+
+    /// Allows `PASV` and `EPSV`
+    pub passive: bool,
+
+    /// Accepts passive data connections from other addresses than the one
+    /// of the client, and `PORT` to other addresses, for site-to-site
+    /// transfers
+    pub fxp: bool,
+
+    /// Allows `PORT` to ports below 1024
+    pub privileged_ports: bool,
+
+    /// Hashes the data of every `RETR`, `STOR` and `APPE` as it's sent or
+    /// received, for the `226` reply, the log and the audit log
+    pub checksum: Option<HashAlgorithm>,
+
+    /// Sends `RETR` of `<dir>.tar`, when there's no such file, as a tar
+    /// archive of the directory `<dir>`
+    pub archives: bool,
+}
+
+impl Default for DataConfig {
+    fn default() -> Self {
+        Self {
+            active: true,
+            passive: true,
+            fxp: false,
+            privileged_ports: false,
+            checksum: None,
+            archives: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataMode {
+    Active,
+    Passive,
+}
+
+impl Config {
+    /// Returns `true` if `user` is a configured administrator.
+    pub fn is_admin(&self, user: &str) -> bool {
+        self.users
+            .iter()
+            .any(|admin| admin.admin && admin.name == user)
+    }
+
+    /// Returns `true` if `user`, or anyone if `None`, may open
+    /// data connections in `mode`.
+    pub fn allows_data_mode(&self, user: Option<&str>, mode: DataMode) -> bool {
+        let user = user.and_then(|name| self.users.iter().find(|user| user.name == name));
+        match mode {
+            DataMode::Active => user
+                .and_then(|user| user.active)
+                .unwrap_or(self.data.active),
+            DataMode::Passive => user
+                .and_then(|user| user.passive)
+                .unwrap_or(self.data.passive),
+        }
+    }
+
+    /// Returns `true` if the sessions of `user` may transfer from or to
+    /// other hosts than the client.
+    pub fn allows_fxp(&self, user: Option<&str>) -> bool {
+        user.and_then(|name| self.users.iter().find(|user| user.name == name))
+            .and_then(|user| user.fxp)
+            .unwrap_or(self.data.fxp)
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM encoded certificate chain
+    pub certificate: Option<PathBuf>,
+
+    /// PEM encoded private key
+    pub key: Option<PathBuf>,
+
+    /// Refuses logins until the control connection is secured
+    pub required: bool,
+
+    /// Refuses `CCC`, keeping the control connection secured once it is
+    pub forbid_ccc: bool,
+
+    /// PEM encoded certificates of the authorities trusted to issue client
+    /// certificates, which clients are asked for if set
+    pub client_ca: Option<PathBuf>,
+
+    /// Refuses the handshake without a valid client certificate
+    pub client_certificate_required: bool,
+
+    /// Logs in users after `USER` without `PASS` if they present their
+    /// certificate
+    pub certificate_login: bool,
+}
+
+/// ```toml
+/// [limits]
+/// session_memory = 1048576
+/// connections_per_address = 10
+/// max_sessions = 500
+/// command_rate = 20.0
+/// max_upload = 1073741824
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Bytes of buffers a session may hold at once, unlimited if unset
+    pub session_memory: Option<usize>,
+
+    /// Control connections a client address may have open at once,
+    /// unlimited if unset
+    pub connections_per_address: Option<usize>,
+
+    /// Sessions the server has open at once, unlimited if unset
+    pub max_sessions: Option<usize>,
+
+    /// Commands per second a session may send on average, unlimited if unset
+    pub command_rate: Option<f64>,
+
+    /// Commands a session may send in a row before `command_rate` kicks in,
+    /// a second worth of them if unset
+    pub command_burst: Option<u32>,
+
+    /// What happens to sessions sending commands faster than `command_rate`
+    pub over_rate: OverRate,
+
+    /// Bytes a command line may take, longer ones are refused with `500`
+    pub max_command_line: usize,
+
+    /// Closes sessions sending a command line longer than `max_command_line`
+    /// instead of skipping it
+    pub close_on_long_command: bool,
+
+    /// Bytes an uploaded file may take, unlimited if unset
+    pub max_upload: Option<u64>,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            session_memory: None,
+            connections_per_address: None,
+            max_sessions: None,
+            command_rate: None,
+            command_burst: None,
+            over_rate: OverRate::default(),
+            max_command_line: 8192,
+            close_on_long_command: false,
+            max_upload: None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverRate {
+    /// Their commands are held back until they are within the rate again
+    #[default]
+    Delay,
+
+    /// They are closed with `421`
+    Close,
+}
+
+/// ```toml
+/// [throttle]
+/// max_failures = 5
+/// ban_seconds = 600
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThrottleConfig {
+    /// Failed logins from an address after which it is banned, never if 0
+    pub max_failures: u32,
+
+    /// Seconds an address stays banned, and failed logins are counted for
+    pub ban_seconds: u64,
+
+    /// Milliseconds the reply to a failed login is held back for, times the
+    /// number of failures so far
+    pub delay_ms: u64,
+
+    /// Milliseconds the reply to a failed login is held back for at most
+    pub max_delay_ms: u64,
+}
+
+impl Default for ThrottleConfig {
+    fn default() -> Self {
+        Self {
+            max_failures: 10,
+            ban_seconds: 600,
+            delay_ms: 500,
+            max_delay_ms: 5000,
+        }
+    }
+}
+
+/// ```toml
+/// [listing]
+/// symlinks = "target"
+/// cache_seconds = 5
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListingConfig {
+    pub symlinks: SymlinkFacts,
+    /// How long the entries of a listed directory are served again without
+    /// reading it, 0 to always read it
+    pub cache_seconds: u64,
+}
+
+/// Where the size and date of a symbolic link in a listing come from
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SymlinkFacts {
+    /// The link itself, as `ls -l` shows it
+    #[default]
+    Link,
+
+    /// The file the link points to, the link itself if it's broken
+    Target,
+}
+
+/// ```toml
+/// [lang.catalogs]
+/// es = "/etc/ftpy/lang/es.toml"
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LangConfig {
+    /// The reply catalog of every language tag
+    pub catalogs: BTreeMap<String, PathBuf>,
+}
+
+/// ```toml
+/// [commands]
+/// disabled = ["SITE", "PORT"]
+/// reply = "policy"
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CommandsConfig {
+    /// The only commands available if set, besides the ones logging in
+    /// and out, compared case insensitively
+    pub enabled: Option<Vec<String>>,
+
+    /// Commands refused even though they're implemented,
+    /// compared case insensitively
+    pub disabled: Vec<String>,
+
+    /// The reply to disabled commands, and disabled `SITE` verbs
+    pub reply: DisabledReply,
+}
+
+impl CommandsConfig {
+    /// Available whatever `enabled` lists, or no one could log in
+    const LOGIN: [&'static str; 3] = ["USER", "PASS", "QUIT"];
+
+    /// Returns `true` if the command is disabled.
+    pub fn is_disabled(&self, keyword: &str) -> bool {
+        let listed = |commands: &[String]| {
+            commands
+                .iter()
+                .any(|command| command.eq_ignore_ascii_case(keyword))
+        };
+        let enabled = match &self.enabled {
+            Some(enabled) => {
+                listed(enabled)
+                    || Self::LOGIN
+                        .iter()
+                        .any(|command| command.eq_ignore_ascii_case(keyword))
+            }
+            None => true,
+        };
+        !enabled || listed(&self.disabled)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DisabledReply {
+    /// `502` explaining that the command is disabled
+    #[default]
+    Disabled,
+
+    /// `533`, denied for policy reasons
+    Policy,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SiteConfig {
+    /// `SITE` verbs refused with `502`, compared case insensitively
+    pub disabled: Vec<String>,
+
+    /// Lines of the session trace kept for `SITE DEBUG SHOW`
+    pub debug_lines: usize,
+}
+
+impl Default for SiteConfig {
+    fn default() -> Self {
+        Self {
+            disabled: Vec::new(),
+            debug_lines: 50,
+        }
+    }
+}
+
+impl SiteConfig {
+    /// Returns `true` if the `SITE` verb is disabled.
+    pub fn is_disabled(&self, verb: &str) -> bool {
+        self.disabled
+            .iter()
+            .any(|disabled| disabled.eq_ignore_ascii_case(verb))
+    }
+}
+
+/// ```toml
+/// [replies]
+/// banner = """
+/// Welcome to {hostname}, {sessions} of {max_sessions} users are connected.
+/// """
+/// directory_message = ".message"
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RepliesConfig {
+    /// Explains why an operation was refused, like the path that couldn't
+    /// be accessed, instead of sending the bare status line
+    pub details: bool,
+
+    /// Lines sent before the `220` greeting, with placeholders
+    pub banner: Option<String>,
+
+    /// File shown on `CWD` into the directory holding it, none if empty
+    pub directory_message: String,
+}
+
+impl Default for RepliesConfig {
+    fn default() -> Self {
+        Self {
+            details: true,
+            banner: None,
+            directory_message: ".message".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RecordConfig {
+    /// Directory the transcripts are written to, nothing is recorded if unset
+    pub directory: Option<PathBuf>,
+}
+
+/// ```toml
+/// [storage]
+/// url = "s3://backups/ftp"
+/// options = { region = "eu-west-1" }
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    /// The bucket the files are kept in, like `s3://bucket/prefix`,
+    /// `gs://bucket` or `az://container`, which needs the `object-store`
+    /// feature
+    pub url: Option<String>,
+
+    /// Settings of the store, like `region` or `endpoint`, read from the
+    /// environment like `AWS_REGION` if missing
+    pub options: BTreeMap<String, String>,
+
+    /// Refuses every upload or change to the files, whatever the
+    /// permissions of the users
+    pub read_only: bool,
+}
+
+/// ```toml
+/// [audit]
+/// log = "/var/log/ftp-server/audit.jsonl"
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    /// Where the events are appended as JSON, relative to the config file,
+    /// nothing is recorded if unset
+    pub log: Option<PathBuf>,
+}
+
+/// ```toml
+/// [honeypot]
+/// enabled = true
+/// log = "honeypot.jsonl"
+///
+/// [honeypot.files]
+/// "backup/passwords.txt" = "admin:hunter2"
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HoneypotConfig {
+    /// Logs in anyone to decoy files kept in memory, and drops their
+    /// uploads, instead of serving the root
+    pub enabled: bool,
+
+    /// Where every command, login and upload is appended as JSON,
+    /// relative to the config file, logged if unset
+    pub log: Option<PathBuf>,
+
+    /// The decoy files, by their path from the root, with their contents
+    pub files: BTreeMap<PathBuf, String>,
+}
+
+/// ```toml
+/// [privacy]
+/// client_ips = "hash"
+/// key = "a long random secret"
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PrivacyConfig {
+    pub client_ips: ClientIpMode,
+
+    /// Key of the HMAC used by `hash`, random on every start if unset
+    pub key: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ClientIpMode {
+    /// Logs the full address
+    #[default]
+    Plain,
+
+    /// Logs the `/24` (IPv4) or `/48` (IPv6) network only
+    Truncate,
+
+    /// Logs a keyed hash of the address
+    Hash,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PassiveConfig {
+    /// Advertises a random token in every `227` reply that clients must
+    /// send as the first line of the data connection before it's accepted.
+    ///
+    /// This isn't part of any RFC, only enable it for clients built to use it.
+    pub data_token: bool,
+
+    /// Address advertised to clients in `227` replies, like the public
+    /// address of a server behind NAT, the one of `bind` or of the control
+    /// connection if unset
+    pub address: Option<Ipv4Addr>,
+
+    /// Address the data connection listeners are bound to, the one the
+    /// client reached the control connection on if unset
+    pub bind: Option<IpAddr>,
+
+    /// Ports the data connection listeners are bound to, any free port if unset
+    pub ports: Option<PortRange>,
+
+    /// Translates the bound ports to the ones advertised to clients,
+    /// for deployments where they differ, like containers behind NAT
+    pub port_map: Vec<PortMapping>,
+}
+
+impl PassiveConfig {
+    /// The port advertised to clients for the bound `port`
+    pub fn advertised_port(&self, port: u16) -> u16 {
+        self.port_map
+            .iter()
+            .find_map(|mapping| mapping.translate(port))
+            .unwrap_or(port)
+    }
+}
+
+/// ```toml
+/// [process]
+/// user = "ftp"
+/// chroot = true
+/// sandbox = true
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProcessConfig {
+    /// The user a server started as root switches to once it's listening
+    pub user: Option<String>,
+
+    /// The group it switches to, the primary group of `user` if unset
+    pub group: Option<String>,
+
+    /// Whether a server started as root chroots into the root before
+    /// accepting connections
+    pub chroot: bool,
+
+    /// Whether the filesystem and the system calls are confined with
+    /// Landlock and seccomp, which needs the `sandbox` feature on Linux
+    pub sandbox: bool,
+}
+
+/// ```toml
+/// [access]
+/// allow = ["10.0.0.0/8", "192.0.2.7"]
+/// deny = ["10.13.0.0/16"]
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessConfig {
+    /// Networks clients may connect from, every one if empty
+    pub allow: Vec<IpNetwork>,
+
+    /// Networks clients may never connect from, even if allowed
+    pub deny: Vec<IpNetwork>,
+}
+
+impl AccessConfig {
+    /// Returns `true` if clients may connect from `address`.
+    pub fn allows(&self, address: IpAddr) -> bool {
+        let address = address.to_canonical();
+        let allowed =
+            self.allow.is_empty() || self.allow.iter().any(|network| network.contains(address));
+        allowed && !self.deny.iter().any(|network| network.contains(address))
+    }
+}
+
+/// ```toml
+/// [geoip]
+/// database = "GeoLite2-Country.mmdb"
+/// deny = ["KP"]
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GeoIpConfig {
+    /// A MaxMind country or city database, relative to the config file,
+    /// which needs the `geoip` feature
+    pub database: Option<PathBuf>,
+
+    /// ISO codes of the countries clients may connect from, every one if
+    /// empty, compared case insensitively
+    pub allow: Vec<String>,
+
+    /// ISO codes of the countries clients may never connect from
+    pub deny: Vec<String>,
+
+    /// Whether addresses missing from the database, like private ones,
+    /// may connect even though `allow` isn't empty
+    pub allow_unknown: bool,
+}
+
+impl GeoIpConfig {
+    /// Returns `true` if clients may connect from `country`, unknown if
+    /// `None`.
+    pub fn allows(&self, country: Option<&str>) -> bool {
+        let listed = |countries: &[String], country: &str| {
+            countries
+                .iter()
+                .any(|listed| listed.eq_ignore_ascii_case(country))
+        };
+        match country {
+            Some(country) => {
+                (self.allow.is_empty() || listed(&self.allow, country))
+                    && !listed(&self.deny, country)
+            }
+            None => self.allow.is_empty() || self.allow_unknown,
+        }
+    }
+}
+
+/// ```toml
+/// [filenames]
+/// deny = ["*.php", ".ht*"]
+/// windows_reserved = true
+/// max_length = 128
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FilenamesConfig {
+    /// Patterns of names refused, compared case insensitively
+    pub deny: Vec<NamePattern>,
+
+    /// Whether names with control characters, like line breaks, are refused
+    pub control_characters: bool,
+
+    /// Whether names Windows reserves, like `CON` or `nul.txt`, are refused
+    pub windows_reserved: bool,
+
+    /// Bytes a name may take at most
+    pub max_length: usize,
+}
+
+impl Default for FilenamesConfig {
+    fn default() -> Self {
+        Self {
+            deny: Vec::new(),
+            control_characters: true,
+            windows_reserved: false,
+            max_length: 255,
+        }
+    }
+}
+
+impl FilenamesConfig {
+    /// Names Windows reserves for devices, whatever their extension
+    const WINDOWS_RESERVED: [&'static str; 22] = [
+        "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+        "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+    ];
+
+    /// Checks `name`, the last component of a path, returning the rule it
+    /// breaks if any.
+    pub fn check(&self, name: &str) -> std::result::Result<(), String> {
+        if name.len() > self.max_length {
+            return Err(format!("is longer than {} bytes", self.max_length));
+        }
+        if self.control_characters && name.chars().any(char::is_control) {
+            return Err("has control characters".to_string());
+        }
+        if self.windows_reserved {
+            let stem = name.split('.').next().unwrap_or_default().trim_end();
+            if Self::WINDOWS_RESERVED
+                .iter()
+                .any(|reserved| reserved.eq_ignore_ascii_case(stem))
+            {
+                return Err(format!("is reserved on Windows like {}", stem));
+            }
+            if name.ends_with(['.', ' ']) {
+                return Err("ends with a dot or a space, which Windows drops".to_string());
+            }
+        }
+        match self.deny.iter().find(|pattern| pattern.matches(name)) {
+            Some(pattern) => Err(format!("matches {:?}", pattern.0.as_str())),
+            None => Ok(()),
+        }
+    }
+}
+
+/// ```toml
+/// [hidden]
+/// dotfiles = true
+/// patterns = ["*.bak", "private"]
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HiddenConfig {
+    /// Whether names starting with a dot are hidden
+    pub dotfiles: bool,
+
+    /// Patterns of hidden names, compared case insensitively
+    pub patterns: Vec<NamePattern>,
+}
+
+impl HiddenConfig {
+    /// Returns `true` if files named `name` are hidden.
+    pub fn hides(&self, name: &str) -> bool {
+        (self.dotfiles && name.starts_with('.'))
+            || self.patterns.iter().any(|pattern| pattern.matches(name))
+    }
+
+    /// Returns `true` if any component of `path` is hidden, so nothing in
+    /// a hidden directory can be reached either.
+    pub fn hides_path(&self, path: &Path) -> bool {
+        path.components().any(|component| match component {
+            Component::Normal(name) => self.hides(&name.to_string_lossy()),
+            _ => false,
+        })
+    }
+}
+
+/// ```toml
+/// [overrides]
+/// file = ".ftpaccess"
+/// cache_seconds = 30
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OverridesConfig {
+    /// Name of the files tightening the settings of the directory holding
+    /// them and the ones below, none if empty
+    pub file: String,
+
+    /// Seconds an override file is used before it's read again
+    pub cache_seconds: u64,
+}
+
+impl Default for OverridesConfig {
+    fn default() -> Self {
+        Self {
+            file: String::new(),
+            cache_seconds: 30,
+        }
+    }
+}
+
+/// A shell pattern of file names, like `"*.php"`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct NamePattern(pub glob::Pattern);
+
+impl NamePattern {
+    /// Returns `true` if `name` matches, whatever its case.
+    pub fn matches(&self, name: &str) -> bool {
+        let options = glob::MatchOptions {
+            case_sensitive: false,
+            ..Default::default()
+        };
+        self.0.matches_with(name, options)
+    }
+}
+
+impl TryFrom<String> for NamePattern {
+    type Error = String;
+
+    fn try_from(pattern: String) -> std::result::Result<Self, Self::Error> {
+        glob::Pattern::new(&pattern)
+            .map(Self)
+            .map_err(|error| format!("invalid name pattern {pattern:?}: {error}"))
+    }
+}
+
+/// A network written as `"10.0.0.0/8"`, or a single address
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct IpNetwork {
+    pub address: IpAddr,
+    pub prefix: u8,
+}
+
+impl IpNetwork {
+    pub fn contains(&self, address: IpAddr) -> bool {
+        match (self.address, address) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(address) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(address) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl TryFrom<String> for IpNetwork {
+    type Error = String;
+
+    fn try_from(network: String) -> std::result::Result<Self, Self::Error> {
+        let (address, prefix) = match network.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (network.as_str(), None),
+        };
+        let address = address
+            .trim()
+            .parse::<IpAddr>()
+            .map_err(|_| format!("invalid address in network {network:?}"))?;
+        let bits = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .trim()
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= bits)
+                .ok_or_else(|| format!("invalid prefix length in network {network:?}"))?,
+            None => bits,
+        };
+        Ok(Self { address, prefix })
+    }
+}
+
+/// An inclusive range of ports written as `"30000-30100"`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl PortRange {
+    pub fn contains(&self, port: u16) -> bool {
+        (self.start..=self.end).contains(&port)
+    }
+
+    /// The number of ports in the range
+    pub fn count(&self) -> u32 {
+        u32::from(self.end - self.start) + 1
+    }
+}
+
+impl TryFrom<String> for PortRange {
+    type Error = String;
+
+    fn try_from(range: String) -> std::result::Result<Self, Self::Error> {
+        let (start, end) = range.split_once('-').unwrap_or((&range, &range));
+        let parse = |port: &str| {
+            port.trim()
+                .parse::<u16>()
+                .map_err(|_| format!("invalid port {port:?} in range {range:?}"))
+        };
+        let (start, end) = (parse(start)?, parse(end)?);
+        if start > end {
+            return Err(format!("empty port range {range:?}"));
+        }
+        Ok(Self { start, end })
+    }
+}
+
+/// ```toml
+/// [[passive.port_map]]
+/// internal = "30000-30100"
+/// external = "50000-50100"
+/// ```
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(try_from = "RawPortMapping")]
+pub struct PortMapping {
+    pub internal: PortRange,
+    pub external: PortRange,
+}
+
+impl PortMapping {
+    /// The external port of `port`, if it's in the internal range
+    pub fn translate(&self, port: u16) -> Option<u16> {
+        self.internal
+            .contains(port)
+            .then(|| self.external.start + (port - self.internal.start))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawPortMapping {
+    internal: PortRange,
+    external: PortRange,
+}
+
+impl TryFrom<RawPortMapping> for PortMapping {
+    type Error = String;
+
+    fn try_from(raw: RawPortMapping) -> std::result::Result<Self, Self::Error> {
+        if raw.internal.count() != raw.external.count() {
+            return Err("internal and external port ranges must have the same length".into());
+        }
+        Ok(Self {
+            internal: raw.internal,
+            external: raw.external,
+        })
+    }
+}
+
+/// Replaces the first match of `pattern` in client paths, `$1` and
+/// `${name}` in `replacement` refer to its capture groups
+///
+/// ```toml
+/// [[rewrite]]
+/// pattern = "^/pub/old-name"
+/// replacement = "/pub/new-name"
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "RawRewriteRule")]
+pub struct RewriteRule {
+    pub pattern: Regex,
+    pub replacement: String,
+}
+
+impl RewriteRule {
+    pub fn apply<'a>(&self, path: &'a str) -> Cow<'a, str> {
+        self.pattern.replace(path, self.replacement.as_str())
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRewriteRule {
+    pattern: String,
+    replacement: String,
+}
+
+impl TryFrom<RawRewriteRule> for RewriteRule {
+    type Error = regex::Error;
+
+    fn try_from(raw: RawRewriteRule) -> std::result::Result<Self, Self::Error> {
+        Ok(Self {
+            pattern: Regex::new(&raw.pattern)?,
+            replacement: raw.replacement,
+        })
+    }
+}
+
+/// A directory shown as `/<name>` to the `users` and members of the
+/// `groups`, or to everyone if both are empty
+///
+/// ```toml
+/// [[shares]]
+/// name = "media"
+/// path = "/srv/media"
+/// groups = ["family"]
+/// read_only = true
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ShareConfig {
+    /// The name of its directory at the root
+    pub name: String,
+
+    /// Where its files are, like a root
+    pub path: PathBuf,
+
+    #[serde(default)]
+    pub users: Vec<String>,
+
+    #[serde(default)]
+    pub groups: Vec<String>,
+
+    /// Refuses every change to its files, whatever the permissions of the
+    /// users
+    #[serde(default)]
+    pub read_only: bool,
+}
+
+impl ShareConfig {
+    /// Returns `true` if `user`, a member of `groups`, can see the share.
+    pub fn visible_to(&self, user: &str, groups: &[String]) -> bool {
+        (self.users.is_empty() && self.groups.is_empty())
+            || self.users.iter().any(|name| name == user)
+            || self.groups.iter().any(|group| groups.contains(group))
+    }
+
+    fn validate(&self) -> Result<()> {
+        let mut components = Path::new(&self.name).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(_)), None) => Ok(()),
+            _ => bail!("Share name {:?} has to be a single file name", self.name),
+        }
+    }
+}
+
+/// Grants and revokes permissions under `path`, as clients see it, to the
+/// `users` and members of the `groups`, or to everyone if both are empty
+///
+/// ```toml
+/// [[acl]]
+/// path = "/incoming"
+/// groups = ["partners"]
+/// allow = ["upload"]
+///
+/// [[acl]]
+/// path = "/archive"
+/// deny = ["upload", "delete"]
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AclRule {
+    pub path: PathBuf,
+
+    #[serde(default)]
+    pub users: Vec<String>,
+
+    #[serde(default)]
+    pub groups: Vec<String>,
+
+    #[serde(default)]
+    pub allow: Vec<Permission>,
+
+    #[serde(default)]
+    pub deny: Vec<Permission>,
+}
+
+impl AclRule {
+    /// Returns `true` if the rule covers `path` for `user`, a member of
+    /// `groups`.
+    pub fn applies(&self, path: &Path, user: &str, groups: &[String]) -> bool {
+        let member = (self.users.is_empty() && self.groups.is_empty())
+            || self.users.iter().any(|name| name == user)
+            || self.groups.iter().any(|group| groups.contains(group));
+        member && path.starts_with(&self.path)
+    }
+
+    /// Applies the rule to `permissions`.
+    pub fn apply(&self, permissions: &mut Vec<Permission>) {
+        for permission in &self.allow {
+            if !permissions.contains(permission) {
+                permissions.push(*permission);
+            }
+        }
+        permissions.retain(|permission| !self.deny.contains(permission));
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UploadsConfig {
+    /// Number of workers running the post-upload actions
+    pub workers: usize,
+
+    /// Directories whose uploads are post-processed
+    pub mounts: Vec<UploadMount>,
+
+    /// Reserves the space announced with `ALLO` before the next `STOR`
+    pub preallocate: bool,
+
+    /// Bytes kept free on the filesystem, uploads that would need them
+    /// are refused before any data is received
+    pub reserved_space: u64,
+
+    /// What's done with the part of an upload received before it failed
+    pub partial: PartialUploads,
+
+    /// Directory beneath the root partial uploads are moved to, with
+    /// `partial = "quarantine"`
+    pub quarantine: PathBuf,
+
+    /// Mode bits of the files uploads create, like `0o640`, instead of
+    /// the ones the umask of the server leaves
+    pub file_mode: Option<u32>,
+
+    /// Mode bits of the directories the server creates, like homes and
+    /// the dated directories of uploads
+    pub directory_mode: Option<u32>,
+
+    /// Seconds a post-upload hook may run for before it's killed
+    pub hook_timeout_seconds: u64,
+}
+
+impl Default for UploadsConfig {
+    fn default() -> Self {
+        Self {
+            workers: 2,
+            mounts: Vec::new(),
+            preallocate: false,
+            reserved_space: 0,
+            partial: PartialUploads::default(),
+            quarantine: PathBuf::from(".partial"),
+            file_mode: None,
+            directory_mode: None,
+            hook_timeout_seconds: 300,
+        }
+    }
+}
+
+/// What's done with an upload that failed midway, because the client went
+/// away or the data couldn't be received or stored
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PartialUploads {
+    /// Kept with the name of the upload, to be resumed with `REST` or
+    /// `APPE`
+    Keep,
+
+    /// Removed, or truncated back to where an append or restart started
+    #[default]
+    Delete,
+
+    /// Moved to the quarantine directory, with the time it failed added
+    /// to its name, and truncated back if it was an append or restart
+    Quarantine,
+}
+
+/// A directory with the actions to run, in order,
+/// on every file uploaded below it.
+///
+/// ```toml
+/// [[uploads.mounts]]
+/// path = "incoming"
+/// actions = [
+///     { action = "move-dated", format = "%Y/%m/%d" },
+///     { action = "chmod", mode = 0o640 },
+///     { action = "gzip" },
+///     { action = "hook", command = "/usr/local/bin/notify" },
+/// ]
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UploadMount {
+    pub path: PathBuf,
+    pub actions: Vec<PostUploadAction>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "action", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum PostUploadAction {
+    /// Moves the file into a subdirectory named after the upload date,
+    /// formatted with `chrono` format specifiers, unless a file of its name
+    /// is there already
+    MoveDated {
+        #[serde(default = "default_dated_format")]
+        format: String,
+    },
+
+    /// Sets the file mode bits
+    Chmod { mode: u32 },
+
+    /// Sets the file owner and/or group
+    Chown { uid: Option<u32>, gid: Option<u32> },
+
+    /// Compresses the file, replacing it with `<name>.gz` unless that
+    /// exists already
+    Gzip,
+
+    /// Runs `command` with `args` followed by the file path, killed after
+    /// `hook_timeout_seconds`
+    Hook {
+        command: PathBuf,
+        #[serde(default)]
+        args: Vec<String>,
+    },
+}
+
+fn default_dated_format() -> String {
+    "%Y-%m-%d".to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_are_relative_to_the_config_file() {
+        let directory = tempfile::tempdir().unwrap();
+        let base = directory.path();
+        std::fs::create_dir(base.join("auth")).unwrap();
+        std::fs::write(
+            base.join("auth/users.toml"),
+            "[[users]]\nname = \"bob\"\npassword = \"secret\"\nhome = \"bob\"\n",
+        )
+        .unwrap();
+        std::fs::write(
+            base.join("ftpy.toml"),
+            r#"
+            root = "served"
+
+            [[users]]
+            name = "alice"
+            password = "secret"
+            home = "homes/alice"
+
+            [[users]]
+            name = "carol"
+            password = "secret"
+
+            [virtual_users]
+            home = "homes/{user}"
+
+            [auth]
+            users_file = "auth/users.toml"
+
+            [[hosts]]
+            name = "example.com"
+            root = "/srv/example"
+
+            [[shares]]
+            name = "public"
+            path = "public"
+
+            [tls]
+            certificate = "cert.pem"
+
+            [record]
+            directory = "transcripts"
+
+            [[uploads.mounts]]
+            path = "incoming"
+            actions = [{ action = "hook", command = "notify" }]
+            "#,
+        )
+        .unwrap();
+
+        let config = Config::load(&base.join("ftpy.toml")).unwrap();
+        let home = |name: &str| {
+            let user = config.users.iter().find(|user| user.name == name);
+            user.unwrap().home.clone().unwrap()
+        };
+        assert_eq!(config.root, Some(base.join("served")));
+        assert_eq!(home("alice"), base.join("homes/alice"));
+        assert_eq!(home("carol"), base.join("homes/carol"));
+        assert_eq!(home("bob"), base.join("auth/bob"));
+        assert_eq!(config.hosts[0].root, Path::new("/srv/example"));
+        assert_eq!(config.shares[0].path, base.join("public"));
+        assert_eq!(config.tls.certificate, Some(base.join("cert.pem")));
+        assert_eq!(config.record.directory, Some(base.join("transcripts")));
+        // Beneath the root, and looked up in `PATH`
+        let mount = &config.uploads.mounts[0];
+        assert_eq!(mount.path, Path::new("incoming"));
+        let PostUploadAction::Hook { command, .. } = &mount.actions[0] else {
+            unreachable!();
+        };
+        assert_eq!(command, Path::new("notify"));
+    }
+
+    #[test]
+    fn configs_built_in_code_are_prepared_like_loaded_ones() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [[users]]
+            name = "alice"
+            password = "secret"
+
+            [virtual_users]
+            home = "/srv/ftp/{user}"
+
+            [[acl]]
+            path = "/incoming/private"
+            deny = ["download"]
+
+            [[acl]]
+            path = "/incoming"
+            allow = ["upload"]
+            "#,
+        )
+        .unwrap();
+        config.prepare().unwrap();
+        config.prepare().unwrap();
+
+        assert_eq!(config.users[0].home, Some(PathBuf::from("/srv/ftp/alice")));
+        let paths: Vec<_> = config.acl.iter().map(|rule| rule.path.clone()).collect();
+        assert_eq!(paths, ["/incoming", "/incoming/private"].map(PathBuf::from));
+
+        config.uploads.quarantine = PathBuf::from("../outside");
+        assert!(config.prepare().is_err());
+    }
+
+    #[test]
+    fn certificates_inside_the_chroot_are_reloaded_from_it() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [tls]
+            certificate = "/srv/ftp/tls/cert.pem"
+            key = "/etc/ftpy/key.pem"
+            "#,
+        )
+        .unwrap();
+        config.chroot_tls(Path::new("/srv/ftp")).unwrap();
+
+        assert_eq!(config.tls.certificate, Some(PathBuf::from("/tls/cert.pem")));
+        assert_eq!(config.tls.key, Some(PathBuf::from("/etc/ftpy/key.pem")));
+    }
+}
@@ -0,0 +1,349 @@
+//! Directories downloaded whole, as tar archives built on the fly.
+//!
+//! With `data.archives`, `RETR` of `<dir>.tar`, when there's no such file
+//! but a directory `<dir>`, sends the directory as a GNU tar archive. The
+//! tree is walked and the files read one after the other as the archive is
+//! sent, so nothing is written to disk and only one directory's listing is
+//! held at a time.
+//!
+//! Only what the session could download file by file is in it: hidden
+//! names, and what the ACL rules or override files deny downloading, are
+//! left out. Symbolic links are stored as links, not followed.
+
+use std::{
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use tracing::*;
+
+use crate::config::Permission;
+use crate::storage::{FileKind, Metadata};
+use crate::InnerConnection;
+
+/// Size of the blocks of a tar archive
+pub const BLOCK: usize = 512;
+
+/// A file, directory or link of an archive
+#[derive(Debug, Clone)]
+pub struct Member {
+    /// The path of the file, relative to the root of the session
+    pub path: PathBuf,
+    /// Its name in the archive, starting with the archived directory
+    pub name: String,
+    pub metadata: Metadata,
+}
+
+impl Member {
+    /// The blocks announcing the member, followed by its contents if it's
+    /// a file.
+    pub fn header(&self) -> Vec<u8> {
+        let (kind, name) = match self.metadata.kind {
+            FileKind::Directory => (b'5', format!("{}/", self.name)),
+            FileKind::Symlink => (b'2', self.name.clone()),
+            FileKind::File => (b'0', self.name.clone()),
+        };
+        let link = match &self.metadata.link {
+            Some(link) if self.metadata.kind == FileKind::Symlink => {
+                link.to_string_lossy().replace('\\', "/")
+            }
+            _ => String::new(),
+        };
+        let modified = self
+            .metadata
+            .modified
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_secs())
+            .unwrap_or_default();
+
+        let mut blocks = Vec::with_capacity(BLOCK);
+        // GNU extensions for names that don't fit the header
+        if link.len() > 100 {
+            blocks.extend(long_name(b'K', &link));
+        }
+        if name.len() > 100 {
+            blocks.extend(long_name(b'L', &name));
+        }
+        let mut header = [0; BLOCK];
+        text(&mut header[0..100], &name);
+        number(
+            &mut header[100..108],
+            u64::from(self.metadata.mode & 0o7777),
+        );
+        number(&mut header[108..116], u64::from(self.metadata.uid));
+        number(&mut header[116..124], u64::from(self.metadata.gid));
+        number(&mut header[124..136], self.size());
+        number(&mut header[136..148], modified);
+        header[156] = kind;
+        text(&mut header[157..257], &link);
+        seal(&mut header);
+        blocks.extend(header);
+        blocks
+    }
+
+    /// The bytes of contents following the header
+    pub fn size(&self) -> u64 {
+        match self.metadata.kind {
+            FileKind::File => self.metadata.len,
+            _ => 0,
+        }
+    }
+}
+
+/// The zeros padding `size` bytes of contents to a whole block
+pub fn padding(size: u64) -> usize {
+    (BLOCK - (size % BLOCK as u64) as usize) % BLOCK
+}
+
+/// The two empty blocks ending an archive
+pub const END: [u8; 2 * BLOCK] = [0; 2 * BLOCK];
+
+/// The walk of a directory being archived, one directory at a time so
+/// only the files of the one being sent are held.
+#[derive(Debug)]
+pub struct Walk {
+    /// The directories left, relative to the root of the session, and
+    /// their names in the archive
+    pending: Vec<(PathBuf, String)>,
+}
+
+impl Walk {
+    /// Walks `directory`, relative to the root of the session, named
+    /// `name` in the archive.
+    pub fn new(directory: &Path, name: &str) -> Self {
+        Self {
+            pending: vec![(directory.to_path_buf(), name.to_string())],
+        }
+    }
+
+    /// The members of the next directory the session may download: the
+    /// directory itself and its files, before its subdirectories.
+    ///
+    /// Returns `None` once the whole tree was walked.
+    pub async fn next(&mut self, session: &InnerConnection) -> Option<Vec<Member>> {
+        let config = &session.context.config.hidden;
+        while let Some((directory, name)) = self.pending.pop() {
+            // With its own override file, which may deny all of it
+            let overrides = session.overrides(&directory).await;
+            let mut permissions = session.permissions_at(&session.root.join(&directory));
+            overrides.restrict(&mut permissions);
+            if !permissions.contains(&Permission::Download) {
+                debug!("Leaving {:?} out of the archive", directory);
+                continue;
+            }
+            let metadata = match session.storage.metadata(&directory).await {
+                Ok(metadata) => metadata,
+                Err(error) => {
+                    warn!("Could not archive {:?}: {}", directory, error);
+                    continue;
+                }
+            };
+            let mut entries = match session.storage.list(&directory).await {
+                Ok(entries) => entries,
+                Err(error) => {
+                    warn!("Could not archive {:?}: {}", directory, error);
+                    continue;
+                }
+            };
+            let mut members = vec![Member {
+                path: directory.clone(),
+                name: name.clone(),
+                metadata,
+            }];
+            entries.sort_by(|a, b| a.name.cmp(&b.name));
+            let mut directories = Vec::new();
+            for entry in entries {
+                if config.hides(&entry.name) || overrides.hides(&entry.name) {
+                    continue;
+                }
+                let path = directory.join(&entry.name);
+                let name = format!("{}/{}", name, entry.name);
+                if entry.metadata.is_dir() {
+                    // Checked once it's archived
+                    directories.push((path, name));
+                    continue;
+                }
+                let mut permissions = session.permissions_at(&session.root.join(&path));
+                overrides.restrict(&mut permissions);
+                if permissions.contains(&Permission::Download) {
+                    members.push(Member {
+                        path,
+                        name,
+                        metadata: entry.metadata,
+                    });
+                }
+            }
+            self.pending.extend(directories.into_iter().rev());
+            return Some(members);
+        }
+        None
+    }
+}
+
+/// The GNU blocks holding a name longer than a header can, for the next
+/// header.
+fn long_name(kind: u8, name: &str) -> Vec<u8> {
+    let size = name.len() as u64 + 1;
+    let mut header = [0; BLOCK];
+    text(&mut header[0..100], "././@LongLink");
+    number(&mut header[100..108], 0o644);
+    number(&mut header[108..116], 0);
+    number(&mut header[116..124], 0);
+    number(&mut header[124..136], size);
+    number(&mut header[136..148], 0);
+    header[156] = kind;
+    seal(&mut header);
+    let mut blocks = header.to_vec();
+    blocks.extend(name.as_bytes());
+    blocks.push(0);
+    blocks.resize(blocks.len() + padding(size), 0);
+    blocks
+}
+
+/// Writes `value` in `field`, cut to its length.
+fn text(field: &mut [u8], value: &str) {
+    let length = value.len().min(field.len());
+    field[..length].copy_from_slice(&value.as_bytes()[..length]);
+}
+
+/// Writes `value` in `field` in octal, or in base 256 if it doesn't fit,
+/// as GNU tar does for files over 8 GiB.
+fn number(field: &mut [u8], value: u64) {
+    let digits = field.len() - 1;
+    let octal = format!("{:0digits$o}", value);
+    if octal.len() <= digits {
+        field[..digits].copy_from_slice(octal.as_bytes());
+        field[digits] = 0;
+        return;
+    }
+    field.fill(0);
+    let bytes = value.to_be_bytes();
+    let start = field.len() - bytes.len();
+    field[start..].copy_from_slice(&bytes);
+    field[0] |= 0x80;
+}
+
+/// Sets the magic and checksum of a header.
+fn seal(header: &mut [u8; BLOCK]) {
+    header[257..265].copy_from_slice(b"ustar  \0");
+    header[148..156].fill(b' ');
+    let sum: u32 = header.iter().map(|&byte| u32::from(byte)).sum();
+    let checksum = format!("{:06o}\0 ", sum);
+    header[148..156].copy_from_slice(checksum.as_bytes());
+}
+
+/// Returns the directory `path` archives, if it names a `.tar` file.
+pub fn archived(path: &Path) -> Option<PathBuf> {
+    let name = path.file_name()?.to_str()?;
+    let directory = name.strip_suffix(".tar").filter(|name| !name.is_empty())?;
+    Some(path.with_file_name(directory))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn file(name: &str, len: u64) -> Member {
+        Member {
+            path: PathBuf::from(name),
+            name: name.to_string(),
+            metadata: Metadata {
+                kind: FileKind::File,
+                len,
+                modified: UNIX_EPOCH + Duration::from_secs(1_714_846_867),
+                mode: 0o100644,
+                nlink: 1,
+                uid: 1000,
+                gid: 100,
+                link: None,
+            },
+        }
+    }
+
+    /// The octal number in `field`, as tar reads it
+    fn octal(field: &[u8]) -> u64 {
+        let digits = field.iter().take_while(|byte| byte.is_ascii_digit());
+        digits.fold(0, |value, digit| value * 8 + u64::from(digit - b'0'))
+    }
+
+    /// The name in `field`, up to the first NUL
+    fn name(field: &[u8]) -> &str {
+        let length = field
+            .iter()
+            .position(|&byte| byte == 0)
+            .unwrap_or(field.len());
+        std::str::from_utf8(&field[..length]).unwrap()
+    }
+
+    /// Fails unless the checksum of `header` is right
+    fn assert_sealed(header: &[u8]) {
+        let mut blanked = header[..BLOCK].to_vec();
+        blanked[148..156].fill(b' ');
+        let sum: u64 = blanked.iter().map(|&byte| u64::from(byte)).sum();
+        assert_eq!(octal(&header[148..156]), sum);
+        assert_eq!(&header[257..265], b"ustar  \0");
+    }
+
+    #[test]
+    fn headers_hold_the_fields() {
+        let header = file("docs/guide.txt", 1234).header();
+
+        assert_eq!(header.len(), BLOCK);
+        assert_sealed(&header);
+        assert_eq!(name(&header[0..100]), "docs/guide.txt");
+        assert_eq!(octal(&header[100..108]), 0o644);
+        assert_eq!(octal(&header[108..116]), 1000);
+        assert_eq!(octal(&header[116..124]), 100);
+        assert_eq!(octal(&header[124..136]), 1234);
+        assert_eq!(octal(&header[136..148]), 1_714_846_867);
+        assert_eq!(header[156], b'0');
+        assert_eq!(padding(1234), 3 * BLOCK - 1234);
+        assert_eq!(padding(BLOCK as u64), 0);
+    }
+
+    #[test]
+    fn long_names_come_first() {
+        let long = format!("docs/{}.txt", "a".repeat(120));
+        let header = file(&long, 0).header();
+
+        // The `L` record and the name, then the header cutting it
+        assert_eq!(header.len(), 3 * BLOCK);
+        assert_sealed(&header);
+        assert_eq!(header[156], b'L');
+        assert_eq!(name(&header[0..100]), "././@LongLink");
+        assert_eq!(octal(&header[124..136]), long.len() as u64 + 1);
+        assert_eq!(name(&header[BLOCK..2 * BLOCK]), long);
+        let last = &header[2 * BLOCK..];
+        assert_sealed(last);
+        assert_eq!(name(&last[0..100]), &long[..100]);
+        assert_eq!(last[156], b'0');
+    }
+
+    #[test]
+    fn huge_sizes_are_in_base_256() {
+        let size = 9 * 1024 * 1024 * 1024;
+        let header = file("disk.img", size).header();
+
+        assert_sealed(&header);
+        let field = &header[124..136];
+        assert_eq!(field[0], 0x80);
+        assert_eq!(field[4..], size.to_be_bytes());
+        // Still octal up to 8 GiB
+        let header = file("disk.img", 8 * 1024 * 1024 * 1024 - 1).header();
+        assert_eq!(octal(&header[124..136]), 8 * 1024 * 1024 * 1024 - 1);
+    }
+
+    #[test]
+    fn tar_names_archive_their_directory() {
+        assert_eq!(archived(Path::new("docs.tar")), Some(PathBuf::from("docs")));
+        assert_eq!(
+            archived(Path::new("/srv/x.tar")),
+            Some(PathBuf::from("/srv/x"))
+        );
+        assert_eq!(archived(Path::new(".tar")), None);
+        assert_eq!(archived(Path::new("docs.tar.gz")), None);
+        assert_eq!(archived(Path::new("docs")), None);
+    }
+}
@@ -0,0 +1,142 @@
+//! Audit log, for retention apart from the debug log.
+//!
+//! With `audit.log` configured, logins, uploads, the files removed or
+//! moved, refused operations and `SITE` commands are appended to it as one
+//! JSON object per line, with the session, the real address of the client
+//! and its user if it caused the event:
+//!
+//! ```text
+//! {"time":"2024-05-04T18:21:07Z","session":4,"ip":"203.0.113.7","user":"alice","event":"login","anonymous":false}
+//! {"time":"2024-05-04T18:21:12Z","session":4,"ip":"203.0.113.7","user":"alice","event":"upload","path":"/report.pdf","bytes":52311,"append":false}
+//! {"time":"2024-05-04T18:21:15Z","event":"rename","from":"/srv/ftp/incoming/report.pdf","to":"/srv/ftp/incoming/2024/05/04/report.pdf"}
+//! ```
+//!
+//! The file is only ever appended to, and the fields of the events won't
+//! change.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    net::IpAddr,
+    path::Path,
+    sync::Mutex,
+};
+
+use chrono::Utc;
+use miette::*;
+use serde::Serialize;
+use tracing::*;
+
+/// Something worth keeping track of
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum AuditEvent<'a> {
+    Login {
+        anonymous: bool,
+    },
+    LoginFailed {
+        /// Whether the address was banned for failing too often
+        banned: bool,
+    },
+    Upload {
+        path: &'a str,
+        bytes: u64,
+        append: bool,
+        /// The checksum of the data received, like `SHA-256:9f86d0…`, with
+        /// `data.checksum` set
+        #[serde(skip_serializing_if = "Option::is_none")]
+        checksum: Option<String>,
+    },
+    Delete {
+        path: &'a str,
+    },
+    Rename {
+        from: &'a str,
+        to: &'a str,
+    },
+    /// An operation refused by the server
+    Denied {
+        code: u16,
+        reason: String,
+    },
+    Site {
+        verb: &'a str,
+        args: &'a [&'a str],
+        result: u16,
+        disabled: bool,
+    },
+    /// A post-upload action that failed, which stops the next ones
+    ActionFailed {
+        path: &'a str,
+        action: String,
+        error: String,
+    },
+}
+
+/// The session causing an event, missing for the ones of the server
+#[derive(Debug, Serialize)]
+pub struct Actor<'a> {
+    pub session: u64,
+    pub ip: IpAddr,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    time: String,
+    #[serde(flatten)]
+    actor: Option<Actor<'a>>,
+    #[serde(flatten)]
+    event: AuditEvent<'a>,
+}
+
+/// Appends the events to the audit log, if one is configured
+#[derive(Debug, Default)]
+pub struct AuditLog {
+    file: Option<Mutex<File>>,
+}
+
+impl AuditLog {
+    /// Opens the log at `path` for appending, creating it if needed.
+    pub fn open(path: Option<&Path>) -> Result<Self> {
+        let Some(path) = path else {
+            return Ok(Self::default());
+        };
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Could not open audit log {}", path.display()))?;
+        Ok(Self {
+            file: Some(Mutex::new(file)),
+        })
+    }
+
+    /// Records `event`, caused by `actor` if it's a session.
+    pub fn record(&self, actor: Option<Actor>, event: AuditEvent) {
+        let Some(file) = &self.file else {
+            return;
+        };
+        let line = Line {
+            time: Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string(),
+            actor,
+            event,
+        };
+        let line = match serde_json::to_string(&line) {
+            Ok(line) => line + "\n",
+            Err(error) => {
+                warn!("Could not serialize an audit event: {}", error);
+                return;
+            }
+        };
+        let Ok(mut file) = file.lock() else {
+            return;
+        };
+        // A single write, so lines of concurrent sessions don't interleave
+        if let Err(error) = file.write_all(line.as_bytes()) {
+            warn!("Could not write the audit log: {}", error);
+        }
+    }
+}
@@ -0,0 +1,586 @@
+//! Credential verification.
+//!
+//! Every verification takes the same amount of work whether the user
+//! exists or not, and whether the password matches or not, so that
+//! response timing doesn't leak which accounts exist. Unknown users are
+//! checked against a dummy hashed like the costliest stored password, and
+//! passwords stored in plain text or as digests are checked against it
+//! too once any is hashed. Users whose hash is cheaper than the costliest
+//! still answer faster, until they're hashed with the same parameters.
+//!
+//! Passwords are stored in plain text, as SHA-256 digests, or hashed with
+//! argon2id, bcrypt or the MD5 based APR1 of Apache. The first two can be
+//! upgraded to argon2id when their users log in.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+};
+
+use argon2::password_hash::{rand_core::OsRng, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use md5::Md5;
+use miette::*;
+use sha2::{Digest, Sha256};
+
+use crate::config::{Permission, UserConfig, UsersFile};
+
+/// Compared against when the requested user doesn't exist,
+/// so unknown users cost as much as known ones.
+const DUMMY_PASSWORD: &str = "dummy password for unknown users";
+
+/// Progress of the login sequence of a connection
+///
+/// Check: https://datatracker.ietf.org/doc/html/rfc959#page-50
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LoginState {
+    #[default]
+    LoggedOut,
+    /// `USER` was accepted, waiting for `PASS`
+    NeedPassword,
+    /// The password was accepted, waiting for `ACCT`
+    NeedAccount,
+    LoggedIn,
+}
+
+/// How a password is stored
+#[derive(Debug, Clone)]
+pub enum PasswordHash {
+    Plain(String),
+    /// `sha256:` followed by the hexadecimal digest of the password
+    Sha256([u8; 32]),
+    /// A PHC string starting with `$argon2`
+    Argon2(String),
+    /// A hash starting with `$2a$`, `$2b$` or `$2y$`
+    Bcrypt(String),
+    /// A hash starting with `$apr1$`, as made by `htpasswd -m`
+    Apr1(String),
+    /// Never matches, for users without a valid password
+    Locked,
+}
+
+impl PasswordHash {
+    pub fn parse(hash: &str) -> Result<Self> {
+        if hash.starts_with("$argon2") {
+            argon2::PasswordHash::new(hash).map_err(|error| miette!("{}", error))?;
+            return Ok(Self::Argon2(hash.to_string()));
+        }
+        if ["$2a$", "$2b$", "$2y$"]
+            .iter()
+            .any(|prefix| hash.starts_with(prefix))
+        {
+            hash.parse::<bcrypt::HashParts>().into_diagnostic()?;
+            return Ok(Self::Bcrypt(hash.to_string()));
+        }
+        if let Some(rest) = hash.strip_prefix(APR1_MAGIC) {
+            match rest.split_once('$') {
+                Some((salt, digest)) if salt.len() <= 8 && digest.len() == 22 => {}
+                _ => bail!("Expected `$apr1$`, a salt, `$` and 22 characters"),
+            }
+            return Ok(Self::Apr1(hash.to_string()));
+        }
+        let Some(digest) = hash.strip_prefix("sha256:") else {
+            bail!(
+                help = "Use an argon2 or bcrypt hash, or `sha256:` and the hexadecimal digest",
+                "Unknown hash format"
+            );
+        };
+        if digest.len() != 64 || !digest.is_ascii() {
+            bail!("Expected 64 hexadecimal digits");
+        }
+        let mut bytes = [0u8; 32];
+        for (byte, pair) in bytes.iter_mut().zip(digest.as_bytes().chunks(2)) {
+            let pair = std::str::from_utf8(pair).into_diagnostic()?;
+            *byte = u8::from_str_radix(pair, 16)
+                .into_diagnostic()
+                .wrap_err("Expected 64 hexadecimal digits")?;
+        }
+        Ok(Self::Sha256(bytes))
+    }
+
+    /// Hashes `password` with argon2id and a random salt.
+    pub fn argon2(password: &str) -> Result<Self> {
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map_err(|error| miette!("Could not hash the password: {}", error))?;
+        Ok(Self::Argon2(hash.to_string()))
+    }
+
+    /// Returns `true` for the slow hashes, which would make logins of
+    /// unknown users stand out if they were checked against a fast one.
+    fn is_slow(&self) -> bool {
+        matches!(self, Self::Argon2(_) | Self::Bcrypt(_) | Self::Apr1(_))
+    }
+
+    /// Roughly the microseconds a verification takes, to compare the cost
+    /// of hashes of different algorithms and parameters.
+    fn work(&self) -> u64 {
+        match self {
+            Self::Plain(_) | Self::Sha256(_) | Self::Locked => 0,
+            // A pass over each KiB block of memory, about a microsecond
+            Self::Argon2(hash) => argon2::PasswordHash::new(hash)
+                .ok()
+                .and_then(|hash| argon2::Params::try_from(&hash).ok())
+                .map_or(0, |params| {
+                    u64::from(params.m_cost()) * u64::from(params.t_cost())
+                }),
+            // The key setup repeated 2^cost times, about 60µs each
+            Self::Bcrypt(hash) => hash
+                .parse::<bcrypt::HashParts>()
+                .map_or(0, |parts| 60 << parts.get_cost().min(31)),
+            // A thousand rounds of MD5
+            Self::Apr1(_) => 300,
+        }
+    }
+
+    /// Hashes `password` with the algorithm and parameters of this hash,
+    /// and a random salt.
+    fn rehash(&self, password: &str) -> Result<Self> {
+        match self {
+            Self::Argon2(hash) => {
+                let hash = argon2::PasswordHash::new(hash).map_err(|error| miette!("{}", error))?;
+                let algorithm = argon2::Algorithm::try_from(hash.algorithm)
+                    .map_err(|error| miette!("{}", error))?;
+                let version = match hash.version {
+                    Some(version) => {
+                        argon2::Version::try_from(version).map_err(|error| miette!("{}", error))?
+                    }
+                    None => argon2::Version::default(),
+                };
+                let params =
+                    argon2::Params::try_from(&hash).map_err(|error| miette!("{}", error))?;
+                let salt = SaltString::generate(&mut OsRng);
+                let hash = Argon2::new(algorithm, version, params)
+                    .hash_password(password.as_bytes(), &salt)
+                    .map_err(|error| miette!("Could not hash the password: {}", error))?;
+                Ok(Self::Argon2(hash.to_string()))
+            }
+            Self::Bcrypt(hash) => {
+                let cost = hash
+                    .parse::<bcrypt::HashParts>()
+                    .into_diagnostic()?
+                    .get_cost();
+                Ok(Self::Bcrypt(
+                    bcrypt::hash(password, cost).into_diagnostic()?,
+                ))
+            }
+            Self::Apr1(_) => {
+                let salt = SaltString::generate(&mut OsRng);
+                Ok(Self::Apr1(apr1(password, &salt.as_str()[..8])))
+            }
+            Self::Plain(_) => Ok(Self::Plain(password.to_string())),
+            Self::Sha256(_) => Ok(Self::Sha256(Sha256::digest(password.as_bytes()).into())),
+            Self::Locked => Ok(Self::Locked),
+        }
+    }
+
+    /// Returns `true` if the password should be hashed with argon2id.
+    fn is_legacy(&self) -> bool {
+        matches!(self, Self::Plain(_) | Self::Sha256(_) | Self::Apr1(_))
+    }
+
+    /// Checks `password` against the hash in constant time.
+    fn verify(&self, password: &str) -> bool {
+        match self {
+            Self::Plain(expected) => constant_time_eq(expected.as_bytes(), password.as_bytes()),
+            Self::Sha256(expected) => {
+                let digest = Sha256::digest(password.as_bytes());
+                constant_time_eq(expected, &digest)
+            }
+            Self::Argon2(hash) => argon2::PasswordHash::new(hash).is_ok_and(|hash| {
+                Argon2::default()
+                    .verify_password(password.as_bytes(), &hash)
+                    .is_ok()
+            }),
+            Self::Bcrypt(hash) => bcrypt::verify(password, hash).unwrap_or(false),
+            Self::Apr1(hash) => {
+                let salt = hash[APR1_MAGIC.len()..]
+                    .split('$')
+                    .next()
+                    .unwrap_or_default();
+                constant_time_eq(apr1(password, salt).as_bytes(), hash.as_bytes())
+            }
+            Self::Locked => false,
+        }
+    }
+
+    /// The hash as it's written in the configuration
+    fn to_config(&self) -> Option<String> {
+        match self {
+            Self::Argon2(hash) | Self::Bcrypt(hash) | Self::Apr1(hash) => Some(hash.clone()),
+            Self::Plain(_) | Self::Sha256(_) | Self::Locked => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Credentials {
+    password: RwLock<PasswordHash>,
+    account: Option<String>,
+    hosts: Vec<String>,
+    home: Option<PathBuf>,
+    quota: Option<u64>,
+    permissions: Option<Vec<Permission>>,
+    groups: Vec<String>,
+    /// Subject of the client certificate the user has to present
+    certificate: Option<String>,
+    /// The users file the user was loaded from, if not the config file
+    source: Option<PathBuf>,
+}
+
+impl Credentials {
+    fn allows(&self, host: Option<&str>) -> bool {
+        match host {
+            _ if self.hosts.is_empty() => true,
+            Some(host) => self
+                .hosts
+                .iter()
+                .any(|name| name.eq_ignore_ascii_case(host)),
+            None => false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct UserStore {
+    users: HashMap<String, Credentials>,
+    /// Checked for unknown users, hashed like the costliest stored password
+    dummy: PasswordHash,
+    /// Serializes the rewrites of users files
+    rewrite: Arc<Mutex<()>>,
+}
+
+impl UserStore {
+    /// Returns `true` if no credentials are configured, in which case
+    /// every login is refused unless `auth.allow_any` is set.
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+
+    /// Checks the credentials in constant time, and that the user
+    /// may log in to the virtual `host` selected with `HOST`.
+    ///
+    /// Slow hashes take a while, so this is better called off the runtime.
+    pub fn verify(&self, username: &str, password: &str, host: Option<&str>) -> bool {
+        let (expected, allowed) = self.expected(username, host);
+        let matches = expected.verify(password);
+        if self.pads(&expected) {
+            std::hint::black_box(self.dummy.verify(password));
+        }
+        matches & allowed
+    }
+
+    /// The hash the password of `username` is checked against, the dummy
+    /// if the user is unknown or locked, and whether they may log in to
+    /// `host`.
+    fn expected(&self, username: &str, host: Option<&str>) -> (PasswordHash, bool) {
+        let Some(credentials) = self.users.get(username) else {
+            return (self.dummy.clone(), false);
+        };
+        match credentials.password.read().unwrap().clone() {
+            PasswordHash::Locked => (self.dummy.clone(), false),
+            hash => (hash, credentials.allows(host)),
+        }
+    }
+
+    /// Returns `true` if checking `hash` is followed by checking the dummy,
+    /// so fast hashes take as long as slow ones.
+    fn pads(&self, hash: &PasswordHash) -> bool {
+        !hash.is_slow() && self.dummy.is_slow()
+    }
+
+    /// Returns `true` if the password of the user is stored in plain text
+    /// or as a SHA-256 digest in a users file, which can be upgraded.
+    pub fn needs_upgrade(&self, username: &str) -> bool {
+        self.users.get(username).is_some_and(|credentials| {
+            credentials.source.is_some() && credentials.password.read().unwrap().is_legacy()
+        })
+    }
+
+    /// Hashes the verified `password` of the user with argon2id, replacing
+    /// the old one in memory and in the users file it came from.
+    pub async fn upgrade(&self, username: &str, password: &str) -> Result<()> {
+        let Some(credentials) = self.users.get(username) else {
+            bail!("Unknown user {:?}", username);
+        };
+        let Some(source) = credentials.source.clone() else {
+            bail!("User {:?} is not from a users file", username);
+        };
+        let (name, password) = (username.to_string(), password.to_string());
+        let rewrite = self.rewrite.clone();
+        let hash = tokio::task::spawn_blocking(move || {
+            let hash = PasswordHash::argon2(&password)?;
+            let config = hash.to_config().unwrap_or_default();
+            let _guard = rewrite.lock().unwrap();
+            UsersFile::set_password_hash(&source, &name, &config)?;
+            Ok::<_, miette::Error>(hash)
+        })
+        .await
+        .into_diagnostic()??;
+        *credentials.password.write().unwrap() = hash;
+        Ok(())
+    }
+
+    /// The initial working directory of the user, if configured.
+    pub fn home(&self, username: &str) -> Option<&Path> {
+        self.users
+            .get(username)
+            .and_then(|credentials| credentials.home.as_deref())
+    }
+
+    /// Bytes the files in the home of the user may take, if limited.
+    pub fn quota(&self, username: &str) -> Option<u64> {
+        self.users
+            .get(username)
+            .and_then(|credentials| credentials.quota)
+    }
+
+    /// What the user may do, everything unless configured.
+    pub fn permissions(&self, username: &str) -> Vec<Permission> {
+        self.users
+            .get(username)
+            .and_then(|credentials| credentials.permissions.clone())
+            .unwrap_or_else(|| Permission::ALL.to_vec())
+    }
+
+    /// The groups the user is a member of.
+    pub fn groups(&self, username: &str) -> Vec<String> {
+        self.users
+            .get(username)
+            .map(|credentials| credentials.groups.clone())
+            .unwrap_or_default()
+    }
+
+    /// Returns `true` if the user doesn't need a client certificate, or
+    /// `subject` is the one of theirs.
+    pub fn accepts_certificate(&self, username: &str, subject: Option<&str>) -> bool {
+        match self
+            .users
+            .get(username)
+            .and_then(|credentials| credentials.certificate.as_deref())
+        {
+            Some(expected) => subject == Some(expected),
+            None => true,
+        }
+    }
+
+    /// Returns `true` if the client certificate with `subject` is the one
+    /// of the user, who may log in to the virtual `host`.
+    pub fn verify_certificate(&self, username: &str, subject: &str, host: Option<&str>) -> bool {
+        self.users.get(username).is_some_and(|credentials| {
+            credentials.certificate.as_deref() == Some(subject) && credentials.allows(host)
+        })
+    }
+
+    /// Returns `true` if the user has to send `ACCT` to complete the login.
+    pub fn requires_account(&self, username: &str) -> bool {
+        self.users
+            .get(username)
+            .is_some_and(|credentials| credentials.account.is_some())
+    }
+
+    /// Checks the account of a user in constant time.
+    pub fn verify_account(&self, username: &str, account: &str) -> bool {
+        match self
+            .users
+            .get(username)
+            .and_then(|credentials| credentials.account.as_deref())
+        {
+            Some(expected) => constant_time_eq(expected.as_bytes(), account.as_bytes()),
+            None => false,
+        }
+    }
+}
+
+impl Default for UserStore {
+    fn default() -> Self {
+        Self::from([].as_slice())
+    }
+}
+
+impl From<&[UserConfig]> for UserStore {
+    fn from(users: &[UserConfig]) -> Self {
+        let users: HashMap<_, _> = users
+            .iter()
+            .map(|user| {
+                // Hashes were validated when the config was loaded
+                let password = match (&user.password, &user.password_hash) {
+                    (Some(password), _) => PasswordHash::Plain(password.clone()),
+                    (None, Some(hash)) => PasswordHash::parse(hash).unwrap_or(PasswordHash::Locked),
+                    (None, None) => PasswordHash::Locked,
+                };
+                let credentials = Credentials {
+                    password: RwLock::new(password),
+                    account: user.account.clone(),
+                    hosts: user.hosts.clone(),
+                    home: user.home.clone(),
+                    quota: user.quota,
+                    permissions: user.permissions.clone(),
+                    groups: user.groups.clone(),
+                    certificate: user.certificate.clone(),
+                    source: user.source.clone(),
+                };
+                (user.name.clone(), credentials)
+            })
+            .collect();
+        let costliest = users
+            .values()
+            .map(|credentials| credentials.password.read().unwrap().clone())
+            .max_by_key(PasswordHash::work);
+        let dummy = match costliest {
+            Some(hash) if hash.is_slow() => hash
+                .rehash(DUMMY_PASSWORD)
+                .or_else(|_| PasswordHash::argon2(DUMMY_PASSWORD))
+                .unwrap_or(PasswordHash::Locked),
+            _ => PasswordHash::Plain(DUMMY_PASSWORD.to_string()),
+        };
+        Self {
+            users,
+            dummy,
+            rewrite: Arc::default(),
+        }
+    }
+}
+
+const APR1_MAGIC: &str = "$apr1$";
+
+/// Hashes `password` with the MD5 based crypt of Apache and `salt`.
+///
+/// Check: https://httpd.apache.org/docs/2.4/misc/password_encryptions.html
+fn apr1(password: &str, salt: &str) -> String {
+    let (password, salt) = (password.as_bytes(), salt.as_bytes());
+    let alternate = Md5::new()
+        .chain_update(password)
+        .chain_update(salt)
+        .chain_update(password)
+        .finalize();
+    let mut context = Md5::new()
+        .chain_update(password)
+        .chain_update(APR1_MAGIC)
+        .chain_update(salt);
+    for chunk in password.chunks(16) {
+        context.update(&alternate[..chunk.len()]);
+    }
+    let mut length = password.len();
+    while length > 0 {
+        match length & 1 {
+            1 => context.update([0]),
+            _ => context.update(&password[..1]),
+        }
+        length >>= 1;
+    }
+    let mut digest = context.finalize();
+
+    // Slows down brute forcing
+    for round in 0..1000 {
+        let mut context = Md5::new();
+        match round & 1 {
+            1 => context.update(password),
+            _ => context.update(digest),
+        }
+        if round % 3 != 0 {
+            context.update(salt);
+        }
+        if round % 7 != 0 {
+            context.update(password);
+        }
+        match round & 1 {
+            1 => context.update(digest),
+            _ => context.update(password),
+        }
+        digest = context.finalize();
+    }
+
+    const ALPHABET: &[u8] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+    let mut hash = format!("{}{}$", APR1_MAGIC, String::from_utf8_lossy(salt));
+    let mut encode = |mut value: u32, characters: usize| {
+        for _ in 0..characters {
+            hash.push(ALPHABET[(value & 0x3f) as usize] as char);
+            value >>= 6;
+        }
+    };
+    for (a, b, c) in [(0, 6, 12), (1, 7, 13), (2, 8, 14), (3, 9, 15), (4, 10, 5)] {
+        let value = u32::from(digest[a]) << 16 | u32::from(digest[b]) << 8 | u32::from(digest[c]);
+        encode(value, 4);
+    }
+    encode(u32::from(digest[11]), 2);
+    hash
+}
+
+/// Compares two byte strings without short-circuiting.
+///
+/// Both inputs are hashed first so that the comparison
+/// doesn't depend on their lengths either.
+#[inline(never)]
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let a = Sha256::digest(a);
+    let b = Sha256::digest(b);
+    let diff = a
+        .iter()
+        .zip(b.iter())
+        .fold(0u8, |diff, (a, b)| diff | (a ^ b));
+    std::hint::black_box(diff) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(name: &str, password_hash: &str) -> UserConfig {
+        let user = format!("name = {:?}\npassword_hash = {:?}", name, password_hash);
+        toml::from_str(&user).unwrap()
+    }
+
+    /// The work of checking a password of `username`
+    fn work(store: &UserStore, username: &str) -> u64 {
+        let (expected, _) = store.expected(username, None);
+        let padding = match store.pads(&expected) {
+            true => store.dummy.work(),
+            false => 0,
+        };
+        expected.work() + padding
+    }
+
+    #[test]
+    fn unknown_users_cost_as_much_as_the_costliest() {
+        let bcrypt = bcrypt::hash("secret", 6).unwrap();
+        let argon2 = "$argon2id$v=19$m=64,t=1,p=1$c2FsdHNhbHQ$1aHUhO4PfqZSO5x0bA9HUQ";
+        let users = [
+            user("cheap", argon2),
+            user("costly", &bcrypt),
+            user("digest", &format!("sha256:{}", "0".repeat(64))),
+        ];
+        let store = UserStore::from(users.as_slice());
+
+        assert!(matches!(store.dummy, PasswordHash::Bcrypt(_)));
+        assert_eq!(work(&store, "unknown"), work(&store, "costly"));
+        assert_eq!(work(&store, "digest"), work(&store, "costly"));
+        assert!(work(&store, "cheap") < work(&store, "costly"));
+
+        assert!(store.verify("costly", "secret", None));
+        assert!(!store.verify("unknown", DUMMY_PASSWORD, None));
+    }
+
+    #[test]
+    fn fast_hashes_alone_stay_fast() {
+        let store =
+            UserStore::from([user("digest", &format!("sha256:{}", "0".repeat(64)))].as_slice());
+
+        assert_eq!(work(&store, "unknown"), 0);
+        assert_eq!(work(&store, "digest"), 0);
+    }
+
+    #[test]
+    fn the_dummy_keeps_the_parameters() {
+        let argon2 = "$argon2id$v=19$m=64,t=3,p=1$c2FsdHNhbHQ$1aHUhO4PfqZSO5x0bA9HUQ";
+        let store = UserStore::from([user("alice", argon2)].as_slice());
+
+        let PasswordHash::Argon2(dummy) = &store.dummy else {
+            panic!("Expected an argon2 dummy, got {:?}", store.dummy);
+        };
+        assert!(dummy.starts_with("$argon2id$v=19$m=64,t=3,p=1$"));
+        assert_eq!(work(&store, "unknown"), 64 * 3);
+    }
+}
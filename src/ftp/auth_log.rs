@@ -0,0 +1,91 @@
+//! Authentication log for intrusion prevention tools.
+//!
+//! With `auth.log` configured every login is appended to it as one line
+//! in a format that won't change, with the real address of the client
+//! whatever the `[privacy]` settings are:
+//!
+//! ```text
+//! 2024-05-04T18:21:07Z login=succeeded ip=203.0.113.7 user="alice"
+//! 2024-05-04T18:21:09Z login=failed ip=198.51.100.23 user="admin"
+//! 2024-05-04T18:21:15Z login=banned ip=198.51.100.23 user="admin"
+//! ```
+//!
+//! The address comes before the user name, which is quoted and escaped,
+//! so a user name can't forge it. `contrib/fail2ban` has a filter for it.
+
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io::Write,
+    net::IpAddr,
+    path::Path,
+    sync::Mutex,
+};
+
+use chrono::Utc;
+use miette::*;
+use tracing::*;
+
+/// How a login ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoginOutcome {
+    Succeeded,
+    Failed,
+    /// Failed, and the address is banned for failing too often
+    Banned,
+}
+
+impl fmt::Display for LoginOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Succeeded => write!(f, "succeeded"),
+            Self::Failed => write!(f, "failed"),
+            Self::Banned => write!(f, "banned"),
+        }
+    }
+}
+
+/// Appends the logins to the authentication log, if one is configured
+#[derive(Debug, Default)]
+pub struct AuthLog {
+    file: Option<Mutex<File>>,
+}
+
+impl AuthLog {
+    /// Opens the log at `path` for appending, creating it if needed.
+    pub fn open(path: Option<&Path>) -> Result<Self> {
+        let Some(path) = path else {
+            return Ok(Self::default());
+        };
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Could not open authentication log {}", path.display()))?;
+        Ok(Self {
+            file: Some(Mutex::new(file)),
+        })
+    }
+
+    /// Records a login of `username` from `address`.
+    pub fn login(&self, outcome: LoginOutcome, address: IpAddr, username: &str) {
+        let Some(file) = &self.file else {
+            return;
+        };
+        let line = format!(
+            "{} login={} ip={} user={:?}\n",
+            Utc::now().format("%Y-%m-%dT%H:%M:%SZ"),
+            outcome,
+            address,
+            username
+        );
+        let Ok(mut file) = file.lock() else {
+            return;
+        };
+        // A single write, so lines of concurrent sessions don't interleave
+        if let Err(error) = file.write_all(line.as_bytes()) {
+            warn!("Could not write the authentication log: {}", error);
+        }
+    }
+}
@@ -0,0 +1,116 @@
+//! Capabilities advertised by the server.
+//!
+//! Every command registers its keyword here, together with the `FEAT`
+//! line it declares, so that `FEAT` and `HELP` are derived from the
+//! commands that actually have a handler instead of drifting from them.
+
+use std::collections::BTreeSet;
+
+use crate::FTPCommand;
+
+#[derive(Debug, Clone, Default)]
+pub struct Capabilities {
+    commands: BTreeSet<&'static str>,
+    /// `FEAT` lines with the command providing them, if any
+    features: Vec<(Option<&'static str>, String)>,
+}
+
+impl Capabilities {
+    /// Registers a command with a handler, and its `FEAT` line if any.
+    pub fn register<'a, C: FTPCommand<'a>>(&mut self) -> &mut Self {
+        self.commands.insert(C::KEYWORD);
+        if let Some(feature) = C::feature() {
+            self.features.push((Some(C::KEYWORD), feature));
+        }
+        self
+    }
+
+    /// Removes a command disabled by the configuration,
+    /// together with its `FEAT` lines.
+    pub fn disable(&mut self, keyword: &str) -> &mut Self {
+        self.commands.remove(keyword);
+        self.features
+            .retain(|(command, _)| *command != Some(keyword));
+        self
+    }
+
+    /// Registers a `FEAT` line for a protocol extension
+    /// that isn't tied to a single command, like `UTF8`.
+    pub fn extension(&mut self, feature: impl Into<String>) -> &mut Self {
+        self.features.push((None, feature.into()));
+        self
+    }
+
+    /// Returns `true` if `keyword` has a handler.
+    pub fn supports(&self, keyword: &str) -> bool {
+        self.commands.contains(keyword)
+    }
+
+    /// The registered commands, sorted alphabetically.
+    pub fn commands(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.commands.iter().copied()
+    }
+
+    /// The `FEAT` lines, in registration order.
+    pub fn features(&self) -> impl Iterator<Item = &str> {
+        self.features.iter().map(|(_, feature)| feature.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Command;
+
+    /// Every command of RFC 959 and the extensions clients commonly send
+    const KEYWORDS: &[&str] = &[
+        "ABOR", "ACCT", "ADAT", "ALLO", "APPE", "AUTH", "CCC", "CDUP", "CONF", "CWD", "DELE",
+        "ENC", "EPRT", "EPSV", "FEAT", "HASH", "HELP", "HOST", "LANG", "LIST", "LPRT", "LPSV",
+        "MDTM", "MFCT", "MFF", "MFMT", "MIC", "MKD", "MLSD", "MLST", "MODE", "NLST", "NOOP",
+        "OPTS", "PASS", "PASV", "PBSZ", "PORT", "PROT", "PWD", "QUIT", "RANG", "REIN", "REST",
+        "RETR", "RMD", "RNFR", "RNTO", "SITE", "SIZE", "SMNT", "STAT", "STOR", "STOU", "STRU",
+        "SYST", "TYPE", "USER", "XCRC", "XCUP", "XCWD", "XMD5", "XMKD", "XPWD", "XRMD", "XSHA1",
+        "XSHA256", "XSHA512",
+    ];
+
+    /// Returns `true` if `keyword` is dispatched to a handler, with
+    /// arguments of the count and kind it takes.
+    fn parses(keyword: &str) -> bool {
+        let arguments: [&[&str]; 3] = [&[], &["1"], &["1", "2"]];
+        arguments
+            .iter()
+            .any(|args| Command::try_from((keyword, args.to_vec())).is_ok())
+    }
+
+    #[test]
+    fn every_registered_command_has_a_handler() {
+        for keyword in Command::capabilities(true).commands() {
+            assert!(
+                parses(keyword),
+                "{} is registered without a handler",
+                keyword
+            );
+        }
+    }
+
+    #[test]
+    fn every_handler_is_registered() {
+        let capabilities = Command::capabilities(true);
+        for keyword in KEYWORDS {
+            if parses(keyword) {
+                assert!(
+                    capabilities.supports(keyword),
+                    "{} has a handler but isn't registered",
+                    keyword
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn the_tls_commands_need_tls() {
+        let capabilities = Command::capabilities(false);
+        for keyword in ["AUTH", "PBSZ", "PROT", "CCC"] {
+            assert!(!capabilities.supports(keyword));
+        }
+    }
+}
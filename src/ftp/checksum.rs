@@ -0,0 +1,187 @@
+//! Checksums of files computed on the server, so clients can verify
+//! transfers without downloading the file again.
+//!
+//! Files are hashed in chunks as they're read, never loaded whole. With
+//! `data.checksum` set, the data of every transfer is also hashed as it
+//! goes through, without reading the file again.
+
+use std::{fmt::Write, io, path::Path};
+
+use serde::Deserialize;
+use sha2::digest::DynDigest;
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt},
+};
+
+/// Size of the chunks files are hashed in
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Algorithms of `HASH`, selected with `OPTS HASH`
+///
+/// Check: https://datatracker.ietf.org/doc/html/draft-bryan-ftpext-hash-02
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    Sha1,
+    Md5,
+    Crc32,
+}
+
+impl HashAlgorithm {
+    pub const ALL: [HashAlgorithm; 4] = [Self::Sha256, Self::Sha1, Self::Md5, Self::Crc32];
+
+    /// The name of the algorithm in the `HASH` replies
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Sha256 => "SHA-256",
+            Self::Sha1 => "SHA-1",
+            Self::Md5 => "MD5",
+            Self::Crc32 => "CRC32",
+        }
+    }
+
+    /// Parses an algorithm name, ignoring case.
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|algorithm| algorithm.name().eq_ignore_ascii_case(name))
+    }
+
+    /// The `FEAT` line listing the algorithms, with `*` on the selected one
+    pub fn feature(self) -> String {
+        let names = Self::ALL
+            .into_iter()
+            .map(|algorithm| match algorithm == self {
+                true => format!("{}*", algorithm.name()),
+                false => algorithm.name().to_string(),
+            })
+            .collect::<Vec<_>>();
+        format!("HASH {}", names.join(";"))
+    }
+
+    fn hasher(self) -> Hasher {
+        match self {
+            Self::Sha256 => Hasher::Digest(Box::<sha2::Sha256>::default()),
+            Self::Sha1 => Hasher::Digest(Box::<sha1::Sha1>::default()),
+            Self::Md5 => Hasher::Digest(Box::<md5::Md5>::default()),
+            Self::Crc32 => Hasher::Crc32(crc32fast::Hasher::new()),
+        }
+    }
+}
+
+enum Hasher {
+    Digest(Box<dyn DynDigest + Send>),
+    Crc32(crc32fast::Hasher),
+}
+
+impl Hasher {
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Digest(digest) => digest.update(data),
+            Self::Crc32(crc) => crc.update(data),
+        }
+    }
+
+    fn finalize(self) -> Box<[u8]> {
+        match self {
+            Self::Digest(digest) => digest.finalize(),
+            Self::Crc32(crc) => Box::new(crc.finalize().to_be_bytes()),
+        }
+    }
+}
+
+/// A checksum of data hashed in the chunks it goes by in, like the ones of
+/// a transfer
+pub struct RunningChecksum {
+    algorithm: HashAlgorithm,
+    hasher: Hasher,
+}
+
+impl RunningChecksum {
+    pub fn new(algorithm: HashAlgorithm) -> Self {
+        Self {
+            algorithm,
+            hasher: algorithm.hasher(),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+    }
+
+    /// The algorithm and lower case hexadecimal digest of the data so far
+    pub fn finish(self) -> (HashAlgorithm, String) {
+        (self.algorithm, hex(&self.hasher.finalize()))
+    }
+}
+
+/// An inclusive range of bytes, selected with `RANG`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+/// The checksum of a file, or of the range of it that was hashed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checksum {
+    pub algorithm: HashAlgorithm,
+    /// Lower case hexadecimal digest
+    pub digest: String,
+    pub start: u64,
+    /// The last byte hashed, `start` for empty ranges
+    pub end: u64,
+}
+
+/// Hashes the file at `path`, or its `range` clamped to the file length,
+/// reading it in chunks of the size of `buffer`.
+pub async fn checksum(
+    path: &Path,
+    algorithm: HashAlgorithm,
+    range: Option<ByteRange>,
+    buffer: &mut [u8],
+) -> io::Result<Checksum> {
+    let mut file = File::open(path).await?;
+    let length = file.metadata().await?.len();
+    let (start, mut remaining) = match range {
+        Some(range) => {
+            let start = range.start.min(length);
+            let end = range.end.saturating_add(1).min(length);
+            (start, end.saturating_sub(start))
+        }
+        None => (0, length),
+    };
+    file.seek(io::SeekFrom::Start(start)).await?;
+
+    let mut hasher = algorithm.hasher();
+    let hashed = remaining;
+    while remaining > 0 {
+        let chunk = buffer
+            .len()
+            .min(usize::try_from(remaining).unwrap_or(usize::MAX));
+        let read = file.read(&mut buffer[..chunk]).await?;
+        if read == 0 {
+            // The file was truncated while it was hashed
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        remaining -= read as u64;
+    }
+
+    Ok(Checksum {
+        algorithm,
+        digest: hex(&hasher.finalize()),
+        start,
+        end: start + (hashed - remaining).saturating_sub(1),
+    })
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut digest, byte| {
+        let _ = write!(digest, "{byte:02x}");
+        digest
+    })
+}
@@ -0,0 +1,70 @@
+//! The bits of an FTP client needed by the tools that talk to a server,
+//! like [`crate::transcript::replay`] and [`crate::self_check`].
+
+use std::{net::SocketAddr, time::Duration};
+
+use miette::*;
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    net::TcpStream,
+};
+
+/// Time to wait for each reply
+pub(crate) const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Returns the code of a reply line if it ends a reply, like `226 Done`.
+pub(crate) fn final_reply_code(line: &str) -> Option<u16> {
+    let code = line.get(..3)?.parse().ok()?;
+    matches!(line.as_bytes().get(3), None | Some(b' ')).then_some(code)
+}
+
+/// Reads the next final reply, or `None` if the server stops replying.
+pub(crate) async fn read_reply(
+    control: &mut BufReader<TcpStream>,
+) -> Result<Option<(u16, String)>> {
+    loop {
+        let mut line = String::new();
+        let Ok(read) = tokio::time::timeout(REPLY_TIMEOUT, control.read_line(&mut line)).await
+        else {
+            return Ok(None);
+        };
+        if read.into_diagnostic()? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if let Some(code) = final_reply_code(line) {
+            return Ok(Some((code, line.to_string())));
+        }
+    }
+}
+
+/// Parses the address of a `227 Entering Passive Mode (h1,h2,h3,h4,p1,p2)` reply
+pub(crate) fn passive_address(reply: &str) -> Option<SocketAddr> {
+    let start = reply.find('(')? + 1;
+    let end = reply[start..].find(')')? + start;
+    let numbers = reply[start..end]
+        .split(',')
+        .map(|number| number.trim().parse::<u8>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    let [h1, h2, h3, h4, p1, p2] = numbers[..] else {
+        return None;
+    };
+    Some(SocketAddr::from((
+        [h1, h2, h3, h4],
+        u16::from_be_bytes([p1, p2]),
+    )))
+}
+
+/// Parses the token a `227` or `229` reply ends with, like ` token=3kT9`,
+/// which has to be sent first on the data connection.
+pub(crate) fn data_token(reply: &str) -> Option<&str> {
+    let start = reply.rfind(" token=")? + " token=".len();
+    reply[start..].split_whitespace().next()
+}
+
+/// Parses the port of a `229 Entering Extended Passive Mode (|||port|)` reply
+pub(crate) fn extended_passive_port(reply: &str) -> Option<u16> {
+    let start = reply.find("(|||")? + 4;
+    let end = reply[start..].find("|)")? + start;
+    reply[start..end].parse().ok()
+}
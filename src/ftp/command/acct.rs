@@ -0,0 +1,55 @@
+use miette::*;
+use tracing::*;
+
+use crate::auth::LoginState;
+use crate::{ControlWriter, FTPCommand, InnerConnectionRef, StatusCode};
+
+pub struct Acct<'a>(&'a str);
+
+impl<'a> FTPCommand<'a> for Acct<'a> {
+    const KEYWORD: &'static str = "ACCT";
+
+    /// Completes the login of users configured with an account
+    ///
+    /// Check: https://datatracker.ietf.org/doc/html/rfc959#page-26
+    async fn run(
+        &self,
+        connection: InnerConnectionRef,
+        _writer: &mut ControlWriter,
+    ) -> Result<Option<StatusCode>> {
+        let mut connection = connection.lock().await;
+        match connection.login {
+            LoginState::NeedAccount => {}
+            LoginState::LoggedIn => return Ok(Some(StatusCode::SuperfluousCmdNotImplemented)),
+            LoginState::LoggedOut | LoginState::NeedPassword => {
+                return Ok(Some(StatusCode::CmdBadSequence))
+            }
+        }
+
+        let username = connection.username.clone().unwrap_or_default();
+        if connection.context.users.verify_account(&username, self.0) {
+            info!("User {:?} logged in", username);
+            connection.log_in();
+            Ok(Some(StatusCode::UserLoggedIn))
+        } else {
+            warn!("Failed login for user {:?}, invalid account", username);
+            Ok(Some(connection.fail_login(&username).await))
+        }
+    }
+}
+
+impl<'a> TryFrom<(&'a str, Vec<&'a str>)> for Acct<'a> {
+    type Error = miette::Error;
+
+    fn try_from((command, args): (&'a str, Vec<&'a str>)) -> Result<Self> {
+        if command == Self::KEYWORD {
+            if args.len() == 1 {
+                Ok(Self(args[0]))
+            } else {
+                Err(miette!("Invalid number of arguments"))
+            }
+        } else {
+            Err(miette!("Invalid command"))
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use miette::*;
+
+use tracing::*;
+
+use super::stor::has_space;
+use crate::root_dir::relative_to;
+use crate::{ControlWriter, Denial, FTPCommand, InnerConnectionRef, StatusCode};
+
+pub struct Allo<'a>(&'a str);
+
+impl<'a> FTPCommand<'a> for Allo<'a> {
+    const KEYWORD: &'static str = "ALLO";
+
+    /// Announces the size of the next upload, refused if it doesn't fit in
+    /// the quota or on the disk, and reserved if preallocation is enabled
+    ///
+    /// Check: https://datatracker.ietf.org/doc/html/rfc959#page-29
+    async fn run(
+        &self,
+        connection: InnerConnectionRef,
+        _writer: &mut ControlWriter,
+    ) -> Result<Option<StatusCode>> {
+        let Ok(size) = self.0.parse::<u64>() else {
+            return Ok(Some(StatusCode::SyntaxErrorParams));
+        };
+        let mut connection = connection.lock().await;
+        if let Some(account) = &connection.quota {
+            let storage = connection.storage.as_ref();
+            if !account.fits(storage, size).await.into_diagnostic()? {
+                debug!("Refusing {} bytes, past the quota", size);
+                let quota = account.quota();
+                return Ok(Some(connection.deny(Denial::Quota { quota })));
+            }
+        }
+        let reserved = connection.context.config.uploads.reserved_space;
+        let directory = relative_to(&connection.cwd, &connection.root);
+        match has_space(
+            connection.storage.as_ref(),
+            &directory,
+            Some(size),
+            reserved,
+        ) {
+            Ok(true) => {}
+            Ok(false) => {
+                debug!("Refusing {} bytes, not enough space is left", size);
+                let denial = Denial::NoSpace {
+                    requested: Some(size),
+                };
+                return Ok(Some(connection.deny(denial)));
+            }
+            Err(error) => warn!("Could not measure the space left: {}", error),
+        }
+        // Checked again before the next upload
+        connection.allocation = Some(size);
+        if !connection.context.config.uploads.preallocate {
+            return Ok(Some(StatusCode::SuperfluousCmdNotImplemented));
+        }
+        trace!("Reserving {} bytes for the next upload", size);
+        Ok(Some(StatusCode::Ok))
+    }
+}
+
+impl<'a> TryFrom<(&'a str, Vec<&'a str>)> for Allo<'a> {
+    type Error = miette::Error;
+
+    /// `ALLO <size> [R <record-size>]`, the record size is ignored
+    fn try_from((command, args): (&'a str, Vec<&'a str>)) -> Result<Self> {
+        if command == Self::KEYWORD {
+            match args.as_slice() {
+                [size] | [size, "R", _] => Ok(Self(size)),
+                _ => Err(miette!("Invalid number of arguments")),
+            }
+        } else {
+            Err(miette!("Invalid command"))
+        }
+    }
+}
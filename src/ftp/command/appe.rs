@@ -0,0 +1,42 @@
+use miette::*;
+
+use crate::{ControlWriter, FTPCommand, InnerConnectionRef, StatusCode};
+
+use super::stor::store;
+
+pub struct Appe<'a>(&'a str);
+
+impl<'a> FTPCommand<'a> for Appe<'a> {
+    const KEYWORD: &'static str = "APPE";
+
+    /// Appends the data received to a file, created if it doesn't exist
+    ///
+    /// Check: https://datatracker.ietf.org/doc/html/rfc959#section-4.1.3
+    async fn run(
+        &self,
+        connection: InnerConnectionRef,
+        writer: &mut ControlWriter,
+    ) -> Result<Option<StatusCode>> {
+        store(connection, writer, self.0, true).await
+    }
+
+    fn target(&self) -> Option<String> {
+        Some(self.0.to_string())
+    }
+}
+
+impl<'a> TryFrom<(&'a str, Vec<&'a str>)> for Appe<'a> {
+    type Error = miette::Error;
+
+    fn try_from((command, args): (&'a str, Vec<&'a str>)) -> Result<Self> {
+        if command == Self::KEYWORD {
+            if args.len() == 1 {
+                Ok(Self(args[0]))
+            } else {
+                Err(miette!("Invalid number of arguments"))
+            }
+        } else {
+            Err(miette!("Invalid command"))
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use miette::*;
+
+use tracing::*;
+
+use crate::{ControlWriter, FTPCommand, InnerConnectionRef, StatusCode};
+
+pub struct Auth<'a>(&'a str);
+
+impl<'a> FTPCommand<'a> for Auth<'a> {
+    const KEYWORD: &'static str = "AUTH";
+
+    fn feature() -> Option<String> {
+        Some("AUTH TLS".to_string())
+    }
+
+    /// Accepts the TLS handshake, which the connection
+    /// performs right after sending the reply
+    ///
+    /// Check: https://datatracker.ietf.org/doc/html/rfc4217#section-4
+    async fn run(
+        &self,
+        connection: InnerConnectionRef,
+        _writer: &mut ControlWriter,
+    ) -> Result<Option<StatusCode>> {
+        let mut connection = connection.lock().await;
+        if connection.context.tls().is_none() {
+            return Ok(Some(StatusCode::CmdNotImplemented));
+        }
+        if !matches!(self.0.to_uppercase().as_str(), "TLS" | "TLS-C" | "SSL") {
+            return Ok(Some(StatusCode::CmdNotImplementedParam));
+        }
+        if connection.tls {
+            return Ok(Some(StatusCode::CmdBadSequence));
+        }
+
+        trace!("Starting TLS handshake");
+        connection.pending_tls = true;
+        Ok(Some(StatusCode::SecurityDataExchangeComplete))
+    }
+}
+
+impl<'a> TryFrom<(&'a str, Vec<&'a str>)> for Auth<'a> {
+    type Error = miette::Error;
+
+    fn try_from((command, args): (&'a str, Vec<&'a str>)) -> Result<Self> {
+        if command == Self::KEYWORD {
+            if args.len() == 1 {
+                Ok(Self(args[0]))
+            } else {
+                Err(miette!("Invalid number of arguments"))
+            }
+        } else {
+            Err(miette!("Invalid command"))
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use miette::*;
+
+use tracing::*;
+
+use crate::{ControlWriter, FTPCommand, InnerConnectionRef, StatusCode};
+
+pub struct Ccc;
+
+impl<'a> FTPCommand<'a> for Ccc {
+    const KEYWORD: &'static str = "CCC";
+
+    fn feature() -> Option<String> {
+        Some("CCC".to_string())
+    }
+
+    /// Clears the control connection, which the connection does right
+    /// after sending the reply. The protection of the data connections
+    /// selected with `PROT` is kept.
+    ///
+    /// Check: https://datatracker.ietf.org/doc/html/rfc4217#section-6
+    async fn run(
+        &self,
+        connection: InnerConnectionRef,
+        _writer: &mut ControlWriter,
+    ) -> Result<Option<StatusCode>> {
+        let mut connection = connection.lock().await;
+        if !connection.tls {
+            return Ok(Some(StatusCode::ProtectionLevelDenied));
+        }
+        if connection.context.config.tls.forbid_ccc {
+            return Ok(Some(StatusCode::PolicyDenied));
+        }
+
+        trace!("Clearing the control connection");
+        connection.pending_clear = true;
+        Ok(Some(StatusCode::CommandOk(
+            " Clearing the control connection".to_string(),
+        )))
+    }
+}
+
+impl<'a> TryFrom<(&'a str, Vec<&'a str>)> for Ccc {
+    type Error = miette::Error;
+
+    fn try_from((command, args): (&'a str, Vec<&'a str>)) -> Result<Self> {
+        if command == Self::KEYWORD {
+            if args.is_empty() {
+                Ok(Self)
+            } else {
+                Err(miette!("Invalid number of arguments"))
+            }
+        } else {
+            Err(miette!("Invalid command"))
+        }
+    }
+}
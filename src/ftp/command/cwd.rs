@@ -1,30 +1,47 @@
-use std::ffi::OsString;
-
 use miette::*;
 
-use tokio::net::tcp::WriteHalf;
 use tracing::*;
 
-use crate::{FTPCommand, InnerConnectionRef, StatusCode};
+use crate::messages::{continuation, directory_message, render};
+use crate::root_dir::relative_to;
+use crate::{ControlWriter, FTPCommand, InnerConnectionRef, StatusCode};
 
 pub struct Cwd<'a>(&'a str);
 
 impl<'a> FTPCommand<'a> for Cwd<'a> {
     const KEYWORD: &'static str = "CWD";
 
-    async fn run<'b>(
+    async fn run(
         &self,
         connection: InnerConnectionRef,
-        _writer: &mut WriteHalf<'b>,
+        _writer: &mut ControlWriter,
     ) -> Result<Option<StatusCode>> {
         trace!("Changing working directory");
-        let new_cwd = OsString::from(self.0);
-        trace!("New CWD: {:?}", new_cwd);
-        connection.lock().await.change_dir(new_cwd).await?;
+        trace!("New CWD: {:?}", self.0);
+        let mut connection = connection.lock().await;
+        if let Err(error) = connection.change_dir(self.0).await {
+            debug!("Could not change to {:?}: {}", self.0, error);
+            return Ok(Some(StatusCode::ActionNotTaken));
+        }
 
-        Ok(Some(StatusCode::FileActionOk(
-            " Directory successfully changed".to_string(),
-        )))
+        // The message of the directory, if it has one, comes first
+        let directory = relative_to(&connection.cwd, &connection.root);
+        let name = &connection.context.config.replies.directory_message;
+        let message = match directory_message(connection.storage.as_ref(), &directory, name).await {
+            Some(message) => Some(message),
+            // Set for the whole subtree by an override file otherwise
+            None => connection
+                .overrides(&directory)
+                .await
+                .message()
+                .map(str::to_string),
+        };
+        let message = message
+            .map(|message| continuation(250, &render(&message, &connection.context)))
+            .unwrap_or_default();
+        let reply = format!("{message}250 Directory successfully changed");
+        // The reply adds the code of its first line back
+        Ok(Some(StatusCode::FileActionOk(reply[3..].to_string())))
     }
 }
 
@@ -0,0 +1,58 @@
+use miette::*;
+use tracing::*;
+
+use crate::config::DataMode;
+use crate::{ControlWriter, FTPCommand, InnerConnectionRef, StatusCode};
+
+use super::pasv::{listen, passive_disabled};
+
+pub struct Epsv<'a>(Option<&'a str>);
+
+impl<'a> FTPCommand<'a> for Epsv<'a> {
+    const KEYWORD: &'static str = "EPSV";
+
+    fn feature() -> Option<String> {
+        Some("EPSV".to_string())
+    }
+
+    /// Like `PASV`, but only replies with the port of the data connection
+    ///
+    /// Check: https://datatracker.ietf.org/doc/html/rfc2428#section-3
+    async fn run(
+        &self,
+        connection: InnerConnectionRef,
+        writer: &mut ControlWriter,
+    ) -> Result<Option<StatusCode>> {
+        if !connection.lock().await.allows_data_mode(DataMode::Passive) {
+            return Ok(Some(passive_disabled()));
+        }
+
+        match self.0 {
+            None | Some("1") => listen(connection, writer, true).await,
+            Some(all) if all.eq_ignore_ascii_case("ALL") => {
+                trace!("Refusing every data connection command but EPSV");
+                connection.lock().await.epsv_all = true;
+                Ok(Some(StatusCode::CommandOk(" EPSV ALL ok".to_string())))
+            }
+            // Data connections are only listened for on IPv4
+            Some("2") => Ok(Some(StatusCode::NetworkProtocolNotSupported)),
+            Some(_) => Ok(Some(StatusCode::SyntaxErrorParams)),
+        }
+    }
+}
+
+impl<'a> TryFrom<(&'a str, Vec<&'a str>)> for Epsv<'a> {
+    type Error = miette::Error;
+
+    fn try_from((command, args): (&'a str, Vec<&'a str>)) -> Result<Self> {
+        if command == Self::KEYWORD {
+            if (0..=1).contains(&args.len()) {
+                Ok(Self(args.first().copied()))
+            } else {
+                Err(miette!("Invalid number of arguments"))
+            }
+        } else {
+            Err(miette!("Invalid command"))
+        }
+    }
+}
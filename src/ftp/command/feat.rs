@@ -1,27 +1,55 @@
 use miette::*;
 use tracing::*;
 
-use crate::{FTPCommand, InnerConnectionRef, StatusCode};
+use crate::{ControlWriter, FTPCommand, InnerConnectionRef, StatusCode};
 
 pub struct Feat;
 
 impl<'a> FTPCommand<'a> for Feat {
     const KEYWORD: &'static str = "FEAT";
 
-    async fn run<'b>(
+    async fn run(
         &self,
-        _connection: InnerConnectionRef,
-        _writer: &mut tokio::net::tcp::WriteHalf<'b>,
+        connection: InnerConnectionRef,
+        _writer: &mut ControlWriter,
     ) -> Result<Option<StatusCode>> {
         trace!("Reporting supported features");
-        Ok(Some(StatusCode::SystemStatus(
-            "-Features:
- MLST
- MLSD
- UTF8\
-"
-            .to_string(),
-        )))
+        let (context, facts, hash, language) = {
+            let connection = connection.lock().await;
+            (
+                connection.context.clone(),
+                connection.facts.feature(),
+                connection.hash.feature(),
+                connection.language.tag(),
+            )
+        };
+        let languages = context.languages.feature(&language);
+        // The `MLST`, `HASH` and `LANG` lines mark the facts, the algorithm
+        // and the language selected by this session
+        let features = context
+            .capabilities
+            .features()
+            .map(|feature| {
+                if feature.starts_with("MLST ") {
+                    facts.as_str()
+                } else if feature.starts_with("HASH ") {
+                    hash.as_str()
+                } else if feature.starts_with("LANG ") {
+                    languages.as_str()
+                } else {
+                    feature
+                }
+            })
+            .collect::<Vec<_>>();
+
+        // Every command with a feature may have been disabled
+        if features.is_empty() {
+            return Ok(Some(StatusCode::SystemStatus(" No features".to_string())));
+        }
+        Ok(Some(StatusCode::SystemStatus(format!(
+            "-Features:\n {}",
+            features.join("\n ")
+        ))))
     }
 }
 
@@ -0,0 +1,169 @@
+use std::io;
+
+use miette::*;
+use tracing::*;
+
+use crate::checksum::{checksum, ByteRange, Checksum, HashAlgorithm, CHUNK_SIZE};
+use crate::{ControlWriter, Denial, FTPCommand, InnerConnectionRef, StatusCode};
+
+pub struct Hash<'a>(&'a str);
+
+impl<'a> FTPCommand<'a> for Hash<'a> {
+    const KEYWORD: &'static str = "HASH";
+
+    fn feature() -> Option<String> {
+        Some(HashAlgorithm::default().feature())
+    }
+
+    /// Replies with the checksum of a file, or of the range selected
+    /// with `RANG`, using the algorithm selected with `OPTS HASH`
+    ///
+    /// Check: https://datatracker.ietf.org/doc/html/draft-bryan-ftpext-hash-02#section-3
+    async fn run(
+        &self,
+        connection: InnerConnectionRef,
+        _writer: &mut ControlWriter,
+    ) -> Result<Option<StatusCode>> {
+        let (algorithm, range) = {
+            let mut connection = connection.lock().await;
+            (connection.hash, connection.range.take())
+        };
+        let checksum = match hash_file(&connection, self.0, algorithm, range).await? {
+            Ok(checksum) => checksum,
+            Err(reply) => return Ok(Some(reply)),
+        };
+
+        Ok(Some(StatusCode::FileStatus(format!(
+            " {} {}-{} {} {}",
+            checksum.algorithm.name(),
+            checksum.start,
+            checksum.end,
+            checksum.digest,
+            self.0
+        ))))
+    }
+
+    fn target(&self) -> Option<String> {
+        Some(self.0.to_string())
+    }
+}
+
+impl<'a> TryFrom<(&'a str, Vec<&'a str>)> for Hash<'a> {
+    type Error = miette::Error;
+
+    fn try_from((command, args): (&'a str, Vec<&'a str>)) -> Result<Self> {
+        if command == Self::KEYWORD {
+            if args.len() == 1 {
+                Ok(Self(args[0]))
+            } else {
+                Err(miette!("Invalid number of arguments"))
+            }
+        } else {
+            Err(miette!("Invalid command"))
+        }
+    }
+}
+
+/// The arguments of the legacy `XCRC`, `XMD5` and `XSHA256` commands:
+/// a file name, quoted if it has spaces, and optionally the position
+/// to start hashing at and the one to stop before
+#[derive(Debug)]
+pub(super) struct LegacyHash {
+    name: String,
+    range: Option<ByteRange>,
+}
+
+impl LegacyHash {
+    pub(super) fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub(super) fn parse(args: &[&str]) -> Result<Self> {
+        let Some((_, rest)) = args.split_first() else {
+            return Err(miette!("Invalid number of arguments"));
+        };
+        // Trailing numbers after the name are the positions
+        let positions = rest
+            .iter()
+            .rev()
+            .take(2)
+            .take_while(|arg| arg.parse::<u64>().is_ok())
+            .count();
+        let (name, positions) = args.split_at(args.len() - positions);
+        let positions = positions
+            .iter()
+            .map(|position| position.parse::<u64>().into_diagnostic())
+            .collect::<Result<Vec<_>>>()?;
+        let range = match positions[..] {
+            [] => None,
+            [start] => Some(ByteRange {
+                start,
+                end: u64::MAX,
+            }),
+            [start, end] if start < end => Some(ByteRange {
+                start,
+                end: end - 1,
+            }),
+            _ => return Err(miette!("Invalid range")),
+        };
+        let name = name.join(" ");
+        let name = name.trim_matches('"');
+        if name.is_empty() {
+            return Err(miette!("Missing file name"));
+        }
+        Ok(Self {
+            name: name.to_string(),
+            range,
+        })
+    }
+
+    /// Replies `250` with the bare checksum.
+    pub(super) async fn run(
+        &self,
+        connection: InnerConnectionRef,
+        algorithm: HashAlgorithm,
+    ) -> Result<Option<StatusCode>> {
+        match hash_file(&connection, &self.name, algorithm, self.range).await? {
+            Ok(checksum) => Ok(Some(StatusCode::FileActionOk(format!(
+                " {}",
+                checksum.digest
+            )))),
+            Err(reply) => Ok(Some(reply)),
+        }
+    }
+}
+
+/// Hashes the file `name` sent by the client, or replies why it can't.
+async fn hash_file(
+    connection: &InnerConnectionRef,
+    name: &str,
+    algorithm: HashAlgorithm,
+    range: Option<ByteRange>,
+) -> Result<std::result::Result<Checksum, StatusCode>> {
+    let (path, memory) = {
+        let connection = connection.lock().await;
+        match connection.resolve_path(name) {
+            Ok(path) => (path, connection.memory.clone()),
+            Err(reply) => return Ok(Err(reply)),
+        }
+    };
+    if path.is_dir() {
+        return Ok(Err(StatusCode::ActionNotTaken));
+    }
+
+    trace!("Hashing {:?} with {}", path, algorithm.name());
+    let (mut buffer, _memory) = memory.buffer(CHUNK_SIZE)?;
+    match checksum(&path, algorithm, range, &mut buffer).await {
+        Ok(checksum) => Ok(Ok(checksum)),
+        Err(error) if error.kind() == io::ErrorKind::PermissionDenied => {
+            let denial = Denial::Permission {
+                path: name.to_string(),
+            };
+            Ok(Err(connection.lock().await.deny(denial)))
+        }
+        Err(error) => {
+            warn!("Could not hash {:?}: {}", path, error);
+            Ok(Err(StatusCode::ActionNotTaken))
+        }
+    }
+}
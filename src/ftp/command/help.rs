@@ -0,0 +1,63 @@
+use miette::*;
+
+use tracing::*;
+
+use crate::{ControlWriter, FTPCommand, InnerConnectionRef, StatusCode};
+
+/// Number of commands listed per line of the help message
+const COMMANDS_PER_LINE: usize = 8;
+
+pub struct Help<'a>(Option<&'a str>);
+
+impl<'a> FTPCommand<'a> for Help<'a> {
+    const KEYWORD: &'static str = "HELP";
+
+    async fn run(
+        &self,
+        connection: InnerConnectionRef,
+        _writer: &mut ControlWriter,
+    ) -> Result<Option<StatusCode>> {
+        let context = connection.lock().await.context.clone();
+        let capabilities = &context.capabilities;
+
+        if let Some(command) = self.0 {
+            let command = command.to_uppercase();
+            trace!("Reporting help for {}", command);
+            if !capabilities.supports(&command) {
+                return Ok(Some(StatusCode::CmdNotImplemented));
+            }
+            return Ok(Some(StatusCode::HelpMsg {
+                message: format!(" {command} is supported"),
+            }));
+        }
+
+        trace!("Reporting supported commands");
+        let commands = capabilities.commands().collect::<Vec<_>>();
+        let lines = commands
+            .chunks(COMMANDS_PER_LINE)
+            .map(|chunk| format!(" {}", chunk.join(" ")))
+            .collect::<Vec<_>>();
+        Ok(Some(StatusCode::HelpMsg {
+            message: format!(
+                "-The following commands are recognized:\n{}\n214 Help OK",
+                lines.join("\n")
+            ),
+        }))
+    }
+}
+
+impl<'a> TryFrom<(&'a str, Vec<&'a str>)> for Help<'a> {
+    type Error = miette::Error;
+
+    fn try_from((command, args): (&'a str, Vec<&'a str>)) -> Result<Self> {
+        if command == Self::KEYWORD {
+            if (0..=1).contains(&args.len()) {
+                Ok(Self(args.first().copied()))
+            } else {
+                Err(miette!("Invalid number of arguments"))
+            }
+        } else {
+            Err(miette!("Invalid command"))
+        }
+    }
+}
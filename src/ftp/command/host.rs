@@ -0,0 +1,70 @@
+use miette::*;
+
+use tracing::*;
+
+use crate::auth::LoginState;
+use crate::{ControlWriter, FTPCommand, InnerConnectionRef, StatusCode};
+
+pub struct Host<'a>(&'a str);
+
+impl<'a> FTPCommand<'a> for Host<'a> {
+    const KEYWORD: &'static str = "HOST";
+
+    fn feature() -> Option<String> {
+        Some("HOST".to_string())
+    }
+
+    /// Selects the virtual host before logging in, which decides the
+    /// users that may log in and the initial working directory
+    ///
+    /// Check: https://datatracker.ietf.org/doc/html/rfc7151#section-3
+    async fn run(
+        &self,
+        connection: InnerConnectionRef,
+        _writer: &mut ControlWriter,
+    ) -> Result<Option<StatusCode>> {
+        let mut connection = connection.lock().await;
+        if connection.login != LoginState::LoggedOut {
+            return Ok(Some(StatusCode::CmdBadSequence));
+        }
+        let context = connection.context.clone();
+        if context.config.hosts.is_empty() {
+            // Without virtual hosts every name leads to the same one
+            return Ok(Some(StatusCode::ServiceReadyUser));
+        }
+
+        // IPv6 literals are sent in brackets
+        let name = self.0.trim_start_matches('[').trim_end_matches(']');
+        let Some(host) = context.config.host(name) else {
+            debug!("Unknown virtual host {:?}", name);
+            return Ok(Some(StatusCode::CmdNotImplementedParam));
+        };
+        if !host.root.is_dir() {
+            warn!(
+                "The root of virtual host {:?} is not a directory",
+                host.name
+            );
+            return Ok(Some(StatusCode::CmdNotImplementedParam));
+        }
+        info!("Selected virtual host {:?}", host.name);
+        connection.host = Some(host.name.clone());
+        connection.confine(host.root.clone());
+        Ok(Some(StatusCode::ServiceReadyUser))
+    }
+}
+
+impl<'a> TryFrom<(&'a str, Vec<&'a str>)> for Host<'a> {
+    type Error = miette::Error;
+
+    fn try_from((command, args): (&'a str, Vec<&'a str>)) -> Result<Self> {
+        if command == Self::KEYWORD {
+            if args.len() == 1 {
+                Ok(Self(args[0]))
+            } else {
+                Err(miette!("Invalid number of arguments"))
+            }
+        } else {
+            Err(miette!("Invalid command"))
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use miette::*;
+use tracing::*;
+
+use crate::lang::Language;
+use crate::{ControlWriter, FTPCommand, InnerConnectionRef, StatusCode};
+
+pub struct Lang<'a>(Option<&'a str>);
+
+impl<'a> FTPCommand<'a> for Lang<'a> {
+    const KEYWORD: &'static str = "LANG";
+
+    fn feature() -> Option<String> {
+        Some("LANG EN*".to_string())
+    }
+
+    /// Selects the language of the reply texts, English without argument
+    ///
+    /// Check: https://datatracker.ietf.org/doc/html/rfc2640#section-4.1
+    async fn run(
+        &self,
+        connection: InnerConnectionRef,
+        _writer: &mut ControlWriter,
+    ) -> Result<Option<StatusCode>> {
+        let connection = connection.lock().await;
+        let language = match self.0 {
+            Some(tag) => match connection.context.languages.find(tag) {
+                Some(language) => language,
+                None => {
+                    debug!("Unsupported language {:?}", tag);
+                    return Ok(Some(StatusCode::CmdNotImplementedParam));
+                }
+            },
+            None => Language::default(),
+        };
+        trace!("Selecting language {}", language.tag);
+        let reply = format!(" Language set to {}", language.tag);
+        connection.language.set(language);
+        Ok(Some(StatusCode::CommandOk(reply)))
+    }
+}
+
+impl<'a> TryFrom<(&'a str, Vec<&'a str>)> for Lang<'a> {
+    type Error = miette::Error;
+
+    fn try_from((command, args): (&'a str, Vec<&'a str>)) -> Result<Self> {
+        if command == Self::KEYWORD {
+            match args[..] {
+                [] => Ok(Self(None)),
+                [tag] => Ok(Self(Some(tag))),
+                _ => Err(miette!("Invalid number of arguments")),
+            }
+        } else {
+            Err(miette!("Invalid command"))
+        }
+    }
+}
@@ -1,65 +1,176 @@
-use std::os::{linux::fs::MetadataExt, unix::fs::PermissionsExt};
+use std::{io, path::Path};
 
-use chrono::DateTime;
 use miette::*;
 
-use tokio::{io::AsyncWriteExt, net::tcp::WriteHalf};
 use tracing::*;
 
-use crate::utils::permissions_to_string;
+use crate::config::HiddenConfig;
+use crate::overrides::Overrides;
+use crate::root_dir::relative_to;
+use crate::storage::{DirEntry, StorageBackend};
+use crate::utils::{list_line, Glob};
 
-use crate::{FTPCommand, InnerConnectionRef, StatusCode};
+use crate::{wait_for_data_connection, ControlWriter, FTPCommand, InnerConnectionRef, StatusCode};
 
-pub struct List<'a>(Vec<&'a str>);
+pub struct List<'a> {
+    /// Set by `-a`, lists the hidden files too
+    all: bool,
+    path: Vec<&'a str>,
+}
 
 impl<'a> FTPCommand<'a> for List<'a> {
     const KEYWORD: &'static str = "LIST";
 
-    async fn run<'b>(
+    /// Lists the working directory, or the directory or file at the path
+    /// given
+    ///
+    /// Files starting with a dot are hidden unless `-a` or `-A` is given,
+    /// as with `ls`. Other options are accepted and ignored.
+    ///
+    /// Wildcards in the last component of the path list the matching
+    /// entries, like `ls -d` would.
+    ///
+    /// Check: https://datatracker.ietf.org/doc/html/rfc959#section-4.1.3
+    async fn run(
         &self,
         connection: InnerConnectionRef,
-        writer: &mut WriteHalf<'b>,
+        writer: &mut ControlWriter,
     ) -> Result<Option<StatusCode>> {
-        writer
-            .write(StatusCode::DataOpenTransfer.to_string().as_bytes())
-            .await
-            .into_diagnostic()?;
-
-        while connection.lock().await.data_connection.as_ref().is_none() {
-            trace!("Waiting for data connection");
-            tokio::time::sleep(std::time::Duration::from_millis(250)).await;
+        let path = self.path();
+        let glob = path.as_deref().and_then(Glob::parse);
+        let (storage, context, overrides, target) = {
+            let connection = connection.lock().await;
+            let target = match (&path, &glob) {
+                (Some(_), Some(glob)) => connection.resolve_beneath(glob.directory),
+                (Some(path), None) => connection.resolve_beneath(path),
+                (None, _) => Ok(relative_to(&connection.cwd(), &connection.root)),
+            };
+            match target {
+                Ok(target) => (
+                    connection.storage.clone(),
+                    connection.context.clone(),
+                    connection.overrides(&target).await,
+                    target,
+                ),
+                Err(reply) => return Ok(Some(reply)),
+            }
+        };
+        let hidden = Hidden {
+            config: &context.config.hidden,
+            overrides: &overrides,
+        };
+        // A broken link is listed as a file, others like what they point to
+        let metadata = match storage.metadata(&target).await {
+            Ok(metadata) => Ok(metadata),
+            Err(_) => storage.symlink_metadata(&target).await,
+        };
+        let metadata = match metadata {
+            Ok(metadata) => metadata,
+            Err(error) => {
+                warn!("Could not list {:?}: {}", target, error);
+                return Ok(Some(StatusCode::FileActionNotTaken));
+            }
+        };
+        let matches = match &glob {
+            Some(glob) => match matching(storage.as_ref(), &target, glob, &hidden).await {
+                Ok(entries) if !entries.is_empty() => Some(entries),
+                Ok(_) => {
+                    debug!("Nothing matches {:?}", path.unwrap_or_default());
+                    return Ok(Some(StatusCode::FileActionNotTaken));
+                }
+                Err(error) => {
+                    warn!("Could not list {:?}: {}", target, error);
+                    return Ok(Some(StatusCode::FileActionNotTaken));
+                }
+            },
+            None => None,
+        };
+
+        writer.send(StatusCode::DataOpenTransfer)?;
+
+        if !wait_for_data_connection(&connection).await {
+            return Ok(Some(StatusCode::CantOpenDataConnection));
         }
 
-        let connection = connection.lock().await;
-        let path = connection.cwd();
-        trace!("Listing directory {:?}", path);
+        // Sent from a copy of the session, not to hold it while `STAT` asks
+        // how far the listing got
+        let connection = connection.lock().await.clone();
+        let symlinks = connection.context.config.listing.symlinks;
+        trace!("Listing {:?}", target);
         if let Some(data_connection) = connection.data_connection.as_ref() {
             let mut data_connection = data_connection.lock().await;
-            for entry in std::fs::read_dir(path).into_diagnostic()? {
+            let mut data_connection = connection.data_writer(&mut *data_connection)?;
+            let shown = connection.display_path(&connection.root.join(&target));
+            let transfer = connection.transfer.start(true, &shown);
+            if let (Some(glob), Some(entries)) = (&glob, matches) {
+                // Matches are listed themselves, named with the directory
+                // they were asked in
+                for entry in entries {
+                    let path = target.join(&entry.name);
+                    let name = glob.name(&entry);
+                    let line = list_line(&name, storage.as_ref(), &path, &entry.metadata, symlinks);
+                    let line = format!("{}\r\n", line.await);
+                    trace!("Sending line: {}", line.trim());
+                    data_connection
+                        .write_all(line.as_bytes())
+                        .await
+                        .into_diagnostic()?;
+                    transfer.add(line.len());
+                }
+                data_connection.shutdown().await.into_diagnostic()?;
+                return Ok(Some(StatusCode::ClosingDataConnection));
+            }
+            if !metadata.is_dir() {
+                // A file is listed on its own, by the name it was asked by
+                let name = path.unwrap_or_default();
+                let metadata = storage
+                    .symlink_metadata(&target)
+                    .await
+                    .into_diagnostic()
+                    .wrap_err_with(|| format!("Could not read the metadata of {:?}", target))?;
+                let line = list_line(&name, storage.as_ref(), &target, &metadata, symlinks);
+                let line = format!("{}\r\n", line.await);
+                trace!("Sending line: {}", line.trim());
+                data_connection
+                    .write_all(line.as_bytes())
+                    .await
+                    .into_diagnostic()?;
+                data_connection.shutdown().await.into_diagnostic()?;
+                return Ok(Some(StatusCode::ClosingDataConnection));
+            }
+            let entries = match storage.list(&target).await {
+                Ok(entries) => entries,
+                Err(error) => {
+                    warn!("Could not list {:?}: {}", target, error);
+                    data_connection.shutdown().await.into_diagnostic()?;
+                    return Ok(Some(StatusCode::FileActionNotTaken));
+                }
+            };
+            let mut line_memory = connection.memory.reserve(0)?;
+            for entry in entries {
                 trace!("Reading entry {:?}", entry);
-                let entry = entry.into_diagnostic()?;
-                let metadata = entry.metadata().into_diagnostic()?;
-                let file_type = if metadata.is_dir() { "d" } else { "-" };
-                let permissions = permissions_to_string(metadata.permissions().mode());
-                let links = metadata.st_nlink();
-                let user = metadata.st_uid();
-                let group = metadata.st_gid();
-                let date = metadata.modified().into_diagnostic()?;
-                let formated_date = DateTime::<chrono::Local>::from(date).format("%e %b %y %H:%M");
-                let name = entry.file_name();
-                let name = name.to_string_lossy();
-                let line = format!(
-                    "{}{} {} {} {} {} {}\r\n",
-                    file_type, permissions, links, user, group, formated_date, name
+                if !self.all && is_dotfile(&entry) || hidden.hides(&entry) {
+                    continue;
+                }
+                let path = target.join(&entry.name);
+                let line = list_line(
+                    &entry.name,
+                    storage.as_ref(),
+                    &path,
+                    &entry.metadata,
+                    symlinks,
                 );
+                let line = format!("{}\r\n", line.await);
+                line_memory.resize(line.capacity())?;
                 trace!("Sending line: {}", line.trim());
                 data_connection
-                    .write(line.as_bytes())
+                    .write_all(line.as_bytes())
                     .await
                     .into_diagnostic()?;
+                transfer.add(line.len());
             }
             data_connection
-                .write("\0".as_bytes())
+                .write_all("\0".as_bytes())
                 .await
                 .into_diagnostic()?;
             data_connection.shutdown().await.into_diagnostic()?;
@@ -67,6 +178,50 @@ impl<'a> FTPCommand<'a> for List<'a> {
 
         Ok(Some(StatusCode::ClosingDataConnection))
     }
+
+    fn target(&self) -> Option<String> {
+        self.path()
+    }
+}
+
+impl<'a> List<'a> {
+    /// The path to list, if any
+    fn path(&self) -> Option<String> {
+        match self.path.is_empty() {
+            true => None,
+            false => Some(self.path.join(" ")),
+        }
+    }
+}
+
+/// Returns `true` for the entries `ls` hides without `-a`.
+fn is_dotfile(entry: &DirEntry) -> bool {
+    entry.name.starts_with('.')
+}
+
+/// What's hidden from clients in the listed directory, even with `-a`
+struct Hidden<'a> {
+    config: &'a HiddenConfig,
+    overrides: &'a Overrides,
+}
+
+impl Hidden<'_> {
+    fn hides(&self, entry: &DirEntry) -> bool {
+        self.config.hides(&entry.name) || self.overrides.hides(&entry.name)
+    }
+}
+
+/// The entries of `directory` of `storage` matching `glob` that aren't
+/// hidden.
+async fn matching(
+    storage: &dyn StorageBackend,
+    directory: &Path,
+    glob: &Glob<'_>,
+    hidden: &Hidden<'_>,
+) -> io::Result<Vec<DirEntry>> {
+    let mut entries = glob.matches(storage.list(directory).await?);
+    entries.retain(|entry| !hidden.hides(entry));
+    Ok(entries)
 }
 
 impl<'a> TryFrom<(&'a str, Vec<&'a str>)> for List<'a> {
@@ -74,7 +229,11 @@ impl<'a> TryFrom<(&'a str, Vec<&'a str>)> for List<'a> {
 
     fn try_from((command, args): (&'a str, Vec<&'a str>)) -> Result<Self> {
         if command == Self::KEYWORD {
-            Ok(Self(args))
+            // Options like `-la` come before the path, as sent by many clients
+            let options = args.iter().take_while(|arg| arg.starts_with('-'));
+            let all = options.clone().any(|option| option.contains(['a', 'A']));
+            let path = args[options.count()..].to_vec();
+            Ok(Self { all, path })
         } else {
             Err(miette!("Invalid command"))
         }
@@ -0,0 +1,64 @@
+use chrono::{DateTime, Utc};
+use miette::*;
+
+use tracing::*;
+
+use crate::{ControlWriter, FTPCommand, InnerConnectionRef, StatusCode};
+
+pub struct Mdtm<'a>(&'a str);
+
+impl<'a> FTPCommand<'a> for Mdtm<'a> {
+    const KEYWORD: &'static str = "MDTM";
+
+    fn feature() -> Option<String> {
+        Some("MDTM".to_string())
+    }
+
+    /// Replies with the modification time of the file as `YYYYMMDDHHMMSS` in UTC
+    ///
+    /// Check: https://datatracker.ietf.org/doc/html/rfc3659#section-3
+    async fn run(
+        &self,
+        connection: InnerConnectionRef,
+        _writer: &mut ControlWriter,
+    ) -> Result<Option<StatusCode>> {
+        let (storage, path) = {
+            let connection = connection.lock().await;
+            match connection.resolve_beneath(self.0) {
+                Ok(path) => (connection.storage.clone(), path),
+                Err(reply) => return Ok(Some(reply)),
+            }
+        };
+        trace!("Reading modification time of {:?}", path);
+        let metadata = match storage.metadata(&path).await {
+            Ok(metadata) if metadata.is_file() => metadata,
+            _ => return Ok(Some(StatusCode::ActionNotTaken)),
+        };
+        let modified = DateTime::<Utc>::from(metadata.modified);
+
+        Ok(Some(StatusCode::FileStatus(format!(
+            " {}",
+            modified.format("%Y%m%d%H%M%S")
+        ))))
+    }
+
+    fn target(&self) -> Option<String> {
+        Some(self.0.to_string())
+    }
+}
+
+impl<'a> TryFrom<(&'a str, Vec<&'a str>)> for Mdtm<'a> {
+    type Error = miette::Error;
+
+    fn try_from((command, args): (&'a str, Vec<&'a str>)) -> Result<Self> {
+        if command == Self::KEYWORD {
+            if args.len() == 1 {
+                Ok(Self(args[0]))
+            } else {
+                Err(miette!("Invalid number of arguments"))
+            }
+        } else {
+            Err(miette!("Invalid command"))
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use std::{io, time::SystemTime};
+
+use chrono::NaiveDateTime;
+use miette::*;
+
+use tracing::*;
+
+use crate::{ControlWriter, Denial, FTPCommand, InnerConnectionRef, StatusCode};
+
+pub struct Mfmt<'a> {
+    time: &'a str,
+    path: &'a str,
+}
+
+impl<'a> FTPCommand<'a> for Mfmt<'a> {
+    const KEYWORD: &'static str = "MFMT";
+
+    fn feature() -> Option<String> {
+        Some("MFMT".to_string())
+    }
+
+    /// Sets the modification time of the file from a `YYYYMMDDHHMMSS` UTC time
+    ///
+    /// Check: https://datatracker.ietf.org/doc/html/draft-somers-ftp-mfxx-04#section-3
+    async fn run(
+        &self,
+        connection: InnerConnectionRef,
+        _writer: &mut ControlWriter,
+    ) -> Result<Option<StatusCode>> {
+        let Ok(time) = NaiveDateTime::parse_from_str(self.time, "%Y%m%d%H%M%S") else {
+            return Ok(Some(StatusCode::SyntaxErrorParams));
+        };
+        let modified = SystemTime::from(time.and_utc());
+
+        let (storage, path) = {
+            let connection = connection.lock().await;
+            match connection.resolve_beneath(self.path) {
+                Ok(path) => (connection.storage.clone(), path),
+                Err(reply) => return Ok(Some(reply)),
+            }
+        };
+        trace!("Setting modification time of {:?} to {}", path, time);
+        match storage.set_modified(&path, modified).await {
+            Ok(()) => {}
+            Err(error) if error.kind() == io::ErrorKind::PermissionDenied => {
+                warn!("Permission denied to modify {:?}", self.path);
+                let denial = Denial::Permission {
+                    path: self.path.to_string(),
+                };
+                return Ok(Some(connection.lock().await.deny(denial)));
+            }
+            Err(error) => {
+                error!("Could not set modification time: {}", error);
+                return Ok(Some(StatusCode::ActionNotTaken));
+            }
+        }
+
+        Ok(Some(StatusCode::FileStatus(format!(
+            " Modify={}; {}",
+            self.time, self.path
+        ))))
+    }
+
+    fn target(&self) -> Option<String> {
+        Some(self.path.to_string())
+    }
+}
+
+impl<'a> TryFrom<(&'a str, Vec<&'a str>)> for Mfmt<'a> {
+    type Error = miette::Error;
+
+    fn try_from((command, args): (&'a str, Vec<&'a str>)) -> Result<Self> {
+        if command == Self::KEYWORD {
+            if args.len() == 2 {
+                Ok(Self {
+                    time: args[0],
+                    path: args[1],
+                })
+            } else {
+                Err(miette!("Invalid number of arguments"))
+            }
+        } else {
+            Err(miette!("Invalid command"))
+        }
+    }
+}
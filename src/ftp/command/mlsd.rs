@@ -1,61 +1,63 @@
-use chrono::DateTime;
 use miette::*;
 
-use tokio::{io::AsyncWriteExt, net::tcp::WriteHalf};
 use tracing::*;
 
-use crate::utils::permissions_to_machine_string;
+use crate::root_dir::relative_to;
+use crate::utils::machine_facts;
 
-use crate::{FTPCommand, InnerConnectionRef, StatusCode};
+use crate::{wait_for_data_connection, ControlWriter, FTPCommand, InnerConnectionRef, StatusCode};
 
 pub struct Mlsd<'a>(Vec<&'a str>);
 
 impl<'a> FTPCommand<'a> for Mlsd<'a> {
     const KEYWORD: &'static str = "MLSD";
 
-    async fn run<'b>(
+    fn feature() -> Option<String> {
+        Some("MLSD".to_string())
+    }
+
+    async fn run(
         &self,
         connection: InnerConnectionRef,
-        writer: &mut WriteHalf<'b>,
+        writer: &mut ControlWriter,
     ) -> Result<Option<StatusCode>> {
-        writer
-            .write(
-                StatusCode::FileStatusOk(" Directory listing has started".to_string())
-                    .to_string()
-                    .as_bytes(),
-            )
-            .await
-            .into_diagnostic()?;
+        writer.send(StatusCode::FileStatusOk(
+            " Directory listing has started".to_string(),
+        ))?;
+
+        if !wait_for_data_connection(&connection).await {
+            return Ok(Some(StatusCode::CantOpenDataConnection));
+        }
 
-        let connection = connection.lock().await;
-        let path = connection.cwd();
+        // Sent from a copy of the session, not to hold it while `STAT` asks
+        // how far the listing got
+        let connection = connection.lock().await.clone();
+        let path = relative_to(&connection.cwd(), &connection.root);
+        let facts = connection.facts.clone();
+        let overrides = connection.overrides(&path).await;
         if let Some(data_connection) = connection.data_connection.as_ref() {
             let mut data_connection = data_connection.lock().await;
-            for entry in std::fs::read_dir(path).into_diagnostic()? {
-                let entry = entry.into_diagnostic()?;
-                let metadata = entry.metadata().into_diagnostic()?;
-                let file_type = if metadata.is_dir() { "dir" } else { "file" };
-                let date = metadata.modified().into_diagnostic()?;
-                let formated_date = DateTime::<chrono::Local>::from(date).format("%Y%m%d%H%M%S");
-                let permissions = permissions_to_machine_string(&entry)?;
-                let name = entry.file_name();
-                let name = name.to_string_lossy();
-                let line = format!(
-                    "Type={};Size={};Modify={};Perm={} {}\r\n",
-                    file_type,
-                    metadata.len(),
-                    formated_date,
-                    permissions,
-                    name
-                );
+            let mut data_connection = connection.data_writer(&mut *data_connection)?;
+            let shown = connection.display_path(&connection.root.join(&path));
+            let transfer = connection.transfer.start(true, &shown);
+            let mut line_memory = connection.memory.reserve(0)?;
+            for entry in connection.storage.list(&path).await.into_diagnostic()? {
+                let name = &entry.name;
+                if connection.context.config.hidden.hides(name) || overrides.hides(name) {
+                    continue;
+                }
+                let facts = machine_facts(&entry.metadata, &facts);
+                let line = format!("{} {}\r\n", facts, entry.name);
+                line_memory.resize(line.capacity())?;
                 trace!("Sending line: {}", line.trim());
                 data_connection
-                    .write(line.as_bytes())
+                    .write_all(line.as_bytes())
                     .await
                     .into_diagnostic()?;
+                transfer.add(line.len());
             }
             data_connection
-                .write("\0".as_bytes())
+                .write_all("\0".as_bytes())
                 .await
                 .into_diagnostic()?;
 
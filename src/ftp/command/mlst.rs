@@ -0,0 +1,69 @@
+use miette::*;
+
+use tracing::*;
+
+use crate::root_dir::relative_to;
+use crate::utils::{machine_facts, Facts};
+
+use crate::{ControlWriter, FTPCommand, InnerConnectionRef, StatusCode};
+
+pub struct Mlst<'a>(Option<&'a str>);
+
+impl<'a> FTPCommand<'a> for Mlst<'a> {
+    const KEYWORD: &'static str = "MLST";
+
+    fn feature() -> Option<String> {
+        Some(Facts::default().feature())
+    }
+
+    /// Replies with the facts of a single file over the control connection
+    ///
+    /// Check: https://datatracker.ietf.org/doc/html/rfc3659#section-7.2
+    async fn run(
+        &self,
+        connection: InnerConnectionRef,
+        _writer: &mut ControlWriter,
+    ) -> Result<Option<StatusCode>> {
+        let (storage, path, shown, facts) = {
+            let connection = connection.lock().await;
+            let path = match self.0.map(|path| connection.resolve_beneath(path)) {
+                Some(Ok(path)) => path,
+                Some(Err(reply)) => return Ok(Some(reply)),
+                None => relative_to(&connection.cwd(), &connection.root),
+            };
+            let shown = connection.display_path(&connection.root.join(&path));
+            let storage = connection.storage.clone();
+            (storage, path, shown, connection.facts.clone())
+        };
+        trace!("Reading facts of {:?}", path);
+        let Ok(metadata) = storage.metadata(&path).await else {
+            return Ok(Some(StatusCode::ActionNotTaken));
+        };
+        let facts = machine_facts(&metadata, &facts);
+        let name = self.0.map(ToString::to_string).unwrap_or(shown);
+
+        Ok(Some(StatusCode::FileActionOk(format!(
+            "-Listing {name}\n {facts} {name}\n250 End"
+        ))))
+    }
+
+    fn target(&self) -> Option<String> {
+        self.0.map(ToString::to_string)
+    }
+}
+
+impl<'a> TryFrom<(&'a str, Vec<&'a str>)> for Mlst<'a> {
+    type Error = miette::Error;
+
+    fn try_from((command, args): (&'a str, Vec<&'a str>)) -> Result<Self> {
+        if command == Self::KEYWORD {
+            if (0..=1).contains(&args.len()) {
+                Ok(Self(args.first().copied()))
+            } else {
+                Err(miette!("Invalid number of arguments"))
+            }
+        } else {
+            Err(miette!("Invalid command"))
+        }
+    }
+}
@@ -1,56 +1,122 @@
 use std::sync::Arc;
 
 use miette::*;
-use tokio::net::tcp::WriteHalf;
 use tokio::sync::Mutex;
 
-use crate::ftp::StatusCode;
-use crate::InnerConnection;
+use crate::config::Permission;
+use crate::ftp::{Capabilities, StatusCode};
+use crate::{ControlWriter, InnerConnection};
 
+use self::acct::Acct;
+use self::allo::Allo;
+use self::appe::Appe;
+use self::auth::Auth;
+use self::ccc::Ccc;
 use self::cwd::Cwd;
+use self::epsv::Epsv;
 use self::feat::Feat;
+use self::hash::Hash;
+use self::help::Help;
+use self::host::Host;
+use self::lang::Lang;
 use self::list::List;
+use self::mdtm::Mdtm;
+use self::mfmt::Mfmt;
 use self::mlsd::Mlsd;
+use self::mlst::Mlst;
+use self::mode::Mode;
+use self::nlst::Nlst;
+use self::opts::Opts;
 use self::pass::Pass;
 use self::pasv::Pasv;
+use self::pbsz::Pbsz;
 use self::port::Port;
+use self::prot::Prot;
 use self::pwd::Pwd;
 use self::quit::Quit;
+use self::rang::Rang;
 use self::rest::Rest;
 use self::retr::Retr;
+use self::site::Site;
+pub use self::site::{SiteCommand, SiteCommands, SiteFuture};
+pub(crate) use self::stat::system_status;
+use self::stat::Stat;
 use self::stor::Stor;
+use self::stru::Stru;
 use self::syst::Syst;
 use self::type_cmd::Type;
 use self::user::User;
+use self::xcrc::Xcrc;
+use self::xmd5::Xmd5;
+use self::xsha256::Xsha256;
 
+mod acct;
+mod allo;
+mod appe;
+mod auth;
+mod ccc;
 mod cwd;
+mod epsv;
 mod feat;
+mod hash;
+mod help;
+mod host;
+mod lang;
 mod list;
+mod mdtm;
+mod mfmt;
 mod mlsd;
+mod mlst;
+mod mode;
+mod nlst;
+mod opts;
 mod pass;
 mod pasv;
+mod pbsz;
 mod port;
+mod prot;
 mod pwd;
 mod quit;
+mod rang;
 mod rest;
 mod retr;
+mod site;
+mod stat;
 mod stor;
+mod stru;
 mod syst;
 mod type_cmd;
 mod user;
+mod xcrc;
+mod xmd5;
+mod xsha256;
 
+// Commands only run on the task of their connection, so the futures don't need to be `Send`
+#[allow(async_fn_in_trait)]
 pub trait FTPCommand<'a>
 where
     Self: TryFrom<(&'a str, Vec<&'a str>)>,
 {
     const KEYWORD: &'static str;
 
-    async fn run<'b>(
+    /// The `FEAT` line advertising the command, if it extends RFC 959
+    ///
+    /// Check: https://datatracker.ietf.org/doc/html/rfc2389#section-3.2
+    fn feature() -> Option<String> {
+        None
+    }
+
+    async fn run(
         &self,
         connection: Arc<Mutex<InnerConnection>>,
-        writer: &mut WriteHalf<'b>,
+        writer: &mut ControlWriter,
     ) -> Result<Option<StatusCode>>;
 
+    /// The path the command acts on, as sent by the client, if it takes one
+    fn target(&self) -> Option<String> {
+        None
+    }
+
     fn is_keyword(&self, command: &str) -> bool {
         command == Self::KEYWORD
     }
@@ -71,17 +137,42 @@ pub enum Command<'a> {
     Pwd(Pwd),
     Cwd(Cwd<'a>),
     Rest(Rest),
-    Type(Type),
+    Type(Type<'a>),
     List(List<'a>),
     Mlsd(Mlsd<'a>),
     Quit(Quit),
+    Nlst(Nlst<'a>),
+    Mdtm(Mdtm<'a>),
+    Mfmt(Mfmt<'a>),
+    Mlst(Mlst<'a>),
+    Auth(Auth<'a>),
+    Help(Help<'a>),
+    Site(Site<'a>),
+    Stat(Stat<'a>),
+    Allo(Allo<'a>),
+    Acct(Acct<'a>),
+    Opts(Opts<'a>),
+    Epsv(Epsv<'a>),
+    Pbsz(Pbsz<'a>),
+    Prot(Prot<'a>),
+    Ccc(Ccc),
+    Host(Host<'a>),
+    Lang(Lang<'a>),
+    Hash(Hash<'a>),
+    Rang(Rang),
+    Xcrc(Xcrc),
+    Xmd5(Xmd5),
+    Xsha256(Xsha256),
+    Mode(Mode<'a>),
+    Stru(Stru<'a>),
+    Appe(Appe<'a>),
 }
 
 impl<'a> Command<'a> {
-    pub async fn run<'b>(
+    pub async fn run(
         &self,
         connection: Arc<Mutex<InnerConnection>>,
-        writer: &mut WriteHalf<'b>,
+        writer: &mut ControlWriter,
     ) -> Result<Option<StatusCode>> {
         match self {
             Command::User(cmd) => cmd.run(connection, writer).await,
@@ -99,7 +190,180 @@ impl<'a> Command<'a> {
             Command::List(cmd) => cmd.run(connection, writer).await,
             Command::Mlsd(cmd) => cmd.run(connection, writer).await,
             Command::Quit(cmd) => cmd.run(connection, writer).await,
+            Command::Nlst(cmd) => cmd.run(connection, writer).await,
+            Command::Mdtm(cmd) => cmd.run(connection, writer).await,
+            Command::Mfmt(cmd) => cmd.run(connection, writer).await,
+            Command::Mlst(cmd) => cmd.run(connection, writer).await,
+            Command::Auth(cmd) => cmd.run(connection, writer).await,
+            Command::Help(cmd) => cmd.run(connection, writer).await,
+            Command::Site(cmd) => cmd.run(connection, writer).await,
+            Command::Stat(cmd) => cmd.run(connection, writer).await,
+            Command::Allo(cmd) => cmd.run(connection, writer).await,
+            Command::Acct(cmd) => cmd.run(connection, writer).await,
+            Command::Opts(cmd) => cmd.run(connection, writer).await,
+            Command::Epsv(cmd) => cmd.run(connection, writer).await,
+            Command::Pbsz(cmd) => cmd.run(connection, writer).await,
+            Command::Prot(cmd) => cmd.run(connection, writer).await,
+            Command::Ccc(cmd) => cmd.run(connection, writer).await,
+            Command::Host(cmd) => cmd.run(connection, writer).await,
+            Command::Lang(cmd) => cmd.run(connection, writer).await,
+            Command::Hash(cmd) => cmd.run(connection, writer).await,
+            Command::Rang(cmd) => cmd.run(connection, writer).await,
+            Command::Xcrc(cmd) => cmd.run(connection, writer).await,
+            Command::Xmd5(cmd) => cmd.run(connection, writer).await,
+            Command::Xsha256(cmd) => cmd.run(connection, writer).await,
+            Command::Mode(cmd) => cmd.run(connection, writer).await,
+            Command::Stru(cmd) => cmd.run(connection, writer).await,
+            Command::Appe(cmd) => cmd.run(connection, writer).await,
+        }
+    }
+
+    /// Returns `true` if the command can be used before logging in, to
+    /// log in or set up the session.
+    pub fn allowed_before_login(keyword: &str) -> bool {
+        matches!(
+            keyword,
+            User::KEYWORD
+                | Pass::KEYWORD
+                | Acct::KEYWORD
+                | Auth::KEYWORD
+                | Pbsz::KEYWORD
+                | Prot::KEYWORD
+                | Ccc::KEYWORD
+                | Host::KEYWORD
+                | Lang::KEYWORD
+                | Feat::KEYWORD
+                | Help::KEYWORD
+                | Opts::KEYWORD
+                | Syst::KEYWORD
+                | Quit::KEYWORD
+        )
+    }
+
+    /// The path the command acts on, as sent by the client, if it takes one
+    pub fn target(&self) -> Option<String> {
+        match self {
+            Command::User(cmd) => cmd.target(),
+            Command::Pass(cmd) => cmd.target(),
+            Command::Pasv(cmd) => cmd.target(),
+            Command::Stor(cmd) => cmd.target(),
+            Command::Retr(cmd) => cmd.target(),
+            Command::Port(cmd) => cmd.target(),
+            Command::Syst(cmd) => cmd.target(),
+            Command::Feat(cmd) => cmd.target(),
+            Command::Pwd(cmd) => cmd.target(),
+            Command::Cwd(cmd) => cmd.target(),
+            Command::Rest(cmd) => cmd.target(),
+            Command::Type(cmd) => cmd.target(),
+            Command::List(cmd) => cmd.target(),
+            Command::Mlsd(cmd) => cmd.target(),
+            Command::Quit(cmd) => cmd.target(),
+            Command::Nlst(cmd) => cmd.target(),
+            Command::Mdtm(cmd) => cmd.target(),
+            Command::Mfmt(cmd) => cmd.target(),
+            Command::Mlst(cmd) => cmd.target(),
+            Command::Auth(cmd) => cmd.target(),
+            Command::Help(cmd) => cmd.target(),
+            Command::Site(cmd) => cmd.target(),
+            Command::Stat(cmd) => cmd.target(),
+            Command::Allo(cmd) => cmd.target(),
+            Command::Acct(cmd) => cmd.target(),
+            Command::Opts(cmd) => cmd.target(),
+            Command::Epsv(cmd) => cmd.target(),
+            Command::Pbsz(cmd) => cmd.target(),
+            Command::Prot(cmd) => cmd.target(),
+            Command::Ccc(cmd) => cmd.target(),
+            Command::Host(cmd) => cmd.target(),
+            Command::Lang(cmd) => cmd.target(),
+            Command::Hash(cmd) => cmd.target(),
+            Command::Rang(cmd) => cmd.target(),
+            Command::Xcrc(cmd) => cmd.target(),
+            Command::Xmd5(cmd) => cmd.target(),
+            Command::Xsha256(cmd) => cmd.target(),
+            Command::Mode(cmd) => cmd.target(),
+            Command::Stru(cmd) => cmd.target(),
+            Command::Appe(cmd) => cmd.target(),
+        }
+    }
+
+    /// The permission the command needs, if any.
+    ///
+    /// A command added later needs its own line here, as the ones missing
+    /// need no permission.
+    pub fn permission(keyword: &str, args: &[&str]) -> Option<Permission> {
+        match keyword {
+            // Without a path it's the status of the server
+            Stat::KEYWORD if args.is_empty() => None,
+            List::KEYWORD
+            | Nlst::KEYWORD
+            | Mlsd::KEYWORD
+            | Mlst::KEYWORD
+            | Stat::KEYWORD
+            | Mdtm::KEYWORD => Some(Permission::List),
+            Retr::KEYWORD | Hash::KEYWORD | Xcrc::KEYWORD | Xmd5::KEYWORD | Xsha256::KEYWORD => {
+                Some(Permission::Download)
+            }
+            Stor::KEYWORD | Appe::KEYWORD | Mfmt::KEYWORD => Some(Permission::Upload),
+            _ => None,
+        }
+    }
+
+    /// Returns `true` if `keyword` gives a file the name its target ends
+    /// with, which the filename policy applies to.
+    pub fn names_file(keyword: &str) -> bool {
+        matches!(keyword, Stor::KEYWORD | Appe::KEYWORD)
+    }
+
+    /// Builds the capabilities of the server from the commands with a handler.
+    ///
+    /// `AUTH TLS`, `PBSZ`, `PROT` and `CCC` are only available if `tls` is configured.
+    pub fn capabilities(tls: bool) -> Capabilities {
+        let mut capabilities = Capabilities::default();
+        capabilities
+            .register::<User>()
+            .register::<Pass>()
+            .register::<Pasv>()
+            .register::<Stor>()
+            .register::<Retr>()
+            .register::<Port>()
+            .register::<Syst>()
+            .register::<Feat>()
+            .register::<Pwd>()
+            .register::<Cwd>()
+            .register::<Rest>()
+            .register::<Type>()
+            .register::<List>()
+            .register::<Mlsd>()
+            .register::<Quit>()
+            .register::<Nlst>()
+            .register::<Mdtm>()
+            .register::<Mfmt>()
+            .register::<Mlst>()
+            .register::<Site>()
+            .register::<Stat>()
+            .register::<Allo>()
+            .register::<Acct>()
+            .register::<Opts>()
+            .register::<Epsv>()
+            .register::<Host>()
+            .register::<Lang>()
+            .register::<Hash>()
+            .register::<Rang>()
+            .register::<Xcrc>()
+            .register::<Xmd5>()
+            .register::<Xsha256>()
+            .register::<Mode>()
+            .register::<Stru>()
+            .register::<Appe>()
+            .register::<Help>();
+        if tls {
+            capabilities
+                .register::<Auth>()
+                .register::<Pbsz>()
+                .register::<Prot>()
+                .register::<Ccc>();
         }
+        capabilities
     }
 }
 
@@ -123,6 +387,31 @@ impl<'a> TryFrom<(&'a str, Vec<&'a str>)> for Command<'a> {
             List::KEYWORD => Ok(Command::List(List::try_from((command, args))?)),
             Mlsd::KEYWORD => Ok(Command::Mlsd(Mlsd::try_from((command, args))?)),
             Quit::KEYWORD => Ok(Command::Quit(Quit::try_from((command, args))?)),
+            Nlst::KEYWORD => Ok(Command::Nlst(Nlst::try_from((command, args))?)),
+            Mdtm::KEYWORD => Ok(Command::Mdtm(Mdtm::try_from((command, args))?)),
+            Mfmt::KEYWORD => Ok(Command::Mfmt(Mfmt::try_from((command, args))?)),
+            Mlst::KEYWORD => Ok(Command::Mlst(Mlst::try_from((command, args))?)),
+            Auth::KEYWORD => Ok(Command::Auth(Auth::try_from((command, args))?)),
+            Help::KEYWORD => Ok(Command::Help(Help::try_from((command, args))?)),
+            Site::KEYWORD => Ok(Command::Site(Site::try_from((command, args))?)),
+            Stat::KEYWORD => Ok(Command::Stat(Stat::try_from((command, args))?)),
+            Allo::KEYWORD => Ok(Command::Allo(Allo::try_from((command, args))?)),
+            Acct::KEYWORD => Ok(Command::Acct(Acct::try_from((command, args))?)),
+            Opts::KEYWORD => Ok(Command::Opts(Opts::try_from((command, args))?)),
+            Epsv::KEYWORD => Ok(Command::Epsv(Epsv::try_from((command, args))?)),
+            Pbsz::KEYWORD => Ok(Command::Pbsz(Pbsz::try_from((command, args))?)),
+            Prot::KEYWORD => Ok(Command::Prot(Prot::try_from((command, args))?)),
+            Ccc::KEYWORD => Ok(Command::Ccc(Ccc::try_from((command, args))?)),
+            Host::KEYWORD => Ok(Command::Host(Host::try_from((command, args))?)),
+            Lang::KEYWORD => Ok(Command::Lang(Lang::try_from((command, args))?)),
+            Hash::KEYWORD => Ok(Command::Hash(Hash::try_from((command, args))?)),
+            Rang::KEYWORD => Ok(Command::Rang(Rang::try_from((command, args))?)),
+            Xcrc::KEYWORD => Ok(Command::Xcrc(Xcrc::try_from((command, args))?)),
+            Xmd5::KEYWORD => Ok(Command::Xmd5(Xmd5::try_from((command, args))?)),
+            Xsha256::KEYWORD => Ok(Command::Xsha256(Xsha256::try_from((command, args))?)),
+            Mode::KEYWORD => Ok(Command::Mode(Mode::try_from((command, args))?)),
+            Stru::KEYWORD => Ok(Command::Stru(Stru::try_from((command, args))?)),
+            Appe::KEYWORD => Ok(Command::Appe(Appe::try_from((command, args))?)),
             _ => bail!("Invalid command"),
         }
     }
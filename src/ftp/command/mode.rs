@@ -0,0 +1,52 @@
+use miette::*;
+use tracing::*;
+
+use crate::{ControlWriter, FTPCommand, InnerConnectionRef, StatusCode, TransferMode};
+
+pub struct Mode<'a>(&'a str);
+
+impl<'a> FTPCommand<'a> for Mode<'a> {
+    const KEYWORD: &'static str = "MODE";
+
+    fn feature() -> Option<String> {
+        Some("MODE Z".to_string())
+    }
+
+    /// Selects how files are sent over the following data connections
+    ///
+    /// The stream mode and the deflate mode `Z` are supported, the block and
+    /// compressed modes of RFC 959 are refused with `504`.
+    ///
+    /// Check: https://datatracker.ietf.org/doc/html/rfc959#section-4.1.2
+    /// Check: https://datatracker.ietf.org/doc/html/draft-preston-ftpext-deflate-04
+    async fn run(
+        &self,
+        connection: InnerConnectionRef,
+        _writer: &mut ControlWriter,
+    ) -> Result<Option<StatusCode>> {
+        let mode = match self.0.to_uppercase().as_str() {
+            "S" => TransferMode::Stream,
+            "Z" => TransferMode::Deflate,
+            _ => return Ok(Some(StatusCode::CmdNotImplementedParam)),
+        };
+        trace!("Transfer mode set to {:?}", mode);
+        connection.lock().await.mode = mode;
+        Ok(Some(StatusCode::Ok))
+    }
+}
+
+impl<'a> TryFrom<(&'a str, Vec<&'a str>)> for Mode<'a> {
+    type Error = miette::Error;
+
+    fn try_from((command, args): (&'a str, Vec<&'a str>)) -> Result<Self> {
+        if command == Self::KEYWORD {
+            if args.len() == 1 {
+                Ok(Self(args[0]))
+            } else {
+                Err(miette!("Invalid number of arguments"))
+            }
+        } else {
+            Err(miette!("Invalid command"))
+        }
+    }
+}
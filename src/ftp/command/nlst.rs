@@ -0,0 +1,138 @@
+use miette::*;
+
+use tracing::*;
+
+use crate::root_dir::relative_to;
+use crate::utils::Glob;
+
+use crate::{wait_for_data_connection, ControlWriter, FTPCommand, InnerConnectionRef, StatusCode};
+
+pub struct Nlst<'a>(Option<&'a str>);
+
+impl<'a> FTPCommand<'a> for Nlst<'a> {
+    const KEYWORD: &'static str = "NLST";
+
+    async fn run(
+        &self,
+        connection: InnerConnectionRef,
+        writer: &mut ControlWriter,
+    ) -> Result<Option<StatusCode>> {
+        let glob = self.0.and_then(Glob::parse);
+        let (storage, context, overrides, path) = {
+            let connection = connection.lock().await;
+            let path = match (self.0, &glob) {
+                (Some(_), Some(glob)) => connection.resolve_beneath(glob.directory),
+                (Some(path), None) => connection.resolve_beneath(path),
+                (None, _) => Ok(relative_to(&connection.cwd(), &connection.root)),
+            };
+            match path {
+                Ok(path) => (
+                    connection.storage.clone(),
+                    connection.context.clone(),
+                    connection.overrides(&path).await,
+                    path,
+                ),
+                Err(reply) => return Ok(Some(reply)),
+            }
+        };
+        let hidden = |name: &str| context.config.hidden.hides(name) || overrides.hides(name);
+        let Ok(metadata) = storage.metadata(&path).await else {
+            return Ok(Some(StatusCode::FileActionNotTaken));
+        };
+        let matches = match &glob {
+            Some(glob) => match storage.list(&path).await {
+                Ok(entries) => {
+                    let mut entries = glob.matches(entries);
+                    entries.retain(|entry| !hidden(&entry.name));
+                    if entries.is_empty() {
+                        debug!("Nothing matches {:?}", self.0.unwrap_or_default());
+                        return Ok(Some(StatusCode::FileActionNotTaken));
+                    }
+                    Some(entries)
+                }
+                Err(error) => {
+                    warn!("Could not list {:?}: {}", path, error);
+                    return Ok(Some(StatusCode::FileActionNotTaken));
+                }
+            },
+            None => None,
+        };
+
+        writer.send(StatusCode::DataOpenTransfer)?;
+
+        if !wait_for_data_connection(&connection).await {
+            return Ok(Some(StatusCode::CantOpenDataConnection));
+        }
+
+        // Sent from a copy of the session, not to hold it while `STAT` asks
+        // how far the listing got
+        let connection = connection.lock().await.clone();
+        trace!("Listing names in {:?}", path);
+        if let Some(data_connection) = connection.data_connection.as_ref() {
+            let mut data_connection = data_connection.lock().await;
+            let mut data_connection = connection.data_writer(&mut *data_connection)?;
+            let shown = connection.display_path(&connection.root.join(&path));
+            let transfer = connection.transfer.start(true, &shown);
+            // The names are held until they're all sent
+            let mut names_memory = connection.memory.reserve(0)?;
+            let names = if let (Some(glob), Some(entries)) = (&glob, matches) {
+                // Matches are named with the directory they were asked in,
+                // so they can be fetched as they are
+                let mut names = vec![];
+                for entry in entries {
+                    let name = glob.name(&entry);
+                    names_memory.grow(name.len())?;
+                    names.push(name);
+                }
+                names
+            } else if metadata.is_dir() {
+                let mut names = vec![];
+                for entry in storage.list(&path).await.into_diagnostic()? {
+                    if hidden(&entry.name) {
+                        continue;
+                    }
+                    names_memory.grow(entry.name.len())?;
+                    names.push(entry.name);
+                }
+                names
+            } else {
+                path.file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .into_iter()
+                    .collect()
+            };
+            for name in names {
+                let line = format!("{}\r\n", name);
+                trace!("Sending line: {}", line.trim());
+                data_connection
+                    .write_all(line.as_bytes())
+                    .await
+                    .into_diagnostic()?;
+                transfer.add(line.len());
+            }
+            data_connection.shutdown().await.into_diagnostic()?;
+        }
+
+        Ok(Some(StatusCode::ClosingDataConnection))
+    }
+
+    fn target(&self) -> Option<String> {
+        self.0.map(ToString::to_string)
+    }
+}
+
+impl<'a> TryFrom<(&'a str, Vec<&'a str>)> for Nlst<'a> {
+    type Error = miette::Error;
+
+    fn try_from((command, args): (&'a str, Vec<&'a str>)) -> Result<Self> {
+        if command == Self::KEYWORD {
+            if (0..=1).contains(&args.len()) {
+                Ok(Self(args.first().copied()))
+            } else {
+                Err(miette!("Invalid number of arguments"))
+            }
+        } else {
+            Err(miette!("Invalid command"))
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use miette::*;
+use tracing::*;
+
+use super::{Hash, Mlst, Mode};
+use crate::checksum::HashAlgorithm;
+use crate::utils::Facts;
+
+use crate::{ControlWriter, FTPCommand, InnerConnectionRef, StatusCode};
+
+pub struct Opts<'a> {
+    option: &'a str,
+    values: Vec<&'a str>,
+}
+
+impl<'a> FTPCommand<'a> for Opts<'a> {
+    const KEYWORD: &'static str = "OPTS";
+
+    fn feature() -> Option<String> {
+        Some("UTF8".to_string())
+    }
+
+    /// Sets the options of another command
+    ///
+    /// Check: https://datatracker.ietf.org/doc/html/rfc2389#section-4
+    async fn run(
+        &self,
+        connection: InnerConnectionRef,
+        _writer: &mut ControlWriter,
+    ) -> Result<Option<StatusCode>> {
+        let option = self.option.to_ascii_uppercase();
+        // Options of commands without a handler, or disabled, are unknown
+        let context = connection.lock().await.context.clone();
+        if option != "UTF8" && !context.capabilities.supports(&option) {
+            debug!("Refusing options of the unavailable command {}", option);
+            return Ok(Some(StatusCode::SyntaxErrorParams));
+        }
+
+        if option == Mode::KEYWORD {
+            return self.mode(connection).await;
+        }
+
+        // The remaining options take a single value at most
+        let value = match self.values[..] {
+            [] => None,
+            [value] => Some(value),
+            _ => return Ok(Some(StatusCode::SyntaxErrorParams)),
+        };
+
+        if option == "UTF8" {
+            // Paths are always sent as UTF-8, there's nothing to switch
+            return match value {
+                Some(value) if value.eq_ignore_ascii_case("ON") => Ok(Some(StatusCode::CommandOk(
+                    " Always in UTF8 mode".to_string(),
+                ))),
+                _ => Ok(Some(StatusCode::CmdNotImplementedParam)),
+            };
+        }
+
+        if option == Mlst::KEYWORD {
+            // Check: https://datatracker.ietf.org/doc/html/rfc3659#section-7.9
+            let facts = Facts::select(value.unwrap_or_default());
+            trace!("Selecting facts {}", facts);
+            let reply = format!(" MLST OPTS {facts}");
+            connection.lock().await.facts = facts;
+            return Ok(Some(StatusCode::CommandOk(reply)));
+        }
+
+        if option == Hash::KEYWORD {
+            // Check: https://datatracker.ietf.org/doc/html/draft-bryan-ftpext-hash-02#section-4
+            let mut connection = connection.lock().await;
+            if let Some(name) = value {
+                let Some(algorithm) = HashAlgorithm::from_name(name) else {
+                    return Ok(Some(StatusCode::CmdNotImplementedParam));
+                };
+                trace!("Selecting hash algorithm {}", algorithm.name());
+                connection.hash = algorithm;
+            }
+            let reply = format!(" {}", connection.hash.name());
+            return Ok(Some(StatusCode::CommandOk(reply)));
+        }
+
+        Ok(Some(StatusCode::SyntaxErrorParams))
+    }
+}
+
+impl<'a> Opts<'a> {
+    /// Sets the compression level of `MODE Z` with `OPTS MODE Z LEVEL n`,
+    /// the other parameters of the deflate mode can't be changed.
+    ///
+    /// Check: https://datatracker.ietf.org/doc/html/draft-preston-ftpext-deflate-04#section-5
+    async fn mode(&self, connection: InnerConnectionRef) -> Result<Option<StatusCode>> {
+        let mut connection = connection.lock().await;
+        let level = match self.values[..] {
+            [mode] if mode.eq_ignore_ascii_case("Z") => None,
+            [mode, option, level]
+                if mode.eq_ignore_ascii_case("Z") && option.eq_ignore_ascii_case("LEVEL") =>
+            {
+                match level.parse::<u32>() {
+                    Ok(level) if level <= 9 => Some(level),
+                    _ => return Ok(Some(StatusCode::SyntaxErrorParams)),
+                }
+            }
+            _ => return Ok(Some(StatusCode::CmdNotImplementedParam)),
+        };
+        if let Some(level) = level {
+            trace!("Selecting deflate level {}", level);
+            connection.deflate_level = level;
+        }
+        let reply = format!(" MODE Z LEVEL {}", connection.deflate_level);
+        Ok(Some(StatusCode::CommandOk(reply)))
+    }
+}
+
+impl<'a> TryFrom<(&'a str, Vec<&'a str>)> for Opts<'a> {
+    type Error = miette::Error;
+
+    fn try_from((command, args): (&'a str, Vec<&'a str>)) -> Result<Self> {
+        if command == Self::KEYWORD {
+            match args.split_first() {
+                Some((option, values)) => Ok(Self {
+                    option,
+                    values: values.to_vec(),
+                }),
+                None => Err(miette!("Invalid number of arguments")),
+            }
+        } else {
+            Err(miette!("Invalid command"))
+        }
+    }
+}
@@ -1,19 +1,91 @@
 use miette::*;
-use tokio::net::tcp::WriteHalf;
+use tracing::*;
 
-use crate::{FTPCommand, InnerConnectionRef, StatusCode};
+use crate::auth::LoginState;
+
+use crate::{ControlWriter, FTPCommand, InnerConnectionRef, StatusCode};
 
 pub struct Pass<'a>(&'a str);
 
 impl<'a> FTPCommand<'a> for Pass<'a> {
     const KEYWORD: &'static str = "PASS";
 
-    async fn run<'b>(
+    async fn run(
         &self,
-        _connection: InnerConnectionRef,
-        _writer: &mut WriteHalf<'b>,
+        connection: InnerConnectionRef,
+        _writer: &mut ControlWriter,
     ) -> Result<Option<StatusCode>> {
-        Ok(Some(StatusCode::UserLoggedIn))
+        let mut connection = connection.lock().await;
+        if connection.requires_tls() {
+            return Ok(Some(StatusCode::TlsRequired));
+        }
+        if connection.login != LoginState::NeedPassword {
+            return Ok(Some(StatusCode::CmdBadSequence));
+        }
+
+        let context = connection.context.clone();
+        let username = connection.username.clone().unwrap_or_default();
+        if context.config.honeypot.enabled {
+            let address = connection.peer_addr.ip();
+            let honeypot = &context.honeypot;
+            honeypot.login(connection.id, address, &username, self.0);
+            connection.log_in_honeypot();
+            return Ok(Some(StatusCode::UserLoggedIn));
+        }
+        if context.config.anonymous.matches(&username) {
+            // The password is usually an email address, kept out of the logs
+            info!("Anonymous login as {:?}", username);
+            connection.log_in_anonymously();
+            return Ok(Some(StatusCode::UserLoggedIn));
+        }
+
+        let users = &context.users;
+        if users.is_empty() && context.config.auth.allow_any {
+            connection.log_in();
+            return Ok(Some(StatusCode::UserLoggedIn));
+        }
+
+        // Slow hashes would hold up the other sessions of the runtime
+        let verified = tokio::task::spawn_blocking({
+            let context = context.clone();
+            let (username, password) = (username.clone(), self.0.to_string());
+            let host = connection.host.clone();
+            move || context.users.verify(&username, &password, host.as_deref())
+        })
+        .await
+        .into_diagnostic()?;
+        if !verified {
+            warn!("Failed login for user {:?}", username);
+            return Ok(Some(connection.fail_login(&username).await));
+        }
+        if !users.accepts_certificate(&username, connection.certificate.as_deref()) {
+            warn!(
+                "User {:?} didn't present their client certificate",
+                username
+            );
+            connection.login = LoginState::LoggedOut;
+            return Ok(Some(StatusCode::UserNotLoggedIn));
+        }
+
+        if context.config.auth.upgrade_hashes && users.needs_upgrade(&username) {
+            match users.upgrade(&username, self.0).await {
+                Ok(()) => info!("Upgraded the password of user {:?} to argon2id", username),
+                Err(error) => warn!(
+                    "Could not upgrade the password of {:?}: {}",
+                    username, error
+                ),
+            }
+        }
+
+        if users.requires_account(&username) {
+            debug!("User {:?} needs an account to log in", username);
+            connection.login = LoginState::NeedAccount;
+            Ok(Some(StatusCode::NeedLoginAccount))
+        } else {
+            info!("User {:?} logged in", username);
+            connection.log_in();
+            Ok(Some(StatusCode::UserLoggedIn))
+        }
     }
 }
 
@@ -23,7 +95,8 @@ impl<'a> TryFrom<(&'a str, Vec<&'a str>)> for Pass<'a> {
     fn try_from((command, args): (&'a str, Vec<&'a str>)) -> Result<Self> {
         if command == Self::KEYWORD {
             if (0..=1).contains(&args.len()) {
-                let password = args.first().copied().unwrap_or("anonymous");
+                // A missing password is empty, not any password in particular
+                let password = args.first().copied().unwrap_or_default();
                 Ok(Self(password))
             } else {
                 Err(miette!("Invalid number of arguments"))
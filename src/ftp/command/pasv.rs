@@ -1,82 +1,312 @@
 use std::{
     borrow::BorrowMut,
-    net::{Ipv4Addr, SocketAddr},
+    io,
+    net::{IpAddr, Ipv4Addr},
     sync::Arc,
+    time::Duration,
 };
 
 use miette::*;
 use num_integer::Integer;
+use rand::{
+    distributions::{Alphanumeric, DistString},
+    Rng,
+};
 use tokio::{
-    io::AsyncWriteExt,
-    net::{tcp::WriteHalf, TcpListener},
+    io::AsyncReadExt,
+    net::{TcpListener, TcpStream},
     sync::Mutex,
+    task::JoinSet,
 };
 use tracing::*;
 
-use crate::{DataConnection, FTPCommand, InnerConnectionRef, StatusCode};
+use crate::auth::constant_time_eq;
+use crate::config::{DataMode, PassiveConfig};
+use crate::privacy::IpPrivacy;
+use crate::{
+    ControlWriter, DataConnection, FTPCommand, InnerConnectionRef, StatusCode,
+    DATA_CONNECTION_TIMEOUT,
+};
+
+/// Length of the tokens advertised in the `227` and `229` replies
+const TOKEN_LENGTH: usize = 32;
+
+/// How long a client has to send the token once connected
+const TOKEN_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub struct Pasv;
 
 impl<'a> FTPCommand<'a> for Pasv {
     const KEYWORD: &'static str = "PASV";
 
-    async fn run<'b>(
+    async fn run(
         &self,
         connection: InnerConnectionRef,
-        writer: &mut WriteHalf<'b>,
+        writer: &mut ControlWriter,
     ) -> Result<Option<StatusCode>> {
-        // let ip_address = match local_ip().into_diagnostic()? {
-        //     IpAddr::V4(ip) => ip,
-        //     _ => return Err(miette!("Only IPv4 is supported")),
-        // };
-        let ip_address = Ipv4Addr::from([127, 0, 0, 1]);
-
-        let data_addr = SocketAddr::from((ip_address, 0));
-        let data_listener = TcpListener::bind(data_addr)
-            .await
-            .unwrap_or_else(|_| panic!("Could not bind to address {}", data_addr));
-        let local_addr = data_listener.local_addr().into_diagnostic()?;
-        let data_port = local_addr.port();
-        let (port_high, port_low) = data_port.div_rem(&256);
-        trace!("Data connection listener bound to {}", local_addr);
-
-        writer
-            .write(
-                StatusCode::EnteringPassiveMode {
-                    ip_address,
-                    port_high,
-                    port_low,
+        {
+            let connection = connection.lock().await;
+            if !connection.allows_data_mode(DataMode::Passive) {
+                return Ok(Some(passive_disabled()));
+            }
+            if connection.epsv_all {
+                return Ok(Some(StatusCode::CmdBadSequence));
+            }
+        }
+        listen(connection, writer, false).await
+    }
+}
+
+/// The reply to passive mode commands when the session may only use active mode
+pub(super) fn passive_disabled() -> StatusCode {
+    StatusCode::CmdDisabled(" Passive mode is disabled, use PORT".to_string())
+}
+
+/// Binds a data connection listener, replies with its address and accepts
+/// the data connection in the background.
+///
+/// `extended` selects the `229` reply of `EPSV` over the `227` of `PASV`.
+pub(super) async fn listen(
+    connection: InnerConnectionRef,
+    writer: &mut ControlWriter,
+    extended: bool,
+) -> Result<Option<StatusCode>> {
+    let (config, peer_addr, local_addr) = {
+        let connection = connection.lock().await;
+        let local_addr = connection.local_addr.map(|addr| addr.ip());
+        (
+            connection.context.config.clone(),
+            connection.peer_addr,
+            local_addr,
+        )
+    };
+    let (bind_address, ip_address) = addresses(&config.passive, local_addr);
+    // `EPSV` only advertises the port
+    if !extended {
+        match ip_address {
+            None => {
+                debug!("No IPv4 address to advertise, set `passive.address`");
+                return Ok(Some(StatusCode::NetworkProtocolNotSupported));
+            }
+            Some(ip_address) if ip_address.is_loopback() && !peer_addr.ip().is_loopback() => {
+                warn!(
+                    "Advertising the loopback address {} to a client on another host, \
+                     set `passive.address` to one it can connect to",
+                    ip_address
+                );
+            }
+            Some(_) => {}
+        }
+    }
+    let data_listener = match bind_data_listener(bind_address, &config.passive).await {
+        Ok(listener) => listener,
+        Err(error) => {
+            error!("Could not bind the data connection listener: {}", error);
+            return Ok(Some(StatusCode::CantOpenDataConnection));
+        }
+    };
+    let local_addr = data_listener.local_addr().into_diagnostic()?;
+    let data_port = config.passive.advertised_port(local_addr.port());
+    let (port_high, port_low) = data_port.div_rem(&256);
+    trace!(
+        "Data connection listener bound to {}, advertised on port {}",
+        local_addr,
+        data_port
+    );
+
+    let token = config
+        .passive
+        .data_token
+        .then(|| Alphanumeric.sample_string(&mut rand::thread_rng(), TOKEN_LENGTH));
+
+    writer.send(match ip_address.filter(|_| !extended) {
+        Some(ip_address) => StatusCode::EnteringPassiveMode {
+            ip_address,
+            port_high,
+            port_low,
+            token: token.clone(),
+        },
+        None => StatusCode::EnteringExtendedPassiveMode {
+            port: data_port,
+            token: token.clone(),
+        },
+    })?;
+
+    writer.flush().await?;
+
+    trace!("Waiting for data connection");
+
+    // Locked until the task is recorded, so it can't set the connection first
+    let mut session = connection.lock().await;
+    let (context, tls, fxp) = (
+        session.context.clone(),
+        session.data_tls(),
+        session.allows_fxp(),
+    );
+    let connection = connection.clone();
+    let task = tokio::spawn(async move {
+        let connection_mutex = connection.lock();
+        let client = peer_addr.ip();
+        let accepted = tokio::time::timeout(
+            DATA_CONNECTION_TIMEOUT,
+            accept(
+                &data_listener,
+                client,
+                fxp,
+                token.as_deref(),
+                &context.privacy,
+            ),
+        )
+        .await;
+        // The listener is dropped along with the task, freeing its port
+        let data_socket = match accepted {
+            Ok(Some(data_socket)) => data_socket,
+            Ok(None) => return,
+            Err(_) => {
+                debug!("The client didn't open the data connection in time");
+                return;
+            }
+        };
+        let data_connection = match DataConnection::open(data_socket, tls).await {
+            Ok(data_connection) => Arc::new(Mutex::new(data_connection)),
+            Err(error) => {
+                warn!("TLS handshake of the data connection failed: {}", error);
+                return;
+            }
+        };
+        connection_mutex.await.borrow_mut().data_connection = Some(data_connection);
+        trace!("Data connection established");
+    });
+    session.open_data_connection(task.abort_handle());
+
+    Ok(None)
+}
+
+/// Accepts the data connection of the client at `client`, dropping those
+/// from anyone else unless `fxp` allows it, and those not sending `token`
+/// first.
+///
+/// Tokens are read from every connection at once, so one that sends
+/// nothing doesn't hold up the next.
+async fn accept(
+    listener: &TcpListener,
+    client: IpAddr,
+    fxp: bool,
+    token: Option<&str>,
+    privacy: &IpPrivacy,
+) -> Option<TcpStream> {
+    // Dropping them once one is accepted drops the others' sockets too
+    let mut pending = JoinSet::new();
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            Some(read) = pending.join_next() => match read {
+                Ok(Ok(data_socket)) => return Some(data_socket),
+                Ok(Err(peer)) => {
+                    warn!(
+                        "Rejected data connection from {} with an invalid token",
+                        peer
+                    );
+                    continue;
                 }
-                .to_string()
-                .as_bytes(),
-            )
-            .await
-            .into_diagnostic()?;
-
-        writer.flush().await.into_diagnostic()?;
-
-        trace!("Waiting for data connection");
-
-        connection.lock().await.data_connection = None;
-        let connection = connection.clone();
-        tokio::spawn(async move {
-            let connection_mutex = connection.lock();
-            let (data_socket, _) = data_listener
-                .accept()
-                .await
-                .expect("Error accepting connection to data_socket");
-
-            trace!(
-                "Data connection accepted from {}",
-                data_socket.peer_addr().unwrap()
-            );
-            let data_connection = Arc::new(Mutex::new(DataConnection::from(data_socket)));
-            connection_mutex.await.borrow_mut().data_connection = Some(data_connection);
-            trace!("Data connection established");
+                Err(error) => {
+                    warn!("Could not read the token of a data connection: {}", error);
+                    continue;
+                }
+            },
+        };
+        let (mut data_socket, peer) = match accepted {
+            Ok(accepted) => accepted,
+            Err(error) => {
+                warn!("Could not accept the data connection: {}", error);
+                return None;
+            }
+        };
+        let foreign = peer.ip().to_canonical() != client.to_canonical();
+        let peer = privacy.redact(&peer);
+        trace!("Data connection accepted from {}", peer);
+        // Anyone scanning the port could take the transfer otherwise
+        if foreign && !fxp {
+            warn!("Rejected data connection from {}, not the client", peer);
+            continue;
+        }
+        if foreign {
+            info!("Site-to-site transfer with {}", peer);
+        }
+
+        let Some(token) = token else {
+            return Some(data_socket);
+        };
+        let token = token.to_string();
+        pending.spawn(async move {
+            match tokio::time::timeout(TOKEN_TIMEOUT, read_token(&mut data_socket)).await {
+                Ok(Ok(line)) if constant_time_eq(&line, token.as_bytes()) => Ok(data_socket),
+                _ => Err(peer),
+            }
         });
+    }
+}
+
+/// Binds the data connection listener to a free port of the configured range,
+/// or to any free port if there's none.
+async fn bind_data_listener(ip_address: IpAddr, config: &PassiveConfig) -> io::Result<TcpListener> {
+    let Some(range) = config.ports else {
+        return TcpListener::bind((ip_address, 0)).await;
+    };
+    // Starting at a random port keeps concurrent sessions from racing for the same ones
+    let offset = rand::thread_rng().gen_range(0..range.count());
+    for i in 0..range.count() {
+        let port = range.start + ((offset + i) % range.count()) as u16;
+        match TcpListener::bind((ip_address, port)).await {
+            Ok(listener) => return Ok(listener),
+            Err(error) if error.kind() == io::ErrorKind::AddrInUse => continue,
+            Err(error) => return Err(error),
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::AddrInUse,
+        format!("No free port in {}-{}", range.start, range.end),
+    ))
+}
+
+/// The address data connection listeners are bound to, and the IPv4 one
+/// advertised in `227` replies, as configured or else the `local` address
+/// of the control connection.
+fn addresses(passive: &PassiveConfig, local: Option<IpAddr>) -> (IpAddr, Option<Ipv4Addr>) {
+    let bind = passive
+        .bind
+        .or(local)
+        .unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    let advertised = passive
+        .address
+        .or_else(|| [Some(bind), local].into_iter().flatten().find_map(ipv4));
+    (bind, advertised)
+}
 
-        Ok(None)
+/// `ip` as an IPv4 address a client can connect to, if it's one.
+fn ipv4(ip: IpAddr) -> Option<Ipv4Addr> {
+    let ip = match ip {
+        IpAddr::V4(ip) => ip,
+        IpAddr::V6(ip) => ip.to_ipv4_mapped()?,
+    };
+    (!ip.is_unspecified()).then_some(ip)
+}
+
+/// Reads the first line of the data connection, byte by byte
+/// so that no transfer data is consumed along with it.
+async fn read_token(socket: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut line = Vec::with_capacity(TOKEN_LENGTH + 2);
+    loop {
+        match socket.read_u8().await? {
+            b'\n' => break,
+            byte if line.len() <= TOKEN_LENGTH => line.push(byte),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "Token too long")),
+        }
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
     }
+    Ok(line)
 }
 
 impl<'a> TryFrom<(&'a str, Vec<&'a str>)> for Pasv {
@@ -94,3 +324,112 @@ impl<'a> TryFrom<(&'a str, Vec<&'a str>)> for Pasv {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::{io::AsyncWriteExt, net::TcpSocket, task::JoinHandle};
+
+    use super::*;
+    use crate::config::PrivacyConfig;
+
+    /// Accepts the data connection of `client` in the background, returning
+    /// the address to connect to and where it was accepted from.
+    async fn listen(
+        client: [u8; 4],
+        token: Option<&'static str>,
+    ) -> (SocketAddr, JoinHandle<Option<IpAddr>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let privacy = IpPrivacy::new(&PrivacyConfig::default());
+        let client = IpAddr::from(client);
+        let accepted = tokio::spawn(async move {
+            let accepted = accept(&listener, client, false, token, &privacy).await;
+            accepted.map(|socket| socket.peer_addr().unwrap().ip())
+        });
+        (addr, accepted)
+    }
+
+    /// Connects to `addr` from `source`
+    async fn connect(addr: SocketAddr, source: [u8; 4]) -> TcpStream {
+        let socket = TcpSocket::new_v4().unwrap();
+        socket.bind((Ipv4Addr::from(source), 0).into()).unwrap();
+        socket.connect(addr).await.unwrap()
+    }
+
+    /// Fails unless the server dropped `socket`
+    async fn assert_dropped(mut socket: TcpStream) {
+        let mut buffer = [0; 1];
+        let read = socket.read(&mut buffer).await;
+        assert!(matches!(read, Ok(0) | Err(_)), "{read:?}");
+    }
+
+    // Only Linux routes all of 127.0.0.0/8 to the loopback interface
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn only_the_client_is_accepted() {
+        let (addr, accepted) = listen([127, 0, 0, 2], None).await;
+
+        assert_dropped(connect(addr, [127, 0, 0, 1]).await).await;
+        let _client = connect(addr, [127, 0, 0, 2]).await;
+        let accepted = accepted.await.unwrap();
+        assert_eq!(accepted, Some(IpAddr::from([127, 0, 0, 2])));
+    }
+
+    #[test]
+    fn passive_addresses_default_to_the_control_connection() {
+        let passive = |config: &str| toml::from_str::<PassiveConfig>(config).unwrap();
+        let local = Some(IpAddr::from([192, 0, 2, 7]));
+
+        let (bind, advertised) = addresses(&passive(""), local);
+        assert_eq!(bind, IpAddr::from([192, 0, 2, 7]));
+        assert_eq!(advertised, Some(Ipv4Addr::new(192, 0, 2, 7)));
+
+        // Behind NAT, the public address is advertised
+        let (bind, advertised) = addresses(&passive(r#"address = "203.0.113.7""#), local);
+        assert_eq!(bind, IpAddr::from([192, 0, 2, 7]));
+        assert_eq!(advertised, Some(Ipv4Addr::new(203, 0, 113, 7)));
+
+        // Bound to every address, the one the client reached is advertised
+        let (bind, advertised) = addresses(&passive(r#"bind = "0.0.0.0""#), local);
+        assert_eq!(bind, IpAddr::from([0, 0, 0, 0]));
+        assert_eq!(advertised, Some(Ipv4Addr::new(192, 0, 2, 7)));
+
+        // IPv4 clients of a dual-stack listener reach a mapped address
+        let mapped = Ipv4Addr::new(192, 0, 2, 7).to_ipv6_mapped();
+        let (_, advertised) = addresses(&passive(""), Some(IpAddr::V6(mapped)));
+        assert_eq!(advertised, Some(Ipv4Addr::new(192, 0, 2, 7)));
+
+        let (_, advertised) = addresses(&passive(""), Some("2001:db8::7".parse().unwrap()));
+        assert_eq!(advertised, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn silent_connections_are_dropped() {
+        let (addr, accepted) = listen([127, 0, 0, 1], Some("token")).await;
+
+        // Dropped once the token didn't come in time
+        assert_dropped(connect(addr, [127, 0, 0, 1]).await).await;
+        let mut wrong = connect(addr, [127, 0, 0, 1]).await;
+        wrong.write_all(b"nekot\r\n").await.unwrap();
+        assert_dropped(wrong).await;
+        let mut client = connect(addr, [127, 0, 0, 1]).await;
+        client.write_all(b"token\r\n").await.unwrap();
+        let accepted = accepted.await.unwrap();
+        assert_eq!(accepted, Some(IpAddr::from([127, 0, 0, 1])));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn silent_connections_hold_up_nothing() {
+        let start = tokio::time::Instant::now();
+        let (addr, accepted) = listen([127, 0, 0, 1], Some("token")).await;
+
+        let _silent = connect(addr, [127, 0, 0, 1]).await;
+        let mut client = connect(addr, [127, 0, 0, 1]).await;
+        client.write_all(b"token\r\n").await.unwrap();
+        let accepted = accepted.await.unwrap();
+        assert_eq!(accepted, Some(IpAddr::from([127, 0, 0, 1])));
+        assert!(start.elapsed() < TOKEN_TIMEOUT);
+    }
+}
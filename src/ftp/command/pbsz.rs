@@ -0,0 +1,48 @@
+use miette::*;
+
+use crate::{ControlWriter, FTPCommand, InnerConnectionRef, StatusCode};
+
+pub struct Pbsz<'a>(&'a str);
+
+impl<'a> FTPCommand<'a> for Pbsz<'a> {
+    const KEYWORD: &'static str = "PBSZ";
+
+    fn feature() -> Option<String> {
+        Some("PBSZ".to_string())
+    }
+
+    /// Sets the protection buffer size, always `0` with TLS
+    ///
+    /// Check: https://datatracker.ietf.org/doc/html/rfc4217#section-8
+    async fn run(
+        &self,
+        connection: InnerConnectionRef,
+        _writer: &mut ControlWriter,
+    ) -> Result<Option<StatusCode>> {
+        let mut connection = connection.lock().await;
+        if !connection.tls {
+            return Ok(Some(StatusCode::CmdBadSequence));
+        }
+        if self.0.parse::<u32>().is_err() {
+            return Ok(Some(StatusCode::SyntaxErrorParams));
+        }
+        connection.pbsz = true;
+        Ok(Some(StatusCode::CommandOk(" PBSZ=0".to_string())))
+    }
+}
+
+impl<'a> TryFrom<(&'a str, Vec<&'a str>)> for Pbsz<'a> {
+    type Error = miette::Error;
+
+    fn try_from((command, args): (&'a str, Vec<&'a str>)) -> Result<Self> {
+        if command == Self::KEYWORD {
+            if args.len() == 1 {
+                Ok(Self(args[0]))
+            } else {
+                Err(miette!("Invalid number of arguments"))
+            }
+        } else {
+            Err(miette!("Invalid command"))
+        }
+    }
+}
@@ -1,48 +1,133 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
 
 use miette::*;
 
-use tokio::{
-    net::{tcp::WriteHalf, TcpStream},
-    sync::Mutex,
-};
+use tokio::{net::TcpStream, sync::Mutex};
+use tracing::*;
 
-use crate::{DataConnection, FTPCommand, InnerConnectionRef, StatusCode};
+use crate::config::DataMode;
+use crate::{
+    ControlWriter, DataConnection, FTPCommand, InnerConnectionRef, StatusCode,
+    DATA_CONNECTION_TIMEOUT,
+};
 
 pub struct Port<'a>(&'a str);
 
 impl<'a> FTPCommand<'a> for Port<'a> {
     const KEYWORD: &'static str = "PORT";
 
-    async fn run<'b>(
+    async fn run(
         &self,
         connection: InnerConnectionRef,
-        _writer: &mut WriteHalf<'b>,
+        _writer: &mut ControlWriter,
     ) -> Result<Option<StatusCode>> {
-        let address = self.0;
-
-        let address = address
-            .split(',')
-            .map(|e| e.parse::<u8>().unwrap())
-            .collect::<Vec<u8>>();
-        let port = (address[4] as u16) << 8 | address[5] as u16;
-        let ip = [address[0], address[1], address[2], address[3]];
-        let data_addr = SocketAddr::from((ip, port));
-
-        tokio::spawn(async move {
-            let data_socket = TcpStream::connect(data_addr)
-                .await
-                .expect("Could not connect to data socket");
-
-            let mut connection = connection.lock().await;
-            let data_connection = Arc::new(Mutex::new(DataConnection::from(data_socket)));
-            connection.data_connection = Some(data_connection);
+        let (peer_addr, fxp) = {
+            let connection = connection.lock().await;
+            if !connection.allows_data_mode(DataMode::Active) {
+                return Ok(Some(StatusCode::CmdDisabled(
+                    " Active mode is disabled, use PASV or EPSV".to_string(),
+                )));
+            }
+            if connection.epsv_all {
+                return Ok(Some(StatusCode::CmdBadSequence));
+            }
+            (connection.peer_addr, connection.allows_fxp())
+        };
+
+        let Some(data_addr) = parse_host_port(self.0) else {
+            return Ok(Some(StatusCode::SyntaxErrorParams));
+        };
+        let context = connection.lock().await.context.clone();
+        let privileged_ports = context.config.data.privileged_ports;
+        let target = context.privacy.redact(&data_addr);
+        match check_target(&data_addr, peer_addr.ip(), fxp, privileged_ports) {
+            Ok(Target::Client) => {}
+            Ok(Target::Fxp) => info!("Site-to-site transfer with {}", target),
+            Err(reason) => {
+                warn!("Refusing PORT to {}, {}", target, reason);
+                return Ok(Some(StatusCode::SyntaxErrorParams));
+            }
+        }
+
+        // Locked until the task is recorded, so it can't set the connection
+        // first
+        let mut session = connection.lock().await;
+        let tls = session.data_tls();
+        let connection = connection.clone();
+        let task = tokio::spawn(async move {
+            let connect = TcpStream::connect(data_addr);
+            let data_socket = match tokio::time::timeout(DATA_CONNECTION_TIMEOUT, connect).await {
+                Ok(Ok(data_socket)) => data_socket,
+                Ok(Err(error)) => {
+                    warn!("Could not open the data connection: {}", error);
+                    return;
+                }
+                Err(_) => {
+                    warn!("Could not open the data connection in time");
+                    return;
+                }
+            };
+            let data_connection = match DataConnection::open(data_socket, tls).await {
+                Ok(data_connection) => Arc::new(Mutex::new(data_connection)),
+                Err(error) => {
+                    warn!("TLS handshake of the data connection failed: {}", error);
+                    return;
+                }
+            };
+
+            connection.lock().await.data_connection = Some(data_connection);
         });
+        session.open_data_connection(task.abort_handle());
 
         Ok(Some(StatusCode::Ok))
     }
 }
 
+/// Who a data connection the server opens goes to
+#[derive(Debug, PartialEq)]
+enum Target {
+    Client,
+    /// Another server, in a site-to-site transfer
+    Fxp,
+}
+
+/// Checks that the server may connect to `target` for a client at
+/// `client`, or why not.
+fn check_target(
+    target: &SocketAddr,
+    client: IpAddr,
+    fxp: bool,
+    privileged_ports: bool,
+) -> std::result::Result<Target, &'static str> {
+    // Otherwise the server could be made to connect anywhere on behalf of
+    // the client, the FTP bounce attack
+    let kind = match target.ip() == client.to_canonical() {
+        true => Target::Client,
+        false if fxp => Target::Fxp,
+        false => return Err("not the client"),
+    };
+    if target.port() < 1024 && !privileged_ports {
+        return Err("a privileged port");
+    }
+    Ok(kind)
+}
+
+/// Parses the `h1,h2,h3,h4,p1,p2` argument of `PORT`.
+fn parse_host_port(argument: &str) -> Option<SocketAddr> {
+    let numbers = argument
+        .split(',')
+        .map(|number| number.trim().parse::<u8>().ok())
+        .collect::<Option<Vec<u8>>>()?;
+    let [h1, h2, h3, h4, p1, p2] = numbers[..] else {
+        return None;
+    };
+    let port = u16::from(p1) << 8 | u16::from(p2);
+    Some(SocketAddr::from(([h1, h2, h3, h4], port)))
+}
+
 impl<'a> TryFrom<(&'a str, Vec<&'a str>)> for Port<'a> {
     type Error = miette::Error;
 
@@ -58,3 +143,57 @@ impl<'a> TryFrom<(&'a str, Vec<&'a str>)> for Port<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT: [u8; 4] = [192, 0, 2, 10];
+
+    fn target(ip: [u8; 4], port: u16) -> SocketAddr {
+        SocketAddr::from((ip, port))
+    }
+
+    #[test]
+    fn only_the_client_is_connected_to() {
+        let client = IpAddr::from(CLIENT);
+        let check = |ip, fxp| check_target(&target(ip, 50000), client, fxp, false);
+
+        assert_eq!(check(CLIENT, false), Ok(Target::Client));
+        assert!(check([198, 51, 100, 7], false).is_err());
+        assert!(check([127, 0, 0, 1], false).is_err());
+    }
+
+    #[test]
+    fn mapped_addresses_are_the_client() {
+        let client = IpAddr::from(std::net::Ipv4Addr::from(CLIENT).to_ipv6_mapped());
+
+        let checked = check_target(&target(CLIENT, 50000), client, false, false);
+        assert_eq!(checked, Ok(Target::Client));
+        let checked = check_target(&target([198, 51, 100, 7], 50000), client, false, false);
+        assert!(checked.is_err());
+    }
+
+    #[test]
+    fn privileged_ports_are_refused() {
+        let client = IpAddr::from(CLIENT);
+        let check =
+            |port, privileged| check_target(&target(CLIENT, port), client, false, privileged);
+
+        assert!(check(20, false).is_err());
+        assert!(check(1023, false).is_err());
+        assert_eq!(check(1024, false), Ok(Target::Client));
+        assert_eq!(check(20, true), Ok(Target::Client));
+    }
+
+    #[test]
+    fn fxp_allows_other_servers() {
+        let client = IpAddr::from(CLIENT);
+
+        let checked = check_target(&target([198, 51, 100, 7], 50000), client, true, false);
+        assert_eq!(checked, Ok(Target::Fxp));
+        // Still not on their privileged ports
+        let checked = check_target(&target([198, 51, 100, 7], 21), client, true, false);
+        assert!(checked.is_err());
+    }
+}
@@ -0,0 +1,53 @@
+use miette::*;
+use tracing::*;
+
+use crate::{ControlWriter, DataProtection, FTPCommand, InnerConnectionRef, StatusCode};
+
+pub struct Prot<'a>(&'a str);
+
+impl<'a> FTPCommand<'a> for Prot<'a> {
+    const KEYWORD: &'static str = "PROT";
+
+    fn feature() -> Option<String> {
+        Some("PROT".to_string())
+    }
+
+    /// Selects whether the following data connections are secured with TLS
+    ///
+    /// Check: https://datatracker.ietf.org/doc/html/rfc4217#section-9
+    async fn run(
+        &self,
+        connection: InnerConnectionRef,
+        _writer: &mut ControlWriter,
+    ) -> Result<Option<StatusCode>> {
+        let mut connection = connection.lock().await;
+        if !connection.tls || !connection.pbsz {
+            return Ok(Some(StatusCode::CmdBadSequence));
+        }
+        let protection = match self.0.to_uppercase().as_str() {
+            "C" => DataProtection::Clear,
+            "P" => DataProtection::Private,
+            "S" | "E" => return Ok(Some(StatusCode::ProtectionLevelNotSupported)),
+            _ => return Ok(Some(StatusCode::CmdNotImplementedParam)),
+        };
+        trace!("Data connections protection set to {:?}", protection);
+        connection.protection = protection;
+        Ok(Some(StatusCode::Ok))
+    }
+}
+
+impl<'a> TryFrom<(&'a str, Vec<&'a str>)> for Prot<'a> {
+    type Error = miette::Error;
+
+    fn try_from((command, args): (&'a str, Vec<&'a str>)) -> Result<Self> {
+        if command == Self::KEYWORD {
+            if args.len() == 1 {
+                Ok(Self(args[0]))
+            } else {
+                Err(miette!("Invalid number of arguments"))
+            }
+        } else {
+            Err(miette!("Invalid command"))
+        }
+    }
+}
@@ -1,24 +1,21 @@
 use miette::*;
-use tokio::net::tcp::WriteHalf;
 
-use crate::{FTPCommand, InnerConnectionRef, StatusCode};
+use crate::{ControlWriter, FTPCommand, InnerConnectionRef, StatusCode};
 
 pub struct Pwd;
 
 impl<'a> FTPCommand<'a> for Pwd {
     const KEYWORD: &'static str = "PWD";
 
-    async fn run<'b>(
+    async fn run(
         &self,
         connection: InnerConnectionRef,
-        _writer: &mut WriteHalf<'b>,
+        _writer: &mut ControlWriter,
     ) -> Result<Option<StatusCode>> {
-        let cwd = connection.lock().await.cwd();
+        let connection = connection.lock().await;
+        let cwd = connection.display_path(&connection.cwd);
 
-        Ok(Some(StatusCode::PathCreated(format!(
-            "{}",
-            cwd.to_string_lossy()
-        ))))
+        Ok(Some(StatusCode::PathCreated(cwd)))
     }
 }
 
@@ -1,29 +1,33 @@
 use miette::*;
+use tokio::io::AsyncWriteExt;
+use tracing::*;
 
-use tokio::{io::AsyncWriteExt, net::tcp::WriteHalf};
-
-use crate::{FTPCommand, InnerConnectionRef, StatusCode};
+use crate::{ControlWriter, FTPCommand, InnerConnectionRef, StatusCode};
 
 pub struct Quit;
 
 impl<'a> FTPCommand<'a> for Quit {
     const KEYWORD: &'static str = "QUIT";
 
-    async fn run<'b>(
+    /// Ends the session, closing its data connection if one is open
+    ///
+    /// Check: https://datatracker.ietf.org/doc/html/rfc959#section-4.1.1
+    async fn run(
         &self,
         connection: InnerConnectionRef,
-        writer: &mut WriteHalf<'b>,
+        writer: &mut ControlWriter,
     ) -> Result<Option<StatusCode>> {
-        writer
-            .write(
-                StatusCode::ServiceClosingControlConnection
-                    .to_string()
-                    .as_bytes(),
-            )
-            .await
-            .into_diagnostic()?;
-        writer.shutdown().await.into_diagnostic()?;
-        connection.lock().await.cancelation_token.cancel();
+        let mut connection = connection.lock().await;
+        connection.abort_data_task();
+        if let Some(data_connection) = connection.data_connection.take() {
+            trace!("Closing the data connection");
+            if let Err(error) = data_connection.lock().await.shutdown().await {
+                trace!("Could not shut down the data connection: {}", error);
+            }
+        }
+        writer.send(StatusCode::ServiceClosingControlConnection)?;
+        // The control connection is shut down once the reply is sent
+        connection.cancelation_token.cancel();
 
         Ok(None)
     }
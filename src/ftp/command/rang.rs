@@ -0,0 +1,65 @@
+use miette::*;
+use tracing::*;
+
+use crate::checksum::ByteRange;
+use crate::{ControlWriter, FTPCommand, InnerConnectionRef, StatusCode};
+
+pub struct Rang {
+    start: u64,
+    end: u64,
+}
+
+impl<'a> FTPCommand<'a> for Rang {
+    const KEYWORD: &'static str = "RANG";
+
+    /// Selects the inclusive byte range of the next `HASH`,
+    /// `RANG 1 0` selects the whole file again
+    ///
+    /// Check: https://datatracker.ietf.org/doc/html/draft-bryan-ftp-range-08#section-3
+    async fn run(
+        &self,
+        connection: InnerConnectionRef,
+        _writer: &mut ControlWriter,
+    ) -> Result<Option<StatusCode>> {
+        let mut connection = connection.lock().await;
+        if (self.start, self.end) == (1, 0) {
+            trace!("Resetting the byte range");
+            connection.range = None;
+            return Ok(Some(StatusCode::Restarting(
+                " Restarting at 0. Ending byte range at EOF".to_string(),
+            )));
+        }
+        if self.start > self.end {
+            return Ok(Some(StatusCode::SyntaxErrorParams));
+        }
+
+        trace!("Selecting bytes {} to {}", self.start, self.end);
+        connection.range = Some(ByteRange {
+            start: self.start,
+            end: self.end,
+        });
+        Ok(Some(StatusCode::Restarting(format!(
+            " Restarting at {}. Ending byte range at {}",
+            self.start, self.end
+        ))))
+    }
+}
+
+impl<'a> TryFrom<(&'a str, Vec<&'a str>)> for Rang {
+    type Error = miette::Error;
+
+    fn try_from((command, args): (&'a str, Vec<&'a str>)) -> Result<Self> {
+        if command == Self::KEYWORD {
+            if args.len() == 2 {
+                Ok(Self {
+                    start: args[0].parse().into_diagnostic()?,
+                    end: args[1].parse().into_diagnostic()?,
+                })
+            } else {
+                Err(miette!("Invalid number of arguments"))
+            }
+        } else {
+            Err(miette!("Invalid command"))
+        }
+    }
+}
@@ -1,23 +1,34 @@
 use miette::*;
 
-use tokio::net::tcp::WriteHalf;
 use tracing::*;
 
-use crate::{FTPCommand, InnerConnectionRef, StatusCode};
+use crate::{ControlWriter, FTPCommand, InnerConnectionRef, StatusCode};
 
 pub struct Rest(u64);
 
 impl<'a> FTPCommand<'a> for Rest {
     const KEYWORD: &'static str = "REST";
 
-    #[tracing::instrument(skip(self, _connection, _writer))]
-    async fn run<'b>(
+    fn feature() -> Option<String> {
+        Some("REST STREAM".to_string())
+    }
+
+    /// Sets the offset the next `STOR` or `RETR` starts at, so interrupted
+    /// transfers can be resumed
+    ///
+    /// Check: https://datatracker.ietf.org/doc/html/rfc3659#section-5
+    #[tracing::instrument(skip(self, connection, _writer))]
+    async fn run(
         &self,
-        _connection: InnerConnectionRef,
-        _writer: &mut WriteHalf<'b>,
+        connection: InnerConnectionRef,
+        _writer: &mut ControlWriter,
     ) -> Result<Option<StatusCode>> {
         trace!("Restarting at {}", self.0);
-        Ok(Some(StatusCode::FileActionPending))
+        connection.lock().await.restart = Some(self.0).filter(|offset| *offset > 0);
+        Ok(Some(StatusCode::Restarting(format!(
+            " Restarting at {}. Send STOR or RETR to initiate transfer",
+            self.0
+        ))))
     }
 }
 
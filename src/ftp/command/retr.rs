@@ -1,61 +1,270 @@
+use std::{io, path::Path};
+
 use miette::*;
 
-use tokio::{
-    fs::File,
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::tcp::WriteHalf,
-};
+use tokio::io::{AsyncReadExt, AsyncWrite};
 use tracing::*;
 
-use crate::{FTPCommand, InnerConnectionRef, StatusCode};
+use crate::archive::{self, Member, Walk, BLOCK};
+use crate::checksum::RunningChecksum;
+use crate::config::Permission;
+use crate::storage::{FileReader, StorageBackend};
+use crate::transfer::{DataWriter, Transfer};
+use crate::{
+    wait_for_data_connection, ControlWriter, Denial, FTPCommand, InnerConnection,
+    InnerConnectionRef, StatusCode,
+};
 
 pub struct Retr<'a>(&'a str);
 
 impl<'a> FTPCommand<'a> for Retr<'a> {
     const KEYWORD: &'static str = "RETR";
 
-    async fn run<'b>(
+    async fn run(
         &self,
         connection: InnerConnectionRef,
-        writer: &mut WriteHalf<'b>,
+        writer: &mut ControlWriter,
     ) -> Result<Option<StatusCode>> {
         let source = self.0;
 
-        let path = connection.lock().await.cwd().join(source);
+        let (storage, path, restart) = {
+            let mut connection = connection.lock().await;
+            match connection.resolve_beneath(source) {
+                Ok(path) => (connection.storage.clone(), path, connection.restart.take()),
+                Err(reply) => return Ok(Some(reply)),
+            }
+        };
+        // Directories are only sent as the archive named after them
+        if let Ok(metadata) = storage.metadata(&path).await {
+            if !metadata.is_file() {
+                warn!("{:?} is not a file", source);
+                return Ok(Some(StatusCode::ActionNotTaken));
+            }
+        }
         trace!("Opening file {:?}", path);
-        let mut file = match File::open(path).await.into_diagnostic() {
-            Ok(file) => file,
-            Err(_) => {
-                error!("File not found");
-                return Ok(Some(StatusCode::FileActionNotTaken));
+        let offset = restart.unwrap_or_default();
+        let sent = match storage.open_read(&path, offset).await {
+            Ok(file) => {
+                if offset > 0 {
+                    trace!("Restarting {:?} at {}", source, offset);
+                }
+                Sent::File(file)
             }
+            Err(error) if error.kind() == io::ErrorKind::InvalidInput => {
+                warn!("Can't restart {:?} at {}: {}", source, offset, error);
+                return Ok(Some(StatusCode::InvalidRestart));
+            }
+            Err(error) if error.kind() == io::ErrorKind::PermissionDenied => {
+                warn!("Permission denied to read {:?}", source);
+                let denial = Denial::Permission {
+                    path: source.to_string(),
+                };
+                return Ok(Some(connection.lock().await.deny(denial)));
+            }
+            Err(_) => match open_archive(&connection, &path, offset).await {
+                Some(Ok(walk)) => Sent::Archive(walk),
+                Some(Err(reply)) => return Ok(Some(reply)),
+                None => {
+                    error!("File not found");
+                    return Ok(Some(StatusCode::FileActionNotTaken));
+                }
+            },
         };
 
-        writer
-            .write(StatusCode::DataOpenTransfer.to_string().as_bytes())
-            .await
-            .into_diagnostic()?;
+        writer.send(StatusCode::DataOpenTransfer)?;
+
+        if !wait_for_data_connection(&connection).await {
+            return Ok(Some(StatusCode::CantOpenDataConnection));
+        }
 
-        let connection = connection.lock().await;
-        let data_connection = connection.data_connection.as_ref().unwrap();
+        // Sent from a copy of the session, not to hold it while archives are
+        // walked
+        let session = connection.lock().await.clone();
+        let data_connection = session.data_connection.as_ref().unwrap();
         let mut data_connection = data_connection.lock().await;
+        let mut data_connection = session.data_writer(&mut *data_connection)?;
+
+        let (mut buffer, _memory) = session.memory.buffer(4096)?;
+        let shown = session.display_path(&session.root.join(&path));
+        let algorithm = session.context.config.data.checksum;
+        let mut checksum = algorithm.map(RunningChecksum::new);
+        let mut output = Output {
+            data_connection: &mut data_connection,
+            checksum: &mut checksum,
+            transfer: session.transfer.start(true, &shown),
+        };
+        let result = match sent {
+            Sent::File(mut file) => output
+                .copy(&mut file, &mut buffer, u64::MAX)
+                .await
+                .map(drop),
+            Sent::Archive(mut walk) => output.archive(&session, &mut walk, &mut buffer).await,
+        };
+        drop(output);
+        if let Err(reply) = result {
+            if let Err(error) = data_connection.shutdown().await {
+                trace!("Could not shut down the data connection: {}", error);
+            }
+            return Ok(Some(reply));
+        }
+        if let Err(error) = data_connection.shutdown().await {
+            warn!("Could not finish sending {:?}: {}", source, error);
+            return Ok(Some(StatusCode::TransferAborted));
+        }
+
+        debug!("Data sent");
+
+        let Some((algorithm, digest)) = checksum.map(RunningChecksum::finish) else {
+            return Ok(Some(StatusCode::ClosingDataConnection));
+        };
+        info!("Sent {:?}, {} {}", source, algorithm.name(), digest);
+        Ok(Some(StatusCode::TransferChecksum { algorithm, digest }))
+    }
+
+    fn target(&self) -> Option<String> {
+        Some(self.0.to_string())
+    }
+}
+
+/// What's sent over the data connection
+enum Sent {
+    File(FileReader),
+    /// A directory, as a tar archive of what's found walking it
+    Archive(Walk),
+}
+
+/// The walk of the directory `path` names the archive of, if archives are
+/// enabled and the session may download it, or the reply refusing it.
+async fn open_archive(
+    connection: &InnerConnectionRef,
+    path: &Path,
+    offset: u64,
+) -> Option<std::result::Result<Walk, StatusCode>> {
+    let connection = connection.lock().await;
+    if !connection.context.config.data.archives {
+        return None;
+    }
+    let directory = archive::archived(path)?;
+    match connection.storage.metadata(&directory).await {
+        Ok(metadata) if metadata.is_dir() => {}
+        _ => return None,
+    }
+    // Only the name of the archive was checked before the command ran
+    let absolute = connection.root.join(&directory);
+    let overrides = connection.overrides(&directory).await;
+    if connection.is_hidden(&absolute) || overrides.hides_path(&directory) {
+        return None;
+    }
+    let mut permissions = connection.permissions_at(&absolute);
+    overrides.restrict(&mut permissions);
+    if !permissions.contains(&Permission::Download) {
+        let denial = Denial::NotPermitted {
+            command: Retr::KEYWORD.to_string(),
+            permission: Permission::Download,
+        };
+        return Some(Err(connection.deny(denial)));
+    }
+    if offset > 0 {
+        warn!("Can't restart the archive of {:?}", directory);
+        return Some(Err(StatusCode::InvalidRestart));
+    }
+    let name = directory.file_name()?.to_string_lossy();
+    debug!("Archiving {:?}", directory);
+    Some(Ok(Walk::new(&directory, &name)))
+}
+
+/// The data connection, and the checksum and progress of what's sent over
+/// it
+struct Output<'a, 'b, W> {
+    data_connection: &'a mut DataWriter<'b, W>,
+    checksum: &'a mut Option<RunningChecksum>,
+    transfer: Transfer,
+}
 
-        let mut buffer = vec![0; 4096];
+/// Answers a transfer that failed once started, failing to read what's sent
+/// or to send it.
+type Sending<T> = std::result::Result<T, StatusCode>;
+
+impl<W: AsyncWrite + Unpin> Output<'_, '_, W> {
+    async fn send(&mut self, bytes: &[u8]) -> Sending<()> {
+        if let Err(error) = self.data_connection.write_all(bytes).await {
+            warn!("Could not send data: {}", error);
+            return Err(StatusCode::TransferAborted);
+        }
+        if let Some(checksum) = self.checksum {
+            checksum.update(bytes);
+        }
+        self.transfer.add(bytes.len());
+        Ok(())
+    }
+
+    /// Sends up to `limit` bytes of `file`, returning how many it had.
+    async fn copy(&mut self, file: &mut FileReader, buffer: &mut [u8], limit: u64) -> Sending<u64> {
+        let mut file = file.take(limit);
+        let mut sent = 0;
         loop {
-            let bytes_read = file.read(&mut buffer).await.into_diagnostic()?;
+            let bytes_read = match file.read(buffer).await {
+                Ok(bytes_read) => bytes_read,
+                Err(error) => {
+                    warn!("Could not read what's sent: {}", error);
+                    return Err(StatusCode::ActionAbortedLocal);
+                }
+            };
             if bytes_read == 0 {
-                break;
+                return Ok(sent);
             }
-            data_connection
-                .write_all(&buffer[..bytes_read])
-                .await
-                .into_diagnostic()?;
+            self.send(&buffer[..bytes_read]).await?;
+            sent += bytes_read as u64;
         }
-        data_connection.shutdown().await.into_diagnostic()?;
+    }
 
-        debug!("Data sent");
+    /// Sends the tar archive of what `walk` finds, reading the files from
+    /// the storage of `session` one by one.
+    async fn archive(
+        &mut self,
+        session: &InnerConnection,
+        walk: &mut Walk,
+        buffer: &mut [u8],
+    ) -> Sending<()> {
+        while let Some(members) = walk.next(session).await {
+            for member in members {
+                self.archive_member(session.storage.as_ref(), &member, buffer)
+                    .await?;
+            }
+        }
+        self.send(&archive::END).await
+    }
 
-        Ok(Some(StatusCode::ClosingDataConnection))
+    /// Sends the header of `member`, and its contents if it's a file.
+    async fn archive_member(
+        &mut self,
+        storage: &dyn StorageBackend,
+        member: &Member,
+        buffer: &mut [u8],
+    ) -> Sending<()> {
+        let zeros = [0; BLOCK];
+        self.send(&member.header()).await?;
+        let size = member.size();
+        if size == 0 {
+            return Ok(());
+        }
+        let mut sent = match storage.open_read(&member.path, 0).await {
+            Ok(mut file) => self.copy(&mut file, buffer, size).await?,
+            Err(error) => {
+                warn!("Could not archive {:?}: {}", member.path, error);
+                0
+            }
+        };
+        // The header is sent, so a file that shrank is padded to it
+        if sent < size {
+            warn!("{:?} shrank while archived", member.path);
+        }
+        while sent < size {
+            let length = (size - sent).min(BLOCK as u64) as usize;
+            self.send(&zeros[..length]).await?;
+            sent += length as u64;
+        }
+        self.send(&zeros[..archive::padding(size)]).await
     }
 }
 
@@ -0,0 +1,63 @@
+use tracing::*;
+
+use crate::auth::LoginState;
+use crate::{Denial, InnerConnectionRef, StatusCode};
+
+use super::{SiteCommand, SiteFuture};
+
+/// `SITE DEBUG [ON|OFF|SHOW]`, traces the protocol of the session
+/// of an administrator
+pub struct SiteDebug;
+
+impl SiteCommand for SiteDebug {
+    fn verb(&self) -> &'static str {
+        "DEBUG"
+    }
+
+    fn description(&self) -> &'static str {
+        "Trace this session (ON, OFF or SHOW the last lines)"
+    }
+
+    fn run<'a>(&'a self, connection: InnerConnectionRef, args: &'a [&'a str]) -> SiteFuture<'a> {
+        Box::pin(async move {
+            let connection = connection.lock().await;
+            let user = connection.username.clone().unwrap_or_default();
+            if connection.login != LoginState::LoggedIn {
+                return Ok(StatusCode::UserNotLoggedIn);
+            }
+            if !connection.context.config.is_admin(&user) {
+                return Ok(connection.deny(Denial::AdminOnly {
+                    command: "SITE DEBUG".to_string(),
+                }));
+            }
+
+            let debug = &connection.debug;
+            let mode = args.first().map(|mode| mode.to_uppercase());
+            match mode.as_deref() {
+                Some("ON") => {
+                    info!("Session {} debugging enabled by {:?}", connection.id, user);
+                    debug.set_enabled(true);
+                    Ok(StatusCode::CommandOk(format!(
+                        " Debugging session {}",
+                        connection.id
+                    )))
+                }
+                Some("OFF") => {
+                    debug.set_enabled(false);
+                    info!("Session {} debugging disabled by {:?}", connection.id, user);
+                    Ok(StatusCode::CommandOk(" Debugging disabled".to_string()))
+                }
+                None | Some("SHOW") => {
+                    let state = if debug.is_enabled() { "on" } else { "off" };
+                    let mut status = format!("-Session {} debugging is {state}", connection.id);
+                    for line in debug.lines() {
+                        status.push_str("\n ");
+                        status.push_str(&line);
+                    }
+                    Ok(StatusCode::SystemStatus(status))
+                }
+                Some(_) => Ok(StatusCode::SyntaxErrorParams),
+            }
+        })
+    }
+}
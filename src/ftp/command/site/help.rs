@@ -0,0 +1,34 @@
+use crate::{InnerConnectionRef, StatusCode};
+
+use super::{SiteCommand, SiteFuture};
+
+/// `SITE HELP`, lists the enabled subcommands
+pub struct SiteHelp;
+
+impl SiteCommand for SiteHelp {
+    fn verb(&self) -> &'static str {
+        "HELP"
+    }
+
+    fn description(&self) -> &'static str {
+        "List the available SITE commands"
+    }
+
+    fn run<'a>(&'a self, connection: InnerConnectionRef, _args: &'a [&'a str]) -> SiteFuture<'a> {
+        Box::pin(async move {
+            let context = connection.lock().await.context.clone();
+            let lines = context
+                .site
+                .iter()
+                .filter(|command| !context.config.site.is_disabled(command.verb()))
+                .map(|command| format!(" {:<8} {}", command.verb(), command.description()))
+                .collect::<Vec<_>>();
+            Ok(StatusCode::HelpMsg {
+                message: format!(
+                    "-The following SITE commands are recognized:\n{}\n214 Help OK",
+                    lines.join("\n")
+                ),
+            })
+        })
+    }
+}
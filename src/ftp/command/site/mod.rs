@@ -0,0 +1,125 @@
+use std::{collections::BTreeMap, fmt, future::Future, pin::Pin, sync::Arc};
+
+use miette::*;
+
+use crate::audit::AuditEvent;
+use crate::{ControlWriter, FTPCommand, InnerConnectionRef, StatusCode};
+
+use self::debug::SiteDebug;
+use self::help::SiteHelp;
+
+mod debug;
+mod help;
+
+/// The future returned by [`SiteCommand::run`]
+pub type SiteFuture<'a> = Pin<Box<dyn Future<Output = Result<StatusCode>> + Send + 'a>>;
+
+/// A server specific subcommand of `SITE`
+///
+/// Subcommands are registered in [`SiteCommands`] instead of the [`Command`](crate::Command)
+/// enum, so extensions can be added without touching the core commands.
+pub trait SiteCommand: Send + Sync {
+    /// The verb following `SITE`, in uppercase
+    fn verb(&self) -> &'static str;
+
+    /// One line description listed by `SITE HELP`
+    fn description(&self) -> &'static str;
+
+    fn run<'a>(&'a self, connection: InnerConnectionRef, args: &'a [&'a str]) -> SiteFuture<'a>;
+}
+
+/// The registered `SITE` subcommands, by verb
+#[derive(Clone)]
+pub struct SiteCommands {
+    commands: BTreeMap<&'static str, Arc<dyn SiteCommand>>,
+}
+
+impl SiteCommands {
+    /// Registers a subcommand, replacing any other with the same verb.
+    pub fn register(&mut self, command: impl SiteCommand + 'static) -> &mut Self {
+        self.commands.insert(command.verb(), Arc::new(command));
+        self
+    }
+
+    /// Returns the subcommand for `verb`, which must be in uppercase.
+    pub fn get(&self, verb: &str) -> Option<Arc<dyn SiteCommand>> {
+        self.commands.get(verb).cloned()
+    }
+
+    /// The registered subcommands, sorted by verb.
+    pub fn iter(&self) -> impl Iterator<Item = &dyn SiteCommand> {
+        self.commands.values().map(AsRef::as_ref)
+    }
+}
+
+impl Default for SiteCommands {
+    fn default() -> Self {
+        let mut commands = Self {
+            commands: BTreeMap::new(),
+        };
+        commands.register(SiteHelp).register(SiteDebug);
+        commands
+    }
+}
+
+impl fmt::Debug for SiteCommands {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.commands.keys()).finish()
+    }
+}
+
+pub struct Site<'a> {
+    verb: &'a str,
+    args: Vec<&'a str>,
+}
+
+impl<'a> FTPCommand<'a> for Site<'a> {
+    const KEYWORD: &'static str = "SITE";
+
+    /// Dispatches to the registered subcommand.
+    ///
+    /// Every `SITE` command is recorded in the audit log,
+    /// since it's the usual vector for server specific abuse
+    async fn run(
+        &self,
+        connection: InnerConnectionRef,
+        _writer: &mut ControlWriter,
+    ) -> Result<Option<StatusCode>> {
+        let verb = self.verb.to_uppercase();
+        let context = connection.lock().await.context.clone();
+        let disabled = context.config.site.is_disabled(&verb);
+
+        let response = match context.site.get(&verb) {
+            _ if disabled => context.disabled(&format!("SITE {verb}")),
+            Some(command) => command.run(connection.clone(), &self.args).await?,
+            None => StatusCode::CmdNotImplementedParam,
+        };
+
+        connection.lock().await.audit(AuditEvent::Site {
+            verb: &verb,
+            args: &self.args,
+            result: response.code(),
+            disabled,
+        });
+
+        Ok(Some(response))
+    }
+}
+
+impl<'a> TryFrom<(&'a str, Vec<&'a str>)> for Site<'a> {
+    type Error = miette::Error;
+
+    fn try_from((command, args): (&'a str, Vec<&'a str>)) -> Result<Self> {
+        if command == Self::KEYWORD {
+            match args.split_first() {
+                Some((verb, args)) => Ok(Self {
+                    verb,
+                    args: args.to_vec(),
+                }),
+                None => Err(miette!("Invalid number of arguments")),
+            }
+        } else {
+            Err(miette!("Invalid command"))
+        }
+    }
+}
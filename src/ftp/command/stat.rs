@@ -0,0 +1,119 @@
+use std::sync::atomic::Ordering;
+
+use miette::*;
+
+use tracing::*;
+
+use crate::auth::LoginState;
+use crate::utils::list_line;
+
+use crate::{ControlWriter, FTPCommand, InnerConnection, InnerConnectionRef, StatusCode};
+
+pub struct Stat<'a>(Option<&'a str>);
+
+impl<'a> FTPCommand<'a> for Stat<'a> {
+    const KEYWORD: &'static str = "STAT";
+
+    /// Without arguments replies with the status of the server,
+    /// otherwise lists the path over the control connection
+    ///
+    /// Check: https://datatracker.ietf.org/doc/html/rfc959#page-36
+    async fn run(
+        &self,
+        connection: InnerConnectionRef,
+        _writer: &mut ControlWriter,
+    ) -> Result<Option<StatusCode>> {
+        let connection = connection.lock().await;
+        let Some(name) = self.0 else {
+            return Ok(Some(system_status(&connection)));
+        };
+
+        let path = match connection.resolve_beneath(name) {
+            Ok(path) => path,
+            Err(reply) => return Ok(Some(reply)),
+        };
+        trace!("Reading status of {:?}", path);
+        let storage = connection.storage.as_ref();
+        let Ok(metadata) = storage.metadata(&path).await else {
+            return Ok(Some(StatusCode::ActionNotTaken));
+        };
+        let symlinks = connection.context.config.listing.symlinks;
+        let mut lines = vec![];
+        if metadata.is_dir() {
+            let overrides = connection.overrides(&path).await;
+            for entry in storage.list(&path).await.into_diagnostic()? {
+                let name = &entry.name;
+                if connection.context.config.hidden.hides(name) || overrides.hides(name) {
+                    continue;
+                }
+                let entry_path = path.join(&entry.name);
+                let line = list_line(&entry.name, storage, &entry_path, &entry.metadata, symlinks);
+                lines.push(line.await);
+            }
+        } else {
+            let metadata = storage.symlink_metadata(&path).await.into_diagnostic()?;
+            lines.push(list_line(name, storage, &path, &metadata, symlinks).await);
+        }
+
+        let mut status = format!("-Status of {name}:\n");
+        for line in lines {
+            status.push_str(&format!(" {line}\n"));
+        }
+        status.push_str("213 End");
+        Ok(Some(StatusCode::FileStatus(status)))
+    }
+
+    fn target(&self) -> Option<String> {
+        self.0.map(ToString::to_string)
+    }
+}
+
+/// The status of the server and of the session of `connection`, with the
+/// transfer it runs if any.
+pub(crate) fn system_status(connection: &InnerConnection) -> StatusCode {
+    let user = match (connection.login, connection.username.as_deref()) {
+        (LoginState::LoggedIn, Some(user)) => format!("Logged in as {user}"),
+        _ => "Not logged in".to_string(),
+    };
+    let control = if connection.tls { "TLS" } else { "plain text" };
+    let transfer = connection.transfer.describe();
+    let data = if connection.data_connection.is_some() || transfer.is_some() {
+        "open"
+    } else {
+        "not open"
+    };
+    let transfer = transfer.unwrap_or_else(|| "No data transfer in progress".to_string());
+    // Clients have to send it, or their passive transfers fail
+    let token = match connection.context.config.passive.data_token {
+        true => "Passive data connections start with the token of the reply\n ",
+        false => "",
+    };
+    let sessions = connection.context.sessions.load(Ordering::Relaxed);
+    StatusCode::SystemStatus(format!(
+        "-FTP server status:\n \
+         Connected from {}\n \
+         {user}\n \
+         Control connection is {control}\n \
+         Data connection is {data}\n \
+         {transfer}\n \
+         {token}\
+         {sessions} client(s) connected",
+        connection.peer_addr,
+    ))
+}
+
+impl<'a> TryFrom<(&'a str, Vec<&'a str>)> for Stat<'a> {
+    type Error = miette::Error;
+
+    fn try_from((command, args): (&'a str, Vec<&'a str>)) -> Result<Self> {
+        if command == Self::KEYWORD {
+            if (0..=1).contains(&args.len()) {
+                Ok(Self(args.first().copied()))
+            } else {
+                Err(miette!("Invalid number of arguments"))
+            }
+        } else {
+            Err(miette!("Invalid command"))
+        }
+    }
+}
@@ -1,53 +1,626 @@
-use miette::*;
-use tokio::{
-    fs::File,
-    io::{AsyncReadExt, AsyncWriteExt},
+use std::{
+    fmt::Write as _,
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
 };
+
+use cap_std::fs::OpenOptions;
+#[cfg(unix)]
+use cap_std::fs::{Permissions, PermissionsExt as _};
+use chrono::Utc;
+use miette::*;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use tracing::*;
 
-use crate::{FTPCommand, InnerConnectionRef, StatusCode};
+use crate::audit::AuditEvent;
+use crate::checksum::RunningChecksum;
+use crate::config::{PartialUploads, Permission};
+use crate::quota::QuotaAccount;
+use crate::storage::{FileWriter, StorageBackend, WriteMode};
+use crate::utils::{available_space, is_disk_full, preallocate};
+
+use crate::{
+    wait_for_data_connection, ControlWriter, Denial, FTPCommand, InnerConnection,
+    InnerConnectionRef, StatusCode,
+};
 
 pub struct Stor<'a>(&'a str);
 
 impl<'a> FTPCommand<'a> for Stor<'a> {
     const KEYWORD: &'static str = "STOR";
 
-    async fn run<'b>(
+    async fn run(
         &self,
         connection: InnerConnectionRef,
-        writer: &mut tokio::net::tcp::WriteHalf<'b>,
+        writer: &mut ControlWriter,
     ) -> Result<Option<StatusCode>> {
-        let destination = self.0;
+        store(connection, writer, self.0, false).await
+    }
 
-        writer
-            .write(StatusCode::DataOpenTransfer.to_string().as_bytes())
-            .await
-            .into_diagnostic()?;
+    fn target(&self) -> Option<String> {
+        Some(self.0.to_string())
+    }
+}
+
+/// Receives `destination` over the data connection, appended to the file
+/// if `append` is set, or from the offset of a pending `REST` otherwise.
+pub(super) async fn store(
+    connection: InnerConnectionRef,
+    writer: &mut ControlWriter,
+    destination: &str,
+    append: bool,
+) -> Result<Option<StatusCode>> {
+    writer.send(StatusCode::DataOpenTransfer)?;
 
-        let connection = connection.lock().await;
+    if !wait_for_data_connection(&connection).await {
+        return Ok(Some(StatusCode::CantOpenDataConnection));
+    }
 
-        let data_connection = connection.data_connection.as_ref().unwrap();
-        let mut data_connection = data_connection.lock().await;
+    let mut connection = connection.lock().await;
+    let allocation = connection.allocation.take();
+    let restart = connection.restart.take();
+    if append && restart.is_some() {
+        debug!(
+            "Ignoring the restart offset, appending to {:?}",
+            destination
+        );
+    }
 
-        let path = connection.cwd().join(destination);
-        let mut file = File::create(path).await.into_diagnostic()?;
+    let data_connection = connection.data_connection.as_ref().unwrap();
+    let mut data_connection = data_connection.lock().await;
 
-        let mut buffer = vec![0; 4096];
-        loop {
-            let bytes_read = data_connection.read(&mut buffer).await.into_diagnostic()?;
-            if bytes_read == 0 {
-                break;
+    let relative = match connection.resolve_beneath(destination) {
+        Ok(relative) => relative,
+        Err(reply) => {
+            data_connection.shutdown().await.into_diagnostic()?;
+            return Ok(Some(reply));
+        }
+    };
+    let path = connection.root.join(&relative);
+    if connection.context.config.honeypot.enabled {
+        let shown = connection.display_path(&path);
+        let reply = drop_upload(&connection, &mut *data_connection, &shown).await?;
+        if reply.is_none() {
+            writer.send(StatusCode::ClosingDataConnection)?;
+        }
+        return Ok(reply);
+    }
+    let storage = connection.storage.clone();
+    let reserved = connection.context.config.uploads.reserved_space;
+    let directory = relative.parent().unwrap_or(Path::new("."));
+    match has_space(storage.as_ref(), directory, allocation, reserved) {
+        Ok(true) => {}
+        Ok(false) => {
+            debug!("Not enough space left to store {:?}", path);
+            data_connection.shutdown().await.into_diagnostic()?;
+            let denial = Denial::NoSpace {
+                requested: allocation,
+            };
+            return Ok(Some(connection.deny(denial)));
+        }
+        Err(error) => warn!("Could not measure the space left for {:?}: {}", path, error),
+    }
+    let mode = match (append, restart) {
+        (true, _) => WriteMode::Append,
+        (false, Some(offset)) => {
+            trace!("Restarting {:?} at {}", path, offset);
+            WriteMode::At(offset)
+        }
+        (false, None) => WriteMode::Truncate,
+    };
+    // Replacing a file, or cutting it short, takes away what was in it
+    let existing = storage
+        .metadata(&relative)
+        .await
+        .ok()
+        .map(|metadata| metadata.len);
+    let replaces = match (mode, existing) {
+        (WriteMode::Truncate, Some(_)) => true,
+        (WriteMode::At(offset), Some(len)) => offset < len,
+        _ => false,
+    };
+    if replaces {
+        let overrides = connection.overrides(&relative).await;
+        let mut permissions = connection.permissions_at(&path);
+        overrides.restrict(&mut permissions);
+        if !permissions.contains(&Permission::Delete) {
+            debug!("Refusing to replace {:?}, the session can't delete", path);
+            data_connection.shutdown().await.into_diagnostic()?;
+            let denial = Denial::NotPermitted {
+                command: Stor::KEYWORD.to_string(),
+                permission: Permission::Delete,
+            };
+            return Ok(Some(connection.deny(denial)));
+        }
+    }
+    // The file a new one replaces counts until it's moved over it, but not
+    // what a restart cuts off it
+    let (replaced, cut) = match (mode, existing) {
+        (WriteMode::Truncate, Some(len)) => (len, 0),
+        (WriteMode::At(offset), Some(len)) => (0, len.saturating_sub(offset)),
+        _ => (0, 0),
+    };
+    // Measured before anything is opened, so a refusal leaves nothing behind
+    let account = connection.quota.clone();
+    if let Some(account) = &account {
+        let size = allocation.unwrap_or_default().saturating_sub(cut);
+        match account.fits(storage.as_ref(), size).await {
+            Ok(true) => {}
+            Ok(false) => {
+                debug!("Refusing {} bytes, past the quota", size);
+                data_connection.shutdown().await.into_diagnostic()?;
+                let quota = account.quota();
+                return Ok(Some(connection.deny(Denial::Quota { quota })));
+            }
+            Err(error) => {
+                warn!(
+                    "Could not measure the usage of {:?}: {}",
+                    connection.root, error
+                );
+                data_connection.shutdown().await.into_diagnostic()?;
+                return Ok(Some(StatusCode::ActionAbortedLocal));
             }
-            file.write_all(&buffer[..bytes_read])
-                .await
-                .into_diagnostic()?;
         }
+    }
+    // A new file is written aside, and only takes its name once complete,
+    // unless the storage only stores it then anyway
+    let aside = mode == WriteMode::Truncate && !storage.stores_on_shutdown(&relative);
+    let temporary = aside.then(|| temporary_path(&relative));
+    let written = temporary.as_deref().unwrap_or(&relative);
+    let file_mode = connection.context.config.uploads.file_mode;
+    let created = match mode {
+        WriteMode::Truncate => true,
+        WriteMode::Append if file_mode.is_some() => storage.metadata(&relative).await.is_err(),
+        WriteMode::Append | WriteMode::At(_) => false,
+    };
+    let mut file = match storage.open_write(written, mode).await {
+        Ok(file) => file,
+        Err(error) if error.kind() == io::ErrorKind::InvalidInput => {
+            warn!("Can't restart {:?} past its end: {}", path, error);
+            data_connection.shutdown().await.into_diagnostic()?;
+            return Ok(Some(StatusCode::InvalidRestart));
+        }
+        Err(error) if error.kind() == io::ErrorKind::Unsupported => {
+            debug!("Can't write {:?} in {:?} mode: {}", path, mode, error);
+            data_connection.shutdown().await.into_diagnostic()?;
+            return Ok(Some(StatusCode::CmdNotImplementedParam));
+        }
+        Err(error) => {
+            warn!("Could not create {:?}: {}", path, error);
+            data_connection.shutdown().await.into_diagnostic()?;
+            if error.kind() == io::ErrorKind::PermissionDenied {
+                let denial = Denial::Permission {
+                    path: destination.to_string(),
+                };
+                return Ok(Some(connection.deny(denial)));
+            }
+            return Ok(Some(StatusCode::ActionNotTaken));
+        }
+    };
+    if let Some(account) = account.as_ref().filter(|_| cut > 0) {
+        account.release(cut).await;
+    }
+    if let Some(file_mode) = file_mode.filter(|_| created) {
+        if let Err(error) = set_mode(storage.as_ref(), written, file_mode) {
+            warn!("Could not set the mode of {:?}: {}", path, error);
+        }
+    }
+
+    let start = match mode {
+        WriteMode::Truncate => 0,
+        WriteMode::Append => storage.metadata(&relative).await.into_diagnostic()?.len,
+        WriteMode::At(offset) => offset,
+    };
+    let max_upload = connection.context.config.limits.max_upload;
+    if let (Some(limit), Some(size)) = (max_upload, allocation) {
+        if start.saturating_add(size) > limit {
+            debug!("Refusing {} more bytes of {:?}, past the limit", size, path);
+            discard(&connection, file, written, start, temporary.is_some()).await?;
+            data_connection.shutdown().await.into_diagnostic()?;
+            return Ok(Some(connection.deny(Denial::UploadTooLarge { limit })));
+        }
+    }
+
+    if let Some(size) = allocation.filter(|_| connection.context.config.uploads.preallocate) {
+        trace!("Reserving {} bytes for {:?}", size, path);
+        match reserve(storage.as_ref(), written, size) {
+            Ok(()) => {}
+            Err(error) if is_disk_full(&error) => {
+                warn!("Not enough space to store {:?}", path);
+                discard(&connection, file, written, start, temporary.is_some()).await?;
+                data_connection.shutdown().await.into_diagnostic()?;
+                let denial = Denial::NoSpace {
+                    requested: Some(size),
+                };
+                return Ok(Some(connection.deny(denial)));
+            }
+            Err(error) => warn!("Could not reserve space for {:?}: {}", path, error),
+        }
+    }
+
+    let (mut buffer, _memory) = connection.memory.buffer(4096)?;
+    let mut data_connection = connection.data_reader(&mut *data_connection)?;
+    let mut checksum = connection
+        .context
+        .config
+        .data
+        .checksum
+        .map(RunningChecksum::new);
+    let mut received = 0;
+    let transfer = connection
+        .transfer
+        .start(false, &connection.display_path(&path));
+    loop {
+        let bytes_read = match data_connection.read(&mut buffer).await {
+            Ok(bytes_read) => bytes_read,
+            Err(error) => {
+                // Like compressed data that isn't a valid zlib stream
+                warn!("Could not receive {:?}: {}", path, error);
+                if let Err(error) = data_connection.shutdown().await {
+                    trace!("Could not shut down the data connection: {}", error);
+                }
+                let temporary = temporary.is_some();
+                abandon(&connection, file, written, &relative, start, temporary).await;
+                forget_usage(&account).await;
+                return Ok(Some(StatusCode::TransferAborted));
+            }
+        };
+        if bytes_read == 0 {
+            break;
+        }
+        received += bytes_read as u64;
+        transfer.add(bytes_read);
+        if let Some(limit) = max_upload.filter(|limit| start + received > *limit) {
+            debug!("Upload of {:?} exceeds the limit of {} bytes", path, limit);
+            discard(&connection, file, written, start, temporary.is_some()).await?;
+            forget_usage(&account).await;
+            if let Err(error) = data_connection.shutdown().await {
+                trace!("Could not shut down the data connection: {}", error);
+            }
+            return Ok(Some(connection.deny(Denial::UploadTooLarge { limit })));
+        }
+        // Shared with the other sessions of the user as it's received
+        if let Some(account) = &account {
+            let refusal = match account.reserve(storage.as_ref(), bytes_read as u64).await {
+                Ok(true) => None,
+                Ok(false) => {
+                    debug!("Upload of {:?} exceeds the quota", path);
+                    let quota = account.quota();
+                    Some(connection.deny(Denial::Quota { quota }))
+                }
+                Err(error) => {
+                    warn!("Could not measure the usage of {:?}: {}", path, error);
+                    Some(StatusCode::ActionAbortedLocal)
+                }
+            };
+            if let Some(refusal) = refusal {
+                // Takes back what was stored so far
+                match temporary {
+                    Some(_) => discard(&connection, file, written, start, true).await?,
+                    None => truncate(storage.as_ref(), file, written, start).await?,
+                }
+                account.forget().await;
+                if let Err(error) = data_connection.shutdown().await {
+                    trace!("Could not shut down the data connection: {}", error);
+                }
+                return Ok(Some(refusal));
+            }
+        }
+        if let Err(error) = file.write_all(&buffer[..bytes_read]).await {
+            warn!("Could not write {:?}: {}", path, error);
+            let temporary = temporary.is_some();
+            abandon(&connection, file, written, &relative, start, temporary).await;
+            forget_usage(&account).await;
+            data_connection.shutdown().await.into_diagnostic()?;
+            if is_disk_full(&error) {
+                return Ok(Some(connection.deny(Denial::NoSpace { requested: None })));
+            }
+            return Ok(Some(StatusCode::ActionAbortedLocal));
+        }
+        if let Some(checksum) = &mut checksum {
+            checksum.update(&buffer[..bytes_read]);
+        }
+    }
+    // Only complete once it's shut down, for storage that buffers writes
+    if let Err(error) = file.shutdown().await {
+        warn!("Could not store {:?}: {}", path, error);
+        let temporary = temporary.is_some();
+        abandon(&connection, file, written, &relative, start, temporary).await;
+        forget_usage(&account).await;
         data_connection.shutdown().await.into_diagnostic()?;
+        if is_disk_full(&error) {
+            return Ok(Some(connection.deny(Denial::NoSpace { requested: None })));
+        }
+        return Ok(Some(StatusCode::ActionAbortedLocal));
+    }
+    if let Some(temporary) = &temporary {
+        trace!("Moving {:?} to {:?}", temporary, relative);
+        if let Err(error) = storage.rename(temporary, &relative).await {
+            warn!("Could not move {:?} to {:?}: {}", temporary, path, error);
+            if let Err(error) = storage.remove(temporary).await {
+                debug!("Could not remove {:?}: {}", temporary, error);
+            }
+            forget_usage(&account).await;
+            data_connection.shutdown().await.into_diagnostic()?;
+            return Ok(Some(StatusCode::ActionAbortedLocal));
+        }
+    }
+    if let Some(account) = account.as_ref().filter(|_| replaced > 0) {
+        account.release(replaced).await;
+    }
+    // The client may have closed the connection already once it sent everything
+    if let Err(error) = data_connection.shutdown().await {
+        trace!("Could not shut down the data connection: {}", error);
+    }
+
+    debug!("Data received");
+
+    let shown = connection.display_path(&path);
+    let checksum = checksum.map(RunningChecksum::finish);
+    match &checksum {
+        Some((algorithm, digest)) => {
+            info!("Received {:?}, {} {}", shown, algorithm.name(), digest);
+            writer.send(StatusCode::TransferChecksum {
+                algorithm: *algorithm,
+                digest: digest.clone(),
+            })?;
+        }
+        None => writer.send(StatusCode::ClosingDataConnection)?,
+    }
+    connection.audit(AuditEvent::Upload {
+        path: &shown,
+        bytes: received,
+        append,
+        checksum: checksum.map(|(algorithm, digest)| format!("{}:{}", algorithm.name(), digest)),
+    });
+    let uploads = &connection.context.uploads;
+    let quota = connection.quota.clone();
+    uploads.submit(&connection.storage, &connection.root, &relative, quota);
+
+    Ok(None)
+}
 
-        debug!("Data received");
+/// Receives an upload to the honeypot without storing it, and records
+/// its size and digest.
+async fn drop_upload<R: AsyncRead + AsyncWrite + Unpin>(
+    connection: &InnerConnection,
+    data_connection: &mut R,
+    path: &str,
+) -> Result<Option<StatusCode>> {
+    let (mut buffer, _memory) = connection.memory.buffer(4096)?;
+    let mut data_connection = connection.data_reader(data_connection)?;
+    let mut hasher = Sha256::new();
+    let mut received = 0;
+    loop {
+        let bytes_read = match data_connection.read(&mut buffer).await {
+            Ok(0) => break,
+            Ok(bytes_read) => bytes_read,
+            Err(error) => {
+                debug!("Could not receive {:?}: {}", path, error);
+                return Ok(Some(StatusCode::TransferAborted));
+            }
+        };
+        hasher.update(&buffer[..bytes_read]);
+        received += bytes_read as u64;
+    }
+    if let Err(error) = data_connection.shutdown().await {
+        trace!("Could not shut down the data connection: {}", error);
+    }
+    let digest = hasher
+        .finalize()
+        .iter()
+        .fold(String::new(), |mut digest, byte| {
+            let _ = write!(digest, "{byte:02x}");
+            digest
+        });
+    let address = connection.peer_addr.ip();
+    let honeypot = &connection.context.honeypot;
+    honeypot.upload(connection.id, address, Path::new(path), received, &digest);
+    Ok(None)
+}
+
+/// Whether the filesystem holding `directory` of `storage` has room for
+/// `requested` bytes, or any at all, besides the `reserved` ones.
+///
+/// Storage that isn't local is taken to always have room.
+pub(super) fn has_space(
+    storage: &dyn StorageBackend,
+    directory: &Path,
+    requested: Option<u64>,
+    reserved: u64,
+) -> io::Result<bool> {
+    let Some(root) = storage.local() else {
+        return Ok(true);
+    };
+    // An empty directory name is the root itself
+    let directory = match directory == Path::new("") {
+        true => Path::new("."),
+        false => directory,
+    };
+    let available = available_space(&root.open_dir(directory)?)?;
+    Ok(match requested {
+        Some(requested) => available >= reserved.saturating_add(requested),
+        None => available > reserved,
+    })
+}
+
+/// Reserves `size` bytes of disk space for the file at `path`, only
+/// possible on local storage.
+fn reserve(storage: &dyn StorageBackend, path: &Path, size: u64) -> io::Result<()> {
+    let Some(root) = storage.local() else {
+        return Ok(());
+    };
+    let mut options = OpenOptions::new();
+    options.write(true);
+    preallocate(&root.dir()?.open_with(path, &options)?, size)
+}
+
+/// Sets the mode bits of the file at `path`, only possible on local
+/// storage.
+#[cfg(unix)]
+fn set_mode(storage: &dyn StorageBackend, path: &Path, mode: u32) -> io::Result<()> {
+    let Some(root) = storage.local() else {
+        return Ok(());
+    };
+    root.dir()?
+        .set_permissions(path, Permissions::from_mode(mode))
+}
+
+/// Sets the mode bits of the file at `path`, which only Unix has.
+#[cfg(not(unix))]
+fn set_mode(_storage: &dyn StorageBackend, _path: &Path, _mode: u32) -> io::Result<()> {
+    Ok(())
+}
+
+/// Takes back an upload refused midway, removing the file if it was
+/// written from the start, or truncating it back to `start` otherwise.
+/// Storage that only stores it once it's shut down is left as it was.
+///
+/// A `temporary` file never had the name of the upload, so removing it
+/// isn't audited.
+async fn discard(
+    connection: &InnerConnection,
+    file: FileWriter,
+    path: &Path,
+    start: u64,
+    temporary: bool,
+) -> Result<()> {
+    let storage = connection.storage.as_ref();
+    if start > 0 || storage.stores_on_shutdown(path) {
+        return truncate(storage, file, path, start).await;
+    }
+    drop(file);
+    storage.remove(path).await.into_diagnostic()?;
+    if !temporary {
+        let shown = connection.display_path(&connection.root.join(path));
+        connection.audit(AuditEvent::Delete { path: &shown });
+    }
+    Ok(())
+}
+
+/// Deals with an upload to `relative` that failed midway, written to
+/// `written` from `start`, as `uploads.partial` says.
+///
+/// The upload is answered as failed either way, so what can't be cleaned
+/// up is only logged.
+async fn abandon(
+    connection: &InnerConnection,
+    file: FileWriter,
+    written: &Path,
+    relative: &Path,
+    start: u64,
+    temporary: bool,
+) {
+    let result = match connection.context.config.uploads.partial {
+        PartialUploads::Keep => keep(connection.storage.as_ref(), file, written, relative).await,
+        // What was there before an append or restart is kept
+        PartialUploads::Quarantine if start == 0 => {
+            quarantine(connection, file, written, relative, temporary).await
+        }
+        PartialUploads::Delete | PartialUploads::Quarantine => {
+            discard(connection, file, written, start, temporary).await
+        }
+    };
+    if let Err(error) = result {
+        let path = connection.root.join(relative);
+        warn!(
+            "Could not clean up the partial upload of {:?}: {}",
+            path, error
+        );
+    }
+}
+
+/// Measures the usage of the user of `account` again before their next
+/// upload, once an upload left behind what it can't tell the size of.
+async fn forget_usage(account: &Option<Arc<QuotaAccount>>) {
+    if let Some(account) = account {
+        account.forget().await;
+    }
+}
+
+/// Keeps what was stored of an upload to `relative`, giving it that name
+/// if it was `written` aside, so it can be resumed.
+async fn keep(
+    storage: &dyn StorageBackend,
+    mut file: FileWriter,
+    written: &Path,
+    relative: &Path,
+) -> Result<()> {
+    if let Err(error) = file.shutdown().await {
+        trace!("Could not flush {:?}: {}", written, error);
+    }
+    drop(file);
+    if written != relative {
+        trace!("Moving {:?} to {:?}", written, relative);
+        storage.rename(written, relative).await.into_diagnostic()?;
+    }
+    Ok(())
+}
+
+/// Moves what was stored of a new upload to `relative` to the quarantine
+/// directory, like `.partial/report.pdf.20240131T120000`, removing it if
+/// it can't be moved.
+async fn quarantine(
+    connection: &InnerConnection,
+    mut file: FileWriter,
+    written: &Path,
+    relative: &Path,
+    temporary: bool,
+) -> Result<()> {
+    if let Err(error) = file.shutdown().await {
+        trace!("Could not flush {:?}: {}", written, error);
+    }
+    let name = relative.file_name().unwrap_or_default().to_string_lossy();
+    let time = Utc::now().format("%Y%m%dT%H%M%S");
+    let quarantined = connection
+        .context
+        .config
+        .uploads
+        .quarantine
+        .join(format!("{}.{}", name, time));
+    debug!("Quarantining {:?} as {:?}", relative, quarantined);
+    if let Err(error) = connection.storage.rename(written, &quarantined).await {
+        warn!("Could not quarantine {:?}: {}", quarantined, error);
+        return discard(connection, file, written, 0, temporary).await;
+    }
+    if !temporary {
+        let from = connection.display_path(&connection.root.join(relative));
+        let to = connection.display_path(&connection.root.join(&quarantined));
+        connection.audit(AuditEvent::Rename {
+            from: &from,
+            to: &to,
+        });
+    }
+    Ok(())
+}
+
+/// Where a new file at `path` is written until it's complete, hidden next
+/// to it, like `.in.report.pdf.3f2a9c1e`.
+fn temporary_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".in.{}.{:08x}", name, rand::random::<u32>()))
+}
 
-        Ok(Some(StatusCode::ClosingDataConnection))
+/// Truncates the file at `path`, being written through `file`, back to
+/// `start`, or leaves it as it was if it isn't stored until `file` is shut
+/// down.
+async fn truncate(
+    storage: &dyn StorageBackend,
+    file: FileWriter,
+    path: &Path,
+    start: u64,
+) -> Result<()> {
+    drop(file);
+    if storage.stores_on_shutdown(path) {
+        return Ok(());
     }
+    let mut file = storage
+        .open_write(path, WriteMode::At(start))
+        .await
+        .into_diagnostic()?;
+    file.shutdown().await.into_diagnostic()
 }
 
 impl<'a> TryFrom<(&'a str, Vec<&'a str>)> for Stor<'a> {
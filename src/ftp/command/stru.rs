@@ -0,0 +1,46 @@
+use miette::*;
+use tracing::*;
+
+use crate::{ControlWriter, FTPCommand, FileStructure, InnerConnectionRef, StatusCode};
+
+pub struct Stru<'a>(&'a str);
+
+impl<'a> FTPCommand<'a> for Stru<'a> {
+    const KEYWORD: &'static str = "STRU";
+
+    /// Selects the structure of the transferred files
+    ///
+    /// Only the file structure is supported, the record and page structures
+    /// are refused with `504`.
+    ///
+    /// Check: https://datatracker.ietf.org/doc/html/rfc959#section-4.1.2
+    async fn run(
+        &self,
+        connection: InnerConnectionRef,
+        _writer: &mut ControlWriter,
+    ) -> Result<Option<StatusCode>> {
+        let structure = match self.0.to_uppercase().as_str() {
+            "F" => FileStructure::File,
+            _ => return Ok(Some(StatusCode::CmdNotImplementedParam)),
+        };
+        trace!("File structure set to {:?}", structure);
+        connection.lock().await.structure = structure;
+        Ok(Some(StatusCode::Ok))
+    }
+}
+
+impl<'a> TryFrom<(&'a str, Vec<&'a str>)> for Stru<'a> {
+    type Error = miette::Error;
+
+    fn try_from((command, args): (&'a str, Vec<&'a str>)) -> Result<Self> {
+        if command == Self::KEYWORD {
+            if args.len() == 1 {
+                Ok(Self(args[0]))
+            } else {
+                Err(miette!("Invalid number of arguments"))
+            }
+        } else {
+            Err(miette!("Invalid command"))
+        }
+    }
+}
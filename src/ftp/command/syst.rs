@@ -3,17 +3,17 @@ use miette::*;
 use tracing::*;
 
 use crate::types::{System, SystemType};
-use crate::{FTPCommand, InnerConnectionRef, StatusCode};
+use crate::{ControlWriter, FTPCommand, InnerConnectionRef, StatusCode};
 
 pub struct Syst;
 
 impl<'a> FTPCommand<'a> for Syst {
     const KEYWORD: &'static str = "SYST";
 
-    async fn run<'b>(
+    async fn run(
         &self,
         _connection: InnerConnectionRef,
-        _writer: &mut tokio::net::tcp::WriteHalf<'b>,
+        _writer: &mut ControlWriter,
     ) -> Result<Option<StatusCode>> {
         trace!(
             "Reporting {} system type",
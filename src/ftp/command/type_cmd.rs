@@ -2,33 +2,60 @@ use miette::*;
 
 use tracing::*;
 
-use crate::{FTPCommand, InnerConnectionRef, StatusCode};
+use crate::{ControlWriter, FTPCommand, InnerConnectionRef, StatusCode, TransferType};
 
-pub struct Type(char);
+pub struct Type<'a> {
+    kind: &'a str,
+    parameter: Option<&'a str>,
+}
 
-impl<'a> FTPCommand<'a> for Type {
+impl<'a> FTPCommand<'a> for Type<'a> {
     const KEYWORD: &'static str = "TYPE";
 
-    #[tracing::instrument(skip(self, _connection, _writer))]
-    async fn run<'b>(
+    /// Selects how files are represented over the following data connections
+    ///
+    /// `TYPE A` sends line endings as `CRLF`, `TYPE I` and `TYPE L 8` send
+    /// files byte by byte. `EBCDIC`, other byte sizes and the Telnet and
+    /// carriage control formats are refused with `504`.
+    ///
+    /// Check: https://datatracker.ietf.org/doc/html/rfc959#section-4.1.2
+    #[tracing::instrument(skip(self, connection, _writer))]
+    async fn run(
         &self,
-        _connection: InnerConnectionRef,
-        _writer: &mut tokio::net::tcp::WriteHalf<'b>,
+        connection: InnerConnectionRef,
+        _writer: &mut ControlWriter,
     ) -> Result<Option<StatusCode>> {
-        trace!("Setting transfer type to {}", self.0);
-        Ok(Some(StatusCode::Ok))
+        let kind = self.kind.to_uppercase();
+        let parameter = self.parameter.map(str::to_uppercase);
+        let transfer_type = match (kind.as_str(), parameter.as_deref()) {
+            ("A", None | Some("N")) => TransferType::Ascii,
+            ("I", None) | ("L", Some("8")) => TransferType::Image,
+            _ => return Ok(Some(StatusCode::CmdNotImplementedParam)),
+        };
+        trace!("Setting transfer type to {:?}", transfer_type);
+        connection.lock().await.transfer_type = transfer_type;
+        Ok(Some(StatusCode::CommandOk(format!(
+            " Type set to {}",
+            kind
+        ))))
     }
 }
 
-impl<'a> TryFrom<(&'a str, Vec<&'a str>)> for Type {
+impl<'a> TryFrom<(&'a str, Vec<&'a str>)> for Type<'a> {
     type Error = miette::Error;
 
     fn try_from((command, args): (&'a str, Vec<&'a str>)) -> Result<Self> {
         if command == Self::KEYWORD {
-            if args.len() == 1 {
-                Ok(Self(args[0].chars().next().unwrap()))
-            } else {
-                Err(miette!("Invalid number of arguments"))
+            match args[..] {
+                [kind] => Ok(Self {
+                    kind,
+                    parameter: None,
+                }),
+                [kind, parameter] => Ok(Self {
+                    kind,
+                    parameter: Some(parameter),
+                }),
+                _ => Err(miette!("Invalid number of arguments")),
             }
         } else {
             Err(miette!("Invalid command"))
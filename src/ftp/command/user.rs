@@ -1,18 +1,55 @@
 use miette::*;
-use tokio::net::tcp::WriteHalf;
+use tracing::*;
 
-use crate::{FTPCommand, InnerConnectionRef, StatusCode};
+use crate::auth::LoginState;
+
+use crate::{ControlWriter, FTPCommand, InnerConnectionRef, StatusCode};
 
 pub struct User<'a>(&'a str);
 
 impl<'a> FTPCommand<'a> for User<'a> {
     const KEYWORD: &'static str = "USER";
 
-    async fn run<'b>(
+    /// Always asks for a password, even for unknown users,
+    /// so the reply doesn't reveal which accounts exist, unless the client
+    /// certificate logs the user in.
+    async fn run(
         &self,
-        _connection: InnerConnectionRef,
-        _writer: &mut WriteHalf<'b>,
+        connection: InnerConnectionRef,
+        _writer: &mut ControlWriter,
     ) -> Result<Option<StatusCode>> {
+        let mut connection = connection.lock().await;
+        if connection.requires_tls() {
+            return Ok(Some(StatusCode::TlsRequired));
+        }
+        let anonymous = &connection.context.config.anonymous;
+        if anonymous.only && !anonymous.matches(self.0) {
+            debug!(
+                "Refusing user {:?}, only anonymous logins are allowed",
+                self.0
+            );
+            connection.login = LoginState::LoggedOut;
+            return Ok(Some(StatusCode::UserNotLoggedIn));
+        }
+        connection.username = Some(self.0.to_string());
+
+        let context = connection.context.clone();
+        let certified = context.config.tls.certificate_login
+            && connection.certificate.as_deref().is_some_and(|subject| {
+                let host = connection.host.as_deref();
+                context.users.verify_certificate(self.0, subject, host)
+            });
+        if certified {
+            if context.users.requires_account(self.0) {
+                debug!("User {:?} needs an account to log in", self.0);
+                connection.login = LoginState::NeedAccount;
+                return Ok(Some(StatusCode::NeedLoginAccount));
+            }
+            info!("User {:?} logged in with a client certificate", self.0);
+            connection.log_in();
+            return Ok(Some(StatusCode::UserLoggedInByCertificate));
+        }
+        connection.login = LoginState::NeedPassword;
         Ok(Some(StatusCode::UsernameOkNeedPassword))
     }
 }
@@ -0,0 +1,41 @@
+use miette::*;
+
+use crate::checksum::HashAlgorithm;
+use crate::{ControlWriter, FTPCommand, InnerConnectionRef, StatusCode};
+
+use super::hash::LegacyHash;
+
+pub struct Xmd5(LegacyHash);
+
+impl<'a> FTPCommand<'a> for Xmd5 {
+    const KEYWORD: &'static str = "XMD5";
+
+    fn feature() -> Option<String> {
+        Some("XMD5".to_string())
+    }
+
+    /// The MD5 of a file, the way older clients and sync tools ask for it
+    async fn run(
+        &self,
+        connection: InnerConnectionRef,
+        _writer: &mut ControlWriter,
+    ) -> Result<Option<StatusCode>> {
+        self.0.run(connection, HashAlgorithm::Md5).await
+    }
+
+    fn target(&self) -> Option<String> {
+        Some(self.0.name().to_string())
+    }
+}
+
+impl<'a> TryFrom<(&'a str, Vec<&'a str>)> for Xmd5 {
+    type Error = miette::Error;
+
+    fn try_from((command, args): (&'a str, Vec<&'a str>)) -> Result<Self> {
+        if command == Self::KEYWORD {
+            Ok(Self(LegacyHash::parse(&args)?))
+        } else {
+            Err(miette!("Invalid command"))
+        }
+    }
+}
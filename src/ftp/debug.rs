@@ -0,0 +1,86 @@
+//! Protocol tracing of a single session, toggled with `SITE DEBUG`.
+//!
+//! While enabled, every command and reply of the session is logged at
+//! `INFO` under the `session_debug` target, so it shows up without
+//! raising the verbosity of the whole server, and the last lines are
+//! kept for `SITE DEBUG SHOW`.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use tracing::*;
+
+use crate::transcript::sanitize;
+
+#[derive(Debug, Default)]
+struct DebugState {
+    enabled: bool,
+    lines: VecDeque<String>,
+}
+
+/// The debug state of a session, shared with its writer task
+#[derive(Debug, Clone)]
+pub struct SessionDebug {
+    session: u64,
+    capacity: usize,
+    state: Arc<Mutex<DebugState>>,
+}
+
+impl SessionDebug {
+    /// Keeps up to `capacity` lines of session `session` once enabled.
+    pub fn new(session: u64, capacity: usize) -> Self {
+        Self {
+            session,
+            capacity,
+            state: Arc::default(),
+        }
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        if let Ok(mut state) = self.state.lock() {
+            state.enabled = enabled;
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.state.lock().is_ok_and(|state| state.enabled)
+    }
+
+    /// Traces a command as received, with credentials masked.
+    pub fn command(&self, input: &str) {
+        self.trace(&format!("> {}", sanitize(input)));
+    }
+
+    /// Traces a reply, which may span multiple lines.
+    pub fn reply(&self, reply: &str) {
+        for line in reply.lines() {
+            self.trace(&format!("< {line}"));
+        }
+    }
+
+    /// The traced lines, oldest first.
+    pub fn lines(&self) -> Vec<String> {
+        self.state
+            .lock()
+            .map(|state| state.lines.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    fn trace(&self, line: &str) {
+        let Ok(mut state) = self.state.lock() else {
+            return;
+        };
+        if !state.enabled {
+            return;
+        }
+        info!(target: "session_debug", session = self.session, "{}", line);
+        if state.lines.len() == self.capacity {
+            state.lines.pop_front();
+        }
+        if self.capacity > 0 {
+            state.lines.push_back(line.to_string());
+        }
+    }
+}
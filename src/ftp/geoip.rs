@@ -0,0 +1,84 @@
+//! Countries of the clients, from a MaxMind GeoIP2 or GeoLite2 database.
+//!
+//! With `geoip.database` and the `geoip` feature, the country of every
+//! client is looked up when it connects, to allow or refuse it and to tag
+//! its session in the logs.
+
+use std::{fmt, net::IpAddr};
+
+use miette::*;
+
+use crate::config::GeoIpConfig;
+
+/// The country database and the countries allowed to connect
+#[derive(Default)]
+pub struct GeoIp {
+    #[cfg(feature = "geoip")]
+    reader: Option<maxminddb::Reader<Vec<u8>>>,
+    config: GeoIpConfig,
+}
+
+impl GeoIp {
+    /// Loads the configured database, if any.
+    #[cfg(feature = "geoip")]
+    pub fn open(config: &GeoIpConfig) -> Result<Self> {
+        let reader = match &config.database {
+            Some(path) => Some(
+                maxminddb::Reader::open_readfile(path)
+                    .into_diagnostic()
+                    .wrap_err_with(|| {
+                        format!("Could not open the GeoIP database {}", path.display())
+                    })?,
+            ),
+            None => None,
+        };
+        Ok(Self {
+            reader,
+            config: config.clone(),
+        })
+    }
+
+    /// Loads the configured database, if any.
+    #[cfg(not(feature = "geoip"))]
+    pub fn open(config: &GeoIpConfig) -> Result<Self> {
+        if config.database.is_some() {
+            bail!("A GeoIP database is configured but the server was built without the `geoip` feature");
+        }
+        Ok(Self::default())
+    }
+
+    /// The ISO code of the country of `address`, if it's in the database.
+    #[cfg(feature = "geoip")]
+    pub fn country(&self, address: IpAddr) -> Option<String> {
+        let reader = self.reader.as_ref()?;
+        let country: maxminddb::geoip2::Country = reader.lookup(address.to_canonical()).ok()?;
+        country
+            .country
+            .and_then(|country| country.iso_code)
+            .map(str::to_string)
+    }
+
+    /// The ISO code of the country of `address`, if it's in the database.
+    #[cfg(not(feature = "geoip"))]
+    pub fn country(&self, _address: IpAddr) -> Option<String> {
+        None
+    }
+
+    /// Returns `true` if clients may connect from `address`, always
+    /// without a database.
+    pub fn allows(&self, address: IpAddr) -> bool {
+        if self.config.database.is_none() {
+            return true;
+        }
+        self.config.allows(self.country(address).as_deref())
+    }
+}
+
+impl fmt::Debug for GeoIp {
+    /// Without the database, which is large
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GeoIp")
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,177 @@
+//! Honeypot mode, to study the clients attacking FTP servers.
+//!
+//! With `honeypot.enabled` any user name and password log in, to the
+//! decoy files of `honeypot.files`, kept in memory: the root and every
+//! other served directory are never opened. Uploads are received and
+//! hashed but never written, and the commands that could change anything
+//! else are off.
+//!
+//! Every command, login and upload is appended to `honeypot.log` as one
+//! JSON object per line, or logged if it isn't set:
+//!
+//! ```text
+//! {"time":"2024-05-04T18:21:07Z","session":4,"ip":"198.51.100.23","event":"login","user":"root","password":"123456"}
+//! {"time":"2024-05-04T18:21:08Z","session":4,"ip":"198.51.100.23","event":"command","command":"STOR","args":["x.sh"]}
+//! {"time":"2024-05-04T18:21:09Z","session":4,"ip":"198.51.100.23","event":"upload","path":"/x.sh","bytes":812,"sha256":"9f86d0…"}
+//! ```
+
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    net::IpAddr,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use chrono::Utc;
+use miette::*;
+use serde_json::{json, Value};
+use tracing::*;
+
+use crate::config::HoneypotConfig;
+use crate::storage::{Memory, ReadOnly};
+
+/// Commands that could change something besides uploading, off in the
+/// honeypot
+pub const DISABLED: [&str; 2] = ["MFMT", "SITE"];
+
+/// The decoy files the clients of the honeypot see, instead of the root
+pub fn decoys(config: &HoneypotConfig) -> ReadOnly {
+    let decoys = config
+        .files
+        .iter()
+        .fold(Memory::default(), |decoys, (path, contents)| {
+            decoys.with_file(path, contents.as_bytes())
+        });
+    ReadOnly(Arc::new(decoys))
+}
+
+/// Appends what the clients of the honeypot do to its log
+#[derive(Debug, Default)]
+pub struct HoneypotLog {
+    enabled: bool,
+    file: Option<Mutex<File>>,
+}
+
+impl HoneypotLog {
+    /// Opens the log of the honeypot for appending, creating it if needed.
+    pub fn open(config: &HoneypotConfig) -> Result<Self> {
+        let file = match (&config.log, config.enabled) {
+            (Some(path), true) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .into_diagnostic()
+                    .wrap_err_with(|| {
+                        format!("Could not open the honeypot log {}", path.display())
+                    })?;
+                Some(Mutex::new(file))
+            }
+            _ => None,
+        };
+        Ok(Self {
+            enabled: config.enabled,
+            file,
+        })
+    }
+
+    /// Records the credentials a client logged in with.
+    pub fn login(&self, session: u64, address: IpAddr, username: &str, password: &str) {
+        let fields = json!({ "user": username, "password": password });
+        self.record(session, address, "login", fields);
+    }
+
+    /// Records a command, before it runs.
+    pub fn command(&self, session: u64, address: IpAddr, command: &str, args: &[&str]) {
+        let fields = json!({ "command": command, "args": args });
+        self.record(session, address, "command", fields);
+    }
+
+    /// Records a file a client uploaded, and didn't store.
+    pub fn upload(&self, session: u64, address: IpAddr, path: &Path, bytes: u64, sha256: &str) {
+        let fields = json!({ "path": path, "bytes": bytes, "sha256": sha256 });
+        self.record(session, address, "upload", fields);
+    }
+
+    fn record(&self, session: u64, address: IpAddr, event: &str, fields: Value) {
+        if !self.enabled {
+            return;
+        }
+        let mut line = json!({
+            "time": Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string(),
+            "session": session,
+            "ip": address,
+            "event": event,
+        });
+        if let (Some(line), Value::Object(fields)) = (line.as_object_mut(), fields) {
+            line.extend(fields);
+        }
+        let Some(file) = &self.file else {
+            info!(target: "honeypot", "{}", line);
+            return;
+        };
+        let Ok(mut file) = file.lock() else {
+            return;
+        };
+        // A single write, so lines of concurrent sessions don't interleave
+        if let Err(error) = file.write_all(format!("{line}\n").as_bytes()) {
+            warn!("Could not write the honeypot log: {}", error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::storage::{Storage, WriteMode};
+
+    fn config(files: &str) -> HoneypotConfig {
+        toml::from_str(&format!("enabled = true\n[files]\n{files}")).unwrap()
+    }
+
+    #[tokio::test]
+    async fn only_the_decoys_are_served() {
+        let config = config("\"backup/passwords.txt\" = \"admin:hunter2\"");
+        // Whatever the root, which isn't opened
+        let storage = decoys(&config).open(Path::new("/etc")).unwrap();
+
+        let root = storage.list(Path::new("")).await.unwrap();
+        let names: Vec<_> = root.iter().map(|entry| entry.name.as_str()).collect();
+        assert_eq!(names, ["backup"]);
+        assert!(root[0].metadata.is_dir());
+
+        let mut file = storage
+            .open_read(Path::new("backup/passwords.txt"), 0)
+            .await
+            .unwrap();
+        let mut contents = String::new();
+        file.read_to_string(&mut contents).await.unwrap();
+        assert_eq!(contents, "admin:hunter2");
+
+        assert!(storage.metadata(Path::new("passwd")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn the_decoys_cant_be_changed() {
+        let storage = decoys(&config("\"notes.txt\" = \"\""))
+            .open(Path::new(""))
+            .unwrap();
+        let write = storage
+            .open_write(Path::new("x.sh"), WriteMode::Truncate)
+            .await;
+        assert_eq!(
+            write.err().unwrap().kind(),
+            std::io::ErrorKind::PermissionDenied
+        );
+        let removal = storage.remove(Path::new("notes.txt")).await;
+        assert_eq!(
+            removal.unwrap_err().kind(),
+            std::io::ErrorKind::PermissionDenied
+        );
+    }
+}
@@ -0,0 +1,169 @@
+//! Localized reply texts, selected per session with `LANG`.
+//!
+//! A catalog is a TOML file mapping the English text of a reply line,
+//! without its code, to its translation:
+//!
+//! ```toml
+//! "User logged in, proceed" = "Usuario conectado, adelante"
+//! ```
+//!
+//! Lines missing from the catalog, like the ones carrying file names,
+//! are sent in English.
+//!
+//! Check: https://datatracker.ietf.org/doc/html/rfc2640#section-4
+
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use miette::*;
+
+/// The language of every reply text, which needs no catalog
+pub const DEFAULT_LANGUAGE: &str = "EN";
+
+/// Translations of reply texts to one language
+#[derive(Debug, Default)]
+pub struct Catalog {
+    messages: HashMap<String, String>,
+}
+
+impl Catalog {
+    /// Loads the catalog from the TOML file at `path`.
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Could not read catalog {}", path.display()))?;
+        let messages = toml::from_str(&contents)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Invalid catalog {}", path.display()))?;
+        Ok(Self { messages })
+    }
+
+    /// Translates every line of `reply` found in the catalog.
+    pub fn translate<'a>(&self, reply: &'a str) -> Cow<'a, str> {
+        let mut translated = String::with_capacity(reply.len());
+        let mut changed = false;
+        for line in reply.split_inclusive('\n') {
+            let text = line.trim_end_matches(['\r', '\n']);
+            // Only the text after the code and its separator is translated
+            let message = text
+                .get(4..)
+                .filter(|_| text.as_bytes()[..3].iter().all(u8::is_ascii_digit))
+                .and_then(|message| self.messages.get(message));
+            match message {
+                Some(message) => {
+                    translated.push_str(&text[..4]);
+                    translated.push_str(message);
+                    translated.push_str(&line[text.len()..]);
+                    changed = true;
+                }
+                None => translated.push_str(line),
+            }
+        }
+        match changed {
+            true => Cow::Owned(translated),
+            false => Cow::Borrowed(reply),
+        }
+    }
+}
+
+/// A supported language, English without a catalog
+#[derive(Debug, Clone)]
+pub struct Language {
+    pub tag: String,
+    catalog: Option<Arc<Catalog>>,
+}
+
+impl Default for Language {
+    fn default() -> Self {
+        Self {
+            tag: DEFAULT_LANGUAGE.to_string(),
+            catalog: None,
+        }
+    }
+}
+
+/// The catalogs of every configured language, by upper case tag
+#[derive(Debug, Default)]
+pub struct Catalogs {
+    languages: BTreeMap<String, Arc<Catalog>>,
+}
+
+impl Catalogs {
+    /// Loads the catalog of every language tag in `catalogs`.
+    pub fn load(catalogs: &BTreeMap<String, PathBuf>) -> Result<Self> {
+        let languages = catalogs
+            .iter()
+            .map(|(tag, path)| Ok((tag.to_uppercase(), Arc::new(Catalog::load(path)?))))
+            .collect::<Result<_>>()?;
+        Ok(Self { languages })
+    }
+
+    /// The supported language matching `tag`, either exactly or by its
+    /// primary subtag, like `ES` for `es-MX`.
+    pub fn find(&self, tag: &str) -> Option<Language> {
+        let tag = tag.to_uppercase();
+        let primary = tag.split('-').next().unwrap_or_default();
+        if primary == DEFAULT_LANGUAGE {
+            return Some(Language::default());
+        }
+        let (tag, catalog) = self
+            .languages
+            .get_key_value(tag.as_str())
+            .or_else(|| self.languages.get_key_value(primary))?;
+        Some(Language {
+            tag: tag.clone(),
+            catalog: Some(catalog.clone()),
+        })
+    }
+
+    /// The `FEAT` line listing the languages, with `*` on `current`.
+    pub fn feature(&self, current: &str) -> String {
+        let tags = std::iter::once(DEFAULT_LANGUAGE)
+            .chain(self.languages.keys().map(String::as_str))
+            .map(|tag| match tag == current {
+                true => format!("{tag}*"),
+                false => tag.to_string(),
+            })
+            .collect::<Vec<_>>();
+        format!("LANG {}", tags.join(";"))
+    }
+}
+
+/// The language of a session, shared with its writer task
+#[derive(Debug, Clone, Default)]
+pub struct SessionLanguage {
+    selected: Arc<Mutex<Language>>,
+}
+
+impl SessionLanguage {
+    pub fn set(&self, language: Language) {
+        if let Ok(mut selected) = self.selected.lock() {
+            *selected = language;
+        }
+    }
+
+    /// The tag of the selected language.
+    pub fn tag(&self) -> String {
+        self.selected
+            .lock()
+            .map(|selected| selected.tag.clone())
+            .unwrap_or_else(|_| DEFAULT_LANGUAGE.to_string())
+    }
+
+    /// Translates a reply to the selected language.
+    pub fn translate<'a>(&self, reply: &'a str) -> Cow<'a, str> {
+        let catalog = self
+            .selected
+            .lock()
+            .ok()
+            .and_then(|selected| selected.catalog.clone());
+        match catalog {
+            Some(catalog) => catalog.translate(reply),
+            None => Cow::Borrowed(reply),
+        }
+    }
+}
@@ -0,0 +1,111 @@
+//! Memory accounting of a session.
+//!
+//! The buffers a session allocates on behalf of its client, like the
+//! command buffer, the transfer buffers and the listings, are reserved
+//! against the budget of the session first. A session going over its
+//! budget is closed with `421`, so a pathological client can't exhaust
+//! the memory of a small deployment.
+
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use miette::*;
+
+/// The error of a reservation over the budget, which ends the session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryLimitExceeded {
+    pub limit: usize,
+}
+
+impl fmt::Display for MemoryLimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Session memory limit of {} bytes exceeded", self.limit)
+    }
+}
+
+impl std::error::Error for MemoryLimitExceeded {}
+
+impl Diagnostic for MemoryLimitExceeded {}
+
+/// The memory budget of a session, unlimited without a `limit`
+#[derive(Debug, Clone, Default)]
+pub struct MemoryBudget {
+    limit: Option<usize>,
+    used: Arc<AtomicUsize>,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: Option<usize>) -> Self {
+        Self {
+            limit,
+            used: Arc::default(),
+        }
+    }
+
+    /// Bytes currently reserved
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// Reserves `bytes` until the reservation is dropped.
+    pub fn reserve(&self, bytes: usize) -> Result<Reservation> {
+        let mut reservation = Reservation {
+            budget: self.clone(),
+            bytes: 0,
+        };
+        reservation.resize(bytes)?;
+        Ok(reservation)
+    }
+
+    /// Allocates a zeroed buffer of `size` bytes, reserved until the
+    /// reservation is dropped.
+    pub fn buffer(&self, size: usize) -> Result<(Vec<u8>, Reservation)> {
+        let reservation = self.reserve(size)?;
+        Ok((vec![0; size], reservation))
+    }
+}
+
+/// Bytes reserved from a [`MemoryBudget`], given back on drop
+#[derive(Debug)]
+pub struct Reservation {
+    budget: MemoryBudget,
+    bytes: usize,
+}
+
+impl Reservation {
+    /// Changes the reserved bytes, for buffers that grow or shrink.
+    ///
+    /// The reservation is left unchanged if the budget can't fit it.
+    pub fn resize(&mut self, bytes: usize) -> Result<()> {
+        let used = &self.budget.used;
+        if bytes <= self.bytes {
+            used.fetch_sub(self.bytes - bytes, Ordering::Relaxed);
+            self.bytes = bytes;
+            return Ok(());
+        }
+        let extra = bytes - self.bytes;
+        let reserved = used.fetch_add(extra, Ordering::Relaxed) + extra;
+        if let Some(limit) = self.budget.limit.filter(|limit| reserved > *limit) {
+            used.fetch_sub(extra, Ordering::Relaxed);
+            return Err(MemoryLimitExceeded { limit }.into());
+        }
+        self.bytes = bytes;
+        Ok(())
+    }
+
+    /// Reserves `bytes` more.
+    pub fn grow(&mut self, bytes: usize) -> Result<()> {
+        self.resize(self.bytes.saturating_add(bytes))
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget.used.fetch_sub(self.bytes, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,90 @@
+//! Text shown to clients, the welcome banner and directory messages.
+//!
+//! Both are written by the operator, with placeholders filled in for
+//! each session, and sent as the continuation lines of a reply, like
+//! wu-ftpd and ProFTPD do.
+
+use std::{path::Path, sync::atomic::Ordering};
+
+use tokio::io::AsyncReadExt;
+
+use crate::{storage::StorageBackend, ServerContext};
+
+/// Bytes of a directory message shown at most
+const MESSAGE_LENGTH: u64 = 4096;
+
+/// Fills in the placeholders of `template`:
+///
+/// - `{hostname}`, the name of the machine
+/// - `{version}`, the version of the server
+/// - `{sessions}`, the sessions open right now
+/// - `{max_sessions}`, the sessions allowed at once, or `unlimited`
+pub fn render(template: &str, context: &ServerContext) -> String {
+    let max_sessions = match context.config.limits.max_sessions {
+        Some(limit) => limit.to_string(),
+        None => "unlimited".to_string(),
+    };
+    template
+        .replace("{hostname}", &hostname())
+        .replace("{version}", env!("CARGO_PKG_VERSION"))
+        .replace(
+            "{sessions}",
+            &context.sessions.load(Ordering::Relaxed).to_string(),
+        )
+        .replace("{max_sessions}", &max_sessions)
+}
+
+/// `text` as continuation lines of a reply with `code`, ready to be
+/// followed by its last line.
+///
+/// Every line starts with the code and a dash, so a line of the text
+/// can't be mistaken for the end of the reply.
+pub fn continuation(code: u16, text: &str) -> String {
+    let mut lines = String::new();
+    for line in text.lines() {
+        let line: String = line
+            .chars()
+            .filter(|c| !c.is_control() || *c == '\t')
+            .collect();
+        lines.push_str(&format!("{code}-{line}\n"));
+    }
+    lines
+}
+
+/// The message file named `name` in `directory` of `storage`, if it has
+/// one, cut short past a few kilobytes.
+pub async fn directory_message(
+    storage: &dyn StorageBackend,
+    directory: &Path,
+    name: &str,
+) -> Option<String> {
+    if name.is_empty() {
+        return None;
+    }
+    let file = storage.open_read(&directory.join(name), 0).await.ok()?;
+    let mut message = Vec::new();
+    file.take(MESSAGE_LENGTH)
+        .read_to_end(&mut message)
+        .await
+        .ok()?;
+    Some(String::from_utf8_lossy(&message).into_owned())
+}
+
+/// The name of the machine, `localhost` if it can't be found.
+#[cfg(unix)]
+fn hostname() -> String {
+    let mut buffer = [0u8; 256];
+    // SAFETY: the buffer is valid for its whole length
+    let result = unsafe { libc::gethostname(buffer.as_mut_ptr().cast(), buffer.len()) };
+    if result != 0 {
+        return "localhost".to_string();
+    }
+    let length = buffer.iter().position(|b| *b == 0).unwrap_or(buffer.len());
+    String::from_utf8_lossy(&buffer[..length]).into_owned()
+}
+
+/// The name of the machine, `localhost` if it can't be found.
+#[cfg(not(unix))]
+fn hostname() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_else(|_| "localhost".to_string())
+}
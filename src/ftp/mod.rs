@@ -1,8 +1,40 @@
+pub mod archive;
+pub mod audit;
+pub mod auth;
+pub mod auth_log;
+pub mod capabilities;
+pub mod checksum;
+pub(crate) mod client;
 pub mod command;
+pub mod debug;
+pub mod geoip;
+pub mod honeypot;
+pub mod lang;
+pub mod memory;
+pub mod messages;
+pub mod overrides;
+pub mod pipeline;
+pub mod privacy;
+pub mod privileges;
+pub mod quota;
+pub mod rate;
+pub mod root_dir;
+pub mod sandbox;
+pub mod self_check;
 pub mod server;
 pub mod status_codes;
+pub mod storage;
+pub mod summary;
+pub mod telnet;
+pub mod throttle;
+pub mod tls;
+pub mod transcript;
+pub mod transfer;
 pub mod types;
+pub mod writer;
 
+pub use capabilities::*;
 pub use command::*;
 pub use server::*;
 pub use status_codes::*;
+pub use writer::*;
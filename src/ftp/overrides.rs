@@ -0,0 +1,314 @@
+//! Per-directory override files, like the `.ftpaccess` of ProFTPD.
+//!
+//! With `overrides.file` set, a file of that name tightens the settings
+//! of the directory holding it and of every directory below it. It's a
+//! TOML fragment:
+//!
+//! ```toml
+//! deny = ["upload", "delete"]
+//! hidden = ["*.bak", "drafts"]
+//! message = "Read-only mirror, please don't upload here"
+//! ```
+//!
+//! Override files can only take permissions away, on top of the ones of
+//! the user and the ACL rules, and hide names besides the configured
+//! ones. The `message` is shown on `CWD` into a directory without a
+//! message file of its own. A file that can't be parsed denies every
+//! permission until it's fixed.
+//!
+//! They're read through the storage of the session while the paths of
+//! commands are resolved, and kept for `overrides.cache_seconds` before
+//! being read again. Clients never see them, so they can't be replaced.
+
+use std::{
+    collections::HashMap,
+    io,
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+use tokio::io::AsyncReadExt;
+use tracing::*;
+
+use crate::config::{NamePattern, Permission};
+use crate::storage::StorageBackend;
+
+/// Bytes of an override file read at most
+const OVERRIDE_LENGTH: u64 = 64 * 1024;
+
+/// The settings of an override file
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DirectoryOverride {
+    /// Permissions taken away in the directory and below it
+    pub deny: Vec<Permission>,
+
+    /// Patterns of names hidden in the directory and below it
+    pub hidden: Vec<NamePattern>,
+
+    /// Shown on `CWD` into the directory or below it
+    pub message: Option<String>,
+}
+
+impl DirectoryOverride {
+    /// Parses an override file, denying everything if it's invalid.
+    fn parse(path: &Path, contents: &str) -> Self {
+        match toml::from_str(contents) {
+            Ok(overrides) => overrides,
+            Err(error) => {
+                warn!(
+                    "Invalid override file {:?}, denying everything: {}",
+                    path, error
+                );
+                Self {
+                    deny: Permission::ALL.to_vec(),
+                    ..Default::default()
+                }
+            }
+        }
+    }
+}
+
+/// The override files of the directories on the way to a path, from the
+/// root of the session
+#[derive(Debug, Default)]
+pub struct Overrides {
+    /// Name of the override files, which are always hidden
+    file: String,
+    /// The directories with an override file, relative to the root
+    directories: Vec<(PathBuf, Arc<DirectoryOverride>)>,
+}
+
+impl Overrides {
+    /// Takes the permissions denied by any of the files away.
+    pub fn restrict(&self, permissions: &mut Vec<Permission>) {
+        for (_, overrides) in &self.directories {
+            permissions.retain(|permission| !overrides.deny.contains(permission));
+        }
+    }
+
+    /// Returns `true` if `name`, in the directory the overrides were
+    /// loaded for, is hidden.
+    pub fn hides(&self, name: &str) -> bool {
+        (!self.file.is_empty() && name == self.file)
+            || self
+                .directories
+                .iter()
+                .any(|(_, overrides)| overrides.hidden.iter().any(|pattern| pattern.matches(name)))
+    }
+
+    /// Returns `true` if any component of `path`, relative to the root,
+    /// is hidden by the override files of the directories above it.
+    pub fn hides_path(&self, path: &Path) -> bool {
+        let mut directory = PathBuf::new();
+        for component in path.components() {
+            let Component::Normal(name) = component else {
+                continue;
+            };
+            let name = name.to_string_lossy();
+            if !self.file.is_empty() && name == self.file {
+                return true;
+            }
+            let hidden = self
+                .directories
+                .iter()
+                .filter(|(path, _)| directory.starts_with(path))
+                .flat_map(|(_, overrides)| &overrides.hidden)
+                .any(|pattern| pattern.matches(&name));
+            if hidden {
+                return true;
+            }
+            directory.push(name.as_ref());
+        }
+        false
+    }
+
+    /// The message of the closest override file setting one
+    pub fn message(&self) -> Option<&str> {
+        self.directories
+            .iter()
+            .rev()
+            .find_map(|(_, overrides)| overrides.message.as_deref())
+    }
+}
+
+/// When an override file was read, and what it held if it was there
+type Cached = (Instant, Option<Arc<DirectoryOverride>>);
+
+/// The override files a session read lately
+#[derive(Debug, Default)]
+pub struct OverrideCache {
+    files: Mutex<HashMap<PathBuf, Cached>>,
+}
+
+impl OverrideCache {
+    /// The override files named `file` of `storage` on the way to `path`,
+    /// relative to its root, reread once older than `lifetime`.
+    pub async fn load(
+        &self,
+        storage: &dyn StorageBackend,
+        file: &str,
+        path: &Path,
+        lifetime: Duration,
+    ) -> Overrides {
+        let mut overrides = Overrides {
+            file: file.to_string(),
+            directories: Vec::new(),
+        };
+        if file.is_empty() {
+            return overrides;
+        }
+        let mut directory = PathBuf::new();
+        let names = path.components().filter_map(|component| match component {
+            Component::Normal(name) => Some(name),
+            _ => None,
+        });
+        // The root, then every directory below it on the way
+        for name in std::iter::once(None).chain(names.map(Some)) {
+            if let Some(name) = name {
+                directory.push(name);
+            }
+            let location = directory.join(file);
+            if let Some(found) = self.get(storage, &location, lifetime).await {
+                overrides.directories.push((directory.clone(), found));
+            }
+        }
+        overrides
+    }
+
+    /// Forgets every file read, for a session moved to another root.
+    pub fn clear(&self) {
+        if let Ok(mut files) = self.files.lock() {
+            files.clear();
+        }
+    }
+
+    async fn get(
+        &self,
+        storage: &dyn StorageBackend,
+        path: &Path,
+        lifetime: Duration,
+    ) -> Option<Arc<DirectoryOverride>> {
+        if let Ok(files) = self.files.lock() {
+            if let Some((read, overrides)) = files.get(path) {
+                if read.elapsed() < lifetime {
+                    return overrides.clone();
+                }
+            }
+        }
+        let overrides = read(storage, path).await.map(Arc::new);
+        if let Ok(mut files) = self.files.lock() {
+            files.insert(path.to_path_buf(), (Instant::now(), overrides.clone()));
+        }
+        overrides
+    }
+}
+
+/// Reads the override file at `path`, if there's one.
+async fn read(storage: &dyn StorageBackend, path: &Path) -> Option<DirectoryOverride> {
+    let file = match storage.open_read(path, 0).await {
+        Ok(file) => file,
+        // Like one beneath a file, which isn't a directory
+        Err(error) if error.kind() != io::ErrorKind::PermissionDenied => return None,
+        Err(error) => {
+            warn!("Could not read the override file {:?}: {}", path, error);
+            return None;
+        }
+    };
+    let mut contents = Vec::new();
+    if let Err(error) = file.take(OVERRIDE_LENGTH).read_to_end(&mut contents).await {
+        warn!("Could not read the override file {:?}: {}", path, error);
+        return None;
+    }
+    trace!("Read the override file {:?}", path);
+    Some(DirectoryOverride::parse(
+        path,
+        &String::from_utf8_lossy(&contents),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{Memory, Storage};
+
+    /// The overrides on the way to `path` among `files`
+    async fn load(files: &[(&str, &str)], path: &str) -> Overrides {
+        let memory = files
+            .iter()
+            .fold(Memory::default(), |memory, (path, contents)| {
+                memory.with_file(path, *contents)
+            });
+        let storage = memory.open(Path::new("")).unwrap();
+        OverrideCache::default()
+            .load(
+                storage.as_ref(),
+                ".ftpaccess",
+                Path::new(path),
+                Duration::ZERO,
+            )
+            .await
+    }
+
+    /// The permissions left to a user with all of them
+    fn left(overrides: &Overrides) -> Vec<Permission> {
+        let mut permissions = Permission::ALL.to_vec();
+        overrides.restrict(&mut permissions);
+        permissions
+    }
+
+    #[tokio::test]
+    async fn denials_add_up_down_the_tree() {
+        let files = [
+            (".ftpaccess", r#"deny = ["upload"]"#),
+            ("docs/.ftpaccess", r#"deny = ["delete"]"#),
+        ];
+
+        let permissions = left(&load(&files, "docs/guides/intro.txt").await);
+        assert!(!permissions.contains(&Permission::Upload));
+        assert!(!permissions.contains(&Permission::Delete));
+        assert_eq!(permissions.len(), Permission::ALL.len() - 2);
+
+        let permissions = left(&load(&files, "photos/cat.jpg").await);
+        assert_eq!(permissions.len(), Permission::ALL.len() - 1);
+        assert!(permissions.contains(&Permission::Delete));
+    }
+
+    #[tokio::test]
+    async fn hidden_directories_hide_what_they_hold() {
+        let files = [
+            (".ftpaccess", r#"hidden = ["drafts"]"#),
+            ("docs/.ftpaccess", r#"hidden = ["*.bak"]"#),
+        ];
+
+        let path = Path::new("drafts/2024/plan.txt");
+        assert!(load(&files, "drafts/2024/plan.txt").await.hides_path(path));
+        let path = Path::new("docs/old.bak/notes.txt");
+        assert!(load(&files, "docs/old.bak/notes.txt")
+            .await
+            .hides_path(path));
+        // Only hidden beneath the directory of the file hiding it
+        let path = Path::new("old.bak/notes.txt");
+        assert!(!load(&files, "old.bak/notes.txt").await.hides_path(path));
+    }
+
+    #[tokio::test]
+    async fn override_files_are_always_hidden() {
+        let overrides = load(&[], "docs").await;
+
+        assert!(overrides.hides(".ftpaccess"));
+        assert!(overrides.hides_path(Path::new(".ftpaccess")));
+        assert!(overrides.hides_path(Path::new("docs/.ftpaccess")));
+        assert!(!overrides.hides_path(Path::new("docs/readme.txt")));
+    }
+
+    #[tokio::test]
+    async fn invalid_files_deny_everything() {
+        let files = [("docs/.ftpaccess", "deny = [")];
+
+        assert!(left(&load(&files, "docs/readme.txt").await).is_empty());
+        assert_eq!(left(&load(&files, "readme.txt").await), Permission::ALL);
+    }
+}
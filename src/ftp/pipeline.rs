@@ -0,0 +1,326 @@
+//! Post-upload processing.
+//!
+//! Finished uploads below a configured mount are queued to a pool of
+//! workers that run the mount's actions once the client has already
+//! received its `226` reply. Failures are reported to the audit log, and
+//! the files moved are recorded in it.
+//!
+//! The actions reach the file through the storage of the session that
+//! uploaded it, so they stay beneath its root and cached listings are
+//! forgotten as files are moved. Setting modes and owners, and running
+//! hooks, need the local filesystem.
+
+use std::{
+    io::{self, Write as _},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+#[cfg(unix)]
+use cap_std::fs::{Permissions, PermissionsExt as _};
+use flate2::{write::GzEncoder, Compression};
+use miette::*;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::{mpsc, Mutex},
+};
+use tracing::*;
+
+use crate::audit::{AuditEvent, AuditLog};
+use crate::config::{PostUploadAction, UploadMount, UploadsConfig};
+use crate::quota::QuotaAccount;
+use crate::root_dir::RootDir;
+use crate::storage::{StorageBackend, WriteMode};
+
+#[derive(Debug)]
+struct UploadJob {
+    storage: Arc<dyn StorageBackend>,
+    /// The root of the session, which `path` is relative to
+    root: PathBuf,
+    path: PathBuf,
+    actions: Vec<PostUploadAction>,
+    /// The usage of the uploader, changed by the actions
+    quota: Option<Arc<QuotaAccount>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct UploadPipeline {
+    mounts: Arc<Vec<UploadMount>>,
+    sender: mpsc::UnboundedSender<UploadJob>,
+}
+
+impl UploadPipeline {
+    /// Creates the pipeline, spawning its workers if any mount is configured.
+    ///
+    /// Relative mount paths are resolved against the served `root`.
+    pub fn spawn(config: &UploadsConfig, root: &Path, audit: Arc<AuditLog>) -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let mounts = config
+            .mounts
+            .iter()
+            .map(|mount| UploadMount {
+                path: root.join(&mount.path),
+                actions: mount.actions.clone(),
+            })
+            .collect::<Vec<_>>();
+
+        if !mounts.is_empty() {
+            let limits = Limits {
+                directory_mode: config.directory_mode,
+                hook_timeout: Duration::from_secs(config.hook_timeout_seconds),
+            };
+            for id in 0..config.workers.max(1) {
+                tokio::spawn(worker(id, receiver.clone(), audit.clone(), limits));
+            }
+        }
+
+        Self {
+            mounts: Arc::new(mounts),
+            sender,
+        }
+    }
+
+    /// Queues the actions of the most specific mount containing the file
+    /// at `path` of `storage`, relative to the session `root`, whose usage
+    /// is measured again after them if its user has a `quota`.
+    pub fn submit(
+        &self,
+        storage: &Arc<dyn StorageBackend>,
+        root: &Path,
+        path: &Path,
+        quota: Option<Arc<QuotaAccount>>,
+    ) {
+        let full = root.join(path);
+        let Some(mount) = self
+            .mounts
+            .iter()
+            .filter(|mount| full.starts_with(&mount.path))
+            .max_by_key(|mount| mount.path.components().count())
+        else {
+            return;
+        };
+
+        trace!("Queueing post-upload actions for {:?}", full);
+        let job = UploadJob {
+            storage: storage.clone(),
+            root: root.to_path_buf(),
+            path: path.to_path_buf(),
+            actions: mount.actions.clone(),
+            quota,
+        };
+        if let Err(error) = self.sender.send(job) {
+            error!(
+                "Could not queue the post-upload actions of {:?}: {}",
+                full, error
+            );
+        }
+    }
+}
+
+/// How the actions of every job are run
+#[derive(Debug, Clone, Copy)]
+struct Limits {
+    /// Mode bits of the directories actions create, if set
+    directory_mode: Option<u32>,
+    /// How long hooks may run for
+    hook_timeout: Duration,
+}
+
+async fn worker(
+    id: usize,
+    receiver: Arc<Mutex<mpsc::UnboundedReceiver<UploadJob>>>,
+    audit: Arc<AuditLog>,
+    limits: Limits,
+) {
+    trace!("Starting post-upload worker {}", id);
+    loop {
+        let Some(job) = receiver.lock().await.recv().await else {
+            break;
+        };
+
+        let storage = job.storage.as_ref();
+        let mut path = job.path;
+        for action in &job.actions {
+            match run_action(storage, &job.root, action, &path, limits).await {
+                Ok(new_path) => {
+                    if new_path != path {
+                        let from = job.root.join(&path);
+                        let to = job.root.join(&new_path);
+                        let (from, to) = (from.to_string_lossy(), to.to_string_lossy());
+                        audit.record(
+                            None,
+                            AuditEvent::Rename {
+                                from: &from,
+                                to: &to,
+                            },
+                        );
+                    }
+                    path = new_path
+                }
+                Err(error) => {
+                    let full = job.root.join(&path);
+                    error!("Post-upload action {:?} failed on {:?}", action, full);
+                    audit.record(
+                        None,
+                        AuditEvent::ActionFailed {
+                            path: &full.to_string_lossy(),
+                            action: format!("{:?}", action),
+                            error: error.to_string(),
+                        },
+                    );
+                    break;
+                }
+            }
+        }
+        // Like a compressed file, that takes less
+        if let Some(quota) = &job.quota {
+            quota.forget().await;
+        }
+        debug!(
+            "Post-upload actions finished for {:?}",
+            job.root.join(&path)
+        );
+    }
+}
+
+/// Runs a single action on the file at `path` of `storage`, relative to
+/// the session `root`, returning its path afterwards.
+async fn run_action(
+    storage: &dyn StorageBackend,
+    root: &Path,
+    action: &PostUploadAction,
+    path: &Path,
+    limits: Limits,
+) -> Result<PathBuf> {
+    trace!("Running {:?} on {:?}", action, path);
+    match action {
+        PostUploadAction::MoveDated { format } => {
+            let parent = path.parent().unwrap_or(Path::new(""));
+            let directory = parent.join(chrono::Local::now().format(format).to_string());
+            // Other storage has its directories made as files are put in them
+            if let Some(local) = storage.local() {
+                let (target, mode) = (directory.clone(), limits.directory_mode);
+                local
+                    .blocking(move |dir| create_dir(dir, &target, mode))
+                    .await
+                    .into_diagnostic()?;
+            }
+            let destination = directory.join(path.file_name().unwrap_or_default());
+            ensure_absent(storage, &destination).await?;
+            storage.rename(path, &destination).await.into_diagnostic()?;
+            Ok(destination)
+        }
+        #[cfg(unix)]
+        PostUploadAction::Chmod { mode } => {
+            let (mode, target) = (*mode, path.to_path_buf());
+            local(storage, action)?
+                .blocking(move |dir| dir.set_permissions(target, Permissions::from_mode(mode)))
+                .await
+                .into_diagnostic()?;
+            Ok(path.to_path_buf())
+        }
+        #[cfg(unix)]
+        PostUploadAction::Chown { uid, gid } => {
+            let (uid, gid, target) = (*uid, *gid, path.to_path_buf());
+            local(storage, action)?
+                .blocking(move |dir| {
+                    let file = dir.open(target)?.into_std();
+                    std::os::unix::fs::fchown(file, uid, gid)
+                })
+                .await
+                .into_diagnostic()?;
+            Ok(path.to_path_buf())
+        }
+        // Files have no mode or owner off Unix
+        #[cfg(not(unix))]
+        PostUploadAction::Chmod { .. } | PostUploadAction::Chown { .. } => {
+            bail!("{:?} is only possible on Unix", action)
+        }
+        PostUploadAction::Gzip => {
+            let mut destination = path.to_path_buf().into_os_string();
+            destination.push(".gz");
+            let destination = PathBuf::from(destination);
+            ensure_absent(storage, &destination).await?;
+            gzip(storage, path, &destination).await.into_diagnostic()?;
+            Ok(destination)
+        }
+        PostUploadAction::Hook { command, args } => {
+            local(storage, action)?;
+            let mut child = tokio::process::Command::new(command)
+                .args(args)
+                .arg(root.join(path))
+                .kill_on_drop(true)
+                .spawn()
+                .into_diagnostic()?;
+            // A hung hook would hold its worker forever
+            let status = match tokio::time::timeout(limits.hook_timeout, child.wait()).await {
+                Ok(status) => status.into_diagnostic()?,
+                Err(_) => {
+                    child.kill().await.into_diagnostic()?;
+                    bail!(
+                        "Hook {} didn't finish within {:?}",
+                        command.display(),
+                        limits.hook_timeout
+                    );
+                }
+            };
+            if !status.success() {
+                bail!("Hook {} exited with {}", command.display(), status);
+            }
+            Ok(path.to_path_buf())
+        }
+    }
+}
+
+/// Fails if there's a file at `destination` of `storage`, which an action
+/// would otherwise replace without the uploader being allowed to delete it.
+async fn ensure_absent(storage: &dyn StorageBackend, destination: &Path) -> Result<()> {
+    match storage.symlink_metadata(destination).await {
+        Ok(_) => bail!("{:?} exists already", destination),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(error) => Err(error).into_diagnostic(),
+    }
+}
+
+/// The local directory of `storage`, which `action` can't do without.
+fn local<'a>(storage: &'a dyn StorageBackend, action: &PostUploadAction) -> Result<&'a RootDir> {
+    storage
+        .local()
+        .ok_or_else(|| miette!("{:?} is only possible on the local filesystem", action))
+}
+
+/// Creates the directory at `path` beneath `dir` with its parents, setting
+/// its `mode` bits if it's new, which only Unix has.
+fn create_dir(dir: &cap_std::fs::Dir, path: &Path, mode: Option<u32>) -> io::Result<()> {
+    let new = !dir.exists(path);
+    dir.create_dir_all(path)?;
+    match mode.filter(|_| new) {
+        #[cfg(unix)]
+        Some(mode) => dir.set_permissions(path, Permissions::from_mode(mode)),
+        _ => Ok(()),
+    }
+}
+
+/// Compresses the file at `source` of `storage` into `destination`, then
+/// removes it.
+async fn gzip(storage: &dyn StorageBackend, source: &Path, destination: &Path) -> io::Result<()> {
+    let mut input = storage.open_read(source, 0).await?;
+    let mut output = storage.open_write(destination, WriteMode::Truncate).await?;
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    let mut buffer = vec![0; 8192];
+    loop {
+        let read = input.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        encoder.write_all(&buffer[..read])?;
+        output.write_all(encoder.get_ref()).await?;
+        encoder.get_mut().clear();
+    }
+    output.write_all(&encoder.finish()?).await?;
+    output.shutdown().await?;
+    drop(input);
+    storage.remove(source).await
+}
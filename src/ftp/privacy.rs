@@ -0,0 +1,82 @@
+//! Client addresses as they appear in the logs.
+//!
+//! Hashed addresses use a keyed HMAC, so the same client can still be
+//! correlated across log lines without its address being recoverable
+//! by anyone who doesn't know the key.
+//! Everything besides logging keeps using the real address.
+
+use std::fmt::Write;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+
+use crate::config::{ClientIpMode, PrivacyConfig};
+
+/// Number of bytes of the HMAC kept in the logs
+const HASH_LENGTH: usize = 8;
+
+#[derive(Debug, Clone)]
+pub struct IpPrivacy {
+    mode: ClientIpMode,
+    key: Vec<u8>,
+}
+
+impl IpPrivacy {
+    /// Uses the configured key, or a random one if there's none,
+    /// in which case hashes only correlate within a single run.
+    pub fn new(config: &PrivacyConfig) -> Self {
+        let key = match &config.key {
+            Some(key) => key.as_bytes().to_vec(),
+            None => {
+                let mut key = vec![0; 32];
+                rand::thread_rng().fill_bytes(&mut key);
+                key
+            }
+        };
+        Self {
+            mode: config.client_ips,
+            key,
+        }
+    }
+
+    /// Formats `addr` for the logs according to the configured mode.
+    pub fn redact(&self, addr: &SocketAddr) -> String {
+        match self.mode {
+            ClientIpMode::Plain => addr.to_string(),
+            ClientIpMode::Truncate => truncate(addr.ip()).to_string(),
+            ClientIpMode::Hash => self.hash(addr.ip()),
+        }
+    }
+
+    fn hash(&self, ip: IpAddr) -> String {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts keys of any length");
+        match ip {
+            IpAddr::V4(ip) => mac.update(&ip.octets()),
+            IpAddr::V6(ip) => mac.update(&ip.octets()),
+        }
+        let digest = mac.finalize().into_bytes();
+        digest[..HASH_LENGTH]
+            .iter()
+            .fold(String::from("ip-"), |mut hex, byte| {
+                let _ = write!(hex, "{byte:02x}");
+                hex
+            })
+    }
+}
+
+/// Keeps the `/24` network of IPv4 addresses and the `/48` of IPv6 ones
+fn truncate(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            IpAddr::V4(Ipv4Addr::new(a, b, c, 0))
+        }
+        IpAddr::V6(ip) => {
+            let [a, b, c, ..] = ip.segments();
+            IpAddr::V6(Ipv6Addr::new(a, b, c, 0, 0, 0, 0, 0))
+        }
+    }
+}
@@ -0,0 +1,215 @@
+//! Dropping root privileges.
+//!
+//! Binding port 21 needs root, serving files doesn't. With `process.user`
+//! configured, a server started as root switches to that user once its
+//! listener is bound and everything it needs is opened, so no command is
+//! ever handled as root.
+//!
+//! With `process.chroot` it first chroots into the served root, so even a
+//! path slipping past the jailing of the sessions can't reach the rest of
+//! the system. The user is looked up before, `/etc/passwd` is gone after.
+//!
+//! Neither is possible off Unix, where configuring them is refused.
+
+use std::path::Path;
+#[cfg(unix)]
+use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+use miette::*;
+#[cfg(unix)]
+use tracing::*;
+
+use crate::config::ProcessConfig;
+
+/// The user and group the server switches to
+#[cfg(unix)]
+#[derive(Debug, Clone)]
+pub struct Identity {
+    name: String,
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+}
+
+#[cfg(unix)]
+impl Identity {
+    /// Looks up the configured user and group.
+    ///
+    /// Returns `None` if no user is configured, or the process isn't root.
+    pub fn lookup(config: &ProcessConfig) -> Result<Option<Self>> {
+        let Some(user) = &config.user else {
+            return Ok(None);
+        };
+        if !is_root() {
+            warn!(
+                "Not running as root, staying the current user instead of {:?}",
+                user
+            );
+            return Ok(None);
+        }
+        let (uid, primary_gid) = lookup_user(user)?;
+        let gid = match &config.group {
+            Some(group) => lookup_group(group)?,
+            None => primary_gid,
+        };
+        Ok(Some(Self {
+            name: user.clone(),
+            uid,
+            gid,
+        }))
+    }
+
+    /// Switches to the user and group, without supplementary groups.
+    pub fn assume(&self) -> Result<()> {
+        let Self { name, uid, gid } = self;
+        // The groups go first, only root can change them
+        // SAFETY: an empty list is never read
+        if unsafe { libc::setgroups(0, std::ptr::null()) } != 0 {
+            return Err(os_error("Could not clear the supplementary groups"));
+        }
+        // SAFETY: plain system calls taking ids, which glibc applies to every
+        // thread of the runtime
+        if unsafe { libc::setgid(*gid) } != 0 {
+            return Err(os_error(&format!("Could not switch to group {gid}")));
+        }
+        // SAFETY: as above
+        if unsafe { libc::setuid(*uid) } != 0 {
+            return Err(os_error(&format!("Could not switch to user {name:?}")));
+        }
+        // SAFETY: as above
+        if unsafe { libc::setuid(0) } == 0 {
+            bail!("Could still switch back to root after dropping privileges");
+        }
+        info!(
+            "Dropped privileges, running as {:?} ({}:{})",
+            name, uid, gid
+        );
+        Ok(())
+    }
+}
+
+/// Changes the root directory of the process to `root`, and moves into it.
+#[cfg(unix)]
+pub fn chroot(root: &Path) -> Result<()> {
+    if !is_root() {
+        bail!(
+            help = "Start the server as root, or don't chroot",
+            "Only root can chroot into {:?}",
+            root
+        );
+    }
+    let c_root = CString::new(root.as_os_str().as_bytes()).into_diagnostic()?;
+    // SAFETY: the path is a valid C string for the call, and the root
+    // directory is shared by every thread
+    if unsafe { libc::chroot(c_root.as_ptr()) } != 0 {
+        return Err(os_error(&format!("Could not chroot into {root:?}")));
+    }
+    // Otherwise the working directory would still be outside of it
+    std::env::set_current_dir("/")
+        .into_diagnostic()
+        .wrap_err("Could not move into the chroot")?;
+    info!("Chrooted into {:?}", root);
+    Ok(())
+}
+
+#[cfg(unix)]
+fn is_root() -> bool {
+    // SAFETY: geteuid can't fail and has no preconditions
+    unsafe { libc::geteuid() == 0 }
+}
+
+#[cfg(unix)]
+fn os_error(message: &str) -> Report {
+    miette!("{}: {}", message, std::io::Error::last_os_error())
+}
+
+/// The uid and primary gid of `name`.
+#[cfg(unix)]
+fn lookup_user(name: &str) -> Result<(libc::uid_t, libc::gid_t)> {
+    let c_name = CString::new(name).into_diagnostic()?;
+    let mut buffer = vec![0 as libc::c_char; 16384];
+    // SAFETY: an all-zero passwd is valid, it only holds integers and pointers
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result = std::ptr::null_mut();
+    // SAFETY: every pointer is valid for the call, and the buffer outlives `passwd`
+    let code = unsafe {
+        libc::getpwnam_r(
+            c_name.as_ptr(),
+            &mut passwd,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+    if result.is_null() {
+        match code {
+            0 => bail!("Unknown user {:?}", name),
+            code => bail!(
+                "Could not look up user {:?}: {}",
+                name,
+                std::io::Error::from_raw_os_error(code)
+            ),
+        }
+    }
+    Ok((passwd.pw_uid, passwd.pw_gid))
+}
+
+/// The gid of `name`.
+#[cfg(unix)]
+fn lookup_group(name: &str) -> Result<libc::gid_t> {
+    let c_name = CString::new(name).into_diagnostic()?;
+    let mut buffer = vec![0 as libc::c_char; 16384];
+    // SAFETY: an all-zero group is valid, it only holds integers and pointers
+    let mut group: libc::group = unsafe { std::mem::zeroed() };
+    let mut result = std::ptr::null_mut();
+    // SAFETY: every pointer is valid for the call, and the buffer outlives `group`
+    let code = unsafe {
+        libc::getgrnam_r(
+            c_name.as_ptr(),
+            &mut group,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+    if result.is_null() {
+        match code {
+            0 => bail!("Unknown group {:?}", name),
+            code => bail!(
+                "Could not look up group {:?}: {}",
+                name,
+                std::io::Error::from_raw_os_error(code)
+            ),
+        }
+    }
+    Ok(group.gr_gid)
+}
+
+/// The user and group the server switches to, never found off Unix
+#[cfg(not(unix))]
+#[derive(Debug, Clone)]
+pub enum Identity {}
+
+#[cfg(not(unix))]
+impl Identity {
+    /// Refuses a configured user, there's none to switch to.
+    pub fn lookup(config: &ProcessConfig) -> Result<Option<Self>> {
+        match &config.user {
+            Some(user) => bail!(
+                help = "Run the server as the user instead",
+                "Can't switch to user {:?}, only possible on Unix",
+                user
+            ),
+            None => Ok(None),
+        }
+    }
+
+    pub fn assume(&self) -> Result<()> {
+        match *self {}
+    }
+}
+
+/// Refuses to chroot, only possible on Unix.
+#[cfg(not(unix))]
+pub fn chroot(root: &Path) -> Result<()> {
+    bail!("Can't chroot into {:?}, only possible on Unix", root)
+}
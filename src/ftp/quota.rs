@@ -0,0 +1,124 @@
+//! Disk usage of the users with a quota.
+//!
+//! The usage of a user is measured through the storage of their session
+//! the first time it's needed, then kept up to date as their uploads
+//! reserve and release space. Uploads don't walk the whole tree of the user
+//! this way, and concurrent sessions of the same user share what's left of
+//! the quota instead of each filling it up.
+
+use std::{
+    collections::HashMap,
+    io,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use crate::storage::{usage, StorageBackend};
+
+/// The usage of every user with a quota, by name
+#[derive(Debug, Default)]
+pub struct Quotas {
+    accounts: Mutex<HashMap<String, Arc<QuotaAccount>>>,
+}
+
+impl Quotas {
+    /// The account of `username`, shared by all of their sessions.
+    pub fn account(&self, username: &str, quota: u64) -> Arc<QuotaAccount> {
+        let mut accounts = self.accounts.lock().unwrap();
+        let account = accounts.entry(username.to_string()).or_insert_with(|| {
+            Arc::new(QuotaAccount {
+                quota,
+                used: tokio::sync::Mutex::new(None),
+            })
+        });
+        account.clone()
+    }
+}
+
+/// The bytes a user may store, and those they use
+#[derive(Debug)]
+pub struct QuotaAccount {
+    quota: u64,
+    /// Measured again once forgotten
+    used: tokio::sync::Mutex<Option<u64>>,
+}
+
+impl QuotaAccount {
+    pub fn quota(&self) -> u64 {
+        self.quota
+    }
+
+    /// Bytes used, measured in `storage` unless known already.
+    pub async fn used(&self, storage: &dyn StorageBackend) -> io::Result<u64> {
+        let mut used = self.used.lock().await;
+        measured(&mut used, storage).await
+    }
+
+    /// Returns `true` if `bytes` more fit in the quota.
+    pub async fn fits(&self, storage: &dyn StorageBackend, bytes: u64) -> io::Result<bool> {
+        let used = self.used(storage).await?;
+        Ok(used.saturating_add(bytes) <= self.quota)
+    }
+
+    /// Counts `bytes` more as used if they fit in the quota, returning
+    /// whether they did.
+    pub async fn reserve(&self, storage: &dyn StorageBackend, bytes: u64) -> io::Result<bool> {
+        let mut used = self.used.lock().await;
+        let total = measured(&mut used, storage).await?.saturating_add(bytes);
+        if total > self.quota {
+            return Ok(false);
+        }
+        *used = Some(total);
+        Ok(true)
+    }
+
+    /// Counts `bytes` as no longer used.
+    pub async fn release(&self, bytes: u64) {
+        if let Some(used) = self.used.lock().await.as_mut() {
+            *used = used.saturating_sub(bytes);
+        }
+    }
+
+    /// Measures the usage again when it's next needed, after changes it
+    /// can't tell the size of.
+    pub async fn forget(&self) {
+        *self.used.lock().await = None;
+    }
+}
+
+/// The usage in `used`, measured in `storage` first if it isn't known.
+async fn measured(used: &mut Option<u64>, storage: &dyn StorageBackend) -> io::Result<u64> {
+    if let Some(used) = *used {
+        return Ok(used);
+    }
+    let measured = usage(storage, Path::new(".")).await?;
+    *used = Some(measured);
+    Ok(measured)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    use crate::storage::{Memory, Storage as _, WriteMode};
+
+    #[tokio::test]
+    async fn sessions_of_a_user_share_the_quota() {
+        let storage = Memory::default().open(Path::new("/")).unwrap();
+        let mut file = storage
+            .open_write(Path::new("old.bin"), WriteMode::Truncate)
+            .await
+            .unwrap();
+        file.write_all(&[0; 600]).await.unwrap();
+        file.shutdown().await.unwrap();
+        let quotas = Quotas::default();
+        let (first, second) = (quotas.account("alice", 1000), quotas.account("alice", 1000));
+
+        assert!(first.reserve(storage.as_ref(), 300).await.unwrap());
+        assert!(!second.reserve(storage.as_ref(), 200).await.unwrap());
+        second.release(300).await;
+        assert!(second.reserve(storage.as_ref(), 400).await.unwrap());
+        assert_eq!(first.used(storage.as_ref()).await.unwrap(), 1000);
+    }
+}
@@ -0,0 +1,54 @@
+//! Rate limiting of the commands of a session.
+//!
+//! Each session with `limits.command_rate` set has a token bucket, filled
+//! with that many tokens per second up to `limits.command_burst`. Every
+//! command takes a token, and a client out of tokens is held back until
+//! it has one again, or disconnected with `421`, so spamming commands
+//! can't hog the parser or flood the logs.
+
+use std::time::{Duration, Instant};
+
+use crate::config::LimitsConfig;
+
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    /// Tokens added per second
+    rate: f64,
+    /// Tokens the bucket holds at most
+    burst: f64,
+    /// Negative while commands wait for tokens that aren't there yet
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: f64, burst: f64) -> Self {
+        Self {
+            rate,
+            burst,
+            tokens: burst,
+            last: Instant::now(),
+        }
+    }
+
+    /// The bucket of a session, if its commands are limited.
+    pub fn for_commands(limits: &LimitsConfig) -> Option<Self> {
+        let rate = limits.command_rate.filter(|rate| *rate > 0.0)?;
+        let burst = limits.command_burst.unwrap_or(rate.ceil() as u32).max(1);
+        Some(Self::new(rate, burst.into()))
+    }
+
+    /// Takes a token, returning how long to wait until it's there.
+    pub fn take(&mut self) -> Duration {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last = now;
+        self.tokens -= 1.0;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
@@ -0,0 +1,90 @@
+//! Filesystem access beneath the root of a session.
+//!
+//! The root is opened once as a `cap-std` directory handle, and files are
+//! opened relative to it. `..`, absolute paths and symbolic links can't
+//! leave it whatever a path looks like, the handle has no way out of it,
+//! so a bug in resolving the paths of the clients can't either.
+//!
+//! Filesystem access may block for long, on a huge directory or a slow
+//! network filesystem, so it runs on the blocking pool of the runtime
+//! rather than on the thread of every other connection.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use cap_std::{
+    ambient_authority,
+    fs::{Dir, OpenOptions},
+};
+use tokio::fs::File;
+
+/// A handle on the root of a session, cheap to clone
+#[derive(Debug, Clone, Default)]
+pub struct RootDir {
+    /// Missing if the root couldn't be opened, nothing can be then
+    dir: Option<Arc<Dir>>,
+}
+
+impl RootDir {
+    /// Opens the directory at `path`, the only one given by its full path.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let dir = Dir::open_ambient_dir(path, ambient_authority())?;
+        Ok(Self {
+            dir: Some(Arc::new(dir)),
+        })
+    }
+
+    /// The handle on the root.
+    pub fn dir(&self) -> io::Result<&Dir> {
+        self.dir.as_deref().ok_or_else(unavailable)
+    }
+
+    /// Runs `f` with the handle on the root on the blocking pool.
+    pub async fn blocking<T, F>(&self, f: F) -> io::Result<T>
+    where
+        F: FnOnce(&Dir) -> io::Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let dir = self.dir.clone().ok_or_else(unavailable)?;
+        tokio::task::spawn_blocking(move || f(&dir))
+            .await
+            .map_err(io::Error::other)?
+    }
+
+    /// Opens the file at `path`, relative to the root, for reading.
+    pub async fn open_file(&self, path: &Path) -> io::Result<File> {
+        let mut options = OpenOptions::new();
+        options.read(true);
+        self.open_with(path, options).await
+    }
+
+    /// Opens the file at `path`, relative to the root, with `options`.
+    pub async fn open_with(&self, path: &Path, options: OpenOptions) -> io::Result<File> {
+        let path = path.to_path_buf();
+        let file = self
+            .blocking(move |dir| dir.open_with(path, &options))
+            .await?;
+        Ok(File::from_std(file.into_std()))
+    }
+
+    /// Opens the directory at `path`, relative to the root.
+    pub fn open_dir(&self, path: &Path) -> io::Result<Dir> {
+        self.dir()?.open_dir(path)
+    }
+}
+
+fn unavailable() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "the root could not be opened")
+}
+
+/// `path` relative to `root`, `.` for the root itself, as the handle
+/// expects it.
+pub fn relative_to(path: &Path, root: &Path) -> PathBuf {
+    match path.strip_prefix(root) {
+        Ok(relative) if relative != Path::new("") => relative.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
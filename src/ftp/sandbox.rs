@@ -0,0 +1,283 @@
+//! Landlock and seccomp confinement on Linux.
+//!
+//! With `process.sandbox` and the `sandbox` feature, the filesystem is
+//! limited with Landlock to the configured roots and the few files the
+//! server reads later, and the system calls a file server never needs are
+//! refused with seccomp. Even a path slipping past the jailing of the
+//! sessions then can't read `/etc/shadow`.
+//!
+//! Landlock confines the calling thread and the threads it spawns, so
+//! [`restrict_filesystem`] has to be called before the runtime starts.
+//! The seccomp filter applies to every thread at once.
+
+#[cfg(not(all(feature = "sandbox", target_os = "linux")))]
+use miette::*;
+
+#[cfg(not(all(feature = "sandbox", target_os = "linux")))]
+use crate::config::Config;
+
+/// Limits the filesystem to the paths the configuration serves or reads.
+///
+/// Does nothing unless `process.sandbox` is set. Must be called before
+/// the process spawns any thread, the ones already running stay free.
+#[cfg(not(all(feature = "sandbox", target_os = "linux")))]
+pub fn restrict_filesystem(config: &Config) -> Result<()> {
+    unsupported(config)
+}
+
+/// Refuses the system calls a file server never needs, in every thread.
+///
+/// Does nothing unless `process.sandbox` is set.
+#[cfg(not(all(feature = "sandbox", target_os = "linux")))]
+pub fn restrict_system_calls(config: &Config) -> Result<()> {
+    unsupported(config)
+}
+
+#[cfg(not(all(feature = "sandbox", target_os = "linux")))]
+fn unsupported(config: &Config) -> Result<()> {
+    if config.process.sandbox {
+        bail!("The sandbox is configured but the server was built without the `sandbox` feature, or not for Linux");
+    }
+    Ok(())
+}
+
+#[cfg(all(feature = "sandbox", target_os = "linux"))]
+pub use linux::*;
+
+#[cfg(all(feature = "sandbox", target_os = "linux"))]
+mod linux {
+    use std::{
+        collections::BTreeMap,
+        fs::OpenOptions,
+        path::{Path, PathBuf},
+    };
+
+    use landlock::{
+        path_beneath_rules, Access, AccessFs, Ruleset, RulesetAttr, RulesetCreatedAttr,
+        RulesetStatus, ABI,
+    };
+    use miette::*;
+    use seccompiler::{BpfProgram, SeccompAction, SeccompFilter, TargetArch};
+    use tracing::*;
+
+    use crate::config::{Config, PostUploadAction};
+
+    /// The newest Landlock ABI handled, older kernels enforce what they can
+    const ABI: ABI = ABI::V3;
+
+    /// Refused whatever the configuration
+    const DENIED: &[i64] = &[
+        libc::SYS_acct,
+        libc::SYS_add_key,
+        libc::SYS_bpf,
+        libc::SYS_chroot,
+        libc::SYS_delete_module,
+        libc::SYS_finit_module,
+        libc::SYS_init_module,
+        libc::SYS_kexec_file_load,
+        libc::SYS_kexec_load,
+        libc::SYS_keyctl,
+        libc::SYS_mount,
+        libc::SYS_open_by_handle_at,
+        libc::SYS_perf_event_open,
+        libc::SYS_pivot_root,
+        libc::SYS_process_vm_readv,
+        libc::SYS_process_vm_writev,
+        libc::SYS_ptrace,
+        libc::SYS_quotactl,
+        libc::SYS_reboot,
+        libc::SYS_request_key,
+        libc::SYS_setfsgid,
+        libc::SYS_setfsuid,
+        libc::SYS_setgid,
+        libc::SYS_setgroups,
+        libc::SYS_setns,
+        libc::SYS_setregid,
+        libc::SYS_setresgid,
+        libc::SYS_setresuid,
+        libc::SYS_setreuid,
+        libc::SYS_setuid,
+        libc::SYS_swapoff,
+        libc::SYS_swapon,
+        libc::SYS_umount2,
+        libc::SYS_unshare,
+        libc::SYS_userfaultfd,
+    ];
+
+    /// Refused unless upload hooks run programs
+    const EXEC: &[i64] = &[libc::SYS_execve, libc::SYS_execveat];
+
+    /// Where programs run by upload hooks and their libraries live
+    const SYSTEM: &[&str] = &["/bin", "/lib", "/lib64", "/sbin", "/usr"];
+
+    /// Limits the filesystem to the paths the configuration serves or reads.
+    pub fn restrict_filesystem(config: &Config) -> Result<()> {
+        if !config.process.sandbox {
+            return Ok(());
+        }
+        let cwd = std::env::current_dir().into_diagnostic()?;
+        let read = AccessFs::from_read(ABI) & !AccessFs::Execute;
+        let write = AccessFs::from_all(ABI) & !AccessFs::Execute;
+
+        let mut writable = vec![config.served_root()?];
+        writable.extend(config.anonymous.root.clone());
+        writable.extend(config.hosts.iter().map(|host| host.root.clone()));
+        writable.extend(config.shares.iter().map(|share| share.path.clone()));
+        writable.extend(config.users.iter().filter_map(|user| user.home.clone()));
+        writable.extend(
+            config
+                .uploads
+                .mounts
+                .iter()
+                .map(|mount| mount.path.clone())
+                .filter(|path| path.is_absolute()),
+        );
+        writable.extend(config.record.directory.clone());
+        // Upgraded hashes are written next to it, then moved over it
+        writable.extend(
+            config
+                .auth
+                .users_file
+                .as_ref()
+                .and_then(|path| path.parent())
+                .map(Path::to_path_buf),
+        );
+        // Homes created on demand are beneath the directory of the template
+        let homes = config
+            .virtual_users
+            .home
+            .as_deref()
+            .filter(|_| config.virtual_users.create_homes)
+            .map(|template| cwd.join(template_base(template)));
+        let mut writable: Vec<PathBuf> = writable
+            .iter()
+            .map(|path| cwd.join(path))
+            .filter(|path| !homes.as_ref().is_some_and(|homes| path.starts_with(homes)))
+            .chain(homes.clone())
+            .collect();
+        // Landlock only grants access beneath what exists, and granting the
+        // closest directory that does could be all of `/`
+        for path in &writable {
+            if !path.exists() {
+                bail!(
+                    help = "Create it, or fix its path in the configuration",
+                    "{:?} doesn't exist, so the sandbox can't grant access to it",
+                    path
+                );
+            }
+        }
+        // The logs are opened later, so they're created now
+        for log in [&config.auth.log, &config.honeypot.log, &config.audit.log] {
+            let Some(log) = log else {
+                continue;
+            };
+            let log = cwd.join(log);
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&log)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Could not create the log {}", log.display()))?;
+            writable.push(log);
+        }
+
+        let mut readable = Vec::new();
+        readable.extend(config.tls.certificate.clone());
+        readable.extend(config.tls.key.clone());
+        readable.extend(config.tls.client_ca.clone());
+        readable.extend(config.lang.catalogs.values().cloned());
+        readable.extend(config.geoip.database.clone());
+        readable.push(PathBuf::from("/etc/localtime"));
+        if config.process.user.is_some() {
+            readable.extend(["/etc/group", "/etc/nsswitch.conf", "/etc/passwd"].map(PathBuf::from));
+        }
+        let readable: Vec<PathBuf> = readable
+            .iter()
+            .map(|path| cwd.join(path))
+            .filter(|path| path.exists())
+            .collect();
+
+        let mut ruleset = Ruleset::default()
+            .handle_access(AccessFs::from_all(ABI))
+            .into_diagnostic()?
+            .create()
+            .into_diagnostic()?
+            .add_rules(path_beneath_rules(&writable, write))
+            .into_diagnostic()?
+            .add_rules(path_beneath_rules(&readable, read))
+            .into_diagnostic()?;
+        if runs_hooks(config) {
+            let mut programs: Vec<PathBuf> = SYSTEM.iter().map(PathBuf::from).collect();
+            programs.extend(hooks(config).map(|command| cwd.join(command)));
+            programs.retain(|path| path.exists());
+            ruleset = ruleset
+                .add_rules(path_beneath_rules(programs, AccessFs::from_read(ABI)))
+                .into_diagnostic()?
+                .add_rules(path_beneath_rules(["/dev/null"], write))
+                .into_diagnostic()?;
+        }
+        let status = ruleset.restrict_self().into_diagnostic()?;
+        // Called before logging starts, older kernels enforce what they can
+        match status.ruleset {
+            RulesetStatus::FullyEnforced | RulesetStatus::PartiallyEnforced => {}
+            RulesetStatus::NotEnforced => bail!(
+                help = "Landlock needs Linux 5.13 with the `landlock` LSM enabled",
+                "The kernel doesn't support the filesystem sandbox"
+            ),
+        }
+        Ok(())
+    }
+
+    /// Refuses the system calls a file server never needs, in every thread.
+    pub fn restrict_system_calls(config: &Config) -> Result<()> {
+        if !config.process.sandbox {
+            return Ok(());
+        }
+        let mut denied = DENIED.to_vec();
+        if !runs_hooks(config) {
+            denied.extend(EXEC);
+        }
+        let rules: BTreeMap<i64, Vec<_>> = denied
+            .into_iter()
+            .map(|syscall| (syscall, Vec::new()))
+            .collect();
+        let arch = TargetArch::try_from(std::env::consts::ARCH).into_diagnostic()?;
+        let filter = SeccompFilter::new(
+            rules,
+            SeccompAction::Allow,
+            SeccompAction::Errno(libc::EPERM as u32),
+            arch,
+        )
+        .into_diagnostic()?;
+        let program: BpfProgram = filter.try_into().into_diagnostic()?;
+        seccompiler::apply_filter_all_threads(&program).into_diagnostic()?;
+        info!("Sandboxed the filesystem and the system calls");
+        Ok(())
+    }
+
+    /// The programs run by upload hooks.
+    fn hooks(config: &Config) -> impl Iterator<Item = &PathBuf> {
+        config
+            .uploads
+            .mounts
+            .iter()
+            .flat_map(|mount| &mount.actions)
+            .filter_map(|action| match action {
+                PostUploadAction::Hook { command, .. } => Some(command),
+                _ => None,
+            })
+    }
+
+    fn runs_hooks(config: &Config) -> bool {
+        hooks(config).next().is_some()
+    }
+
+    /// The directory of a home `template`, up to the component naming the
+    /// user.
+    fn template_base(template: &str) -> PathBuf {
+        Path::new(template)
+            .components()
+            .take_while(|component| !component.as_os_str().to_string_lossy().contains("{user}"))
+            .collect()
+    }
+}
@@ -0,0 +1,314 @@
+//! Checks that a running server is reachable the way clients reach it.
+//!
+//! Most problems clients report with passive mode come from NAT and
+//! firewalls: the advertised address or port isn't the one the client
+//! can connect to. [`self_check`] logs into a running server, opens a few
+//! passive data connections and reports what went wrong, if anything,
+//! with a hint on how to fix it.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use miette::*;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+
+use crate::client::{data_token, extended_passive_port, passive_address, read_reply};
+use crate::config::{Config, PassiveConfig, PortRange};
+
+/// Time to wait for a control or data connection to be established
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Number of `PASV` data connections opened, so that more than one
+/// port of the range is tried
+const PASSIVE_PROBES: usize = 3;
+
+/// Checks the passive mode configuration for mistakes that don't need
+/// a running server to be found, the server logs them on startup.
+pub fn passive_warnings(passive: &PassiveConfig) -> Vec<Report> {
+    let mut warnings = vec![];
+    if passive.port_map.is_empty() {
+        return warnings;
+    }
+    let Some(ports) = passive.ports else {
+        warnings.push(miette!(
+            severity = Severity::Warning,
+            help = "Set `passive.ports` to the internal ports of `passive.port_map`",
+            "`passive.port_map` is set, but the passive ports are picked by the OS"
+        ));
+        return warnings;
+    };
+    let unmapped = (ports.start..=ports.end)
+        .filter(|port| {
+            !passive
+                .port_map
+                .iter()
+                .any(|mapping| mapping.internal.contains(*port))
+        })
+        .count();
+    if unmapped > 0 {
+        warnings.push(miette!(
+            severity = Severity::Warning,
+            help = "Extend `passive.port_map` to cover every port of `passive.ports`",
+            "{} of the passive ports {}-{} aren't mapped and are advertised as is",
+            unmapped,
+            ports.start,
+            ports.end
+        ));
+    }
+    warnings
+}
+
+/// Logs into the server at `addr` and checks that the passive data
+/// connections it advertises can be opened.
+///
+/// Without `credentials` the first user of `config` is used, or
+/// `anonymous` if there's none. Problems that stop the check, like
+/// a failed login, are returned as errors, the rest as findings.
+pub async fn self_check(
+    addr: SocketAddr,
+    config: &Config,
+    credentials: Option<(String, String)>,
+) -> Result<Vec<Report>> {
+    let mut findings = passive_warnings(&config.passive);
+
+    let stream = match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(addr)).await {
+        Ok(Ok(stream)) => stream,
+        _ => bail!(
+            help = "Check that the server is running and that the firewall allows the control port",
+            "Could not connect to {}",
+            addr
+        ),
+    };
+    let mut control = BufReader::new(stream);
+    expect_reply(&mut control, &[220]).await?;
+
+    let (user, password, account) = match credentials {
+        Some((user, password)) => {
+            let account = config
+                .users
+                .iter()
+                .find(|configured| configured.name == user)
+                .and_then(|configured| configured.account.clone());
+            (user, password, account)
+        }
+        None => match config.users.first() {
+            Some(user) => match &user.password {
+                Some(password) => (user.name.clone(), password.clone(), user.account.clone()),
+                None => bail!(
+                    help = "Pass the password with `--user` and `--password`",
+                    "The password of {} is only known by its hash",
+                    user.name
+                ),
+            },
+            None => ("anonymous".to_string(), "anonymous".to_string(), None),
+        },
+    };
+    log_in(&mut control, &user, &password, account.as_deref()).await?;
+
+    let advertised = advertised_ports(&config.passive);
+    for _ in 0..PASSIVE_PROBES {
+        let (code, reply) = command(&mut control, "PASV").await?;
+        if code == 502 {
+            findings.push(miette!(
+                severity = Severity::Warning,
+                "Passive mode is disabled for {}, it wasn't checked",
+                user
+            ));
+            break;
+        }
+        let Some(data_addr) = passive_address(&reply).filter(|_| code == 227) else {
+            bail!("Unexpected reply to PASV: {}", reply);
+        };
+        if let Some(finding) = check_advertised_address(addr.ip(), data_addr.ip()) {
+            findings.push(finding);
+        }
+        if let Some(finding) = check_advertised_port(data_addr.port(), advertised.as_deref()) {
+            findings.push(finding);
+        }
+        if let Err(finding) = list(&mut control, data_addr, data_token(&reply)).await {
+            findings.push(finding);
+            // Every other probe would fail the same way
+            break;
+        }
+    }
+
+    let (code, reply) = command(&mut control, "EPSV").await?;
+    if let Some(port) = extended_passive_port(&reply).filter(|_| code == 229) {
+        if let Some(finding) = check_advertised_port(port, advertised.as_deref()) {
+            findings.push(finding);
+        }
+        // The address of `EPSV` is the one of the control connection
+        let data_addr = SocketAddr::new(addr.ip(), port);
+        if let Err(finding) = list(&mut control, data_addr, data_token(&reply)).await {
+            findings.push(finding);
+        }
+    }
+
+    Ok(findings)
+}
+
+/// Sends `USER`, `PASS` and, if needed, `ACCT`
+async fn log_in(
+    control: &mut BufReader<TcpStream>,
+    user: &str,
+    password: &str,
+    account: Option<&str>,
+) -> Result<()> {
+    let refused = |reply: &str| {
+        miette!(
+            help = "Pass `--user` and `--password` of a configured user, or secure the \
+                    control connection if `tls.required` is set",
+            "Could not log in as {}: {}",
+            user,
+            reply
+        )
+    };
+    let (code, reply) = command(control, &format!("USER {user}")).await?;
+    match code {
+        230 => return Ok(()),
+        331 => {}
+        _ => return Err(refused(&reply)),
+    }
+    let (code, reply) = command(control, &format!("PASS {password}")).await?;
+    match (code, account) {
+        (230, _) => Ok(()),
+        (332, Some(account)) => {
+            let (code, reply) = command(control, &format!("ACCT {account}")).await?;
+            if code == 230 {
+                Ok(())
+            } else {
+                Err(refused(&reply))
+            }
+        }
+        _ => Err(refused(&reply)),
+    }
+}
+
+/// Lists the working directory over a data connection to `data_addr`,
+/// sending the `token` of the passive reply first if there's one
+async fn list(
+    control: &mut BufReader<TcpStream>,
+    data_addr: SocketAddr,
+    token: Option<&str>,
+) -> Result<()> {
+    let unreachable = || {
+        miette!(
+            help = "Allow the passive ports in the firewall, and check that \
+                    `passive.port_map` matches the ports forwarded to the server",
+            "The passive data connection to {} can't be opened",
+            data_addr
+        )
+    };
+    let mut data = match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(data_addr)).await
+    {
+        Ok(Ok(data)) => data,
+        _ => return Err(unreachable()),
+    };
+    if let Some(token) = token {
+        let line = format!("{token}\r\n");
+        if data.write_all(line.as_bytes()).await.is_err() {
+            return Err(unreachable());
+        }
+    }
+
+    control.write_all(b"LIST\r\n").await.into_diagnostic()?;
+    let mut listing = vec![];
+    let (read, replies) = tokio::join!(
+        tokio::time::timeout(CONNECT_TIMEOUT, data.read_to_end(&mut listing)),
+        async {
+            let first = read_reply(control).await?;
+            let last = read_reply(control).await?;
+            Ok::<_, Report>((first, last))
+        }
+    );
+    match (read, replies?) {
+        (Ok(Ok(_)), (Some((125 | 150, _)), Some((226, _)))) => Ok(()),
+        _ => Err(unreachable()),
+    }
+}
+
+/// Clients elsewhere can't reach loopback or private addresses
+fn check_advertised_address(control: IpAddr, advertised: IpAddr) -> Option<Report> {
+    let IpAddr::V4(advertised) = advertised else {
+        return None;
+    };
+    if advertised.is_loopback() && !control.is_loopback() {
+        return Some(miette!(
+            help = "Only clients on the same host as the server can connect to it, \
+                    set `passive.address` to one the others can reach",
+            "The server advertises the loopback address {} to clients connecting to {}",
+            advertised,
+            control
+        ));
+    }
+    let control_private = match control {
+        IpAddr::V4(control) => control.is_private() || control.is_loopback(),
+        IpAddr::V6(control) => control.is_loopback(),
+    };
+    if advertised.is_private() && !control_private {
+        return Some(miette!(
+            severity = Severity::Warning,
+            help = "The server is likely behind NAT, set `passive.address` to its \
+                    public address so clients outside the private network can connect",
+            "The server advertises the private address {} to clients connecting to {}",
+            advertised,
+            control
+        ));
+    }
+    None
+}
+
+fn check_advertised_port(port: u16, advertised: Option<&[PortRange]>) -> Option<Report> {
+    let ranges = advertised?;
+    if ranges.iter().any(|range| range.contains(port)) {
+        return None;
+    }
+    Some(miette!(
+        help = "Check that `passive.port_map` covers every port of `passive.ports`",
+        "The advertised port {} is outside of the configured passive ports",
+        port
+    ))
+}
+
+/// The ports clients are expected to be sent, if they're restricted at all
+fn advertised_ports(passive: &PassiveConfig) -> Option<Vec<PortRange>> {
+    if passive.port_map.is_empty() {
+        passive.ports.map(|ports| vec![ports])
+    } else {
+        Some(
+            passive
+                .port_map
+                .iter()
+                .map(|mapping| mapping.external)
+                .collect(),
+        )
+    }
+}
+
+/// Sends a command and reads its final reply
+async fn command(control: &mut BufReader<TcpStream>, command: &str) -> Result<(u16, String)> {
+    control
+        .write_all(format!("{command}\r\n").as_bytes())
+        .await
+        .into_diagnostic()?;
+    let keyword = command.split_whitespace().next().unwrap_or_default();
+    read_reply(control)
+        .await?
+        .ok_or_else(|| miette!("The server didn't reply to {}", keyword))
+}
+
+async fn expect_reply(control: &mut BufReader<TcpStream>, codes: &[u16]) -> Result<()> {
+    match read_reply(control).await? {
+        Some((code, _)) if codes.contains(&code) => Ok(()),
+        Some((_, reply)) => bail!("Unexpected reply from the server: {}", reply),
+        None => bail!(
+            help = "Check that the address is the one of an FTP server",
+            "The server didn't send a greeting"
+        ),
+    }
+}
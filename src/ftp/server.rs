@@ -14,31 +14,107 @@
 //! The code also includes various helper functions and enums for handling FTP commands,
 //! status codes, and system types.
 
-use std::{borrow::BorrowMut, ffi::OsString, net::SocketAddr, path::PathBuf, str, sync::Arc};
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    path::{Component, Path, PathBuf},
+    str,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, RwLock,
+    },
+};
 
 use miette::*;
 
 use tokio::{
-    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
-    net::{tcp::WriteHalf, TcpListener, TcpStream},
+    io::{
+        AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+        BufReader, ReadHalf,
+    },
+    net::{TcpListener, TcpStream},
     signal,
     sync::Mutex,
+    task::AbortHandle,
 };
+#[cfg(feature = "tls")]
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
 use tokio_util::{sync::CancellationToken, task::TaskTracker};
 use tracing::*;
 
-use crate::StatusCode;
-use crate::{parser::cmd_parser, Command};
+use crate::audit::{Actor, AuditEvent, AuditLog};
+use crate::auth::{LoginState, UserStore};
+use crate::auth_log::{AuthLog, LoginOutcome};
+use crate::checksum::{ByteRange, HashAlgorithm};
+use crate::command::system_status;
+use crate::config::{Config, DataMode, DisabledReply, OverRate, Permission};
+use crate::debug::SessionDebug;
+use crate::geoip::GeoIp;
+use crate::honeypot::{self, HoneypotLog};
+use crate::lang::{Catalogs, SessionLanguage};
+use crate::memory::{MemoryBudget, MemoryLimitExceeded};
+use crate::messages::{continuation, render};
+use crate::overrides::{OverrideCache, Overrides};
+use crate::pipeline::UploadPipeline;
+use crate::privacy::IpPrivacy;
+use crate::privileges;
+use crate::quota::{QuotaAccount, Quotas};
+use crate::rate::TokenBucket;
+use crate::root_dir::relative_to;
+use crate::sandbox;
+use crate::self_check;
+use crate::storage::{
+    self, Cached, Local, ReadOnly, ReadOnlyStorage, Shares, Storage, StorageBackend, Unavailable,
+};
+use crate::summary::Summary;
+use crate::telnet;
+use crate::throttle::{LoginThrottle, Verdict};
+#[cfg(feature = "tls")]
+use crate::tls::certificate_subject;
+use crate::tls::{load_server_config, ServerConfig};
+use crate::transcript::{sanitize, Recorder};
+use crate::transfer::{DataReader, DataWriter, TransferProgress, DEFAULT_DEFLATE_LEVEL};
+use crate::utils::{create_dir, Facts};
+use crate::{
+    parser::{cmd_parser, cmd_text_parser},
+    Command, SiteCommand, SiteCommands,
+};
+use crate::{Capabilities, ControlWriter, Denial, StatusCode};
 
 #[derive(Debug, Clone)]
 pub struct FTPServer {
     addr: SocketAddr,
     tracker: TaskTracker,
     cancelation_token: CancellationToken,
+    config: Config,
+    site: SiteCommands,
+    storage: Arc<dyn Storage>,
 }
 
 impl FTPServer {
+    /// Registers a `SITE` subcommand, replacing any built-in one with the same verb.
+    pub fn site_command(&mut self, command: impl SiteCommand + 'static) -> &mut Self {
+        self.site.register(command);
+        self
+    }
+
+    /// Keeps the files of the sessions in `storage` instead of the local
+    /// filesystem.
+    pub fn storage(&mut self, storage: impl Storage + 'static) -> &mut Self {
+        self.storage = Arc::new(storage);
+        self
+    }
+
     pub async fn listen(&mut self) -> Result<()> {
+        let listener = TcpListener::bind(self.addr).await.into_diagnostic()?;
+        info!("Listening on {}", self.addr);
+        self.serve(listener).await
+    }
+
+    /// Serves the connections of an already bound listener,
+    /// ignoring the configured address.
+    pub async fn serve(&mut self, listener: TcpListener) -> Result<()> {
         let cancelation_token = self.cancelation_token.clone();
         self.tracker.spawn(async move {
             tokio::select! {
@@ -50,123 +126,920 @@ impl FTPServer {
         });
         self.tracker.close();
 
-        let listener = TcpListener::bind(self.addr).await.into_diagnostic()?;
-        info!("Listening on {}", self.addr);
-        self.listen_for_connections(listener).await
+        // Looked up while `/etc/passwd` is still there
+        let identity = privileges::Identity::lookup(&self.config.process)?;
+        let mut config = self.config.clone();
+        // Before the homes from templates are moved into the chroot
+        config.prepare()?;
+        let jail = if config.process.chroot {
+            let root = config.served_root()?.canonicalize().into_diagnostic()?;
+            config.chroot(&root)?;
+            Some(root)
+        } else {
+            None
+        };
+        let mut context = ServerContext::new(config, self.site.clone(), self.storage.clone())?;
+        // The listener, the certificate and the logs are open by now
+        if let Some(root) = &jail {
+            context.config.chroot_tls(root)?;
+            privileges::chroot(root)?;
+        }
+        let context = Arc::new(context);
+        if let Some(identity) = &identity {
+            identity.assume()?;
+        }
+        sandbox::restrict_system_calls(&context.config)?;
+        if context.tls().is_some() {
+            // Read again from inside the chroot, as the user the server runs as
+            if let Err(error) = load_server_config(&context.config.tls) {
+                warn!(
+                    "The TLS certificate can't be reloaded on SIGHUP: {:?}",
+                    error
+                );
+            }
+            tokio::spawn(reload_tls_on_hangup(
+                context.clone(),
+                self.cancelation_token.clone(),
+            ));
+        }
+        let bind = listener.local_addr().into_diagnostic()?;
+        let root = jail.unwrap_or_else(|| context.root.clone());
+        // Prepared, as the sessions see it, like with honeypot overrides
+        Summary::new(bind, root, &context.config).log();
+        for warning in self_check::passive_warnings(&context.config.passive) {
+            warn!("{}", warning);
+        }
+
+        self.listen_for_connections(listener, context).await
     }
 
-    async fn listen_for_connections(&mut self, listener: TcpListener) -> Result<()> {
+    async fn listen_for_connections(
+        &mut self,
+        listener: TcpListener,
+        context: Arc<ServerContext>,
+    ) -> Result<()> {
         let cancelation_token = self.cancelation_token.clone();
         loop {
-            let (socket, _) = tokio::select! {
-                res = listener.accept() => {
-                    res.into_diagnostic()?
-                }
+            let (mut socket, addr) = tokio::select! {
+                res = listener.accept() => match res {
+                    Ok(accepted) => accepted,
+                    Err(error) => {
+                        // Like running out of file descriptors, which the
+                        // sessions ending frees again
+                        warn!("Could not accept a connection: {}", error);
+                        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                        continue;
+                    }
+                },
                 _ = cancelation_token.cancelled() => {
                     break;
                 }
             };
-            let connection = Connection::try_from((socket, self.cancelation_token.clone()))?;
-            self.add_connection(connection).await?;
+            if let Err(reason) = context.admit(&addr) {
+                debug!("Refusing {}: {}", context.privacy.redact(&addr), reason);
+                let reply = StatusCode::Closing(format!(" {reason}"));
+                // Fits in the send buffer of a new socket, so this never waits
+                let _ = socket.write_all(reply.to_string().as_bytes()).await;
+                continue;
+            }
+            // Each session has its own token, so `QUIT` ends only its own
+            // session while shutting the server down still ends them all
+            let session_token = self.cancelation_token.child_token();
+            let connection = match Connection::try_from((socket, session_token, context.clone())) {
+                Ok(connection) => connection,
+                Err(error) => {
+                    warn!(
+                        "Could not set up the connection from {}: {:?}",
+                        context.privacy.redact(&addr),
+                        error
+                    );
+                    context.release(&addr);
+                    continue;
+                }
+            };
+            self.add_connection(connection).await;
         }
         info!("Waiting for all connections to close");
         self.tracker.wait().await;
         Ok(())
     }
 
-    async fn add_connection(&mut self, mut connection: Connection) -> Result<()> {
-        info!(
-            "New connection from {}",
-            connection
-                .inner()
-                .lock()
-                .await
-                .socket
-                .lock()
-                .await
-                .peer_addr()
-                .unwrap()
-        );
+    async fn add_connection(&mut self, mut connection: Connection) {
+        {
+            let inner = connection.inner();
+            let inner = inner.lock().await;
+            match &inner.country {
+                Some(country) => info!("New connection from {} in {}", inner.client(), country),
+                None => info!("New connection from {}", inner.client()),
+            }
+        }
 
+        let context = connection.inner().lock().await.context.clone();
+        let peer_addr = connection.inner().lock().await.peer_addr;
         self.tracker.spawn(async move {
             trace!("Spawning new control connection task");
             if let Err(error) = connection.connect().await {
                 error!("Terminated connection with: {:?}", error);
             }
+            connection.inner().lock().await.abort_data_task();
+            context.release(&peer_addr);
             info!(
-                "Closed connection from {:?}",
-                connection
-                    .inner()
-                    .lock()
-                    .await
-                    .socket
-                    .lock()
-                    .await
-                    .peer_addr()
-                    .unwrap()
+                "Closed connection from {}",
+                connection.inner().lock().await.client()
             );
         });
+    }
+}
 
-        Ok(())
+/// The storage of a session confined to `root`, which every file of it is
+/// opened with.
+fn open_storage(storage: &dyn Storage, root: &Path) -> Arc<dyn StorageBackend> {
+    storage.open(root).unwrap_or_else(|error| {
+        warn!("Could not open the root {:?}: {}", root, error);
+        Arc::new(Unavailable)
+    })
+}
+
+/// Reloads the TLS certificate and key on every `SIGHUP`, so renewed
+/// certificates are used without restarting the server.
+#[cfg(unix)]
+async fn reload_tls_on_hangup(context: Arc<ServerContext>, cancelation_token: CancellationToken) {
+    let mut hangups = match signal::unix::signal(signal::unix::SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(error) => {
+            warn!("Can't reload the TLS certificate on SIGHUP: {}", error);
+            return;
+        }
+    };
+    loop {
+        tokio::select! {
+            _ = hangups.recv() => {}
+            _ = cancelation_token.cancelled() => break,
+        }
+        info!("Received SIGHUP, reloading the TLS certificate");
+        match context.reload_tls() {
+            Ok(()) => info!("Reloaded the TLS certificate"),
+            Err(error) => error!("Kept the previous TLS certificate: {:?}", error),
+        }
     }
 }
 
+/// There's no `SIGHUP` off Unix, the server is restarted for a renewed
+/// certificate instead.
+#[cfg(not(unix))]
+async fn reload_tls_on_hangup(_context: Arc<ServerContext>, _cancelation_token: CancellationToken) {
+}
+
 impl From<SocketAddr> for FTPServer {
     fn from(addr: SocketAddr) -> Self {
+        Self::from((addr, Config::default()))
+    }
+}
+
+impl From<(SocketAddr, Config)> for FTPServer {
+    fn from((addr, config): (SocketAddr, Config)) -> Self {
         Self {
             addr,
             tracker: TaskTracker::new(),
             cancelation_token: CancellationToken::new(),
+            config,
+            site: SiteCommands::default(),
+            storage: Arc::new(Local),
+        }
+    }
+}
+
+/// State shared by the server and all of its connections.
+#[derive(Debug)]
+pub struct ServerContext {
+    pub(crate) config: Config,
+    /// The directory every session is confined to unless its user has a home
+    pub(crate) root: PathBuf,
+    pub(crate) uploads: UploadPipeline,
+    pub(crate) users: UserStore,
+    /// Replaced when the certificate is reloaded
+    tls: RwLock<Option<Arc<ServerConfig>>>,
+    pub(crate) capabilities: Capabilities,
+    pub(crate) site: SiteCommands,
+    /// Opens the files of every session
+    pub(crate) storage: Arc<dyn Storage>,
+    /// Number of connected clients
+    pub(crate) sessions: AtomicUsize,
+    /// Number of open control connections per client address
+    addresses: std::sync::Mutex<HashMap<IpAddr, usize>>,
+    /// Id of the last session
+    pub(crate) last_session: AtomicU64,
+    pub(crate) privacy: IpPrivacy,
+    pub(crate) languages: Catalogs,
+    pub(crate) throttle: LoginThrottle,
+    /// Usage of the users with a quota
+    pub(crate) quotas: Quotas,
+    pub(crate) auth_log: AuthLog,
+    pub(crate) audit: Arc<AuditLog>,
+    pub(crate) honeypot: HoneypotLog,
+    pub(crate) geoip: GeoIp,
+}
+
+impl ServerContext {
+    pub fn new(mut config: Config, site: SiteCommands, storage: Arc<dyn Storage>) -> Result<Self> {
+        config.prepare()?;
+        let tls = load_server_config(&config.tls)?;
+        if config.honeypot.enabled {
+            let disabled = honeypot::DISABLED.iter().map(|keyword| keyword.to_string());
+            config.commands.disabled.extend(disabled);
+        }
+        let mut capabilities = Command::capabilities(tls.is_some());
+        for keyword in &config.commands.disabled {
+            let keyword = keyword.to_uppercase();
+            if !capabilities.supports(&keyword) {
+                warn!("Disabled command {} is not implemented anyway", keyword);
+            }
+        }
+        for keyword in config.commands.enabled.iter().flatten() {
+            let keyword = keyword.to_uppercase();
+            if !capabilities.supports(&keyword) {
+                warn!("Enabled command {} is not implemented", keyword);
+            }
+        }
+        let disabled: Vec<_> = capabilities
+            .commands()
+            .filter(|keyword| config.commands.is_disabled(keyword))
+            .collect();
+        for keyword in disabled {
+            capabilities.disable(keyword);
+        }
+        let root = config.served_root()?;
+        // The clients of the honeypot never get near the real files
+        let mut storage: Arc<dyn Storage> = match config.honeypot.enabled {
+            true => Arc::new(honeypot::decoys(&config.honeypot)),
+            false => match storage::configured(&config.storage, &root)? {
+                // The root of a bucket is only where the sessions are beneath
+                Some(storage) => storage,
+                None if !root.is_dir() => bail!("The root {:?} is not a directory", root),
+                None => storage,
+            },
+        };
+        if config.storage.read_only {
+            storage = Arc::new(ReadOnly(storage));
+        }
+        if config.listing.cache_seconds > 0 {
+            let lifetime = std::time::Duration::from_secs(config.listing.cache_seconds);
+            storage = Arc::new(Cached::new(storage, lifetime));
+        }
+        let audit = Arc::new(AuditLog::open(config.audit.log.as_deref())?);
+        Ok(Self {
+            uploads: UploadPipeline::spawn(&config.uploads, &root, audit.clone()),
+            audit,
+            root,
+            users: UserStore::from(config.users.as_slice()),
+            capabilities,
+            site,
+            storage,
+            sessions: AtomicUsize::new(0),
+            addresses: Default::default(),
+            last_session: AtomicU64::new(0),
+            privacy: IpPrivacy::new(&config.privacy),
+            throttle: LoginThrottle::new(&config.throttle),
+            quotas: Quotas::default(),
+            auth_log: AuthLog::open(config.auth.log.as_deref())?,
+            honeypot: HoneypotLog::open(&config.honeypot)?,
+            geoip: GeoIp::open(&config.geoip)?,
+            languages: Catalogs::load(&config.lang.catalogs)?,
+            tls: RwLock::new(tls),
+            config,
+        })
+    }
+
+    /// Counts a new connection from `addr`, or tells why it has to be
+    /// refused.
+    pub fn admit(&self, addr: &SocketAddr) -> std::result::Result<(), &'static str> {
+        let address = addr.ip().to_canonical();
+        if !self.config.access.allows(address) {
+            return Err("Connections from your address are not allowed");
+        }
+        if !self.geoip.allows(address) {
+            return Err("Connections from your country are not allowed");
+        }
+        if self.throttle.is_banned(address) {
+            return Err("Too many failed logins");
+        }
+        // Held while counting, so concurrent admissions can't overshoot
+        let mut addresses = self.addresses.lock().unwrap();
+        let limits = &self.config.limits;
+        let sessions = self.sessions.load(Ordering::Relaxed);
+        if limits.max_sessions.is_some_and(|limit| sessions >= limit) {
+            return Err("Too many users, try again later");
+        }
+        let open = addresses.get(&address).copied().unwrap_or_default();
+        if limits
+            .connections_per_address
+            .is_some_and(|limit| open >= limit)
+        {
+            return Err("Too many connections from your address");
+        }
+        addresses.insert(address, open + 1);
+        self.sessions.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Stops counting a closed connection from `addr`, which was admitted.
+    pub fn release(&self, addr: &SocketAddr) {
+        let address = addr.ip().to_canonical();
+        let mut addresses = self.addresses.lock().unwrap();
+        self.sessions.fetch_sub(1, Ordering::Relaxed);
+        if let Some(open) = addresses.get_mut(&address) {
+            *open -= 1;
+            if *open == 0 {
+                addresses.remove(&address);
+            }
+        }
+    }
+
+    /// The TLS configuration new handshakes use, if TLS is configured.
+    pub fn tls(&self) -> Option<Arc<ServerConfig>> {
+        self.tls.read().unwrap().clone()
+    }
+
+    /// Loads the certificate and key again, after they were renewed.
+    ///
+    /// Secured connections keep the certificate of their handshake, and the
+    /// current one stays in use if the new one is invalid.
+    pub fn reload_tls(&self) -> Result<()> {
+        let tls = load_server_config(&self.config.tls)?;
+        *self.tls.write().unwrap() = tls;
+        Ok(())
+    }
+
+    /// The reply to `command`, disabled by the configuration.
+    pub fn disabled(&self, command: &str) -> StatusCode {
+        match self.config.commands.reply {
+            DisabledReply::Disabled => {
+                StatusCode::CmdDisabled(format!(" {command} is disabled on this server"))
+            }
+            DisabledReply::Policy => StatusCode::ProtectionLevelDenied,
         }
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct InnerConnection {
-    pub(crate) socket: Arc<Mutex<TcpStream>>,
+    /// Identifies the session in the logs
+    pub(crate) id: u64,
+    pub(crate) peer_addr: SocketAddr,
+    /// The address the client reached the server on, if known
+    pub(crate) local_addr: Option<SocketAddr>,
+    /// ISO code of the country of the client, with a GeoIP database
+    pub(crate) country: Option<String>,
     pub(crate) data_connection: Option<Arc<Mutex<DataConnection>>>,
+    /// Opening the data connection asked for with `PASV`, `EPSV` or `PORT`
+    pub(crate) data_task: Option<Arc<AbortHandle>>,
     pub(crate) cwd: PathBuf,
+    /// The directory the session is confined to, which clients see as `/`
+    pub(crate) root: PathBuf,
+    /// Where the files under `root` are opened
+    pub(crate) storage: Arc<dyn StorageBackend>,
+    pub(crate) username: Option<String>,
+    /// The virtual host selected with `HOST`
+    pub(crate) host: Option<String>,
+    pub(crate) login: LoginState,
+    /// Logged in as `anonymous`
+    pub(crate) anonymous: bool,
+    /// What the user may do, checked before each command
+    pub(crate) permissions: Vec<Permission>,
+    /// Groups of the user, for the ACL rules
+    pub(crate) groups: Vec<String>,
+    /// What the files under the root may take and take already, if limited
+    pub(crate) quota: Option<Arc<QuotaAccount>>,
+    /// Whether the control connection is secured with TLS
+    pub(crate) tls: bool,
+    /// Subject of the verified certificate the client presented
+    pub(crate) certificate: Option<String>,
+    /// Set by `AUTH TLS` to upgrade the control connection after its reply
+    pub(crate) pending_tls: bool,
+    /// Set by `CCC` to clear the control connection after its reply
+    pub(crate) pending_clear: bool,
+    /// Set by `EPSV ALL`, refuses every other way to open data connections
+    pub(crate) epsv_all: bool,
+    /// Set by `PBSZ`, which has to precede `PROT`
+    pub(crate) pbsz: bool,
+    pub(crate) protection: DataProtection,
+    /// Selected with `MODE`
+    pub(crate) mode: TransferMode,
+    /// Selected with `OPTS MODE Z LEVEL`
+    pub(crate) deflate_level: u32,
+    /// Selected with `STRU`
+    pub(crate) structure: FileStructure,
+    /// Selected with `TYPE`
+    pub(crate) transfer_type: TransferType,
+    /// Selected with `OPTS HASH`
+    pub(crate) hash: HashAlgorithm,
+    /// Set by `RANG` for the next `HASH`
+    pub(crate) range: Option<ByteRange>,
+    /// Bytes announced with `ALLO` for the next `STOR`
+    pub(crate) allocation: Option<u64>,
+    /// Offset set by `REST` for the next transfer
+    pub(crate) restart: Option<u64>,
+    /// Facts sent by `MLSD` and `MLST`, selected with `OPTS MLST`
+    pub(crate) facts: Facts,
+    /// Transcript of the session, if recorded
+    pub(crate) recorder: Option<Recorder>,
+    /// Protocol trace toggled with `SITE DEBUG`
+    pub(crate) debug: SessionDebug,
+    /// Selected with `LANG`, shared with the writer task
+    pub(crate) language: SessionLanguage,
+    /// Buffers held on behalf of the client
+    pub(crate) memory: MemoryBudget,
+    /// The transfer running, reported by `STAT`
+    pub(crate) transfer: TransferProgress,
+    /// Override files read lately, with `overrides.file` set
+    pub(crate) overrides: Arc<OverrideCache>,
+    /// Cancelled by `QUIT`, or with every other session on shutdown
     pub(crate) cancelation_token: CancellationToken,
+    pub(crate) context: Arc<ServerContext>,
 }
 
 impl InnerConnection {
-    pub fn new(socket: TcpStream, cwd: PathBuf, cancelation_token: CancellationToken) -> Self {
+    pub fn new(
+        peer_addr: SocketAddr,
+        cwd: PathBuf,
+        cancelation_token: CancellationToken,
+        context: Arc<ServerContext>,
+    ) -> Self {
+        let id = context.last_session.fetch_add(1, Ordering::Relaxed) + 1;
         Self {
-            socket: Arc::new(Mutex::new(socket)),
+            id,
+            country: context.geoip.country(peer_addr.ip()),
+            peer_addr,
+            local_addr: None,
             data_connection: None,
+            data_task: None,
+            storage: open_storage(context.storage.as_ref(), &cwd),
+            root: cwd.clone(),
             cwd,
+            username: None,
+            host: None,
+            login: LoginState::default(),
+            anonymous: false,
+            permissions: Permission::ALL.to_vec(),
+            groups: Vec::new(),
+            quota: None,
+            tls: false,
+            certificate: None,
+            pending_tls: false,
+            pending_clear: false,
+            epsv_all: false,
+            pbsz: false,
+            protection: DataProtection::default(),
+            mode: TransferMode::default(),
+            deflate_level: DEFAULT_DEFLATE_LEVEL,
+            structure: FileStructure::default(),
+            transfer_type: TransferType::default(),
+            hash: HashAlgorithm::default(),
+            range: None,
+            allocation: None,
+            restart: None,
+            facts: Facts::default(),
+            recorder: None,
+            language: SessionLanguage::default(),
+            memory: MemoryBudget::new(context.config.limits.session_memory),
+            transfer: TransferProgress::default(),
+            overrides: Arc::default(),
+            debug: SessionDebug::new(id, context.config.site.debug_lines),
             cancelation_token,
+            context,
         }
     }
 
+    /// Records `event` in the audit log, caused by this session.
+    pub fn audit(&self, event: AuditEvent) {
+        let actor = Actor {
+            session: self.id,
+            ip: self.peer_addr.ip(),
+            user: self.username.as_deref(),
+        };
+        self.context.audit.record(Some(actor), event);
+    }
+
+    /// The client address as it has to appear in the logs
+    pub fn client(&self) -> String {
+        self.context.privacy.redact(&self.peer_addr)
+    }
+
     pub fn cwd(&self) -> PathBuf {
         self.cwd.clone()
     }
 
-    pub async fn change_dir(&mut self, dir: OsString) -> Result<()> {
-        let mut cwd = self.cwd.clone();
-        cwd.push(dir);
-        trace!("Changing directory to {:?}", cwd);
-        if cwd.is_dir() {
-            self.cwd = cwd;
-            Ok(())
+    /// Completes an anonymous login, confined to the anonymous root if
+    /// configured.
+    pub fn log_in_anonymously(&mut self) {
+        self.login = LoginState::LoggedIn;
+        self.anonymous = true;
+        let username = self.username.as_deref().unwrap_or_default();
+        let outcome = LoginOutcome::Succeeded;
+        self.context
+            .auth_log
+            .login(outcome, self.peer_addr.ip(), username);
+        self.audit(AuditEvent::Login { anonymous: true });
+        self.permissions = Permission::READ_ONLY.to_vec();
+        self.groups = Vec::new();
+        self.quota = None;
+        let root = match self.context.config.anonymous.root.clone() {
+            Some(root) => root,
+            None => self.session_root(),
+        };
+        self.confine(root);
+    }
+
+    /// Completes a login to the honeypot, whatever the credentials, to its
+    /// decoy files, with uploads that are dropped.
+    pub fn log_in_honeypot(&mut self) {
+        self.login = LoginState::LoggedIn;
+        self.anonymous = false;
+        self.permissions = Permission::READ_ONLY.to_vec();
+        self.permissions.push(Permission::Upload);
+        self.groups = Vec::new();
+        self.quota = None;
+        self.confine(self.session_root());
+    }
+
+    /// Completes the login, confined to the home directory of the user if
+    /// configured.
+    pub fn log_in(&mut self) {
+        self.login = LoginState::LoggedIn;
+        self.anonymous = false;
+        let username = self.username.as_deref().unwrap_or_default();
+        // Like `admit` checks it, IPv4 clients of a dual-stack listener alike
+        let address = self.peer_addr.ip().to_canonical();
+        self.context.throttle.succeeded(address, username);
+        let outcome = LoginOutcome::Succeeded;
+        self.context.auth_log.login(outcome, address, username);
+        self.audit(AuditEvent::Login { anonymous: false });
+        let quota = self.context.users.quota(username);
+        self.quota = quota.map(|quota| self.context.quotas.account(username, quota));
+        self.permissions = self.context.users.permissions(username);
+        self.groups = self.context.users.groups(username);
+        if let Some(home) = self.context.users.home(username) {
+            if self.context.config.virtual_users.create_homes && !home.exists() {
+                debug!("Creating the home directory {:?}", home);
+                let mode = self.context.config.uploads.directory_mode;
+                if let Err(error) = create_dir(home, mode) {
+                    warn!("Could not create the home directory {:?}: {}", home, error);
+                }
+            }
+            self.confine(home.to_path_buf());
+        } else {
+            self.confine(self.session_root());
+        }
+    }
+
+    /// The root of the selected virtual host, or the one of the server.
+    fn session_root(&self) -> PathBuf {
+        let host = self
+            .host
+            .as_deref()
+            .and_then(|name| self.context.config.host(name));
+        match host {
+            Some(host) => host.root.clone(),
+            None => self.context.root.clone(),
+        }
+    }
+
+    /// Counts a failed login of `username`, holding the reply back or
+    /// closing the session if the client failed too often.
+    pub async fn fail_login(&mut self, username: &str) -> StatusCode {
+        self.login = LoginState::LoggedOut;
+        // Banned as `admit` checks it, not as an IPv4-mapped IPv6 address
+        let address = self.peer_addr.ip().to_canonical();
+        let verdict = self.context.throttle.failed(address, username);
+        let outcome = match verdict {
+            Verdict::Retry(_) => LoginOutcome::Failed,
+            Verdict::Banned => LoginOutcome::Banned,
+        };
+        self.context.auth_log.login(outcome, address, username);
+        let banned = outcome == LoginOutcome::Banned;
+        self.context.audit.record(
+            Some(Actor {
+                session: self.id,
+                ip: address,
+                user: Some(username),
+            }),
+            AuditEvent::LoginFailed { banned },
+        );
+        match verdict {
+            Verdict::Retry(delay) => {
+                tokio::time::sleep(delay).await;
+                StatusCode::UserNotLoggedIn
+            }
+            Verdict::Banned => {
+                warn!("Banning {} after too many failed logins", self.client());
+                // The session ends once the reply is sent
+                self.cancelation_token.cancel();
+                StatusCode::Closing(" Too many failed logins".to_string())
+            }
+        }
+    }
+
+    /// Confines the session to `root`, starting at its top.
+    pub(crate) fn confine(&mut self, root: PathBuf) {
+        // Still confined, so the user can't reach anything else
+        if !self.context.config.honeypot.enabled && !root.is_dir() {
+            warn!("The root {:?} is not a directory", root);
+        }
+        trace!("Confining the session to {:?}", root);
+        self.storage = match self.context.config.shares.is_empty() {
+            true => open_storage(self.context.storage.as_ref(), &root),
+            false => self.open_shares(),
+        };
+        self.overrides.clear();
+        self.cwd = root.clone();
+        self.root = root;
+    }
+
+    /// The shares the user can see, side by side at the root of the
+    /// session.
+    fn open_shares(&self) -> Arc<dyn StorageBackend> {
+        let user = self.username.as_deref().unwrap_or_default();
+        let mut shares = Shares::default();
+        for share in &self.context.config.shares {
+            if !share.visible_to(user, &self.groups) {
+                continue;
+            }
+            let mut storage = open_storage(self.context.storage.as_ref(), &share.path);
+            if share.read_only {
+                storage = Arc::new(ReadOnlyStorage(storage));
+            }
+            shares.add(&share.name, storage);
+        }
+        Arc::new(shares)
+    }
+
+    /// What the user may do with `path`, after the ACL rules covering it.
+    pub fn permissions_at(&self, path: &Path) -> Vec<Permission> {
+        let mut permissions = self.permissions.clone();
+        let shown = self.display_path(path);
+        let user = self.username.as_deref().unwrap_or_default();
+        for rule in &self.context.config.acl {
+            if rule.applies(Path::new(&shown), user, &self.groups) {
+                rule.apply(&mut permissions);
+            }
+        }
+        permissions
+    }
+
+    /// Returns `true` if `path`, or a directory it's in, is hidden from
+    /// clients.
+    pub fn is_hidden(&self, path: &Path) -> bool {
+        let relative = path.strip_prefix(&self.root).unwrap_or(path);
+        self.context.config.hidden.hides_path(relative)
+    }
+
+    /// The override files of the directories on the way to `path`,
+    /// relative to the root of the session.
+    pub async fn overrides(&self, path: &Path) -> Overrides {
+        let config = &self.context.config.overrides;
+        let lifetime = std::time::Duration::from_secs(config.cache_seconds);
+        let storage = self.storage.as_ref();
+        self.overrides
+            .load(storage, &config.file, path, lifetime)
+            .await
+    }
+
+    /// How the client sees `path`, relative to the root of the session,
+    /// separated by `/` whatever the platform.
+    pub fn display_path(&self, path: &Path) -> String {
+        let Ok(relative) = path.strip_prefix(&self.root) else {
+            return path.to_string_lossy().to_string();
+        };
+        let mut shown = String::new();
+        for component in relative.components() {
+            if let Component::Normal(name) = component {
+                shown.push('/');
+                shown.push_str(&name.to_string_lossy());
+            }
+        }
+        match shown.is_empty() {
+            true => "/".to_string(),
+            false => shown,
+        }
+    }
+
+    /// Resolves a path sent by the client against the working directory,
+    /// after the rewrite rules of the configuration.
+    ///
+    /// Every path a command touches goes through here. `..` and absolute
+    /// paths stop at the root of the session, and paths leading out of it
+    /// through symbolic links are refused with the reply to send.
+    pub fn resolve_path(&self, path: &str) -> std::result::Result<PathBuf, StatusCode> {
+        let rewritten = self.context.config.rewrite_path(path);
+        if rewritten != path {
+            debug!("Rewrote {:?} to {:?}", path, rewritten);
+        }
+        // `..` is resolved before joining, so it stops at the root
+        let cwd = self.cwd.strip_prefix(&self.root).unwrap_or(Path::new(""));
+        let mut relative = PathBuf::new();
+        for component in cwd.join(rewritten.as_ref()).components() {
+            match component {
+                Component::Normal(name) => relative.push(name),
+                Component::ParentDir => {
+                    relative.pop();
+                }
+                Component::RootDir => relative.clear(),
+                Component::CurDir | Component::Prefix(_) => {}
+            }
+        }
+        let resolved = self.root.join(relative);
+        // Only local files can be links out of the root
+        if self.storage.local().is_none() {
+            return Ok(resolved);
+        }
+
+        // What doesn't exist yet can't be a link, the closest ancestor
+        // that does is checked instead
+        let root = self
+            .root
+            .canonicalize()
+            .unwrap_or_else(|_| self.root.clone());
+        let mut existing = resolved.as_path();
+        let canonical = loop {
+            match existing.canonicalize() {
+                Ok(canonical) => break Some(canonical),
+                // A dangling link, which could be created outside
+                Err(_) if existing.symlink_metadata().is_ok() => break None,
+                Err(_) => match existing.parent() {
+                    Some(parent) => existing = parent,
+                    None => break None,
+                },
+            }
+        };
+        match canonical {
+            Some(canonical) if canonical.starts_with(root) => Ok(resolved),
+            _ => {
+                warn!("Refusing {:?}, it leads out of {:?}", resolved, self.root);
+                Err(self.deny(Denial::OutsideRoot {
+                    path: path.to_string(),
+                }))
+            }
+        }
+    }
+
+    /// Resolves a path sent by the client like [`Self::resolve_path`],
+    /// relative to the root of the session to be opened beneath
+    /// [`Self::storage`].
+    pub fn resolve_beneath(&self, path: &str) -> std::result::Result<PathBuf, StatusCode> {
+        let resolved = self.resolve_path(path)?;
+        Ok(relative_to(&resolved, &self.root))
+    }
+
+    /// Writes a transfer to `data_connection` in the selected transfer mode
+    /// and type.
+    pub fn data_writer<'a, W: AsyncWrite + Unpin>(
+        &self,
+        data_connection: &'a mut W,
+    ) -> Result<DataWriter<'a, W>> {
+        DataWriter::new(
+            data_connection,
+            self.mode,
+            self.deflate_level,
+            self.transfer_type,
+            &self.memory,
+        )
+    }
+
+    /// Reads a transfer from `data_connection` in the selected transfer mode
+    /// and type.
+    pub fn data_reader<'a, R: AsyncRead + Unpin>(
+        &self,
+        data_connection: &'a mut R,
+    ) -> Result<DataReader<'a, R>> {
+        DataReader::new(data_connection, self.mode, self.transfer_type, &self.memory)
+    }
+
+    /// Returns `true` if the session may open data connections in `mode`.
+    pub fn allows_data_mode(&self, mode: DataMode) -> bool {
+        self.context
+            .config
+            .allows_data_mode(self.username.as_deref(), mode)
+    }
+
+    /// Returns `true` if the session may transfer from or to other hosts
+    /// than the client.
+    pub fn allows_fxp(&self) -> bool {
+        self.context.config.allows_fxp(self.username.as_deref())
+    }
+
+    /// The reply to an operation refused because of `denial`,
+    /// with the reason only if the policy allows it.
+    pub fn deny(&self, denial: Denial) -> StatusCode {
+        self.audit(AuditEvent::Denied {
+            code: denial.code(),
+            reason: denial.to_string(),
+        });
+        if self.context.config.replies.details {
+            StatusCode::Denied(denial)
         } else {
-            Err(miette!("Invalid directory"))
+            denial.bare()
+        }
+    }
+
+    /// The TLS configuration the data connections have to be secured with,
+    /// if `PROT P` was selected.
+    pub fn data_tls(&self) -> Option<Arc<ServerConfig>> {
+        self.context
+            .tls()
+            .filter(|_| self.protection == DataProtection::Private)
+    }
+
+    /// Forgets the data connection for the one `task` opens, asked for
+    /// with `PASV`, `EPSV` or `PORT`.
+    pub fn open_data_connection(&mut self, task: AbortHandle) {
+        self.abort_data_task();
+        self.data_connection = None;
+        self.data_task = Some(Arc::new(task));
+    }
+
+    /// Stops opening the data connection, if it's still being opened, so a
+    /// passive listener doesn't keep its port nor set the connection
+    /// later.
+    pub fn abort_data_task(&mut self) {
+        if let Some(task) = self.data_task.take() {
+            task.abort();
+        }
+    }
+
+    /// Returns `true` if the policy requires TLS
+    /// and the control connection isn't secured yet.
+    pub fn requires_tls(&self) -> bool {
+        self.context.config.tls.required && !self.tls
+    }
+
+    pub async fn change_dir(&mut self, dir: &str) -> Result<()> {
+        let cwd = self
+            .resolve_path(dir)
+            .map_err(|_| miette!("{:?} is outside of the root", dir))?;
+        trace!("Changing directory to {:?}", cwd);
+        let relative = relative_to(&cwd, &self.root);
+        if self.is_hidden(&cwd) || self.overrides(&relative).await.hides_path(&relative) {
+            bail!("{:?} is hidden", dir);
+        }
+        // Found beneath the root, so it's a directory in it
+        match self.storage.metadata(&relative).await {
+            Ok(metadata) if metadata.is_dir() => {
+                self.cwd = cwd;
+                Ok(())
+            }
+            Ok(_) => Err(miette!("{:?} is not a directory", dir)),
+            Err(error) => Err(miette!("Invalid directory: {}", error)),
         }
     }
 }
 
 pub type InnerConnectionRef = Arc<Mutex<InnerConnection>>;
 
-#[derive(Debug, Clone)]
+/// How long a transfer waits for its data connection to be opened, and a
+/// passive listener for the client to connect
+pub(crate) const DATA_CONNECTION_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Waits for the data connection of a transfer, returning `false` if none
+/// was asked for, or if it couldn't be opened in time, for a `425` reply.
+pub async fn wait_for_data_connection(connection: &InnerConnectionRef) -> bool {
+    let opened = tokio::time::timeout(DATA_CONNECTION_TIMEOUT, async {
+        loop {
+            {
+                let connection = connection.lock().await;
+                if connection.data_connection.is_some() {
+                    return true;
+                }
+                match &connection.data_task {
+                    Some(task) if !task.is_finished() => {}
+                    // Never asked for, or it failed to open
+                    _ => return false,
+                }
+            }
+            trace!("Waiting for data connection");
+            tokio::time::sleep(std::time::Duration::from_millis(250)).await;
+        }
+    })
+    .await;
+    opened.unwrap_or_else(|_| {
+        warn!("The data connection wasn't opened in time");
+        false
+    })
+}
+
+#[derive(Debug)]
 pub struct Connection {
     inner: InnerConnectionRef,
+    stream: Option<ControlStream>,
+    /// Limits the commands of the session, if configured
+    commands: Option<TokenBucket>,
 }
 
 impl Connection {
-    pub fn new(inner: InnerConnection) -> Self {
+    pub fn new(socket: TcpStream, inner: InnerConnection) -> Self {
         Self {
+            commands: TokenBucket::for_commands(&inner.context.config.limits),
             inner: Arc::new(Mutex::new(inner)),
+            stream: Some(ControlStream::Plain(socket)),
         }
     }
 
@@ -174,44 +1047,211 @@ impl Connection {
         self.inner.clone()
     }
 
-    #[tracing::instrument(skip(self), name = "connection", fields(ip = %self.inner().lock().await.socket.lock().await.peer_addr().unwrap()))]
+    #[tracing::instrument(
+        skip(self),
+        name = "connection",
+        fields(
+            ip = %self.inner().lock().await.client(),
+            session = tracing::field::Empty,
+            country = tracing::field::Empty,
+        )
+    )]
     pub async fn connect(&mut self) -> Result<()> {
-        let _addr = self
-            .inner
-            .lock()
-            .await
-            .socket
-            .lock()
-            .await
-            .peer_addr()
-            .unwrap();
-        let socket_clone = self.inner.lock().await.socket.clone();
-        let mut socket_mutex = socket_clone.lock().await;
-        let socket = socket_mutex.borrow_mut();
-        let (mut read_stream, mut write_stream) = socket.split();
-        let mut reader = BufReader::new(&mut read_stream);
-
-        write_stream
-            .write(StatusCode::ServiceReadyUser.to_string().as_bytes())
+        let mut stream = self
+            .stream
+            .take()
+            .ok_or_else(|| miette!("Connection already started"))?;
+        let (context, id, country) = {
+            let inner = self.inner.lock().await;
+            (inner.context.clone(), inner.id, inner.country.clone())
+        };
+        Span::current().record("session", id);
+        if let Some(country) = &country {
+            Span::current().record("country", country.as_str());
+        }
+        let recorder = self.start_recording(&context).await;
+
+        let mut greeting = String::new();
+        if let Some(banner) = &context.config.replies.banner {
+            greeting.push_str(&continuation(220, &render(banner, &context)));
+        }
+        if context.config.tls.required {
+            greeting.push_str("220-TLS is required, use AUTH TLS before logging in\n");
+        }
+        greeting.push_str(&StatusCode::ServiceReadyUser.to_string());
+        stream
+            .write_all(greeting.as_bytes())
             .await
             .into_diagnostic()?;
+        if let Some(recorder) = &recorder {
+            recorder.reply(&greeting);
+        }
+
+        loop {
+            let (read_half, write_half) = tokio::io::split(stream);
+            let mut reader = BufReader::new(read_half);
+            let (session_debug, language) = {
+                let inner = self.inner.lock().await;
+                (inner.debug.clone(), inner.language.clone())
+            };
+            let (mut writer, writer_task) =
+                ControlWriter::spawn(write_half, recorder.clone(), session_debug, language);
+            let switch = self.handle_commands(&mut reader, &mut writer).await;
+
+            // Every reply has to be written before the stream is switched or dropped
+            drop(writer);
+            let write_half = writer_task.await.into_diagnostic()?.into_diagnostic()?;
+            if !switch? {
+                return Ok(());
+            }
 
+            stream = reader.into_inner().unsplit(write_half);
+            stream = if self.inner.lock().await.pending_clear {
+                self.clear(stream).await?
+            } else {
+                self.secure(stream, &context).await?
+            };
+        }
+    }
+
+    /// Creates the transcript of the session if recording is enabled.
+    async fn start_recording(&self, context: &ServerContext) -> Option<Recorder> {
+        let directory = context.config.record.directory.as_ref()?;
+        let mut inner = self.inner.lock().await;
+        match Recorder::create(directory, &inner.client()) {
+            Ok(recorder) => {
+                inner.recorder = Some(recorder.clone());
+                Some(recorder)
+            }
+            Err(error) => {
+                warn!("Not recording the session: {:?}", error);
+                None
+            }
+        }
+    }
+
+    /// Upgrades the control connection to TLS after `AUTH TLS`.
+    #[cfg(feature = "tls")]
+    async fn secure(
+        &mut self,
+        stream: ControlStream,
+        context: &ServerContext,
+    ) -> Result<ControlStream> {
+        let acceptor = TlsAcceptor::from(
+            context
+                .tls()
+                .ok_or_else(|| miette!("TLS is not configured"))?,
+        );
+        trace!("Upgrading control connection to TLS");
+        let stream = stream.upgrade(&acceptor).await.into_diagnostic()?;
+        let mut inner = self.inner.lock().await;
+        inner.pending_tls = false;
+        inner.tls = true;
+        if let Some(subject) = stream.certificate_subject() {
+            info!("Client presented a certificate for {:?}", subject);
+            inner.certificate = Some(subject);
+        }
+        debug!("Control connection secured with TLS");
+        Ok(stream)
+    }
+
+    /// Goes back to plain text after `CCC`.
+    async fn clear(&mut self, stream: ControlStream) -> Result<ControlStream> {
+        trace!("Clearing the control connection");
+        let stream = stream.clear().await.into_diagnostic()?;
+        let mut inner = self.inner.lock().await;
+        inner.pending_clear = false;
+        inner.tls = false;
+        debug!("Control connection cleared");
+        Ok(stream)
+    }
+
+    #[cfg(not(feature = "tls"))]
+    async fn secure(
+        &mut self,
+        _stream: ControlStream,
+        _context: &ServerContext,
+    ) -> Result<ControlStream> {
+        bail!("TLS is not available in this build")
+    }
+
+    /// Reads and executes commands until the session ends.
+    ///
+    /// Returns `true` if the control connection has to be upgraded to TLS,
+    /// or cleared.
+    async fn handle_commands(
+        &mut self,
+        reader: &mut BufReader<ReadHalf<ControlStream>>,
+        writer: &mut ControlWriter,
+    ) -> Result<bool> {
         let mut buf = vec![];
-        let cancelation_token = self.inner.lock().await.cancelation_token.clone();
+        // Read while a transfer ran, and not run yet
+        let mut pending = vec![];
+        let (client, cancelation_token, recorder, session_debug, memory, limits) = {
+            let inner = self.inner.lock().await;
+            (
+                inner.client(),
+                inner.cancelation_token.clone(),
+                inner.recorder.clone(),
+                inner.debug.clone(),
+                inner.memory.clone(),
+                inner.context.config.limits.clone(),
+            )
+        };
+        let mut command_memory = memory.reserve(0)?;
         loop {
+            buf.append(&mut pending);
+            // Longer lines end up in `buf` cut off, without their line feed
+            let line = read_line(reader, &mut buf, limits.max_command_line);
             tokio::select! {
+                // A session ended by `QUIT` reads no further commands
+                biased;
                 _ = cancelation_token.cancelled() => {
-                    write_stream.shutdown().await.into_diagnostic()?;
-                    debug!("Quitting connection {}", socket.peer_addr().unwrap());
-                    return Ok(());
+                    writer.shutdown().await?;
+                    debug!("Quitting connection {}", client);
+                    return Ok(false);
                 }
-                res = reader.read_until(b'\n', &mut buf) => {
+                res = line => {
                     res.into_diagnostic()?;
                 }
             }
+            if let Err(error) = command_memory.resize(buf.capacity()) {
+                return self.close_over_budget(error, writer).await;
+            }
+            if buf.len() >= limits.max_command_line && !buf.ends_with(b"\n") {
+                warn!(
+                    "Refusing a command line over {} bytes",
+                    limits.max_command_line
+                );
+                writer.send(StatusCode::CommandTooLong)?;
+                if limits.close_on_long_command {
+                    writer.shutdown().await?;
+                    return Ok(false);
+                }
+                skip_line(reader).await.into_diagnostic()?;
+                buf.clear();
+                continue;
+            }
+            if buf.contains(&telnet::IAC) {
+                let answer = telnet::strip(&mut buf);
+                if !answer.is_empty() {
+                    writer.telnet(answer)?;
+                }
+                // A line of Telnet commands only isn't an empty command
+                if buf.iter().all(u8::is_ascii_whitespace) {
+                    buf.clear();
+                    continue;
+                }
+            }
 
             let input = str::from_utf8(&buf).into_diagnostic()?.trim_end();
-            debug!("Reading {:?} from stream", input);
+            // Credentials never reach the log
+            let logged = sanitize(input);
+            debug!("Reading {:?} from stream", logged);
+            if let Some(recorder) = &recorder {
+                recorder.command(input);
+            }
+            session_debug.command(input);
             if input.is_empty() {
                 // This is here because if the client crashes
                 // the server will keep reading empty commands
@@ -219,39 +1259,167 @@ impl Connection {
                 return Err(miette!("Empty command"));
             }
 
-            let (_, (cmd, args)) = cmd_parser(input).unwrap();
-            info!("Received {:?} command with args: {:?}", cmd, args);
+            let (_, (cmd, mut args)) = cmd_parser(input).unwrap();
+            // Passwords may hold spaces, so they're the rest of the line
+            if cmd == "PASS" {
+                let (_, (_, password)) = cmd_text_parser(input).unwrap();
+                args = match password {
+                    "" => vec![],
+                    password => vec![password],
+                };
+            }
+            let (_, (_, logged_args)) = cmd_parser(&logged).unwrap();
+            info!("Received {:?} command with args: {:?}", cmd, logged_args);
+
+            if let Some(commands) = &mut self.commands {
+                let wait = commands.take();
+                if !wait.is_zero() {
+                    if limits.over_rate == OverRate::Close {
+                        warn!("Closing the session, it sends too many commands");
+                        writer.send(StatusCode::Closing(" Too many commands".to_string()))?;
+                        writer.shutdown().await?;
+                        return Ok(false);
+                    }
+                    debug!("Holding {} back for {:?}", cmd, wait);
+                    tokio::time::sleep(wait).await;
+                }
+            }
 
-            let response = self.execute_command(cmd, args, &mut write_stream).await;
+            let response = match self.transfer_snapshot(cmd).await {
+                Some(session) => {
+                    let status = writer.clone();
+                    let execution = self.execute_command(cmd, args, writer);
+                    let limit = limits.max_command_line;
+                    answer_status(execution, &session, reader, &mut pending, limit, &status).await
+                }
+                None => self.execute_command(cmd, args, writer).await,
+            };
             match response {
                 Ok(res) => {
                     if let Some(res) = res {
-                        write_stream
-                            .write(res.to_string().as_bytes())
-                            .await
-                            .into_diagnostic()?;
+                        writer.send(res)?;
                     }
                 }
+                Err(e) if e.downcast_ref::<MemoryLimitExceeded>().is_some() => {
+                    return self.close_over_budget(e, writer).await;
+                }
                 Err(e) => {
                     error!("Error executing command: {:?}", e);
                 }
             }
 
+            {
+                let inner = self.inner.lock().await;
+                if inner.pending_tls || inner.pending_clear {
+                    return Ok(true);
+                }
+            }
+
             debug!("Clearing buffer");
             buf.clear();
         }
     }
 
-    async fn execute_command<'a>(
+    /// A copy of the session to answer `STAT` from while `cmd` transfers a
+    /// file or a listing, unless `STAT` is disabled.
+    async fn transfer_snapshot(&self, cmd: &str) -> Option<InnerConnection> {
+        if !matches!(cmd, "RETR" | "STOR" | "APPE" | "LIST" | "NLST" | "MLSD") {
+            return None;
+        }
+        let connection = self.inner.lock().await;
+        if connection.context.config.commands.is_disabled("STAT") {
+            return None;
+        }
+        Some(connection.clone())
+    }
+
+    /// Ends a session that went over its memory budget.
+    async fn close_over_budget(&self, error: Report, writer: &ControlWriter) -> Result<bool> {
+        warn!("Closing the session: {}", error);
+        writer.send(StatusCode::Closing(format!(" {error}")))?;
+        writer.shutdown().await?;
+        Ok(false)
+    }
+
+    async fn execute_command(
         &mut self,
         cmd: &str,
         args: Vec<&str>,
-        writer: &mut WriteHalf<'a>,
+        writer: &mut ControlWriter,
     ) -> Result<Option<StatusCode>> {
-        if let Ok(code) = Command::try_from((cmd, args)) {
-            return code.run(self.inner.clone(), writer).await;
+        let context = {
+            let connection = self.inner.lock().await;
+            let address = connection.peer_addr.ip();
+            connection
+                .context
+                .honeypot
+                .command(connection.id, address, cmd, &args);
+            connection.context.clone()
+        };
+        if context.config.commands.is_disabled(cmd) {
+            debug!("Refusing disabled command {}", cmd);
+            return Ok(Some(context.disabled(cmd)));
+        }
+        if !Command::allowed_before_login(cmd) {
+            let connection = self.inner.lock().await;
+            if connection.login != LoginState::LoggedIn {
+                debug!("Refusing {} before login", cmd);
+                return Ok(Some(StatusCode::UserNotLoggedIn));
+            }
+        }
+        let permission = Command::permission(cmd, &args);
+        let names_file = Command::names_file(cmd);
+        let Ok(command) = Command::try_from((cmd, args)) else {
+            return Ok(Some(StatusCode::CmdNotImplemented));
+        };
+        if let Some(permission) = permission {
+            let connection = self.inner.lock().await;
+            let path = match command.target() {
+                Some(target) => match connection.resolve_path(&target) {
+                    Ok(path) => path,
+                    Err(reply) => return Ok(Some(reply)),
+                },
+                None => connection.cwd(),
+            };
+            let relative = relative_to(&path, &connection.root);
+            let overrides = connection.overrides(&relative).await;
+            // As if it didn't exist, so hiding isn't only cosmetic
+            if connection.is_hidden(&path) || overrides.hides_path(&relative) {
+                debug!("Refusing {} of a hidden path", cmd);
+                return Ok(Some(StatusCode::ActionNotTaken));
+            }
+            let mut permissions = connection.permissions_at(&path);
+            overrides.restrict(&mut permissions);
+            if !permissions.contains(&permission) {
+                debug!("Refusing {}, the session can't {}", cmd, permission);
+                let command = cmd.to_string();
+                let denial = Denial::NotPermitted {
+                    command,
+                    permission,
+                };
+                return Ok(Some(connection.deny(denial)));
+            }
+        }
+        if let (true, Some(target)) = (names_file, command.target()) {
+            let connection = self.inner.lock().await;
+            let path = match connection.resolve_path(&target) {
+                Ok(path) => path,
+                Err(reply) => return Ok(Some(reply)),
+            };
+            let name = path
+                .file_name()
+                .map(|name| name.to_string_lossy())
+                .unwrap_or_default();
+            if let Err(rule) = connection.context.config.filenames.check(&name) {
+                debug!("Refusing {} of {:?}, the name {}", cmd, name, rule);
+                let denial = Denial::Filename {
+                    name: name.into_owned(),
+                    rule,
+                };
+                return Ok(Some(connection.deny(denial)));
+            }
         }
-        Ok(Some(StatusCode::CmdNotImplemented))
+        command.run(self.inner.clone(), writer).await
     }
 }
 
@@ -259,33 +1427,309 @@ impl TryFrom<TcpStream> for Connection {
     type Error = miette::Error;
 
     fn try_from(socket: TcpStream) -> Result<Self> {
-        let cwd = std::env::current_dir().into_diagnostic()?;
-        let inner = InnerConnection::new(socket, cwd, CancellationToken::new());
-        Ok(Self::new(inner))
+        let context = Arc::new(ServerContext::new(
+            Config::default(),
+            SiteCommands::default(),
+            Arc::new(Local),
+        )?);
+        let mut inner = InnerConnection::new(
+            socket.peer_addr().into_diagnostic()?,
+            context.root.clone(),
+            CancellationToken::new(),
+            context,
+        );
+        inner.local_addr = socket.local_addr().ok();
+        Ok(Self::new(socket, inner))
     }
 }
 
-impl TryFrom<InnerConnection> for Connection {
+impl TryFrom<(TcpStream, CancellationToken, Arc<ServerContext>)> for Connection {
     type Error = miette::Error;
 
-    fn try_from(inner: InnerConnection) -> Result<Self> {
-        Ok(Self::new(inner))
+    fn try_from(
+        (socket, cancelation_token, context): (TcpStream, CancellationToken, Arc<ServerContext>),
+    ) -> Result<Self> {
+        let cwd = context.root.clone();
+        let peer_addr = socket.peer_addr().into_diagnostic()?;
+        let mut inner = InnerConnection::new(peer_addr, cwd, cancelation_token, context);
+        inner.local_addr = socket.local_addr().ok();
+        Ok(Self::new(socket, inner))
     }
 }
 
-impl TryFrom<(TcpStream, CancellationToken)> for Connection {
-    type Error = miette::Error;
+/// Reads the rest of a command line into `buf`, up to `limit` bytes in all,
+/// unless it holds a whole line already.
+async fn read_line<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    buf: &mut Vec<u8>,
+    limit: usize,
+) -> std::io::Result<usize> {
+    if buf.ends_with(b"\n") {
+        return Ok(0);
+    }
+    let limit = limit.saturating_sub(buf.len()) as u64;
+    reader.take(limit).read_until(b'\n', buf).await
+}
 
-    fn try_from((socket, cancelation_token): (TcpStream, CancellationToken)) -> Result<Self> {
-        let cwd = std::env::current_dir().into_diagnostic()?;
-        let inner = InnerConnection::new(socket, cwd, cancelation_token);
-        Ok(Self::new(inner))
+/// Runs `execution`, answering `STAT` with the status of `session` while it
+/// runs. Reading stops at any other command, left in `pending` to run next.
+async fn answer_status<F, R>(
+    execution: F,
+    session: &InnerConnection,
+    reader: &mut R,
+    pending: &mut Vec<u8>,
+    limit: usize,
+    writer: &ControlWriter,
+) -> Result<Option<StatusCode>>
+where
+    F: Future<Output = Result<Option<StatusCode>>>,
+    R: AsyncBufRead + Unpin,
+{
+    tokio::pin!(execution);
+    let mut reading = true;
+    loop {
+        tokio::select! {
+            response = &mut execution => return response,
+            res = read_line(reader, pending, limit), if reading => {
+                let complete = matches!(res, Ok(read) if read > 0) && pending.ends_with(b"\n");
+                let line = str::from_utf8(pending).unwrap_or_default().trim_end();
+                if !complete || line != "STAT" {
+                    reading = false;
+                    continue;
+                }
+                debug!("Reading {:?} during a transfer", line);
+                if let Some(recorder) = &session.recorder {
+                    recorder.command(line);
+                }
+                session.debug.command(line);
+                writer.send(system_status(session))?;
+                pending.clear();
+            }
+        }
     }
 }
 
+/// Discards the rest of a line too long to be read, without buffering it.
+async fn skip_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> std::io::Result<()> {
+    loop {
+        let available = reader.fill_buf().await?;
+        if available.is_empty() {
+            return Ok(());
+        }
+        match available.iter().position(|byte| *byte == b'\n') {
+            Some(end) => {
+                reader.consume(end + 1);
+                return Ok(());
+            }
+            None => {
+                let length = available.len();
+                reader.consume(length);
+            }
+        }
+    }
+}
+
+/// The control connection, in plaintext or secured with `AUTH TLS`.
 #[derive(Debug)]
-pub struct DataConnection {
-    socket: TcpStream,
+pub enum ControlStream {
+    Plain(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+#[cfg(feature = "tls")]
+impl ControlStream {
+    /// Performs the TLS handshake over a plaintext stream.
+    pub async fn upgrade(self, acceptor: &TlsAcceptor) -> std::io::Result<Self> {
+        match self {
+            Self::Plain(socket) => Ok(Self::Tls(Box::new(acceptor.accept(socket).await?))),
+            Self::Tls(_) => Ok(self),
+        }
+    }
+
+    /// The subject of the verified certificate the client presented, if any.
+    pub fn certificate_subject(&self) -> Option<String> {
+        match self {
+            Self::Plain(_) => None,
+            Self::Tls(stream) => stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certificates| certificates.first())
+                .and_then(certificate_subject),
+        }
+    }
+}
+
+impl ControlStream {
+    /// Ends the TLS session and goes on in plain text over the same socket.
+    ///
+    /// Some clients answer the `close_notify` alert with their own, which
+    /// is discarded, others go on in plain text right away.
+    pub async fn clear(self) -> std::io::Result<Self> {
+        match self {
+            Self::Plain(_) => Ok(self),
+            #[cfg(feature = "tls")]
+            Self::Tls(mut stream) => {
+                stream.get_mut().1.send_close_notify();
+                stream.flush().await?;
+                let (mut socket, _) = stream.into_inner();
+                discard_close_notify(&mut socket).await?;
+                Ok(Self::Plain(socket))
+            }
+        }
+    }
+}
+
+/// Reads the `close_notify` alert of the client, if it sends one.
+///
+/// Commands are text, so a TLS record starts with a byte no command
+/// starts with: an alert, or application data in TLS 1.3, which
+/// encrypts its alerts.
+#[cfg(feature = "tls")]
+async fn discard_close_notify(socket: &mut TcpStream) -> std::io::Result<()> {
+    use std::time::Duration;
+
+    /// Time to wait for the alert, clients that send one do it right away
+    const TIMEOUT: Duration = Duration::from_millis(500);
+    const ALERT: u8 = 0x15;
+    const APPLICATION_DATA: u8 = 0x17;
+
+    let mut content_type = [0; 1];
+    let peek = tokio::time::timeout(TIMEOUT, socket.peek(&mut content_type));
+    if !matches!(peek.await, Ok(Ok(1))) || !matches!(content_type[0], ALERT | APPLICATION_DATA) {
+        return Ok(());
+    }
+    let mut header = [0; 5];
+    socket.read_exact(&mut header).await?;
+    let length = u16::from_be_bytes([header[3], header[4]]);
+    let mut record = vec![0; length as usize];
+    socket.read_exact(&mut record).await?;
+    trace!("Discarded the close_notify alert of the client");
+    Ok(())
+}
+
+impl AsyncWrite for ControlStream {
+    fn poll_write(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Self::Plain(socket) => std::pin::Pin::new(socket).poll_write(cx, buf),
+            #[cfg(feature = "tls")]
+            Self::Tls(socket) => std::pin::Pin::new(socket).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(socket) => std::pin::Pin::new(socket).poll_flush(cx),
+            #[cfg(feature = "tls")]
+            Self::Tls(socket) => std::pin::Pin::new(socket).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(socket) => std::pin::Pin::new(socket).poll_shutdown(cx),
+            #[cfg(feature = "tls")]
+            Self::Tls(socket) => std::pin::Pin::new(socket).poll_shutdown(cx),
+        }
+    }
+}
+
+impl AsyncRead for ControlStream {
+    fn poll_read(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(socket) => std::pin::Pin::new(socket).poll_read(cx, buf),
+            #[cfg(feature = "tls")]
+            Self::Tls(socket) => std::pin::Pin::new(socket).poll_read(cx, buf),
+        }
+    }
+}
+
+/// Protection of the data connections, selected with `PROT`
+///
+/// Check: https://datatracker.ietf.org/doc/html/rfc4217#section-9
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DataProtection {
+    #[default]
+    Clear,
+    Private,
+}
+
+/// How files are sent over the data connection, selected with `MODE`
+///
+/// Check: https://datatracker.ietf.org/doc/html/rfc959#section-3.4
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TransferMode {
+    #[default]
+    Stream,
+    /// `MODE Z`, compressed with deflate
+    Deflate,
+}
+
+/// The representation of the transferred files, selected with `TYPE`
+///
+/// Files are sent unchanged until a client asks for `TYPE A`, rather than
+/// following RFC 959, so clients that never send `TYPE` get them intact.
+///
+/// Check: https://datatracker.ietf.org/doc/html/rfc959#section-3.1.1
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TransferType {
+    /// `TYPE A`, with `CRLF` line endings
+    Ascii,
+    /// `TYPE I` or `TYPE L 8`, byte by byte
+    #[default]
+    Image,
+}
+
+/// The structure of the transferred files, selected with `STRU`
+///
+/// Check: https://datatracker.ietf.org/doc/html/rfc959#section-3.1.2
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum FileStructure {
+    #[default]
+    File,
+}
+
+/// The data connection, in plaintext or secured with `PROT P`.
+#[derive(Debug)]
+pub enum DataConnection {
+    Plain(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl DataConnection {
+    /// Performs the TLS handshake over the accepted or connected `socket`
+    /// if `tls` is set. The server is always the TLS server, whatever
+    /// side opened the connection.
+    #[cfg(feature = "tls")]
+    pub async fn open(socket: TcpStream, tls: Option<Arc<ServerConfig>>) -> std::io::Result<Self> {
+        match tls {
+            Some(config) => Ok(Self::Tls(Box::new(
+                TlsAcceptor::from(config).accept(socket).await?,
+            ))),
+            None => Ok(Self::Plain(socket)),
+        }
+    }
+
+    #[cfg(not(feature = "tls"))]
+    pub async fn open(socket: TcpStream, _tls: Option<Arc<ServerConfig>>) -> std::io::Result<Self> {
+        Ok(Self::Plain(socket))
+    }
 }
 
 impl AsyncWrite for DataConnection {
@@ -294,21 +1738,33 @@ impl AsyncWrite for DataConnection {
         cx: &mut std::task::Context<'_>,
         buf: &[u8],
     ) -> std::task::Poll<std::io::Result<usize>> {
-        std::pin::Pin::new(&mut self.get_mut().socket).poll_write(cx, buf)
+        match self.get_mut() {
+            Self::Plain(socket) => std::pin::Pin::new(socket).poll_write(cx, buf),
+            #[cfg(feature = "tls")]
+            Self::Tls(socket) => std::pin::Pin::new(socket).poll_write(cx, buf),
+        }
     }
 
     fn poll_flush(
         self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<std::io::Result<()>> {
-        std::pin::Pin::new(&mut self.get_mut().socket).poll_flush(cx)
+        match self.get_mut() {
+            Self::Plain(socket) => std::pin::Pin::new(socket).poll_flush(cx),
+            #[cfg(feature = "tls")]
+            Self::Tls(socket) => std::pin::Pin::new(socket).poll_flush(cx),
+        }
     }
 
     fn poll_shutdown(
         self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<std::io::Result<()>> {
-        std::pin::Pin::new(&mut self.get_mut().socket).poll_shutdown(cx)
+        match self.get_mut() {
+            Self::Plain(socket) => std::pin::Pin::new(socket).poll_shutdown(cx),
+            #[cfg(feature = "tls")]
+            Self::Tls(socket) => std::pin::Pin::new(socket).poll_shutdown(cx),
+        }
     }
 }
 
@@ -318,12 +1774,106 @@ impl AsyncRead for DataConnection {
         cx: &mut std::task::Context<'_>,
         buf: &mut tokio::io::ReadBuf<'_>,
     ) -> std::task::Poll<std::io::Result<()>> {
-        std::pin::Pin::new(&mut self.get_mut().socket).poll_read(cx, buf)
+        match self.get_mut() {
+            Self::Plain(socket) => std::pin::Pin::new(socket).poll_read(cx, buf),
+            #[cfg(feature = "tls")]
+            Self::Tls(socket) => std::pin::Pin::new(socket).poll_read(cx, buf),
+        }
     }
 }
 
 impl From<TcpStream> for DataConnection {
     fn from(socket: TcpStream) -> Self {
-        Self { socket }
+        Self::Plain(socket)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A session at `root`, served from the local filesystem
+    fn session(root: &Path) -> InnerConnection {
+        let config = Config {
+            root: Some(root.to_path_buf()),
+            ..Config::default()
+        };
+        let context = ServerContext::new(config, SiteCommands::default(), Arc::new(Local));
+        InnerConnection::new(
+            SocketAddr::from(([127, 0, 0, 1], 50000)),
+            root.to_path_buf(),
+            CancellationToken::new(),
+            Arc::new(context.unwrap()),
+        )
+    }
+
+    #[test]
+    fn parent_directories_stop_at_the_root() {
+        let root = tempfile::tempdir().unwrap();
+        let session = session(root.path());
+
+        let resolved = session.resolve_path("../../etc/passwd").unwrap();
+        assert_eq!(resolved, root.path().join("etc/passwd"));
+        assert_eq!(session.resolve_path("..").unwrap(), root.path());
+    }
+
+    #[test]
+    fn absolute_paths_start_at_the_root() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir(root.path().join("docs")).unwrap();
+        let mut session = session(root.path());
+        session.cwd = root.path().join("docs");
+
+        let resolved = session.resolve_path("/etc/passwd").unwrap();
+        assert_eq!(resolved, root.path().join("etc/passwd"));
+        assert_eq!(session.resolve_path("/").unwrap(), root.path());
+    }
+
+    #[test]
+    fn relative_paths_follow_the_working_directory() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir(root.path().join("docs")).unwrap();
+        let mut session = session(root.path());
+
+        session.cwd = session.resolve_path("docs").unwrap();
+        assert_eq!(
+            session.resolve_path("guide.txt").unwrap(),
+            root.path().join("docs/guide.txt")
+        );
+        // Like `CWD ../..`, which stops at the root
+        session.cwd = session.resolve_path("../..").unwrap();
+        assert_eq!(session.cwd, root.path());
+        assert_eq!(
+            session.resolve_path("readme.txt").unwrap(),
+            root.path().join("readme.txt")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn links_out_of_the_root_are_refused() {
+        let root = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        std::fs::write(outside.path().join("secret.txt"), "secret").unwrap();
+        std::os::unix::fs::symlink(outside.path(), root.path().join("out")).unwrap();
+        let session = session(root.path());
+
+        assert!(session.resolve_path("out").is_err());
+        assert!(session.resolve_path("out/secret.txt").is_err());
+        // Even to create a file through it
+        assert!(session.resolve_path("out/new.txt").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn links_within_the_root_are_followed() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir(root.path().join("docs")).unwrap();
+        std::fs::write(root.path().join("docs/guide.txt"), "guide").unwrap();
+        std::os::unix::fs::symlink(root.path().join("docs"), root.path().join("link")).unwrap();
+        let session = session(root.path());
+
+        let resolved = session.resolve_path("link/guide.txt").unwrap();
+        assert_eq!(resolved, root.path().join("link/guide.txt"));
     }
 }
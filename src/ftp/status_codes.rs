@@ -1,12 +1,14 @@
-use std::net::Ipv4Addr;
+use std::{fmt, net::Ipv4Addr};
 
+use crate::checksum::HashAlgorithm;
+use crate::config::Permission;
 use crate::types::SystemType;
 
 /// Status codes for FTP
 ///
 /// # Example
 /// ```
-/// use ftp::StatusCode;
+/// use ftp_server::StatusCode;
 ///
 /// let status_code = StatusCode::Ok;
 /// assert_eq!(status_code.code(), 200);
@@ -36,6 +38,9 @@ pub enum StatusCode {
     /// **200** - Ok
     Ok,
 
+    /// **200** - Ok, with a command specific message.
+    CommandOk(String),
+
     /// **202** - Command not implemented, superfluous at this site.
     SuperfluousCmdNotImplemented,
 
@@ -46,7 +51,7 @@ pub enum StatusCode {
     DirectoryStatus,
 
     /// **213** - File status.
-    FileStatus,
+    FileStatus(String),
 
     /// **214** - Help message.
     HelpMsg { message: String },
@@ -67,16 +72,39 @@ pub enum StatusCode {
     /// **226** - Closing data connection.
     ClosingDataConnection,
 
+    /// **226** - Closing data connection, with the checksum of the data
+    /// transferred.
+    TransferChecksum {
+        algorithm: HashAlgorithm,
+        digest: String,
+    },
+
     /// **227** - Entering Passive Mode (h1,h2,h3,h4,p1,p2).
+    ///
+    /// The `token`, if any, is appended as a comment and must be
+    /// sent by the client as the first line of the data connection.
     EnteringPassiveMode {
         ip_address: Ipv4Addr,
         port_high: u16,
         port_low: u16,
+        token: Option<String>,
     },
 
+    /// **229** - Entering Extended Passive Mode (|||port|).
+    ///
+    /// The `token`, if any, is appended like in
+    /// [`StatusCode::EnteringPassiveMode`].
+    EnteringExtendedPassiveMode { port: u16, token: Option<String> },
+
     /// **230** - User logged in, proceed.
     UserLoggedIn,
 
+    /// **232** - User logged in, authorized by security data exchange.
+    UserLoggedInByCertificate,
+
+    /// **234** - Security data exchange complete.
+    SecurityDataExchangeComplete,
+
     /// **250** - Requested file action okay, completed.
     FileActionOk(String),
 
@@ -92,9 +120,15 @@ pub enum StatusCode {
     /// **350** - Requested file action pending further information.
     FileActionPending,
 
+    /// **350** - Restarting at the given position.
+    Restarting(String),
+
     /// **421** - Service not available, closing control connection.
     Unnavaidable,
 
+    /// **421** - Closing control connection, for the given reason.
+    Closing(String),
+
     /// **425** - Can't open data connection.
     CantOpenDataConnection,
 
@@ -113,21 +147,45 @@ pub enum StatusCode {
     /// **500** - Syntax error, command unrecognized.
     SyntaxError,
 
+    /// **500** - Command line too long.
+    CommandTooLong,
+
+    /// **501** - Syntax error in parameters or arguments.
+    SyntaxErrorParams,
+
     /// **502** - Command not implemented.
     CmdNotImplemented,
 
+    /// **502** - Command not implemented, because it's disabled.
+    CmdDisabled(String),
+
     /// **503** - Bad sequence of commands.
     CmdBadSequence,
 
     /// **504** - Command not implemented for that parameter.
     CmdNotImplementedParam,
 
+    /// **522** - Network protocol not supported.
+    NetworkProtocolNotSupported,
+
     /// **530** - Not logged in.
     UserNotLoggedIn,
 
+    /// **530** - Not logged in, because the control connection isn't secured.
+    TlsRequired,
+
     /// **532** - Need account for storing files.
     NeedAccountForStore,
 
+    /// **533** - Command protection level denied for policy reasons.
+    ProtectionLevelDenied,
+
+    /// **534** - Request denied for policy reasons.
+    PolicyDenied,
+
+    /// **536** - Requested PROT level not supported by mechanism.
+    ProtectionLevelNotSupported,
+
     /// **550** - Requested action not taken.
     ActionNotTaken,
 
@@ -139,6 +197,97 @@ pub enum StatusCode {
 
     /// **553** - File name not allowed.
     FilenameNotAllowed,
+
+    /// **554** - Requested action not taken: invalid REST parameter.
+    InvalidRestart,
+
+    /// **450**, **452**, **550** or **552** - An operation refused
+    /// by the server, with the reason why.
+    Denied(Denial),
+}
+
+/// Why an operation was refused
+///
+/// Sent in the reply text if `replies.details` is enabled, so that users
+/// know what to fix. Otherwise [`Denial::bare`] is sent instead.
+#[derive(Debug, Clone)]
+pub enum Denial {
+    /// The file system refused access to `path`
+    Permission { path: String },
+
+    /// The disk is full, or can't fit the `requested` bytes
+    NoSpace { requested: Option<u64> },
+
+    /// Only administrators may run `command`
+    AdminOnly { command: String },
+
+    /// `path` leads out of the root of the session
+    OutsideRoot { path: String },
+
+    /// `command` needs a `permission` the user doesn't have
+    NotPermitted {
+        command: String,
+        permission: Permission,
+    },
+
+    /// The upload would take the user past their `quota` in bytes
+    Quota { quota: u64 },
+
+    /// The file name `name` breaks the `rule` of the filename policy
+    Filename { name: String, rule: String },
+
+    /// The uploaded file would take more than `limit` bytes
+    UploadTooLarge { limit: u64 },
+}
+
+impl Denial {
+    pub fn code(&self) -> u16 {
+        match self {
+            Denial::Filename { .. } => 553,
+            Denial::Permission { .. }
+            | Denial::AdminOnly { .. }
+            | Denial::OutsideRoot { .. }
+            | Denial::NotPermitted { .. } => 550,
+            Denial::NoSpace { .. } => 452,
+            Denial::Quota { .. } | Denial::UploadTooLarge { .. } => 552,
+        }
+    }
+
+    /// The reply without the reason
+    pub fn bare(&self) -> StatusCode {
+        match self {
+            Denial::Filename { .. } => StatusCode::FilenameNotAllowed,
+            Denial::Permission { .. }
+            | Denial::AdminOnly { .. }
+            | Denial::OutsideRoot { .. }
+            | Denial::NotPermitted { .. } => StatusCode::ActionNotTaken,
+            Denial::NoSpace { .. } => StatusCode::InsufficientStorage,
+            Denial::Quota { .. } | Denial::UploadTooLarge { .. } => {
+                StatusCode::ExceededStorageAllocation
+            }
+        }
+    }
+}
+
+impl fmt::Display for Denial {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Denial::Permission { path } => write!(f, "Permission denied: {path}"),
+            Denial::NoSpace {
+                requested: Some(requested),
+            } => write!(f, "Not enough disk space for {requested} bytes"),
+            Denial::NoSpace { requested: None } => write!(f, "No disk space left"),
+            Denial::AdminOnly { command } => write!(f, "Only administrators can use {command}"),
+            Denial::OutsideRoot { path } => write!(f, "{path} is outside of the served directory"),
+            Denial::NotPermitted {
+                command,
+                permission,
+            } => write!(f, "{command} needs the {permission} permission"),
+            Denial::Quota { quota } => write!(f, "Exceeded the quota of {quota} bytes"),
+            Denial::Filename { name, rule } => write!(f, "File name {name:?} {rule}"),
+            Denial::UploadTooLarge { limit } => write!(f, "Files may take at most {limit} bytes"),
+        }
+    }
 }
 
 impl StatusCode {
@@ -150,43 +299,61 @@ impl StatusCode {
             StatusCode::DataOpenTransfer => 125,
             StatusCode::FileStatusOk(_) => 150,
             StatusCode::Ok => 200,
+            StatusCode::CommandOk(_) => 200,
             StatusCode::SuperfluousCmdNotImplemented => 202,
             StatusCode::SystemStatus(_) => 211,
             StatusCode::DirectoryStatus => 212,
-            StatusCode::FileStatus => 213,
+            StatusCode::FileStatus(_) => 213,
             StatusCode::HelpMsg { message: _ } => 214,
             StatusCode::SystemType(_) => 215,
             StatusCode::ServiceReadyUser => 220,
             StatusCode::ServiceClosingControlConnection => 221,
             StatusCode::DataOpenNoTransfer => 225,
             StatusCode::ClosingDataConnection => 226,
+            StatusCode::TransferChecksum { .. } => 226,
             StatusCode::EnteringPassiveMode {
                 ip_address: _,
                 port_high: _,
                 port_low: _,
+                token: _,
             } => 227,
+            StatusCode::EnteringExtendedPassiveMode { port: _, token: _ } => 229,
             StatusCode::UserLoggedIn => 230,
+            StatusCode::UserLoggedInByCertificate => 232,
+            StatusCode::SecurityDataExchangeComplete => 234,
             StatusCode::FileActionOk(_) => 250,
             StatusCode::PathCreated(_) => 257,
             StatusCode::UsernameOkNeedPassword => 331,
             StatusCode::NeedLoginAccount => 332,
             StatusCode::FileActionPending => 350,
+            StatusCode::Restarting(_) => 350,
             StatusCode::Unnavaidable => 421,
+            StatusCode::Closing(_) => 421,
             StatusCode::CantOpenDataConnection => 425,
             StatusCode::TransferAborted => 426,
             StatusCode::FileActionNotTaken => 450,
             StatusCode::ActionAbortedLocal => 451,
             StatusCode::InsufficientStorage => 452,
             StatusCode::SyntaxError => 500,
+            StatusCode::CommandTooLong => 500,
+            StatusCode::SyntaxErrorParams => 501,
             StatusCode::CmdNotImplemented => 502,
+            StatusCode::CmdDisabled(_) => 502,
             StatusCode::CmdBadSequence => 503,
             StatusCode::CmdNotImplementedParam => 504,
+            StatusCode::NetworkProtocolNotSupported => 522,
             StatusCode::UserNotLoggedIn => 530,
+            StatusCode::TlsRequired => 530,
             StatusCode::NeedAccountForStore => 532,
+            StatusCode::ProtectionLevelDenied => 533,
+            StatusCode::PolicyDenied => 534,
+            StatusCode::ProtectionLevelNotSupported => 536,
             StatusCode::ActionNotTaken => 550,
             StatusCode::ActionAbortedPageTypeUnknown => 551,
             StatusCode::ExceededStorageAllocation => 552,
             StatusCode::FilenameNotAllowed => 553,
+            StatusCode::InvalidRestart => 554,
+            StatusCode::Denied(denial) => denial.code(),
         }
     }
 }
@@ -201,7 +368,7 @@ impl StatusCode {
     /// Convert the status code to a byte array
     /// ## Usage
     /// ```
-    /// use ftp::StatusCode;
+    /// use ftp_server::StatusCode;
     ///
     /// let status_code = StatusCode::Ok;
     /// let byte_array = status_code.to_byte_array();
@@ -222,13 +389,17 @@ impl ToString for StatusCode {
             ),
             StatusCode::FileStatusOk(msg) => format!("{}{msg}\n", self.code()),
             StatusCode::Ok => format!("{} Ok\n", self.code()),
-            StatusCode::SuperfluousCmdNotImplemented => todo!(),
+            StatusCode::CommandOk(msg) => format!("{}{msg}\n", self.code()),
+            StatusCode::SuperfluousCmdNotImplemented => format!(
+                "{} Command not implemented, superfluous at this site\n",
+                self.code()
+            ),
             StatusCode::SystemStatus(status) => {
                 format!("{code}{status} \n{code} END\n", code = self.code())
             }
             StatusCode::DirectoryStatus => todo!(),
-            StatusCode::FileStatus => todo!(),
-            StatusCode::HelpMsg { message } => format!("{} {}\n", self.code(), message),
+            StatusCode::FileStatus(status) => format!("{}{status}\n", self.code()),
+            StatusCode::HelpMsg { message } => format!("{}{message}\n", self.code()),
             StatusCode::SystemType(system_type) => {
                 format!("{} {}\n", self.code(), system_type.to_string())
             }
@@ -240,24 +411,52 @@ impl ToString for StatusCode {
             StatusCode::ClosingDataConnection => {
                 format!("{} Closing data connection\n", self.code())
             }
+            StatusCode::TransferChecksum { algorithm, digest } => {
+                let name = algorithm.name();
+                format!("{} Closing data connection, {name} {digest}\n", self.code())
+            }
             StatusCode::EnteringPassiveMode {
                 ip_address,
                 port_high,
                 port_low,
+                token,
             } => {
                 let octets = ip_address.octets();
+                let token = token
+                    .as_ref()
+                    .map(|token| format!(" token={token}"))
+                    .unwrap_or_default();
                 format!(
-                    "{} Entering Passive Mode ({}, {}, {}, {}, {}, {})\n",
+                    "{} Entering Passive Mode ({}, {}, {}, {}, {}, {}){}\n",
                     self.code(),
                     octets[0],
                     octets[1],
                     octets[2],
                     octets[3],
                     port_high,
-                    port_low
+                    port_low,
+                    token
+                )
+            }
+            StatusCode::EnteringExtendedPassiveMode { port, token } => {
+                let token = token
+                    .as_ref()
+                    .map(|token| format!(" token={token}"))
+                    .unwrap_or_default();
+                format!(
+                    "{} Entering Extended Passive Mode (|||{}|){}\n",
+                    self.code(),
+                    port,
+                    token
                 )
             }
             StatusCode::UserLoggedIn => "230 User logged in, proceed\n".to_string(),
+            StatusCode::UserLoggedInByCertificate => {
+                "232 User logged in, authorized by security data exchange\n".to_string()
+            }
+            StatusCode::SecurityDataExchangeComplete => {
+                format!("{} Security data exchange complete\n", self.code())
+            }
             StatusCode::FileActionOk(msg) => {
                 format!("{}{msg}\n", self.code())
             }
@@ -272,26 +471,82 @@ impl ToString for StatusCode {
                 "{} Requested file action pending further information\n",
                 self.code()
             ),
-            StatusCode::Unnavaidable => todo!(),
+            StatusCode::Restarting(msg) => format!("{}{msg}\n", self.code()),
+            StatusCode::Unnavaidable => format!(
+                "{} Service not available, closing control connection\n",
+                self.code()
+            ),
+            StatusCode::Closing(reason) => {
+                format!("{}{reason}, closing control connection\n", self.code())
+            }
             StatusCode::CantOpenDataConnection => {
                 format!("{} Can't open data connection\n", self.code())
             }
-            StatusCode::TransferAborted => todo!(),
+            StatusCode::TransferAborted => {
+                format!("{} Connection closed; transfer aborted\n", self.code())
+            }
             StatusCode::FileActionNotTaken => {
                 format!("{} Requested file action not taken\n", self.code())
             }
-            StatusCode::ActionAbortedLocal => todo!(),
-            StatusCode::InsufficientStorage => todo!(),
-            StatusCode::SyntaxError => todo!(),
+            StatusCode::ActionAbortedLocal => format!(
+                "{} Requested action aborted: local error in processing\n",
+                self.code()
+            ),
+            StatusCode::InsufficientStorage => format!(
+                "{} Requested action not taken, insufficient storage space\n",
+                self.code()
+            ),
+            StatusCode::SyntaxError => {
+                format!("{} Syntax error, command unrecognized\n", self.code())
+            }
+            StatusCode::CommandTooLong => format!("{} Command line too long\n", self.code()),
+            StatusCode::SyntaxErrorParams => {
+                format!("{} Syntax error in parameters or arguments\n", self.code())
+            }
             StatusCode::CmdNotImplemented => format!("{} Command not implemented\n", self.code()),
-            StatusCode::CmdBadSequence => todo!(),
-            StatusCode::CmdNotImplementedParam => todo!(),
-            StatusCode::UserNotLoggedIn => todo!(),
+            StatusCode::CmdDisabled(reason) => format!("{}{reason}\n", self.code()),
+            StatusCode::CmdBadSequence => format!("{} Bad sequence of commands\n", self.code()),
+            StatusCode::CmdNotImplementedParam => format!(
+                "{} Command not implemented for that parameter\n",
+                self.code()
+            ),
+            StatusCode::NetworkProtocolNotSupported => {
+                format!("{} Network protocol not supported, use (1)\n", self.code())
+            }
+            StatusCode::UserNotLoggedIn => format!("{} Not logged in\n", self.code()),
+            StatusCode::TlsRequired => format!(
+                "{} TLS is required, use AUTH TLS before logging in\n",
+                self.code()
+            ),
             StatusCode::NeedAccountForStore => todo!(),
-            StatusCode::ActionNotTaken => todo!(),
+            StatusCode::ProtectionLevelDenied => format!(
+                "{} Command protection level denied for policy reasons\n",
+                self.code()
+            ),
+            StatusCode::PolicyDenied => {
+                format!("{} Request denied for policy reasons\n", self.code())
+            }
+            StatusCode::ProtectionLevelNotSupported => format!(
+                "{} Requested PROT level not supported by mechanism\n",
+                self.code()
+            ),
+            StatusCode::ActionNotTaken => format!("{} Requested action not taken\n", self.code()),
             StatusCode::ActionAbortedPageTypeUnknown => todo!(),
-            StatusCode::ExceededStorageAllocation => todo!(),
-            StatusCode::FilenameNotAllowed => todo!(),
+            StatusCode::ExceededStorageAllocation => format!(
+                "{} Requested file action aborted, exceeded storage allocation\n",
+                self.code()
+            ),
+            StatusCode::FilenameNotAllowed => {
+                format!(
+                    "{} Requested action not taken, file name not allowed\n",
+                    self.code()
+                )
+            }
+            StatusCode::InvalidRestart => format!(
+                "{} Requested action not taken: invalid REST parameter\n",
+                self.code()
+            ),
+            StatusCode::Denied(denial) => format!("{} {denial}\n", self.code()),
         }
     }
 }
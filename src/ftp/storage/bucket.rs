@@ -0,0 +1,264 @@
+//! Files kept in an object store, like an S3, Google Cloud Storage or
+//! Azure bucket.
+//!
+//! Objects are files, and the prefixes of their keys up to a `/` are the
+//! directories they're in. The root of every session is a prefix beneath
+//! the one of the bucket URL, where its directory would be beneath the
+//! served root.
+//!
+//! Uploads are streamed, in parts once they're large, and downloads read
+//! from the restart offset. Objects can only be written whole, so
+//! appending and restarting uploads isn't possible.
+
+use std::{
+    collections::BTreeMap,
+    io,
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::SystemTime,
+};
+
+use miette::*;
+use object_store::{
+    aws::AmazonS3Builder, azure::MicrosoftAzureBuilder, buffered::BufWriter,
+    gcp::GoogleCloudStorageBuilder, memory::InMemory, path::Path as Key, GetOptions, GetRange,
+    ObjectMeta, ObjectStore, ObjectStoreScheme,
+};
+use tokio_util::io::StreamReader;
+use url::Url;
+
+use super::{
+    directory, DirEntry, FileKind, FileReader, FileWriter, Metadata, Storage, StorageBackend,
+    StorageFuture, WriteMode,
+};
+use crate::root_dir::relative_to;
+
+/// A bucket, opened on the prefix every session is confined to
+#[derive(Debug, Clone)]
+pub struct Bucket {
+    store: Arc<dyn ObjectStore>,
+    prefix: Key,
+    /// The served root, which the roots of the sessions are relative to
+    root: PathBuf,
+}
+
+impl Bucket {
+    /// Connects to the bucket at `url`, like `s3://bucket/prefix`, with
+    /// the `options` of its store, taken from the environment otherwise.
+    pub fn open(url: &str, options: &BTreeMap<String, String>, root: &Path) -> Result<Self> {
+        let url = Url::parse(url)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Invalid storage URL {url}"))?;
+        let (scheme, prefix) = ObjectStoreScheme::parse(&url)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Unsupported storage URL {url}"))?;
+        let store = connect(scheme, &url, options)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Could not open the bucket {url}"))?;
+        let Some(store) = store else {
+            bail!("Unsupported storage URL {url}, only buckets can be served");
+        };
+        Ok(Self {
+            store,
+            prefix,
+            root: root.to_path_buf(),
+        })
+    }
+}
+
+impl Storage for Bucket {
+    fn open(&self, root: &Path) -> io::Result<Arc<dyn StorageBackend>> {
+        Ok(Arc::new(BucketStorage {
+            store: self.store.clone(),
+            prefix: child(&self.prefix, &relative_to(root, &self.root)),
+        }))
+    }
+}
+
+/// The store of the bucket at `url`, if it's one of a bucket.
+fn connect(
+    scheme: ObjectStoreScheme,
+    url: &Url,
+    options: &BTreeMap<String, String>,
+) -> object_store::Result<Option<Arc<dyn ObjectStore>>> {
+    let store: Arc<dyn ObjectStore> = match scheme {
+        ObjectStoreScheme::AmazonS3 => {
+            let builder = AmazonS3Builder::from_env().with_url(url.as_str());
+            Arc::new(configure(builder, options, AmazonS3Builder::with_config)?.build()?)
+        }
+        ObjectStoreScheme::GoogleCloudStorage => {
+            let builder = GoogleCloudStorageBuilder::from_env().with_url(url.as_str());
+            let builder = configure(builder, options, GoogleCloudStorageBuilder::with_config)?;
+            Arc::new(builder.build()?)
+        }
+        ObjectStoreScheme::MicrosoftAzure => {
+            let builder = MicrosoftAzureBuilder::from_env().with_url(url.as_str());
+            let builder = configure(builder, options, MicrosoftAzureBuilder::with_config)?;
+            Arc::new(builder.build()?)
+        }
+        // Empty on every start, to try things out
+        ObjectStoreScheme::Memory => Arc::new(InMemory::new()),
+        _ => return Ok(None),
+    };
+    Ok(Some(store))
+}
+
+/// Applies the `options` of a store to its `builder`.
+fn configure<B, K>(
+    builder: B,
+    options: &BTreeMap<String, String>,
+    with_config: fn(B, K, String) -> B,
+) -> object_store::Result<B>
+where
+    K: FromStr<Err = object_store::Error>,
+{
+    options.iter().try_fold(builder, |builder, (key, value)| {
+        Ok(with_config(builder, key.parse()?, value.clone()))
+    })
+}
+
+/// The objects beneath a prefix of a bucket
+#[derive(Debug, Clone)]
+pub struct BucketStorage {
+    store: Arc<dyn ObjectStore>,
+    prefix: Key,
+}
+
+impl BucketStorage {
+    fn key(&self, path: &Path) -> Key {
+        child(&self.prefix, path)
+    }
+
+    /// The metadata of `key`, a directory if objects are beneath it.
+    async fn stat(&self, key: &Key) -> io::Result<Metadata> {
+        if *key == self.prefix {
+            return Ok(directory(None));
+        }
+        match self.store.head(key).await {
+            Ok(object) => return Ok(file(&object)),
+            Err(object_store::Error::NotFound { .. }) => {}
+            Err(error) => return Err(error.into()),
+        }
+        let listed = self.store.list_with_delimiter(Some(key)).await?;
+        if listed.objects.is_empty() && listed.common_prefixes.is_empty() {
+            let error = format!("{key} is not in the bucket");
+            return Err(io::Error::new(io::ErrorKind::NotFound, error));
+        }
+        let modified = listed
+            .objects
+            .iter()
+            .map(|object| object.last_modified)
+            .max();
+        Ok(directory(modified.map(SystemTime::from)))
+    }
+}
+
+impl StorageBackend for BucketStorage {
+    fn metadata<'a>(&'a self, path: &'a Path) -> StorageFuture<'a, Metadata> {
+        Box::pin(async move { self.stat(&self.key(path)).await })
+    }
+
+    fn symlink_metadata<'a>(&'a self, path: &'a Path) -> StorageFuture<'a, Metadata> {
+        // Buckets have no links
+        self.metadata(path)
+    }
+
+    fn list<'a>(&'a self, path: &'a Path) -> StorageFuture<'a, Vec<DirEntry>> {
+        Box::pin(async move {
+            let key = self.key(path);
+            let listed = self.store.list_with_delimiter(Some(&key)).await?;
+            let directories = listed.common_prefixes.iter().filter_map(|prefix| {
+                Some(DirEntry {
+                    name: prefix.filename()?.to_string(),
+                    metadata: directory(None),
+                })
+            });
+            let files = listed.objects.iter().filter_map(|object| {
+                Some(DirEntry {
+                    name: object.location.filename()?.to_string(),
+                    metadata: file(object),
+                })
+            });
+            Ok(directories.chain(files).collect())
+        })
+    }
+
+    fn open_read<'a>(&'a self, path: &'a Path, offset: u64) -> StorageFuture<'a, FileReader> {
+        Box::pin(async move {
+            let key = self.key(path);
+            let length = self.store.head(&key).await?.size as u64;
+            if offset > length {
+                let error = format!("offset {} is past the length {}", offset, length);
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, error));
+            }
+            // A range starting at the end is refused by the store
+            if offset == length && offset > 0 {
+                return Ok(Box::pin(tokio::io::empty()) as FileReader);
+            }
+            let offset = usize::try_from(offset).map_err(io::Error::other)?;
+            let options = GetOptions {
+                range: (offset > 0).then_some(GetRange::Offset(offset)),
+                ..Default::default()
+            };
+            let object = self.store.get_opts(&key, options).await?;
+            Ok(Box::pin(StreamReader::new(object.into_stream())) as FileReader)
+        })
+    }
+
+    fn open_write<'a>(&'a self, path: &'a Path, mode: WriteMode) -> StorageFuture<'a, FileWriter> {
+        Box::pin(async move {
+            if mode != WriteMode::Truncate {
+                let error = "objects can only be written whole";
+                return Err(io::Error::new(io::ErrorKind::Unsupported, error));
+            }
+            // Stored once it's shut down, in parts if it's large
+            let writer = BufWriter::new(self.store.clone(), self.key(path));
+            Ok(Box::pin(writer) as FileWriter)
+        })
+    }
+
+    fn remove<'a>(&'a self, path: &'a Path) -> StorageFuture<'a, ()> {
+        Box::pin(async move { Ok(self.store.delete(&self.key(path)).await?) })
+    }
+
+    fn rename<'a>(&'a self, from: &'a Path, to: &'a Path) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let (from, to) = (self.key(from), self.key(to));
+            Ok(self.store.rename(&from, &to).await?)
+        })
+    }
+
+    fn set_modified<'a>(&'a self, _path: &'a Path, _time: SystemTime) -> StorageFuture<'a, ()> {
+        Box::pin(async {
+            let error = "objects are modified when they're written";
+            Err(io::Error::new(io::ErrorKind::Unsupported, error))
+        })
+    }
+
+    fn stores_on_shutdown(&self, _path: &Path) -> bool {
+        true
+    }
+}
+
+/// The key of `path` beneath `prefix`, `.` being the prefix itself.
+fn child(prefix: &Key, path: &Path) -> Key {
+    path.components()
+        .fold(prefix.clone(), |key, component| match component {
+            Component::Normal(name) => key.child(name.to_string_lossy().as_ref()),
+            _ => key,
+        })
+}
+
+fn file(object: &ObjectMeta) -> Metadata {
+    Metadata {
+        kind: FileKind::File,
+        len: object.size as u64,
+        modified: object.last_modified.into(),
+        mode: 0o644,
+        nlink: 1,
+        uid: 0,
+        gid: 0,
+        link: None,
+    }
+}
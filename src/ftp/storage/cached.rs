@@ -0,0 +1,264 @@
+//! Listings of any storage kept for a while.
+
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    io,
+    path::{Component, Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant, SystemTime},
+};
+
+use tokio::io::AsyncWrite;
+use tracing::*;
+
+use super::{
+    DirEntry, FileReader, FileWriter, Metadata, Storage, StorageBackend, StorageFuture, WriteMode,
+};
+use crate::root_dir::RootDir;
+
+/// When a directory was listed, and its entries
+type Listing = (Instant, Arc<Vec<DirEntry>>);
+
+/// The directories listed lately by every session, by their path
+#[derive(Debug, Default)]
+struct ListingCache {
+    directories: Mutex<HashMap<PathBuf, Listing>>,
+    /// Bumped for the paths changed, by their hash, so listings read while
+    /// their directory or one above it changes aren't kept
+    generations: [AtomicU64; 32],
+}
+
+impl ListingCache {
+    fn slot(&self, path: &Path) -> &AtomicU64 {
+        let mut hasher = DefaultHasher::new();
+        path.hash(&mut hasher);
+        &self.generations[hasher.finish() as usize % self.generations.len()]
+    }
+
+    /// The changes made so far to `directory` and the ones above it
+    fn generation(&self, directory: &Path) -> u64 {
+        directory.ancestors().fold(0, |generation, path| {
+            generation.wrapping_add(self.slot(path).load(Ordering::Acquire))
+        })
+    }
+
+    fn get(&self, directory: &Path, lifetime: Duration) -> Option<Arc<Vec<DirEntry>>> {
+        let directories = self.directories.lock().ok()?;
+        let (listed, entries) = directories.get(directory)?;
+        (listed.elapsed() < lifetime).then(|| entries.clone())
+    }
+
+    /// Keeps the entries of `directory`, read since `generation`, unless
+    /// anything changed meanwhile.
+    fn insert(
+        &self,
+        directory: PathBuf,
+        entries: Arc<Vec<DirEntry>>,
+        lifetime: Duration,
+        generation: u64,
+    ) {
+        if let Ok(mut directories) = self.directories.lock() {
+            // Checked under the lock, which changes are forgotten under
+            if self.generation(&directory) != generation {
+                trace!("Not caching {:?}, changed while listed", directory);
+                return;
+            }
+            directories.retain(|_, (listed, _)| listed.elapsed() < lifetime);
+            directories.insert(directory, (Instant::now(), entries));
+        }
+    }
+
+    /// Forgets `path`, if it's a directory, everything listed beneath it
+    /// and the directory holding it.
+    fn invalidate(&self, path: &Path) {
+        if let Ok(mut directories) = self.directories.lock() {
+            self.slot(path).fetch_add(1, Ordering::AcqRel);
+            if let Some(parent) = path.parent() {
+                self.slot(parent).fetch_add(1, Ordering::AcqRel);
+            }
+            directories.retain(|directory, _| {
+                Some(directory.as_path()) != path.parent() && !directory.starts_with(path)
+            });
+        }
+    }
+}
+
+/// Opens the storage of the sessions with `storage`, keeping the entries of
+/// the directories they list for `lifetime`
+#[derive(Debug, Clone)]
+pub struct Cached {
+    storage: Arc<dyn Storage>,
+    lifetime: Duration,
+    cache: Arc<ListingCache>,
+}
+
+impl Cached {
+    pub fn new(storage: Arc<dyn Storage>, lifetime: Duration) -> Self {
+        Self {
+            storage,
+            lifetime,
+            cache: Default::default(),
+        }
+    }
+}
+
+impl Storage for Cached {
+    fn open(&self, root: &Path) -> io::Result<Arc<dyn StorageBackend>> {
+        Ok(Arc::new(CachedStorage {
+            storage: self.storage.open(root)?,
+            root: root.to_path_buf(),
+            lifetime: self.lifetime,
+            cache: self.cache.clone(),
+        }))
+    }
+}
+
+/// A storage whose listings are served from those of the last `lifetime`,
+/// shared with every session, and forgotten when the directory is changed
+/// through any of them
+#[derive(Debug, Clone)]
+pub struct CachedStorage {
+    storage: Arc<dyn StorageBackend>,
+    /// The root of the session, which the cache is keyed beneath
+    root: PathBuf,
+    lifetime: Duration,
+    cache: Arc<ListingCache>,
+}
+
+impl CachedStorage {
+    /// The key of the directory at `path`, the same for every session
+    /// whatever its root.
+    fn key(&self, path: &Path) -> PathBuf {
+        let mut key = self.root.clone();
+        for component in path.components() {
+            match component {
+                Component::Normal(name) => key.push(name),
+                Component::ParentDir => {
+                    key.pop();
+                }
+                _ => {}
+            }
+        }
+        key
+    }
+
+    /// Forgets the listings `path` is in, or that are of it.
+    fn changed(&self, path: &Path) {
+        self.cache.invalidate(&self.key(path));
+    }
+}
+
+impl StorageBackend for CachedStorage {
+    fn metadata<'a>(&'a self, path: &'a Path) -> StorageFuture<'a, Metadata> {
+        self.storage.metadata(path)
+    }
+
+    fn symlink_metadata<'a>(&'a self, path: &'a Path) -> StorageFuture<'a, Metadata> {
+        self.storage.symlink_metadata(path)
+    }
+
+    fn list<'a>(&'a self, path: &'a Path) -> StorageFuture<'a, Vec<DirEntry>> {
+        Box::pin(async move {
+            let key = self.key(path);
+            if let Some(entries) = self.cache.get(&key, self.lifetime) {
+                trace!("Listed {:?} from the cache", key);
+                return Ok(entries.as_ref().clone());
+            }
+            let generation = self.cache.generation(&key);
+            let entries = self.storage.list(path).await?;
+            self.cache
+                .insert(key, Arc::new(entries.clone()), self.lifetime, generation);
+            Ok(entries)
+        })
+    }
+
+    fn open_read<'a>(&'a self, path: &'a Path, offset: u64) -> StorageFuture<'a, FileReader> {
+        self.storage.open_read(path, offset)
+    }
+
+    fn open_write<'a>(&'a self, path: &'a Path, mode: WriteMode) -> StorageFuture<'a, FileWriter> {
+        Box::pin(async move {
+            let file = self.storage.open_write(path, mode).await;
+            self.changed(path);
+            let file = file?;
+            // Appends aren't renamed in place once written, and their size
+            // changes until then
+            Ok(Box::pin(Invalidating {
+                file,
+                path: self.key(path),
+                cache: self.cache.clone(),
+            }) as FileWriter)
+        })
+    }
+
+    fn remove<'a>(&'a self, path: &'a Path) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let removed = self.storage.remove(path).await;
+            self.changed(path);
+            removed
+        })
+    }
+
+    fn rename<'a>(&'a self, from: &'a Path, to: &'a Path) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let renamed = self.storage.rename(from, to).await;
+            self.changed(from);
+            self.changed(to);
+            renamed
+        })
+    }
+
+    fn set_modified<'a>(&'a self, path: &'a Path, time: SystemTime) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let set = self.storage.set_modified(path, time).await;
+            self.changed(path);
+            set
+        })
+    }
+
+    fn stores_on_shutdown(&self, path: &Path) -> bool {
+        self.storage.stores_on_shutdown(path)
+    }
+
+    /// The local directory, to keep checking links out of it
+    fn local(&self) -> Option<&RootDir> {
+        self.storage.local()
+    }
+}
+
+/// A file being written that forgets the listings it's in once it's shut
+/// down, for its final size and date
+struct Invalidating {
+    file: FileWriter,
+    /// The key of the file in the cache
+    path: PathBuf,
+    cache: Arc<ListingCache>,
+}
+
+impl AsyncWrite for Invalidating {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        context: &mut Context<'_>,
+        buffer: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.file.as_mut().poll_write(context, buffer)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.file.as_mut().poll_flush(context)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let shutdown = self.file.as_mut().poll_shutdown(context);
+        if shutdown.is_ready() {
+            self.cache.invalidate(&self.path);
+        }
+        shutdown
+    }
+}
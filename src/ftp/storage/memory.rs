@@ -0,0 +1,320 @@
+//! Files kept in memory, gone when the server stops.
+
+use std::{
+    collections::BTreeMap,
+    io,
+    path::{Component, Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex, MutexGuard},
+    task::{Context, Poll},
+    time::SystemTime,
+};
+
+use tokio::io::AsyncWrite;
+
+use super::{
+    directory, DirEntry, FileKind, FileReader, FileWriter, Metadata, Storage, StorageBackend,
+    StorageFuture, WriteMode,
+};
+
+/// A file or directory of the tree, by its path
+#[derive(Debug, Clone)]
+enum Node {
+    File {
+        contents: Arc<Vec<u8>>,
+        modified: SystemTime,
+    },
+    Directory {
+        modified: SystemTime,
+    },
+}
+
+impl Node {
+    fn metadata(&self) -> Metadata {
+        match self {
+            Self::File { contents, modified } => Metadata {
+                kind: FileKind::File,
+                len: contents.len() as u64,
+                modified: *modified,
+                mode: 0o644,
+                nlink: 1,
+                uid: 0,
+                gid: 0,
+                link: None,
+            },
+            Self::Directory { modified } => directory(Some(*modified)),
+        }
+    }
+}
+
+/// The files and directories, by their path without the root, which is
+/// always there
+type Tree = BTreeMap<PathBuf, Node>;
+
+/// Opens the same tree of files in memory on every root, seeded with
+/// [`Memory::with_file`]
+#[derive(Debug, Clone, Default)]
+pub struct Memory {
+    tree: Arc<Mutex<Tree>>,
+}
+
+impl Memory {
+    /// Adds a file at `path` holding `contents`, and the directories it's
+    /// in.
+    pub fn with_file(self, path: impl AsRef<Path>, contents: impl Into<Vec<u8>>) -> Self {
+        let path = key(path.as_ref());
+        let now = SystemTime::now();
+        if let Ok(mut tree) = self.tree.lock() {
+            for parent in path.ancestors().skip(1) {
+                if parent.as_os_str().is_empty() {
+                    break;
+                }
+                let modified = now;
+                tree.insert(parent.to_path_buf(), Node::Directory { modified });
+            }
+            let contents = Arc::new(contents.into());
+            tree.insert(
+                path,
+                Node::File {
+                    contents,
+                    modified: now,
+                },
+            );
+        }
+        self
+    }
+}
+
+impl Storage for Memory {
+    fn open(&self, _root: &Path) -> io::Result<Arc<dyn StorageBackend>> {
+        Ok(Arc::new(MemoryStorage {
+            tree: self.tree.clone(),
+        }))
+    }
+}
+
+/// The tree of a [`Memory`] storage, whatever the root of the session
+#[derive(Debug, Clone)]
+pub struct MemoryStorage {
+    tree: Arc<Mutex<Tree>>,
+}
+
+impl MemoryStorage {
+    fn tree(&self) -> io::Result<MutexGuard<'_, Tree>> {
+        self.tree.lock().map_err(|_| io::Error::other("poisoned"))
+    }
+
+    /// What's at `path`, the root being a directory.
+    fn node(tree: &Tree, path: &Path) -> io::Result<Node> {
+        if path.as_os_str().is_empty() {
+            let modified = SystemTime::UNIX_EPOCH;
+            return Ok(Node::Directory { modified });
+        }
+        tree.get(path).cloned().ok_or_else(not_found)
+    }
+
+    /// Fails unless the directory holding `path` exists.
+    fn check_parent(tree: &Tree, path: &Path) -> io::Result<()> {
+        let parent = path.parent().unwrap_or(Path::new(""));
+        match Self::node(tree, parent)? {
+            Node::Directory { .. } => Ok(()),
+            Node::File { .. } => Err(not_found()),
+        }
+    }
+
+    fn metadata_of(&self, path: &Path) -> io::Result<Metadata> {
+        let tree = self.tree()?;
+        Ok(Self::node(&tree, &key(path))?.metadata())
+    }
+}
+
+impl StorageBackend for MemoryStorage {
+    fn metadata<'a>(&'a self, path: &'a Path) -> StorageFuture<'a, Metadata> {
+        Box::pin(async move { self.metadata_of(path) })
+    }
+
+    fn symlink_metadata<'a>(&'a self, path: &'a Path) -> StorageFuture<'a, Metadata> {
+        Box::pin(async move { self.metadata_of(path) })
+    }
+
+    fn list<'a>(&'a self, path: &'a Path) -> StorageFuture<'a, Vec<DirEntry>> {
+        Box::pin(async move {
+            let path = key(path);
+            let tree = self.tree()?;
+            if let Node::File { .. } = Self::node(&tree, &path)? {
+                return Err(io::Error::other("not a directory"));
+            }
+            let entries = tree
+                .iter()
+                .filter(|(child, _)| child.parent() == Some(path.as_path()))
+                .map(|(child, node)| DirEntry {
+                    name: child
+                        .file_name()
+                        .unwrap_or_default()
+                        .to_string_lossy()
+                        .into_owned(),
+                    metadata: node.metadata(),
+                })
+                .collect();
+            Ok(entries)
+        })
+    }
+
+    fn open_read<'a>(&'a self, path: &'a Path, offset: u64) -> StorageFuture<'a, FileReader> {
+        Box::pin(async move {
+            let Node::File { contents, .. } = Self::node(&*self.tree()?, &key(path))? else {
+                return Err(io::Error::other("is a directory"));
+            };
+            let length = contents.len() as u64;
+            if offset > length {
+                let error = format!("offset {} is past the length {}", offset, length);
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, error));
+            }
+            let mut reader = io::Cursor::new(contents.as_ref().clone());
+            reader.set_position(offset);
+            Ok(Box::pin(reader) as FileReader)
+        })
+    }
+
+    fn open_write<'a>(&'a self, path: &'a Path, mode: WriteMode) -> StorageFuture<'a, FileWriter> {
+        Box::pin(async move {
+            let path = key(path);
+            let tree = self.tree()?;
+            Self::check_parent(&tree, &path)?;
+            let existing = match tree.get(&path) {
+                Some(Node::File { contents, .. }) => Some(contents.as_ref().clone()),
+                Some(Node::Directory { .. }) => {
+                    return Err(io::Error::other("is a directory"));
+                }
+                None => None,
+            };
+            let contents = match (mode, existing) {
+                (WriteMode::Truncate, _) => Vec::new(),
+                (WriteMode::Append, existing) => existing.unwrap_or_default(),
+                (WriteMode::At(_), None) => return Err(not_found()),
+                (WriteMode::At(offset), Some(mut existing)) => {
+                    if offset > existing.len() as u64 {
+                        let length = existing.len();
+                        let error = format!("offset {} is past the length {}", offset, length);
+                        return Err(io::Error::new(io::ErrorKind::InvalidInput, error));
+                    }
+                    existing.truncate(offset as usize);
+                    existing
+                }
+            };
+            Ok(Box::pin(MemoryWriter {
+                tree: self.tree.clone(),
+                path,
+                contents,
+            }) as FileWriter)
+        })
+    }
+
+    fn remove<'a>(&'a self, path: &'a Path) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let path = key(path);
+            let mut tree = self.tree()?;
+            if let Node::Directory { .. } = Self::node(&tree, &path)? {
+                let empty = !tree.keys().any(|child| child.parent() == Some(&path));
+                if path.as_os_str().is_empty() || !empty {
+                    let error = "the directory is not empty";
+                    return Err(io::Error::other(error));
+                }
+            }
+            tree.remove(&path);
+            Ok(())
+        })
+    }
+
+    fn rename<'a>(&'a self, from: &'a Path, to: &'a Path) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let (from, to) = (key(from), key(to));
+            let mut tree = self.tree()?;
+            if from.as_os_str().is_empty() || !tree.contains_key(&from) {
+                return Err(not_found());
+            }
+            Self::check_parent(&tree, &to)?;
+            // Everything beneath a directory moves with it
+            let moved: Vec<_> = tree
+                .keys()
+                .filter(|path| path.starts_with(&from))
+                .cloned()
+                .collect();
+            for path in moved {
+                if let Some(node) = tree.remove(&path) {
+                    let beneath = path.strip_prefix(&from).unwrap_or(Path::new(""));
+                    tree.insert(to.join(beneath), node);
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn set_modified<'a>(&'a self, path: &'a Path, time: SystemTime) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let mut tree = self.tree()?;
+            match tree.get_mut(&key(path)).ok_or_else(not_found)? {
+                Node::File { modified, .. } | Node::Directory { modified } => *modified = time,
+            }
+            Ok(())
+        })
+    }
+
+    fn stores_on_shutdown(&self, _path: &Path) -> bool {
+        true
+    }
+}
+
+/// A file being written, stored whole once it's shut down
+struct MemoryWriter {
+    tree: Arc<Mutex<Tree>>,
+    path: PathBuf,
+    contents: Vec<u8>,
+}
+
+impl AsyncWrite for MemoryWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _context: &mut Context<'_>,
+        buffer: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.contents.extend_from_slice(buffer);
+        Poll::Ready(Ok(buffer.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _context: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _context: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let Ok(mut tree) = self.tree.lock() else {
+            return Poll::Ready(Err(io::Error::other("poisoned")));
+        };
+        let node = Node::File {
+            contents: Arc::new(self.contents.clone()),
+            modified: SystemTime::now(),
+        };
+        tree.insert(self.path.clone(), node);
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// The path of the tree `path` names, relative to the root whatever it
+/// starts with.
+fn key(path: &Path) -> PathBuf {
+    let mut key = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => key.push(name),
+            Component::ParentDir => {
+                key.pop();
+            }
+            _ => {}
+        }
+    }
+    key
+}
+
+fn not_found() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "no such file or directory")
+}
@@ -0,0 +1,443 @@
+//! Where the files of the sessions are kept.
+//!
+//! Commands reach files through the [`StorageBackend`] of their session,
+//! opened by the server's [`Storage`] on the root the session is confined
+//! to, so other backends can be added without touching the commands.
+//! Paths are relative to that root, as given by
+//! [`InnerConnection::resolve_beneath`](crate::InnerConnection::resolve_beneath).
+//!
+//! [`Local`] keeps them on the local filesystem, beneath a `cap-std`
+//! handle on the root, and is the default. With the `object-store`
+//! feature, [`Bucket`] keeps them in an S3, Google Cloud Storage or Azure
+//! bucket, and [`Memory`] in memory, as the decoys of the honeypot.
+//! [`ReadOnly`] wraps any of them to refuse every change,
+//! [`Cached`] to keep their listings for a while, and [`Shares`] puts
+//! several of them side by side.
+
+use std::{
+    fmt,
+    future::Future,
+    io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    time::SystemTime,
+};
+
+use cap_std::fs::OpenOptions;
+#[cfg(unix)]
+use cap_std::fs::{MetadataExt as _, PermissionsExt as _};
+use miette::*;
+use tokio::io::{AsyncRead, AsyncSeekExt, AsyncWrite};
+
+use crate::config::StorageConfig;
+use crate::root_dir::RootDir;
+
+#[cfg(feature = "object-store")]
+mod bucket;
+mod cached;
+mod memory;
+mod read_only;
+mod shares;
+
+#[cfg(feature = "object-store")]
+pub use bucket::{Bucket, BucketStorage};
+pub use cached::{Cached, CachedStorage};
+pub use memory::{Memory, MemoryStorage};
+pub use read_only::{ReadOnly, ReadOnlyStorage};
+pub use shares::Shares;
+
+/// The future returned by the methods of [`StorageBackend`]
+pub type StorageFuture<'a, T> = Pin<Box<dyn Future<Output = io::Result<T>> + Send + 'a>>;
+
+/// The contents of a file, from where it was opened
+pub type FileReader = Pin<Box<dyn AsyncRead + Send>>;
+
+/// A file being written, complete once it's shut down
+pub type FileWriter = Pin<Box<dyn AsyncWrite + Send>>;
+
+/// Where a file opened for writing is written from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteMode {
+    /// From the start, creating the file or dropping its contents
+    Truncate,
+    /// From its end, creating the file if needed
+    Append,
+    /// From an offset of an existing file, dropping what follows it
+    At(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    File,
+    Directory,
+    Symlink,
+}
+
+/// What commands know of a file, whatever it's stored in
+#[derive(Debug, Clone)]
+pub struct Metadata {
+    pub kind: FileKind,
+    pub len: u64,
+    pub modified: SystemTime,
+    /// Unix permission bits
+    pub mode: u32,
+    pub nlink: u64,
+    pub uid: u32,
+    pub gid: u32,
+    /// What a symbolic link points to, as written in it
+    pub link: Option<PathBuf>,
+}
+
+impl Metadata {
+    pub fn is_dir(&self) -> bool {
+        self.kind == FileKind::Directory
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.kind == FileKind::File
+    }
+}
+
+/// A directory that only exists in the storage, like a prefix of a
+/// bucket.
+fn directory(modified: Option<SystemTime>) -> Metadata {
+    Metadata {
+        kind: FileKind::Directory,
+        len: 0,
+        modified: modified.unwrap_or(SystemTime::UNIX_EPOCH),
+        mode: 0o755,
+        nlink: 1,
+        uid: 0,
+        gid: 0,
+        link: None,
+    }
+}
+
+/// An entry of a directory, with the metadata of the entry itself, not of
+/// what it points to
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub name: String,
+    pub metadata: Metadata,
+}
+
+/// The files of a session, relative to its root
+pub trait StorageBackend: Send + Sync + fmt::Debug {
+    /// The metadata of the file at `path`, or of what it points to.
+    fn metadata<'a>(&'a self, path: &'a Path) -> StorageFuture<'a, Metadata>;
+
+    /// The metadata of the file at `path`, even if it's a symbolic link.
+    fn symlink_metadata<'a>(&'a self, path: &'a Path) -> StorageFuture<'a, Metadata>;
+
+    /// The entries of the directory at `path`, in no particular order.
+    fn list<'a>(&'a self, path: &'a Path) -> StorageFuture<'a, Vec<DirEntry>>;
+
+    /// Opens the file at `path` for reading from `offset`, which can't be
+    /// past its end.
+    fn open_read<'a>(&'a self, path: &'a Path, offset: u64) -> StorageFuture<'a, FileReader>;
+
+    /// Opens the file at `path` for writing as selected by `mode`.
+    fn open_write<'a>(&'a self, path: &'a Path, mode: WriteMode) -> StorageFuture<'a, FileWriter>;
+
+    /// Removes the file, or empty directory, at `path`.
+    fn remove<'a>(&'a self, path: &'a Path) -> StorageFuture<'a, ()>;
+
+    /// Moves the file at `from` to `to`, replacing any file there.
+    fn rename<'a>(&'a self, from: &'a Path, to: &'a Path) -> StorageFuture<'a, ()>;
+
+    /// Sets the modification time of the file at `path`.
+    fn set_modified<'a>(&'a self, path: &'a Path, time: SystemTime) -> StorageFuture<'a, ()>;
+
+    /// Whether a file opened for writing at `path` is only stored once
+    /// it's shut down, leaving what was there until then, so an upload
+    /// needn't be written aside and is taken back by dropping it.
+    fn stores_on_shutdown(&self, _path: &Path) -> bool {
+        false
+    }
+
+    /// The local directory the files are in, for what only a local
+    /// filesystem can do, like reserving space for uploads.
+    fn local(&self) -> Option<&RootDir> {
+        None
+    }
+}
+
+/// Bytes taken by the files beneath the directory at `path` of `storage`,
+/// without following symbolic links.
+pub async fn usage(storage: &dyn StorageBackend, path: &Path) -> io::Result<u64> {
+    let mut size = 0;
+    let mut directories = vec![path.to_path_buf()];
+    while let Some(directory) = directories.pop() {
+        for entry in storage.list(&directory).await? {
+            match entry.metadata.is_dir() {
+                true => directories.push(directory.join(&entry.name)),
+                false => size += entry.metadata.len,
+            }
+        }
+    }
+    Ok(size)
+}
+
+/// Opens the storage of every session on the root it's confined to
+pub trait Storage: Send + Sync + fmt::Debug {
+    fn open(&self, root: &Path) -> io::Result<Arc<dyn StorageBackend>>;
+}
+
+/// The storage selected by `config`, if it isn't the local filesystem,
+/// for sessions confined beneath `root`.
+#[cfg(feature = "object-store")]
+pub fn configured(config: &StorageConfig, root: &Path) -> Result<Option<Arc<dyn Storage>>> {
+    match &config.url {
+        Some(url) => Ok(Some(Arc::new(Bucket::open(url, &config.options, root)?))),
+        None => Ok(None),
+    }
+}
+
+/// The storage selected by `config`, if it isn't the local filesystem,
+/// for sessions confined beneath `root`.
+#[cfg(not(feature = "object-store"))]
+pub fn configured(config: &StorageConfig, _root: &Path) -> Result<Option<Arc<dyn Storage>>> {
+    if config.url.is_some() {
+        bail!("A storage URL is configured but the server was built without the `object-store` feature");
+    }
+    Ok(None)
+}
+
+/// The local filesystem, the default [`Storage`]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Local;
+
+impl Storage for Local {
+    fn open(&self, root: &Path) -> io::Result<Arc<dyn StorageBackend>> {
+        Ok(Arc::new(LocalStorage {
+            root: RootDir::open(root)?,
+        }))
+    }
+}
+
+/// The files beneath a directory of the local filesystem
+#[derive(Debug, Clone)]
+pub struct LocalStorage {
+    root: RootDir,
+}
+
+impl StorageBackend for LocalStorage {
+    fn metadata<'a>(&'a self, path: &'a Path) -> StorageFuture<'a, Metadata> {
+        let path = path.to_path_buf();
+        Box::pin(
+            self.root
+                .blocking(move |dir| Ok(convert(&dir.metadata(path)?, None))),
+        )
+    }
+
+    fn symlink_metadata<'a>(&'a self, path: &'a Path) -> StorageFuture<'a, Metadata> {
+        let path = path.to_path_buf();
+        Box::pin(self.root.blocking(move |dir| symlink_metadata(dir, &path)))
+    }
+
+    fn list<'a>(&'a self, path: &'a Path) -> StorageFuture<'a, Vec<DirEntry>> {
+        let path = path.to_path_buf();
+        // Read whole, with the metadata of every entry, in one go on the
+        // blocking pool
+        Box::pin(self.root.blocking(move |root| {
+            let dir = root.open_dir(&path)?;
+            let mut entries = Vec::new();
+            for entry in dir.entries()? {
+                let name = entry?.file_name();
+                // An entry that can't be read doesn't spoil the rest of the listing
+                match symlink_metadata(&dir, name.as_ref()) {
+                    Ok(metadata) => entries.push(DirEntry {
+                        name: name.to_string_lossy().into_owned(),
+                        metadata,
+                    }),
+                    Err(error) => {
+                        tracing::debug!("Skipping {:?} in {:?}: {}", name, path, error);
+                    }
+                }
+            }
+            Ok(entries)
+        }))
+    }
+
+    fn open_read<'a>(&'a self, path: &'a Path, offset: u64) -> StorageFuture<'a, FileReader> {
+        Box::pin(async move {
+            let mut file = self.root.open_file(path).await?;
+            if offset > 0 {
+                let length = file.metadata().await?.len();
+                if offset > length {
+                    let error = format!("offset {} is past the length {}", offset, length);
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, error));
+                }
+                file.seek(io::SeekFrom::Start(offset)).await?;
+            }
+            Ok(Box::pin(file) as FileReader)
+        })
+    }
+
+    fn open_write<'a>(&'a self, path: &'a Path, mode: WriteMode) -> StorageFuture<'a, FileWriter> {
+        Box::pin(async move {
+            let mut options = OpenOptions::new();
+            let offset = match mode {
+                WriteMode::Truncate => {
+                    options.write(true).create(true).truncate(true);
+                    return Ok(Box::pin(self.root.open_with(path, options).await?) as FileWriter);
+                }
+                WriteMode::Append => {
+                    options.append(true).create(true);
+                    return Ok(Box::pin(self.root.open_with(path, options).await?) as FileWriter);
+                }
+                WriteMode::At(offset) => offset,
+            };
+            options.write(true);
+            let mut file = self.root.open_with(path, options).await?;
+            let length = file.metadata().await?.len();
+            if offset > length {
+                let error = format!("offset {} is past the length {}", offset, length);
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, error));
+            }
+            file.set_len(offset).await?;
+            file.seek(io::SeekFrom::Start(offset)).await?;
+            Ok(Box::pin(file) as FileWriter)
+        })
+    }
+
+    fn remove<'a>(&'a self, path: &'a Path) -> StorageFuture<'a, ()> {
+        let path = path.to_path_buf();
+        Box::pin(
+            self.root
+                .blocking(move |dir| match dir.symlink_metadata(&path)?.is_dir() {
+                    true => dir.remove_dir(&path),
+                    false => dir.remove_file(&path),
+                }),
+        )
+    }
+
+    fn rename<'a>(&'a self, from: &'a Path, to: &'a Path) -> StorageFuture<'a, ()> {
+        let (from, to) = (from.to_path_buf(), to.to_path_buf());
+        Box::pin(self.root.blocking(move |dir| dir.rename(from, dir, to)))
+    }
+
+    fn set_modified<'a>(&'a self, path: &'a Path, time: SystemTime) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let mut options = OpenOptions::new();
+            options.write(true);
+            let file = self.root.open_with(path, options).await?.into_std().await;
+            tokio::task::spawn_blocking(move || file.set_modified(time))
+                .await
+                .map_err(io::Error::other)?
+        })
+    }
+
+    fn local(&self) -> Option<&RootDir> {
+        Some(&self.root)
+    }
+}
+
+/// The metadata of `path` beneath `dir`, with the target of links.
+fn symlink_metadata(dir: &cap_std::fs::Dir, path: &Path) -> io::Result<Metadata> {
+    let metadata = dir.symlink_metadata(path)?;
+    let link = match metadata.is_symlink() {
+        true => Some(dir.read_link_contents(path)?),
+        false => None,
+    };
+    Ok(convert(&metadata, link))
+}
+
+fn convert(metadata: &cap_std::fs::Metadata, link: Option<PathBuf>) -> Metadata {
+    let file_type = metadata.file_type();
+    let kind = if file_type.is_symlink() {
+        FileKind::Symlink
+    } else if file_type.is_dir() {
+        FileKind::Directory
+    } else {
+        FileKind::File
+    };
+    let (mode, nlink, uid, gid) = ownership(metadata);
+    Metadata {
+        kind,
+        len: metadata.len(),
+        modified: metadata
+            .modified()
+            .map(|modified| modified.into_std())
+            .unwrap_or(SystemTime::UNIX_EPOCH),
+        mode,
+        nlink,
+        uid,
+        gid,
+        link,
+    }
+}
+
+/// The mode, links, user and group of a file.
+#[cfg(unix)]
+fn ownership(metadata: &cap_std::fs::Metadata) -> (u32, u64, u32, u32) {
+    let mode = metadata.permissions().mode();
+    (mode, metadata.nlink(), metadata.uid(), metadata.gid())
+}
+
+/// The mode of a file from whether it's read-only, with a single link and
+/// no owner, which there's no notion of off Unix.
+#[cfg(not(unix))]
+fn ownership(metadata: &cap_std::fs::Metadata) -> (u32, u64, u32, u32) {
+    let mode = match metadata.is_dir() {
+        true => 0o755,
+        false => 0o644,
+    };
+    let mode = match metadata.permissions().readonly() {
+        true => mode & !0o222,
+        false => mode,
+    };
+    (mode, 1, 0, 0)
+}
+
+/// A root that couldn't be opened, nothing can be in it then
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Unavailable;
+
+impl Unavailable {
+    fn error<T>() -> io::Result<T> {
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "the root could not be opened",
+        ))
+    }
+}
+
+impl StorageBackend for Unavailable {
+    fn metadata<'a>(&'a self, _path: &'a Path) -> StorageFuture<'a, Metadata> {
+        Box::pin(async { Self::error() })
+    }
+
+    fn symlink_metadata<'a>(&'a self, _path: &'a Path) -> StorageFuture<'a, Metadata> {
+        Box::pin(async { Self::error() })
+    }
+
+    fn list<'a>(&'a self, _path: &'a Path) -> StorageFuture<'a, Vec<DirEntry>> {
+        Box::pin(async { Self::error() })
+    }
+
+    fn open_read<'a>(&'a self, _path: &'a Path, _offset: u64) -> StorageFuture<'a, FileReader> {
+        Box::pin(async { Self::error() })
+    }
+
+    fn open_write<'a>(
+        &'a self,
+        _path: &'a Path,
+        _mode: WriteMode,
+    ) -> StorageFuture<'a, FileWriter> {
+        Box::pin(async { Self::error() })
+    }
+
+    fn remove<'a>(&'a self, _path: &'a Path) -> StorageFuture<'a, ()> {
+        Box::pin(async { Self::error() })
+    }
+
+    fn rename<'a>(&'a self, _from: &'a Path, _to: &'a Path) -> StorageFuture<'a, ()> {
+        Box::pin(async { Self::error() })
+    }
+
+    fn set_modified<'a>(&'a self, _path: &'a Path, _time: SystemTime) -> StorageFuture<'a, ()> {
+        Box::pin(async { Self::error() })
+    }
+}
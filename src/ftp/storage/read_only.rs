@@ -0,0 +1,78 @@
+//! Read-only exports of any storage.
+
+use std::{io, path::Path, sync::Arc, time::SystemTime};
+
+use super::{
+    DirEntry, FileReader, FileWriter, Metadata, Storage, StorageBackend, StorageFuture, WriteMode,
+};
+use crate::root_dir::RootDir;
+
+/// Opens the storage of the sessions with `0`, refusing every change to it
+#[derive(Debug, Clone)]
+pub struct ReadOnly(pub Arc<dyn Storage>);
+
+impl Storage for ReadOnly {
+    fn open(&self, root: &Path) -> io::Result<Arc<dyn StorageBackend>> {
+        Ok(Arc::new(ReadOnlyStorage(self.0.open(root)?)))
+    }
+}
+
+/// A storage whose files can be read but not written, removed or moved
+#[derive(Debug, Clone)]
+pub struct ReadOnlyStorage(pub Arc<dyn StorageBackend>);
+
+impl ReadOnlyStorage {
+    fn refuse<T>() -> io::Result<T> {
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "the storage is read-only",
+        ))
+    }
+}
+
+impl StorageBackend for ReadOnlyStorage {
+    fn metadata<'a>(&'a self, path: &'a Path) -> StorageFuture<'a, Metadata> {
+        self.0.metadata(path)
+    }
+
+    fn symlink_metadata<'a>(&'a self, path: &'a Path) -> StorageFuture<'a, Metadata> {
+        self.0.symlink_metadata(path)
+    }
+
+    fn list<'a>(&'a self, path: &'a Path) -> StorageFuture<'a, Vec<DirEntry>> {
+        self.0.list(path)
+    }
+
+    fn open_read<'a>(&'a self, path: &'a Path, offset: u64) -> StorageFuture<'a, FileReader> {
+        self.0.open_read(path, offset)
+    }
+
+    fn open_write<'a>(
+        &'a self,
+        _path: &'a Path,
+        _mode: WriteMode,
+    ) -> StorageFuture<'a, FileWriter> {
+        Box::pin(async { Self::refuse() })
+    }
+
+    fn remove<'a>(&'a self, _path: &'a Path) -> StorageFuture<'a, ()> {
+        Box::pin(async { Self::refuse() })
+    }
+
+    fn rename<'a>(&'a self, _from: &'a Path, _to: &'a Path) -> StorageFuture<'a, ()> {
+        Box::pin(async { Self::refuse() })
+    }
+
+    fn set_modified<'a>(&'a self, _path: &'a Path, _time: SystemTime) -> StorageFuture<'a, ()> {
+        Box::pin(async { Self::refuse() })
+    }
+
+    fn stores_on_shutdown(&self, path: &Path) -> bool {
+        self.0.stores_on_shutdown(path)
+    }
+
+    /// The local directory, to keep checking links out of it
+    fn local(&self) -> Option<&RootDir> {
+        self.0.local()
+    }
+}
@@ -0,0 +1,159 @@
+//! Several storages shown as the directories of a virtual root.
+
+use std::{
+    io,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+
+use tracing::*;
+
+use super::{
+    directory, DirEntry, FileReader, FileWriter, Metadata, StorageBackend, StorageFuture, WriteMode,
+};
+
+/// The shares a session can see, each a directory of its root named
+/// after it
+#[derive(Debug, Clone, Default)]
+pub struct Shares {
+    shares: Vec<(String, Arc<dyn StorageBackend>)>,
+}
+
+impl Shares {
+    /// Shows `storage` as the directory `name` of the root.
+    pub fn add(&mut self, name: impl Into<String>, storage: Arc<dyn StorageBackend>) {
+        self.shares.push((name.into(), storage));
+    }
+
+    /// The share `path` is in, and the path in it, `None` for the root.
+    fn split<'a>(&'a self, path: &Path) -> io::Result<Option<(&'a dyn StorageBackend, PathBuf)>> {
+        let mut components = path
+            .components()
+            .filter(|component| matches!(component, Component::Normal(_)));
+        let Some(name) = components.next() else {
+            return Ok(None);
+        };
+        let Some((_, storage)) = self
+            .shares
+            .iter()
+            .find(|(share, _)| name.as_os_str() == share.as_str())
+        else {
+            let error = format!("there is no share {:?}", name);
+            return Err(io::Error::new(io::ErrorKind::NotFound, error));
+        };
+        let rest: PathBuf = components.collect();
+        let rest = match rest == Path::new("") {
+            true => PathBuf::from("."),
+            false => rest,
+        };
+        Ok(Some((storage.as_ref(), rest)))
+    }
+
+    fn refuse<T>() -> io::Result<T> {
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "only the shares can be changed",
+        ))
+    }
+}
+
+impl StorageBackend for Shares {
+    fn metadata<'a>(&'a self, path: &'a Path) -> StorageFuture<'a, Metadata> {
+        Box::pin(async move {
+            match self.split(path)? {
+                Some((storage, path)) => storage.metadata(&path).await,
+                None => Ok(directory(None)),
+            }
+        })
+    }
+
+    fn symlink_metadata<'a>(&'a self, path: &'a Path) -> StorageFuture<'a, Metadata> {
+        Box::pin(async move {
+            match self.split(path)? {
+                Some((storage, path)) => storage.symlink_metadata(&path).await,
+                None => Ok(directory(None)),
+            }
+        })
+    }
+
+    fn list<'a>(&'a self, path: &'a Path) -> StorageFuture<'a, Vec<DirEntry>> {
+        Box::pin(async move {
+            if let Some((storage, path)) = self.split(path)? {
+                return storage.list(&path).await;
+            }
+            let mut entries = Vec::with_capacity(self.shares.len());
+            for (name, storage) in &self.shares {
+                // A share that can't be reached doesn't hide the others
+                match storage.metadata(Path::new(".")).await {
+                    Ok(metadata) => entries.push(DirEntry {
+                        name: name.clone(),
+                        metadata,
+                    }),
+                    Err(error) => debug!("Skipping the share {:?}: {}", name, error),
+                }
+            }
+            Ok(entries)
+        })
+    }
+
+    fn open_read<'a>(&'a self, path: &'a Path, offset: u64) -> StorageFuture<'a, FileReader> {
+        Box::pin(async move {
+            match self.split(path)? {
+                Some((storage, path)) => storage.open_read(&path, offset).await,
+                None => Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "the root is a directory",
+                )),
+            }
+        })
+    }
+
+    fn open_write<'a>(&'a self, path: &'a Path, mode: WriteMode) -> StorageFuture<'a, FileWriter> {
+        Box::pin(async move {
+            match self.split(path)? {
+                Some((storage, path)) if path != Path::new(".") => {
+                    storage.open_write(&path, mode).await
+                }
+                _ => Self::refuse(),
+            }
+        })
+    }
+
+    fn remove<'a>(&'a self, path: &'a Path) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            match self.split(path)? {
+                Some((storage, path)) if path != Path::new(".") => storage.remove(&path).await,
+                _ => Self::refuse(),
+            }
+        })
+    }
+
+    fn rename<'a>(&'a self, from: &'a Path, to: &'a Path) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let (Some((from_share, from)), Some((to_share, to))) =
+                (self.split(from)?, self.split(to)?)
+            else {
+                return Self::refuse();
+            };
+            if !std::ptr::addr_eq(from_share, to_share) {
+                let error = "files can't be moved between shares";
+                return Err(io::Error::new(io::ErrorKind::Other, error));
+            }
+            from_share.rename(&from, &to).await
+        })
+    }
+
+    fn set_modified<'a>(&'a self, path: &'a Path, time: SystemTime) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            match self.split(path)? {
+                Some((storage, path)) => storage.set_modified(&path, time).await,
+                None => Self::refuse(),
+            }
+        })
+    }
+
+    fn stores_on_shutdown(&self, path: &Path) -> bool {
+        matches!(self.split(path), Ok(Some((storage, path))) if storage.stores_on_shutdown(&path))
+    }
+}
@@ -0,0 +1,160 @@
+//! The effective configuration, logged on startup so that mistakes show
+//! up at a glance rather than on the first failed transfer.
+
+use std::{net::SocketAddr, path::PathBuf};
+
+use tracing::*;
+
+use crate::config::{Config, OverRate};
+
+/// What the server is about to do with its configuration
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Summary {
+    pub bind: SocketAddr,
+    /// The directory sessions start in, unless their virtual host has its own
+    pub root: PathBuf,
+    pub passive_ports: String,
+    pub data_modes: String,
+    pub tls: String,
+    pub auth: String,
+    pub limits: String,
+    pub disabled_commands: String,
+    pub hosts: String,
+    pub languages: String,
+    pub transcripts: String,
+}
+
+impl Summary {
+    pub fn new(bind: SocketAddr, root: PathBuf, config: &Config) -> Self {
+        let passive_ports = match (config.passive.ports, config.passive.port_map.len()) {
+            (Some(ports), 0) => format!("{}-{}", ports.start, ports.end),
+            (Some(ports), mappings) => {
+                format!("{}-{} ({} mappings)", ports.start, ports.end, mappings)
+            }
+            (None, _) => "any".to_string(),
+        };
+        let data_modes = match (config.data.active, config.data.passive) {
+            (true, true) => "active, passive",
+            (true, false) => "active",
+            (false, true) => "passive",
+            (false, false) => "none",
+        };
+        let tls = match (&config.tls.certificate, config.tls.required) {
+            (None, _) => "off".to_string(),
+            (Some(_), true) => "required".to_string(),
+            (Some(_), false) => "optional".to_string(),
+        };
+        let auth = match (config.users.len(), &config.anonymous) {
+            _ if config.honeypot.enabled => "anyone, honeypot".to_string(),
+            (_, anonymous) if anonymous.enabled && anonymous.only => "anonymous only".to_string(),
+            (0, _) if config.auth.allow_any => "anyone".to_string(),
+            (users, anonymous) if anonymous.enabled => {
+                format!("{} configured users, anonymous", users)
+            }
+            (0, _) => "no one, no users are configured".to_string(),
+            (users, _) => format!("{} configured users", users),
+        };
+        let limits = limits(config);
+        let languages = std::iter::once("en")
+            .chain(config.lang.catalogs.keys().map(String::as_str))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let transcripts = match &config.record.directory {
+            Some(directory) => directory.display().to_string(),
+            None => "off".to_string(),
+        };
+        let disabled_commands = match &config.commands.enabled {
+            Some(enabled) => format!("all but {}", list(enabled.iter())),
+            None => list(config.commands.disabled.iter()),
+        };
+        Self {
+            bind,
+            root,
+            passive_ports,
+            data_modes: data_modes.to_string(),
+            tls,
+            auth,
+            limits,
+            disabled_commands,
+            hosts: list(config.hosts.iter().map(|host| &host.name)),
+            languages,
+            transcripts,
+        }
+    }
+
+    /// Logs the summary as a single structured event.
+    pub fn log(&self) {
+        info!(
+            target: "startup",
+            bind = %self.bind,
+            root = %self.root.display(),
+            passive_ports = %self.passive_ports,
+            data_modes = %self.data_modes,
+            tls = %self.tls,
+            auth = %self.auth,
+            limits = %self.limits,
+            disabled_commands = %self.disabled_commands,
+            hosts = %self.hosts,
+            languages = %self.languages,
+            transcripts = %self.transcripts,
+            "Effective configuration"
+        );
+    }
+
+    /// The summary as labeled rows, for humans.
+    pub fn rows(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("Bind", self.bind.to_string()),
+            ("Root", self.root.display().to_string()),
+            ("Passive ports", self.passive_ports.clone()),
+            ("Data modes", self.data_modes.clone()),
+            ("TLS", self.tls.clone()),
+            ("Auth", self.auth.clone()),
+            ("Limits", self.limits.clone()),
+            ("Disabled", self.disabled_commands.clone()),
+            ("Virtual hosts", self.hosts.clone()),
+            ("Languages", self.languages.clone()),
+            ("Transcripts", self.transcripts.clone()),
+        ]
+    }
+}
+
+/// The limits of `[limits]` that are set, the line length always is.
+fn limits(config: &Config) -> String {
+    let limits = &config.limits;
+    let mut set = Vec::new();
+    if let Some(sessions) = limits.max_sessions {
+        set.push(format!("{} sessions", sessions));
+    }
+    if let Some(connections) = limits.connections_per_address {
+        set.push(format!("{} per address", connections));
+    }
+    // Like the bucket of the sessions
+    if let Some(rate) = limits.command_rate.filter(|rate| *rate > 0.0) {
+        let burst = limits.command_burst.unwrap_or(rate.ceil() as u32).max(1);
+        let over = match limits.over_rate {
+            OverRate::Delay => "delayed",
+            OverRate::Close => "closed",
+        };
+        set.push(format!(
+            "{} commands/s, bursts of {}, {} past it",
+            rate, burst, over
+        ));
+    }
+    if let Some(bytes) = limits.max_upload {
+        set.push(format!("uploads of {} bytes", bytes));
+    }
+    if let Some(bytes) = limits.session_memory {
+        set.push(format!("{} bytes of memory", bytes));
+    }
+    set.push(format!("lines of {} bytes", limits.max_command_line));
+    set.join(", ")
+}
+
+fn list<'a>(items: impl Iterator<Item = &'a String>) -> String {
+    let items = items.map(String::as_str).collect::<Vec<_>>();
+    match items.is_empty() {
+        true => "none".to_string(),
+        false => items.join(", "),
+    }
+}
@@ -0,0 +1,74 @@
+//! Telnet commands on the control connection.
+//!
+//! The control connection is a Telnet connection, so clients may send
+//! `IAC` sequences, like the `IAC IP IAC DM` some send before `ABOR`. They
+//! are removed before the commands are parsed, and the options clients
+//! offer or ask for are refused, as the server doesn't support any.
+//!
+//! Check: https://datatracker.ietf.org/doc/html/rfc854
+//! Check: https://datatracker.ietf.org/doc/html/rfc959#section-4.1.3
+
+/// Interpret As Command, the escape of every Telnet command
+pub const IAC: u8 = 255;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+const WONT: u8 = 252;
+const WILL: u8 = 251;
+/// Start of a subnegotiation, up to `SE`
+const SB: u8 = 250;
+const SE: u8 = 240;
+
+/// Removes the Telnet commands from `line`, returning the answers to the
+/// option negotiations in it.
+pub fn strip(line: &mut Vec<u8>) -> Vec<u8> {
+    let mut answers = vec![];
+    if !line.contains(&IAC) {
+        return answers;
+    }
+    let mut kept = 0;
+    let mut index = 0;
+    let mut subnegotiation = false;
+    while index < line.len() {
+        let byte = line[index];
+        if byte != IAC {
+            if !subnegotiation {
+                line[kept] = byte;
+                kept += 1;
+            }
+            index += 1;
+            continue;
+        }
+        match line.get(index + 1).copied() {
+            // An escaped data byte
+            Some(IAC) => {
+                if !subnegotiation {
+                    line[kept] = IAC;
+                    kept += 1;
+                }
+                index += 2;
+            }
+            Some(verb @ (WILL | WONT | DO | DONT)) => {
+                match (verb, line.get(index + 2).copied()) {
+                    (WILL, Some(option)) => answers.extend([IAC, DONT, option]),
+                    (DO, Some(option)) => answers.extend([IAC, WONT, option]),
+                    _ => {}
+                }
+                index += 3;
+            }
+            Some(SB) => {
+                subnegotiation = true;
+                index += 2;
+            }
+            Some(SE) => {
+                subnegotiation = false;
+                index += 2;
+            }
+            // Like `IP`, `DM` or `NOP`, which need no answer
+            Some(command) if command > SE => index += 2,
+            // The command was lost, like a `DM` sent as urgent data is
+            _ => index += 1,
+        }
+    }
+    line.truncate(kept);
+    answers
+}
@@ -0,0 +1,195 @@
+//! Throttling of failed logins.
+//!
+//! Every failed login from an address, or for a user, holds the reply back
+//! a little longer than the previous one. Once an address failed too often
+//! it is banned for a while: its session is closed with `421` and its new
+//! connections are refused, so guessing passwords isn't free anymore. The
+//! failures for a user only hold replies back, or anyone could get the
+//! address of the user banned by failing for them first.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::config::ThrottleConfig;
+
+/// What a failed login is counted against
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Subject {
+    Address(IpAddr),
+    User(String),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Failures {
+    count: u32,
+    last: Instant,
+}
+
+/// What happens to the client after a failed login
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    /// The client may try again once the delay is over
+    Retry(Duration),
+    /// The client failed too often and its address is banned
+    Banned,
+}
+
+#[derive(Debug)]
+pub struct LoginThrottle {
+    config: ThrottleConfig,
+    failures: Mutex<HashMap<Subject, Failures>>,
+    /// When the ban of each address ends
+    bans: Mutex<HashMap<IpAddr, Instant>>,
+}
+
+impl LoginThrottle {
+    pub fn new(config: &ThrottleConfig) -> Self {
+        Self {
+            config: config.clone(),
+            failures: Mutex::default(),
+            bans: Mutex::default(),
+        }
+    }
+
+    /// How long bans last, and failures are remembered for
+    fn window(&self) -> Duration {
+        Duration::from_secs(self.config.ban_seconds)
+    }
+
+    /// Returns `true` if connections from `address` have to be refused.
+    pub fn is_banned(&self, address: IpAddr) -> bool {
+        let mut bans = self.bans.lock().unwrap();
+        match bans.get(&address) {
+            Some(until) if *until > Instant::now() => true,
+            Some(_) => {
+                bans.remove(&address);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Counts a failed login of `username` from `address`.
+    pub fn failed(&self, address: IpAddr, username: &str) -> Verdict {
+        let now = Instant::now();
+        let window = self.window();
+        let mut failures = self.failures.lock().unwrap();
+        // Forgotten failures would otherwise pile up forever
+        failures.retain(|_, failures| now.duration_since(failures.last) < window);
+
+        let [from_address, for_user] = [Subject::Address(address), Subject::User(username.into())]
+            .map(|subject| {
+                let failures = failures.entry(subject).or_insert(Failures {
+                    count: 0,
+                    last: now,
+                });
+                failures.count += 1;
+                failures.last = now;
+                failures.count
+            });
+
+        if self.config.max_failures != 0 && from_address >= self.config.max_failures {
+            failures.remove(&Subject::Address(address));
+            let mut bans = self.bans.lock().unwrap();
+            bans.retain(|_, until| *until > now);
+            bans.insert(address, now + window);
+            return Verdict::Banned;
+        }
+        let count = from_address.max(for_user);
+        let delay = self.config.delay_ms.saturating_mul(count.into());
+        Verdict::Retry(Duration::from_millis(delay.min(self.config.max_delay_ms)))
+    }
+
+    /// Forgets the failures of `username` and `address` after a login.
+    pub fn succeeded(&self, address: IpAddr, username: &str) {
+        let mut failures = self.failures.lock().unwrap();
+        failures.remove(&Subject::Address(address));
+        failures.remove(&Subject::User(username.into()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT: [u8; 4] = [192, 0, 2, 10];
+    const OTHER: [u8; 4] = [198, 51, 100, 7];
+
+    fn throttle(max_failures: u32, ban_seconds: u64) -> LoginThrottle {
+        LoginThrottle::new(&ThrottleConfig {
+            max_failures,
+            ban_seconds,
+            delay_ms: 100,
+            max_delay_ms: 250,
+        })
+    }
+
+    fn retry(milliseconds: u64) -> Verdict {
+        Verdict::Retry(Duration::from_millis(milliseconds))
+    }
+
+    #[test]
+    fn delays_grow_with_the_failures() {
+        let throttle = throttle(0, 600);
+        let client = IpAddr::from(CLIENT);
+
+        assert_eq!(throttle.failed(client, "alice"), retry(100));
+        assert_eq!(throttle.failed(client, "alice"), retry(200));
+        assert_eq!(throttle.failed(client, "alice"), retry(250));
+        assert!(!throttle.is_banned(client));
+    }
+
+    #[test]
+    fn addresses_are_banned_after_too_many_failures() {
+        let throttle = throttle(3, 600);
+        let client = IpAddr::from(CLIENT);
+
+        assert_eq!(throttle.failed(client, "alice"), retry(100));
+        assert_eq!(throttle.failed(client, "bob"), retry(200));
+        assert_eq!(throttle.failed(client, "carol"), Verdict::Banned);
+        assert!(throttle.is_banned(client));
+        assert!(!throttle.is_banned(IpAddr::from(OTHER)));
+    }
+
+    #[test]
+    fn failures_for_a_user_only_delay() {
+        let throttle = throttle(3, 600);
+        let (client, other) = (IpAddr::from(CLIENT), IpAddr::from(OTHER));
+        for _ in 0..5 {
+            throttle.failed(other, "alice");
+        }
+        // Banned for what it did, not the user
+        assert!(throttle.is_banned(other));
+
+        assert_eq!(throttle.failed(client, "alice"), retry(250));
+        assert!(!throttle.is_banned(client));
+    }
+
+    #[test]
+    fn bans_end() {
+        let throttle = throttle(1, 1);
+        let client = IpAddr::from(CLIENT);
+
+        assert_eq!(throttle.failed(client, "alice"), Verdict::Banned);
+        assert!(throttle.is_banned(client));
+        std::thread::sleep(Duration::from_millis(1100));
+        assert!(!throttle.is_banned(client));
+    }
+
+    #[test]
+    fn logins_forget_the_failures() {
+        let throttle = throttle(3, 600);
+        let client = IpAddr::from(CLIENT);
+
+        throttle.failed(client, "alice");
+        throttle.failed(client, "alice");
+        throttle.succeeded(client, "alice");
+
+        assert_eq!(throttle.failed(client, "alice"), retry(100));
+        assert_eq!(throttle.failed(client, "alice"), retry(200));
+    }
+}
@@ -0,0 +1,119 @@
+//! TLS support for explicit FTPS (`AUTH TLS`).
+//!
+//! Without the `tls` feature TLS is never available and
+//! configuring it is an error.
+//!
+//! Check: https://datatracker.ietf.org/doc/html/rfc4217
+
+use std::sync::Arc;
+#[cfg(feature = "tls")]
+use std::{fs::File, io::BufReader, path::Path};
+
+use miette::*;
+#[cfg(feature = "tls")]
+use tokio_rustls::rustls::{
+    crypto::ring,
+    pki_types::{CertificateDer, PrivateKeyDer},
+    server::WebPkiClientVerifier,
+    RootCertStore,
+};
+
+use crate::config::TlsConfig;
+
+#[cfg(feature = "tls")]
+pub use tokio_rustls::rustls::ServerConfig;
+
+/// Can't be constructed, so TLS is never available
+#[cfg(not(feature = "tls"))]
+pub type ServerConfig = std::convert::Infallible;
+
+/// Fails if TLS is configured, since the `tls` feature is disabled.
+#[cfg(not(feature = "tls"))]
+pub fn load_server_config(config: &TlsConfig) -> Result<Option<Arc<ServerConfig>>> {
+    if config.certificate.is_some()
+        || config.key.is_some()
+        || config.required
+        || config.client_ca.is_some()
+    {
+        bail!("TLS is configured but the server was built without the `tls` feature");
+    }
+    Ok(None)
+}
+
+/// Builds the TLS server configuration from the configured certificate and key.
+///
+/// Returns `None` if TLS isn't configured.
+#[cfg(feature = "tls")]
+pub fn load_server_config(config: &TlsConfig) -> Result<Option<Arc<ServerConfig>>> {
+    let (Some(certificate), Some(key)) = (&config.certificate, &config.key) else {
+        if config.required {
+            bail!("TLS is required but no certificate and key are configured");
+        }
+        return Ok(None);
+    };
+
+    let provider = Arc::new(ring::default_provider());
+    let verifier = match &config.client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for certificate in load_certificates(client_ca)? {
+                roots
+                    .add(certificate)
+                    .into_diagnostic()
+                    .wrap_err_with(|| format!("Invalid CA certificate {}", client_ca.display()))?;
+            }
+            let builder =
+                WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone());
+            let builder = match config.client_certificate_required {
+                true => builder,
+                false => builder.allow_unauthenticated(),
+            };
+            builder.build().into_diagnostic()?
+        }
+        None if config.client_certificate_required => {
+            bail!(
+                help = "Set `client_ca` to the authorities issuing the client certificates",
+                "Client certificates are required but no CA is configured"
+            );
+        }
+        None => WebPkiClientVerifier::no_client_auth(),
+    };
+
+    let server_config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .into_diagnostic()?
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(load_certificates(certificate)?, load_key(key)?)
+        .into_diagnostic()
+        .wrap_err("Invalid TLS certificate or key")?;
+    Ok(Some(Arc::new(server_config)))
+}
+
+#[cfg(feature = "tls")]
+fn load_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let file = File::open(path)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Could not open certificate {}", path.display()))?;
+    rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<std::io::Result<Vec<_>>>()
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Invalid certificate {}", path.display()))
+}
+
+#[cfg(feature = "tls")]
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let file = File::open(path)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Could not open private key {}", path.display()))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Invalid private key {}", path.display()))?
+        .ok_or_else(|| miette!("No private key found in {}", path.display()))
+}
+
+/// The subject of a verified client certificate, like `O=Example, CN=alice`.
+#[cfg(feature = "tls")]
+pub fn certificate_subject(certificate: &CertificateDer) -> Option<String> {
+    let (_, certificate) = x509_parser::parse_x509_certificate(certificate).ok()?;
+    Some(certificate.subject().to_string())
+}
@@ -0,0 +1,262 @@
+//! Control connection transcripts.
+//!
+//! With `[record]` configured every session is written to its own file,
+//! client commands prefixed with `> ` and server replies with `< `:
+//!
+//! ```text
+//! # ftpy transcript of ip-0f4c2d8e1a9b3c77
+//! < 220 Service ready for new user
+//! > USER alice
+//! < 331 User name okay, need password
+//! > PASS ********
+//! < 230 User logged in, proceed
+//! ```
+//!
+//! Credentials are masked, and the client is written the way the logs
+//! show it. [`replay`] feeds a transcript back to a server and compares
+//! the reply codes, so interop bugs seen in production can be reproduced.
+
+use std::{
+    borrow::Cow,
+    fs::File,
+    io::{BufWriter, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use chrono::Local;
+use miette::*;
+use rand::distributions::{Alphanumeric, DistString};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+use tracing::*;
+
+use crate::client::{final_reply_code, passive_address, read_reply};
+use crate::config::Config;
+use crate::FTPServer;
+
+/// Commands whose arguments never make it into a transcript
+const MASKED_COMMANDS: [&str; 2] = ["PASS", "ACCT"];
+
+const MASK: &str = "********";
+
+/// Commands skipped on replay, they need something a transcript doesn't have
+const UNREPLAYABLE_COMMANDS: [&str; 4] = ["AUTH", "PORT", "EPRT", "REIN"];
+
+/// Writes the transcript of a single session
+#[derive(Debug, Clone)]
+pub struct Recorder {
+    file: Arc<Mutex<BufWriter<File>>>,
+}
+
+impl Recorder {
+    /// Creates a new transcript in `directory` for `client`.
+    pub fn create(directory: &Path, client: &str) -> Result<Self> {
+        std::fs::create_dir_all(directory)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Could not create {}", directory.display()))?;
+        let name = format!(
+            "{}-{}.ftp",
+            Local::now().format("%Y%m%dT%H%M%S"),
+            Alphanumeric.sample_string(&mut rand::thread_rng(), 8)
+        );
+        let path = directory.join(name);
+        let file = File::create(&path)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Could not create transcript {}", path.display()))?;
+        debug!("Recording session in {}", path.display());
+        let recorder = Self {
+            file: Arc::new(Mutex::new(BufWriter::new(file))),
+        };
+        recorder.write(&format!("# ftpy transcript of {client}"));
+        Ok(recorder)
+    }
+
+    /// Records a command as received, with credentials masked.
+    pub fn command(&self, input: &str) {
+        self.write(&format!("> {}", sanitize(input)));
+    }
+
+    /// Records a reply, which may span multiple lines.
+    pub fn reply(&self, reply: &str) {
+        for line in reply.lines() {
+            self.write(&format!("< {line}"));
+        }
+    }
+
+    fn write(&self, line: &str) {
+        let Ok(mut file) = self.file.lock() else {
+            return;
+        };
+        if let Err(error) = writeln!(file, "{line}").and_then(|_| file.flush()) {
+            warn!("Could not write transcript: {}", error);
+        }
+    }
+}
+
+/// Masks the arguments of commands carrying credentials.
+pub fn sanitize(input: &str) -> Cow<'_, str> {
+    let keyword = input.split_whitespace().next().unwrap_or_default();
+    if MASKED_COMMANDS
+        .iter()
+        .any(|masked| masked.eq_ignore_ascii_case(keyword))
+    {
+        Cow::Owned(format!("{keyword} {MASK}"))
+    } else {
+        Cow::Borrowed(input)
+    }
+}
+
+/// A command of a transcript with the codes of its final replies
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Exchange {
+    pub line: usize,
+    pub command: String,
+    pub replies: Vec<u16>,
+}
+
+/// Parses a transcript into the greeting codes and the exchanges after it.
+pub fn parse(transcript: &str) -> Result<(Vec<u16>, Vec<Exchange>)> {
+    let mut greeting = vec![];
+    let mut exchanges: Vec<Exchange> = vec![];
+    for (index, line) in transcript.lines().enumerate() {
+        if let Some(command) = line.strip_prefix("> ") {
+            exchanges.push(Exchange {
+                line: index + 1,
+                command: command.to_string(),
+                replies: vec![],
+            });
+        } else if let Some(reply) = line.strip_prefix("< ") {
+            let Some(code) = final_reply_code(reply) else {
+                continue;
+            };
+            match exchanges.last_mut() {
+                Some(exchange) => exchange.replies.push(code),
+                None => greeting.push(code),
+            }
+        } else if !line.starts_with('#') && !line.trim().is_empty() {
+            bail!("Invalid transcript line {}: {:?}", index + 1, line);
+        }
+    }
+    Ok((greeting, exchanges))
+}
+
+/// Outcome of replaying a transcript
+#[derive(Debug, Default)]
+pub struct Replay {
+    pub replayed: usize,
+    pub skipped: Vec<Exchange>,
+    /// The exchanges with the codes the server replied instead
+    pub mismatches: Vec<(Exchange, Vec<u16>)>,
+}
+
+/// Replays the transcript at `path` against a server with `config`,
+/// serving an empty scratch directory.
+///
+/// Masked credentials are sent as is, so logins only succeed
+/// if `config` has no users, which lets anyone in while replaying.
+pub async fn replay(path: &Path, mut config: Config) -> Result<Replay> {
+    let transcript = std::fs::read_to_string(path)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Could not read transcript {}", path.display()))?;
+    config.root = Some(scratch_directory()?);
+    config.auth.allow_any = true;
+    let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+    replay_against(&transcript, FTPServer::from((addr, config))).await
+}
+
+/// Replays `transcript` against `server`, serving on a port of its own
+/// whatever its configured address.
+pub async fn replay_against(transcript: &str, mut server: FTPServer) -> Result<Replay> {
+    let (greeting, exchanges) = parse(transcript)?;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.into_diagnostic()?;
+    let addr = listener.local_addr().into_diagnostic()?;
+    tokio::spawn(async move { server.serve(listener).await });
+
+    let mut control = BufReader::new(TcpStream::connect(addr).await.into_diagnostic()?);
+    let mut report = Replay::default();
+    let mut codes = vec![];
+    while codes.len() < greeting.len() {
+        let Some((code, _)) = read_reply(&mut control).await? else {
+            break;
+        };
+        codes.push(code);
+    }
+    if codes != greeting {
+        let exchange = Exchange {
+            line: 0,
+            command: "(greeting)".to_string(),
+            replies: greeting,
+        };
+        report.mismatches.push((exchange, codes));
+    }
+
+    let mut data: Option<TcpStream> = None;
+    for exchange in exchanges {
+        let keyword = exchange
+            .command
+            .split_whitespace()
+            .next()
+            .unwrap_or_default()
+            .to_uppercase();
+        if UNREPLAYABLE_COMMANDS.contains(&keyword.as_str()) {
+            report.skipped.push(exchange);
+            continue;
+        }
+
+        control
+            .write_all(format!("{}\r\n", exchange.command).as_bytes())
+            .await
+            .into_diagnostic()?;
+        let mut codes = vec![];
+        while codes.len() < exchange.replies.len() {
+            let Some((code, reply)) = read_reply(&mut control).await? else {
+                break;
+            };
+            codes.push(code);
+            match code {
+                227 => {
+                    data = match passive_address(&reply) {
+                        Some(addr) => TcpStream::connect(addr).await.ok(),
+                        None => None,
+                    };
+                }
+                125 | 150 => {
+                    if let Some(data) = data.take() {
+                        tokio::spawn(transfer(data, keyword.clone()));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        report.replayed += 1;
+        if codes != exchange.replies {
+            report.mismatches.push((exchange, codes));
+        }
+    }
+    Ok(report)
+}
+
+/// Uploads nothing and discards downloads, the data isn't recorded
+async fn transfer(mut data: TcpStream, keyword: String) {
+    if matches!(keyword.as_str(), "STOR" | "STOU" | "APPE") {
+        let _ = data.shutdown().await;
+    } else {
+        let mut sink = vec![];
+        let _ = data.read_to_end(&mut sink).await;
+    }
+}
+
+fn scratch_directory() -> Result<PathBuf> {
+    let path = std::env::temp_dir().join(format!(
+        "ftpy-replay-{}",
+        Alphanumeric.sample_string(&mut rand::thread_rng(), 8)
+    ));
+    std::fs::create_dir_all(&path).into_diagnostic()?;
+    Ok(path)
+}
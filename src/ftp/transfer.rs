@@ -0,0 +1,491 @@
+//! The data sent over the data connections, in the transfer mode selected
+//! with `MODE` and the representation type selected with `TYPE`.
+//!
+//! In `MODE Z` the data is a zlib stream, compressed and decompressed in
+//! chunks as it goes through the connection.
+//!
+//! In `TYPE A` line endings are sent as `CRLF`, converted from and to the
+//! `LF` of the files on the server.
+//!
+//! Check: https://datatracker.ietf.org/doc/html/draft-preston-ftpext-deflate-04
+//! Check: https://datatracker.ietf.org/doc/html/rfc959#section-3.1.1.1
+
+use std::{
+    io,
+    sync::{Arc, Mutex},
+};
+
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use miette::*;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::*;
+
+use crate::memory::{MemoryBudget, Reservation};
+use crate::{TransferMode, TransferType};
+
+/// Size of the buffers of compressed data
+const DEFLATE_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Size of the chunks converted to `CRLF` line endings at once
+const ASCII_CHUNK_SIZE: usize = 8 * 1024;
+
+/// Compression level of `MODE Z` until changed with `OPTS MODE Z LEVEL`
+pub const DEFAULT_DEFLATE_LEVEL: u32 = 6;
+
+/// The transfer a session is running, shared with its control loop so
+/// `STAT` can report it while it runs
+#[derive(Debug, Clone, Default)]
+pub struct TransferProgress(Arc<Mutex<Option<Progress>>>);
+
+#[derive(Debug)]
+struct Progress {
+    sending: bool,
+    path: String,
+    bytes: u64,
+}
+
+impl TransferProgress {
+    /// Records the transfer of `path`, sent to the client if `sending` or
+    /// received from it otherwise, until the returned [`Transfer`] is
+    /// dropped.
+    pub fn start(&self, sending: bool, path: &str) -> Transfer {
+        if let Ok(mut progress) = self.0.lock() {
+            *progress = Some(Progress {
+                sending,
+                path: path.to_string(),
+                bytes: 0,
+            });
+        }
+        Transfer(self.clone())
+    }
+
+    /// The transfer in progress, like `Sending /readme.txt, 4096 bytes so
+    /// far`, if any.
+    pub fn describe(&self) -> Option<String> {
+        let progress = self.0.lock().ok()?;
+        let Progress {
+            sending,
+            path,
+            bytes,
+        } = progress.as_ref()?;
+        let direction = if *sending { "Sending" } else { "Receiving" };
+        Some(format!("{direction} {path}, {bytes} bytes so far"))
+    }
+}
+
+/// A transfer in progress, forgotten once it's dropped
+#[derive(Debug)]
+pub struct Transfer(TransferProgress);
+
+impl Transfer {
+    /// Counts `bytes` more transferred.
+    pub fn add(&self, bytes: usize) {
+        if let Ok(mut progress) = self.0 .0.lock() {
+            if let Some(progress) = progress.as_mut() {
+                progress.bytes += bytes as u64;
+            }
+        }
+    }
+}
+
+impl Drop for Transfer {
+    fn drop(&mut self) {
+        if let Ok(mut progress) = self.0 .0.lock() {
+            *progress = None;
+        }
+    }
+}
+
+/// Writes the data of a transfer to the data connection
+pub struct DataWriter<'a, W> {
+    inner: &'a mut W,
+    deflate: Option<Deflate>,
+    ascii: Option<AsciiEncoder>,
+}
+
+struct Deflate {
+    compress: Compress,
+    buffer: Vec<u8>,
+    _memory: Reservation,
+}
+
+impl<'a, W: AsyncWrite + Unpin> DataWriter<'a, W> {
+    pub fn new(
+        inner: &'a mut W,
+        mode: TransferMode,
+        level: u32,
+        kind: TransferType,
+        memory: &MemoryBudget,
+    ) -> Result<Self> {
+        let deflate = match mode {
+            TransferMode::Stream => None,
+            TransferMode::Deflate => {
+                let (buffer, memory) = memory.buffer(DEFLATE_BUFFER_SIZE)?;
+                Some(Deflate {
+                    compress: Compress::new(Compression::new(level), true),
+                    buffer,
+                    _memory: memory,
+                })
+            }
+        };
+        let ascii = match kind {
+            TransferType::Image => None,
+            TransferType::Ascii => {
+                // Every byte may take two once converted
+                let (mut buffer, memory) = memory.buffer(2 * ASCII_CHUNK_SIZE)?;
+                buffer.clear();
+                Some(AsciiEncoder {
+                    buffer,
+                    last: 0,
+                    _memory: memory,
+                })
+            }
+        };
+        Ok(Self {
+            inner,
+            deflate,
+            ascii,
+        })
+    }
+
+    pub async fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        let Some(ascii) = &mut self.ascii else {
+            return send(self.inner, &mut self.deflate, data).await;
+        };
+        for chunk in data.chunks(ASCII_CHUNK_SIZE) {
+            ascii.encode(chunk);
+            send(self.inner, &mut self.deflate, &ascii.buffer).await?;
+        }
+        Ok(())
+    }
+
+    /// Ends the compressed stream, if any, and shuts the connection down.
+    pub async fn shutdown(&mut self) -> io::Result<()> {
+        if let Some(deflate) = &mut self.deflate {
+            loop {
+                let (_, produced, status) = deflate.compress(&[], FlushCompress::Finish)?;
+                self.inner.write_all(&deflate.buffer[..produced]).await?;
+                if status == Status::StreamEnd {
+                    break;
+                }
+            }
+        }
+        self.inner.shutdown().await
+    }
+}
+
+/// Sends `data` through the connection, compressed if `deflate` is set.
+async fn send<W: AsyncWrite + Unpin>(
+    inner: &mut W,
+    deflate: &mut Option<Deflate>,
+    mut data: &[u8],
+) -> io::Result<()> {
+    let Some(deflate) = deflate else {
+        return inner.write_all(data).await;
+    };
+    while !data.is_empty() {
+        let (consumed, produced, _) = deflate.compress(data, FlushCompress::None)?;
+        inner.write_all(&deflate.buffer[..produced]).await?;
+        data = &data[consumed..];
+    }
+    Ok(())
+}
+
+/// Converts bare `LF` line endings to `CRLF`
+struct AsciiEncoder {
+    buffer: Vec<u8>,
+    /// The last byte encoded, which may be the `CR` of a split `CRLF`
+    last: u8,
+    _memory: Reservation,
+}
+
+impl AsciiEncoder {
+    /// Encodes `chunk` into the buffer, which it replaces.
+    fn encode(&mut self, chunk: &[u8]) {
+        self.buffer.clear();
+        for &byte in chunk {
+            if byte == b'\n' && self.last != b'\r' {
+                self.buffer.push(b'\r');
+            }
+            self.buffer.push(byte);
+            self.last = byte;
+        }
+    }
+}
+
+impl Deflate {
+    /// Compresses `data` into the buffer, returning the bytes consumed and
+    /// produced.
+    fn compress(
+        &mut self,
+        data: &[u8],
+        flush: FlushCompress,
+    ) -> io::Result<(usize, usize, Status)> {
+        let (total_in, total_out) = (self.compress.total_in(), self.compress.total_out());
+        let status = self
+            .compress
+            .compress(data, &mut self.buffer, flush)
+            .map_err(io::Error::other)?;
+        Ok((
+            (self.compress.total_in() - total_in) as usize,
+            (self.compress.total_out() - total_out) as usize,
+            status,
+        ))
+    }
+}
+
+/// Reads the data of a transfer from the data connection
+pub struct DataReader<'a, R> {
+    inner: &'a mut R,
+    inflate: Option<Inflate>,
+    ascii: bool,
+    /// A `CR` ending the last read, held until it's known not to start a `CRLF`
+    pending_cr: bool,
+}
+
+struct Inflate {
+    decompress: Decompress,
+    buffer: Vec<u8>,
+    /// The compressed bytes of the buffer not decompressed yet
+    pending: std::ops::Range<usize>,
+    finished: bool,
+    _memory: Reservation,
+}
+
+impl<'a, R: AsyncRead + Unpin> DataReader<'a, R> {
+    pub fn new(
+        inner: &'a mut R,
+        mode: TransferMode,
+        kind: TransferType,
+        memory: &MemoryBudget,
+    ) -> Result<Self> {
+        let inflate = match mode {
+            TransferMode::Stream => None,
+            TransferMode::Deflate => {
+                let (buffer, memory) = memory.buffer(DEFLATE_BUFFER_SIZE)?;
+                Some(Inflate {
+                    decompress: Decompress::new(true),
+                    buffer,
+                    pending: 0..0,
+                    finished: false,
+                    _memory: memory,
+                })
+            }
+        };
+        Ok(Self {
+            inner,
+            inflate,
+            ascii: kind == TransferType::Ascii,
+            pending_cr: false,
+        })
+    }
+
+    /// Reads into `buffer`, returning `0` at the end of the transfer.
+    ///
+    /// In `TYPE A` `buffer` has to fit two bytes at least.
+    pub async fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        if !self.ascii {
+            return receive(self.inner, &mut self.inflate, buffer).await;
+        }
+        loop {
+            // The `CR` held from the last read goes first
+            let offset = usize::from(self.pending_cr);
+            if buffer.len() <= offset {
+                let error = "Buffers of ASCII transfers must fit two bytes";
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, error));
+            }
+            let read = receive(self.inner, &mut self.inflate, &mut buffer[offset..]).await?;
+            if read == 0 {
+                // A `CR` ending the transfer is kept as it is
+                if std::mem::take(&mut self.pending_cr) {
+                    buffer[0] = b'\r';
+                    return Ok(1);
+                }
+                return Ok(0);
+            }
+            if self.pending_cr {
+                buffer[0] = b'\r';
+            }
+            let end = offset + read;
+            let mut decoded = 0;
+            self.pending_cr = false;
+            for index in 0..end {
+                let byte = buffer[index];
+                if byte == b'\r' {
+                    match buffer.get(index + 1).filter(|_| index + 1 < end) {
+                        Some(b'\n') => continue,
+                        Some(_) => {}
+                        None => {
+                            self.pending_cr = true;
+                            continue;
+                        }
+                    }
+                }
+                buffer[decoded] = byte;
+                decoded += 1;
+            }
+            if decoded > 0 {
+                return Ok(decoded);
+            }
+        }
+    }
+}
+
+/// Receives from the connection into `buffer`, decompressed if `inflate` is
+/// set, returning `0` at the end of the transfer.
+async fn receive<R: AsyncRead + Unpin>(
+    inner: &mut R,
+    inflate: &mut Option<Inflate>,
+    buffer: &mut [u8],
+) -> io::Result<usize> {
+    let Some(inflate) = inflate else {
+        return inner.read(buffer).await;
+    };
+    while !inflate.finished && !buffer.is_empty() {
+        // After the end of the connection, what's left in the
+        // decompressor is drained
+        let mut closed = false;
+        if inflate.pending.is_empty() {
+            let read = inner.read(&mut inflate.buffer).await?;
+            inflate.pending = 0..read;
+            closed = read == 0;
+        }
+        let (total_in, total_out) = (
+            inflate.decompress.total_in(),
+            inflate.decompress.total_out(),
+        );
+        let status = inflate
+            .decompress
+            .decompress(
+                &inflate.buffer[inflate.pending.clone()],
+                buffer,
+                FlushDecompress::None,
+            )
+            .map_err(io::Error::other)?;
+        inflate.pending.start += (inflate.decompress.total_in() - total_in) as usize;
+        inflate.finished = status == Status::StreamEnd;
+        let produced = (inflate.decompress.total_out() - total_out) as usize;
+        if produced > 0 {
+            return Ok(produced);
+        }
+        if closed && !inflate.finished {
+            debug!("The compressed data ended before the end of its stream");
+            inflate.finished = true;
+        }
+    }
+    Ok(0)
+}
+
+impl<'a, R: AsyncRead + AsyncWrite + Unpin> DataReader<'a, R> {
+    pub async fn shutdown(&mut self) -> io::Result<()> {
+        self.inner.shutdown().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::VecDeque,
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    use tokio::io::ReadBuf;
+
+    use super::*;
+
+    /// A connection delivering every chunk in a read of its own
+    struct Chunks(VecDeque<&'static [u8]>);
+
+    impl AsyncRead for Chunks {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _context: &mut Context<'_>,
+            buffer: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            if let Some(chunk) = self.0.pop_front() {
+                buffer.put_slice(chunk);
+            }
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// What's received in `TYPE A` over `chunks`, read after read
+    async fn receive_ascii(chunks: &[&'static [u8]]) -> Vec<u8> {
+        let mut connection = Chunks(chunks.iter().copied().collect());
+        let memory = MemoryBudget::default();
+        let (mode, kind) = (TransferMode::Stream, TransferType::Ascii);
+        let mut reader = DataReader::new(&mut connection, mode, kind, &memory).unwrap();
+        let mut received = vec![];
+        let mut buffer = [0; 16];
+        loop {
+            match reader.read(&mut buffer).await.unwrap() {
+                0 => return received,
+                read => received.extend_from_slice(&buffer[..read]),
+            }
+        }
+    }
+
+    /// What's sent in `TYPE A` for `writes`, one after the other
+    async fn send_ascii(writes: &[&[u8]]) -> Vec<u8> {
+        let mut connection = vec![];
+        let memory = MemoryBudget::default();
+        let (mode, kind) = (TransferMode::Stream, TransferType::Ascii);
+        let mut writer = DataWriter::new(&mut connection, mode, 6, kind, &memory).unwrap();
+        for data in writes {
+            writer.write_all(data).await.unwrap();
+        }
+        writer.shutdown().await.unwrap();
+        connection
+    }
+
+    #[tokio::test]
+    async fn line_endings_split_across_reads_are_decoded() {
+        let received = receive_ascii(&[b"one\r", b"\ntwo\r\n"]).await;
+        assert_eq!(received, b"one\ntwo\n");
+
+        // A `CR` alone is data
+        let received = receive_ascii(&[b"one\r", b"two"]).await;
+        assert_eq!(received, b"one\rtwo");
+    }
+
+    #[tokio::test]
+    async fn a_carriage_return_ending_the_transfer_is_kept() {
+        assert_eq!(receive_ascii(&[b"one\r\ntwo\r"]).await, b"one\ntwo\r");
+        assert_eq!(receive_ascii(&[b"one", b"\r"]).await, b"one\r");
+    }
+
+    #[tokio::test]
+    async fn line_endings_are_encoded_once() {
+        assert_eq!(send_ascii(&[b"one\ntwo\r\n"]).await, b"one\r\ntwo\r\n");
+        // Even with the `CRLF` split between writes
+        assert_eq!(send_ascii(&[b"one\r", b"\ntwo\n"]).await, b"one\r\ntwo\r\n");
+    }
+
+    #[tokio::test]
+    async fn compressed_transfers_round_trip() {
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let memory = MemoryBudget::default();
+        let (mode, kind) = (TransferMode::Deflate, TransferType::Image);
+
+        let mut connection = vec![];
+        let mut writer = DataWriter::new(&mut connection, mode, 9, kind, &memory).unwrap();
+        for chunk in data.chunks(5000) {
+            writer.write_all(chunk).await.unwrap();
+        }
+        writer.shutdown().await.unwrap();
+        assert!(connection.len() < data.len());
+
+        let mut compressed = connection.as_slice();
+        let mut reader = DataReader::new(&mut compressed, mode, kind, &memory).unwrap();
+        let mut received = vec![];
+        let mut buffer = [0; 4096];
+        loop {
+            match reader.read(&mut buffer).await.unwrap() {
+                0 => break,
+                read => received.extend_from_slice(&buffer[..read]),
+            }
+        }
+        assert_eq!(received, data);
+    }
+}
@@ -0,0 +1,127 @@
+//! The writing half of the control connection.
+//!
+//! A single task owns the stream and writes the replies in the order they
+//! were sent, so replies from different code paths can't interleave on the wire.
+
+use tokio::{
+    io::{AsyncWriteExt, WriteHalf},
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+
+use miette::*;
+use tracing::*;
+
+use crate::debug::SessionDebug;
+use crate::lang::SessionLanguage;
+use crate::transcript::Recorder;
+use crate::{ControlStream, StatusCode};
+
+#[derive(Debug)]
+enum Message {
+    Reply(StatusCode),
+    /// Telnet negotiation, written as it is
+    Telnet(Vec<u8>),
+    /// Answered once every earlier message is written
+    Flush(oneshot::Sender<()>),
+    Shutdown(oneshot::Sender<()>),
+}
+
+/// Handle to the writer task of a control connection
+#[derive(Debug, Clone)]
+pub struct ControlWriter {
+    sender: mpsc::UnboundedSender<Message>,
+}
+
+/// The writer task, gives the stream back once every handle is dropped
+pub type WriterTask = JoinHandle<std::io::Result<WriteHalf<ControlStream>>>;
+
+impl ControlWriter {
+    /// Spawns the task writing the replies to `stream` in the language of
+    /// the session, to the transcript of the session if it's recorded, and
+    /// to its debug trace.
+    pub fn spawn(
+        stream: WriteHalf<ControlStream>,
+        recorder: Option<Recorder>,
+        debug: SessionDebug,
+        language: SessionLanguage,
+    ) -> (Self, WriterTask) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let task = tokio::spawn(write_replies(stream, receiver, recorder, debug, language));
+        (Self { sender }, task)
+    }
+
+    /// Queues a reply after every reply sent before it.
+    pub fn send(&self, reply: StatusCode) -> Result<()> {
+        self.sender
+            .send(Message::Reply(reply))
+            .map_err(|_| miette!("Control connection closed"))
+    }
+
+    /// Queues the answer to a Telnet negotiation after every reply sent
+    /// before it.
+    pub fn telnet(&self, answer: Vec<u8>) -> Result<()> {
+        self.sender
+            .send(Message::Telnet(answer))
+            .map_err(|_| miette!("Control connection closed"))
+    }
+
+    /// Waits until every reply sent before is written.
+    pub async fn flush(&self) -> Result<()> {
+        let (done, written) = oneshot::channel();
+        self.sender
+            .send(Message::Flush(done))
+            .map_err(|_| miette!("Control connection closed"))?;
+        written
+            .await
+            .map_err(|_| miette!("Control connection closed"))
+    }
+
+    /// Writes every reply sent before and shuts the stream down.
+    pub async fn shutdown(&self) -> Result<()> {
+        let (done, closed) = oneshot::channel();
+        self.sender
+            .send(Message::Shutdown(done))
+            .map_err(|_| miette!("Control connection closed"))?;
+        closed
+            .await
+            .map_err(|_| miette!("Control connection closed"))
+    }
+}
+
+async fn write_replies(
+    mut stream: WriteHalf<ControlStream>,
+    mut receiver: mpsc::UnboundedReceiver<Message>,
+    recorder: Option<Recorder>,
+    debug: SessionDebug,
+    language: SessionLanguage,
+) -> std::io::Result<WriteHalf<ControlStream>> {
+    while let Some(message) = receiver.recv().await {
+        match message {
+            Message::Reply(reply) => {
+                let reply = reply.to_string();
+                let reply = language.translate(&reply);
+                trace!("Writing {:?} to stream", reply);
+                stream.write_all(reply.as_bytes()).await?;
+                stream.flush().await?;
+                if let Some(recorder) = &recorder {
+                    recorder.reply(&reply);
+                }
+                debug.reply(&reply);
+            }
+            Message::Telnet(answer) => {
+                trace!("Writing Telnet answer {:?} to stream", answer);
+                stream.write_all(&answer).await?;
+                stream.flush().await?;
+            }
+            Message::Flush(done) => {
+                let _ = done.send(());
+            }
+            Message::Shutdown(done) => {
+                stream.shutdown().await?;
+                let _ = done.send(());
+            }
+        }
+    }
+    Ok(stream)
+}
@@ -0,0 +1,22 @@
+//! The FTP server behind `ftpy`, usable without the binary.
+//!
+//! Only the FTP core is built with `default-features = false`,
+//! enable `tls` for explicit FTPS.
+//!
+//! ```no_run
+//! use std::net::SocketAddr;
+//!
+//! use ftp_server::{config::Config, FTPServer};
+//!
+//! # async fn run() -> miette::Result<()> {
+//! let addr = SocketAddr::from(([127, 0, 0, 1], 2121));
+//! FTPServer::from((addr, Config::default())).listen().await
+//! # }
+//! ```
+
+pub mod config;
+pub mod ftp;
+pub mod parser;
+pub mod utils;
+
+pub use crate::ftp::*;
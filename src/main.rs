@@ -1,8 +1,6 @@
+#[cfg(feature = "tui")]
 mod app;
 mod cli;
-mod ftp;
-mod parser;
-mod utils;
 
 use std::io;
 use std::net::SocketAddr;
@@ -13,17 +11,38 @@ use tracing::*;
 use tracing_subscriber::filter::{EnvFilter, LevelFilter};
 use tracing_subscriber::prelude::*;
 
+use ftp_server::config::Config;
+use ftp_server::sandbox;
+#[cfg(feature = "tui")]
+use ftp_server::summary::Summary;
+use ftp_server::FTPServer;
+
+#[cfg(feature = "tui")]
 use crate::app::*;
 use crate::cli::*;
-use crate::ftp::*;
 
-#[tokio::main]
 #[instrument]
-async fn main() -> Result<()> {
+fn main() -> Result<()> {
     if let Some(cli) = Args::init_cli() {
+        if let Some(command) = &cli.command {
+            return runtime()?.block_on(command.run(&cli));
+        }
+
+        let mut config = match &cli.config {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+        config.process.chroot |= cli.chroot;
+        if !cli.interactive() {
+            // Before the logger and the runtime spawn their threads,
+            // which are confined along with this one
+            sandbox::restrict_filesystem(&config)?;
+        }
+
         let (non_blocking, _guard) = tracing_appender::non_blocking(io::stdout());
 
-        if cli.interactive {
+        if cli.interactive() {
+            #[cfg(feature = "tui")]
             tracing_subscriber::registry()
                 .with(tui_logger::tracing_subscriber_layer())
                 .init();
@@ -42,24 +61,39 @@ async fn main() -> Result<()> {
             warn!("You are currently running a debug build");
         }
 
-        if cli.interactive {
-            info!("Starting FTP server");
-            warn!("Currently interactive mode is WIP");
-
-            let mut terminal = init_terminal()?;
-            terminal.hide_cursor().into_diagnostic()?;
-            terminal.clear().into_diagnostic()?;
-
-            let mut app = App::default();
-            app.start(&mut terminal)?;
-            terminal.show_cursor().into_diagnostic()?;
-
-            restore_terminal()?;
+        let addr = SocketAddr::from(([127, 0, 0, 1], cli.port));
+        if cli.interactive() {
+            #[cfg(feature = "tui")]
+            run_interactive(&cli, Summary::new(addr, config.served_root()?, &config))?;
         } else {
-            let addr = SocketAddr::from(([127, 0, 0, 1], cli.port));
-            let mut server = FTPServer::from(addr);
-            server.listen().await?;
+            let mut server = FTPServer::from((addr, config));
+            runtime()?.block_on(server.listen())?;
         }
     }
     Ok(())
 }
+
+/// The runtime of the server, started only once the filesystem is confined.
+fn runtime() -> Result<tokio::runtime::Runtime> {
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .into_diagnostic()
+}
+
+#[cfg(feature = "tui")]
+fn run_interactive(cli: &Args, summary: Summary) -> Result<()> {
+    info!("Starting FTP server");
+    warn!("Currently interactive mode is WIP");
+
+    install_panic_hook();
+    let _guard = TerminalGuard;
+    let mut terminal = init_terminal()?;
+    terminal.hide_cursor().into_diagnostic()?;
+    terminal.clear().into_diagnostic()?;
+
+    let mut app = App::default()
+        .with_theme(Theme::from(ThemeName::resolve(cli.theme)))
+        .with_summary(summary.rows());
+    app.start(&mut terminal)
+}
@@ -1,8 +1,9 @@
 use nom::{
     bytes::complete::take_while1,
-    character::complete::multispace0,
+    character::complete::{char, multispace0},
+    combinator::{opt, rest},
     multi::many0,
-    sequence::{delimited, tuple},
+    sequence::{delimited, preceded, tuple},
     *,
 };
 
@@ -30,3 +31,12 @@ pub fn cmd_name_parser(input: &str) -> IResult<&str, &str> {
 pub fn cmd_parser(input: &str) -> IResult<&str, (&str, Vec<&str>)> {
     tuple((cmd_name_parser, cmd_arg_parser))(input)
 }
+
+/// Parses the command name and the rest of the line after the space
+/// following it as is, for arguments that may hold spaces
+pub fn cmd_text_parser(input: &str) -> IResult<&str, (&str, &str)> {
+    tuple((
+        preceded(multispace0, non_space),
+        preceded(opt(char(' ')), rest),
+    ))(input)
+}
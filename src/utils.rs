@@ -1,31 +1,30 @@
-use std::{fs::DirEntry, os::unix::fs::PermissionsExt};
+use std::{
+    borrow::Cow,
+    collections::BTreeSet,
+    fmt::{self, Write},
+    io,
+    path::Path,
+};
+#[cfg(unix)]
+use std::{
+    fs::Permissions,
+    os::{fd::AsRawFd, unix::fs::PermissionsExt},
+};
 
-use miette::*;
+use chrono::DateTime;
 
-pub fn permissions_to_string(permissions: u32) -> String {
-    let mut result = String::with_capacity(6);
-    let mask = [0b100, 0b010, 0b001]; // Mask for checking read, write, and execute permissions
-
-    for &m in &mask {
-        if permissions & m != 0 {
-            result.push('r');
-        } else {
-            result.push('-');
-        }
+use crate::config::SymlinkFacts;
+use crate::storage::{DirEntry, FileKind, Metadata, StorageBackend};
 
-        if permissions & (m << 3) != 0 {
-            result.push('w');
-        } else {
-            result.push('-');
-        }
-
-        if permissions & (m << 6) != 0 {
-            result.push('x');
-        } else {
-            result.push('-');
-        }
+pub fn permissions_to_string(permissions: u32) -> String {
+    let mut result = String::with_capacity(9);
+    // The owner, group and other bits, from the highest
+    for shift in [6, 3, 0] {
+        let bits = permissions >> shift;
+        result.push(if bits & 0b100 != 0 { 'r' } else { '-' });
+        result.push(if bits & 0b010 != 0 { 'w' } else { '-' });
+        result.push(if bits & 0b001 != 0 { 'x' } else { '-' });
     }
-
     result
 }
 
@@ -39,10 +38,8 @@ pub fn permissions_to_string(permissions: u32) -> String {
 /// If the file is a directory returns the appropiate permissions
 ///
 /// Check: https://datatracker.ietf.org/doc/html/rfc3659#section-7.5.5
-pub fn permissions_to_machine_string(entry: &DirEntry) -> Result<String> {
-    let metadata = entry.metadata().into_diagnostic()?;
-    let permissions = metadata.permissions();
-    let mode = permissions.mode();
+pub fn permissions_to_machine_string(metadata: &Metadata) -> String {
+    let mode = metadata.mode;
     let mask = [0b100, 0b010, 0b001]; // Mask for checking read, write, and execute permissions
     let mut result = String::with_capacity(9);
 
@@ -59,7 +56,7 @@ pub fn permissions_to_machine_string(entry: &DirEntry) -> Result<String> {
         if mode & 0o1000 != 0 {
             result.push('c');
         }
-        return Ok(result);
+        return result;
     }
 
     for &m in &mask {
@@ -69,5 +66,276 @@ pub fn permissions_to_machine_string(entry: &DirEntry) -> Result<String> {
             result.push('w');
         }
     }
-    Ok(result)
+    result
+}
+
+/// A fact of `MLSD` and `MLST` entries
+///
+/// Check: https://datatracker.ietf.org/doc/html/rfc3659#section-7.5
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Fact {
+    Type,
+    Size,
+    Modify,
+    Perm,
+}
+
+impl Fact {
+    /// Every supported fact, in the order they're sent
+    pub const ALL: [Fact; 4] = [Fact::Type, Fact::Size, Fact::Modify, Fact::Perm];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Fact::Type => "Type",
+            Fact::Size => "Size",
+            Fact::Modify => "Modify",
+            Fact::Perm => "Perm",
+        }
+    }
+
+    /// Fact names are case insensitive
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|fact| fact.name().eq_ignore_ascii_case(name))
+    }
+}
+
+/// The facts selected with `OPTS MLST`, every fact by default
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Facts(BTreeSet<Fact>);
+
+impl Default for Facts {
+    fn default() -> Self {
+        Self(Fact::ALL.into_iter().collect())
+    }
+}
+
+impl Facts {
+    /// Parses the `;` terminated list of `OPTS MLST`, ignoring unsupported facts
+    pub fn select(list: &str) -> Self {
+        Self(list.split(';').filter_map(Fact::from_name).collect())
+    }
+
+    pub fn contains(&self, fact: Fact) -> bool {
+        self.0.contains(&fact)
+    }
+
+    /// The `MLST` line of `FEAT`, with the selected facts marked with `*`
+    pub fn feature(&self) -> String {
+        Fact::ALL
+            .iter()
+            .fold(String::from("MLST "), |mut line, fact| {
+                let selected = if self.contains(*fact) { "*" } else { "" };
+                let _ = write!(line, "{}{};", fact.name(), selected);
+                line
+            })
+    }
+}
+
+impl fmt::Display for Facts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0
+            .iter()
+            .try_for_each(|fact| write!(f, "{};", fact.name()))
+    }
+}
+
+/// # Machine Facts
+/// ```text
+/// facts        = 1*( fact ";" )
+/// fact         = factname "=" value
+/// ```
+/// Formats the selected facts of a file as sent by `MLSD` and `MLST`,
+/// without the trailing pathname.
+/// `Modify` is always in UTC
+///
+/// Check: https://datatracker.ietf.org/doc/html/rfc3659#section-7.2
+pub fn machine_facts(metadata: &Metadata, facts: &Facts) -> String {
+    let mut result = String::new();
+    for fact in Fact::ALL.into_iter().filter(|fact| facts.contains(*fact)) {
+        let value = match fact {
+            Fact::Type => if metadata.is_dir() { "dir" } else { "file" }.to_string(),
+            Fact::Size => metadata.len.to_string(),
+            Fact::Modify => DateTime::<chrono::Utc>::from(metadata.modified)
+                .format("%Y%m%d%H%M%S")
+                .to_string(),
+            Fact::Perm => permissions_to_machine_string(metadata),
+        };
+        let _ = write!(result, "{}={};", fact.name(), value);
+    }
+    result
+}
+
+/// Formats the file at `path` of `storage`, with the `metadata` of the
+/// file itself, the way `ls -l` and `LIST` do, without the line
+/// terminator.
+///
+/// Symbolic links are listed with their target, and the size and date
+/// of the link or its target as selected by `symlinks`.
+pub async fn list_line(
+    name: &str,
+    storage: &dyn StorageBackend,
+    path: &Path,
+    metadata: &Metadata,
+    symlinks: SymlinkFacts,
+) -> String {
+    let (kind, name, facts) = match metadata.kind {
+        FileKind::Symlink => {
+            // A target out of the root can't be followed, like a broken link
+            let facts = match symlinks {
+                SymlinkFacts::Link => None,
+                SymlinkFacts::Target => storage.metadata(path).await.ok(),
+            };
+            let target = metadata.link.as_deref().unwrap_or(Path::new(""));
+            let name = format!("{} -> {}", name, target.display());
+            (
+                'l',
+                Cow::Owned(name),
+                facts.unwrap_or_else(|| metadata.clone()),
+            )
+        }
+        FileKind::Directory => ('d', Cow::Borrowed(name), metadata.clone()),
+        FileKind::File => ('-', Cow::Borrowed(name), metadata.clone()),
+    };
+    let permissions = permissions_to_string(metadata.mode);
+    let modified = DateTime::<chrono::Local>::from(facts.modified);
+    // Like `ls`, dates older than six months show the year instead of the time
+    let format = match chrono::Local::now().signed_duration_since(modified) {
+        age if age.num_days().abs() < 180 => "%b %e %H:%M",
+        _ => "%b %e  %Y",
+    };
+    format!(
+        "{}{} {} {} {} {} {} {}",
+        kind,
+        permissions,
+        metadata.nlink,
+        metadata.uid,
+        metadata.gid,
+        facts.len,
+        modified.format(format),
+        name
+    )
+}
+
+/// A path sent by a client with wildcards in its last component, like
+/// `data/2024-*`, as used by `mget` and mirroring scripts.
+#[derive(Debug, Clone)]
+pub struct Glob<'a> {
+    /// The directory part with its trailing slash, as sent, empty for the
+    /// working directory
+    pub directory: &'a str,
+    pattern: glob::Pattern,
+}
+
+impl<'a> Glob<'a> {
+    /// Parses `path` if its last component has any of `*`, `?` or `[`.
+    pub fn parse(path: &'a str) -> Option<Self> {
+        let (directory, name) = match path.rfind('/') {
+            Some(index) => (&path[..index + 1], &path[index + 1..]),
+            None => ("", path),
+        };
+        if !name.contains(['*', '?', '[']) {
+            return None;
+        }
+        let pattern = glob::Pattern::new(name).ok()?;
+        Some(Self { directory, pattern })
+    }
+
+    /// The `entries` of the directory, listed by the caller, matching the
+    /// pattern, sorted by name.
+    ///
+    /// Like in a shell, names starting with a dot only match patterns
+    /// starting with one.
+    pub fn matches(&self, entries: Vec<DirEntry>) -> Vec<DirEntry> {
+        let options = glob::MatchOptions {
+            require_literal_leading_dot: true,
+            ..Default::default()
+        };
+        let mut entries: Vec<_> = entries
+            .into_iter()
+            .filter(|entry| self.pattern.matches_with(&entry.name, options))
+            .collect();
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        entries
+    }
+
+    /// The name of a matching entry as the client would refer to it,
+    /// with the directory part it was sent with.
+    pub fn name(&self, entry: &DirEntry) -> String {
+        format!("{}{}", self.directory, entry.name)
+    }
+}
+
+/// Bytes left to unprivileged users on the filesystem holding `file`
+#[cfg(unix)]
+pub fn available_space(file: &impl AsRawFd) -> io::Result<u64> {
+    let mut stats = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: the file descriptor is valid for as long as `file` is borrowed
+    let result = unsafe { libc::fstatvfs(file.as_raw_fd(), stats.as_mut_ptr()) };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: filled in by `fstatvfs` on success
+    let stats = unsafe { stats.assume_init() };
+    // Narrower than 64 bits on some targets
+    #[allow(clippy::unnecessary_cast)]
+    let (blocks, block_size) = (stats.f_bavail as u64, stats.f_frsize as u64);
+    Ok(blocks.saturating_mul(block_size))
+}
+
+/// Reserves `size` bytes of disk space for `file` without changing its length
+#[cfg(target_os = "linux")]
+pub fn preallocate(file: &impl AsRawFd, size: u64) -> io::Result<()> {
+    let size =
+        libc::off_t::try_from(size).map_err(|_| io::Error::from_raw_os_error(libc::EFBIG))?;
+    // SAFETY: the file descriptor is valid for as long as `file` is borrowed
+    let result = unsafe { libc::fallocate(file.as_raw_fd(), libc::FALLOC_FL_KEEP_SIZE, 0, size) };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Bytes left on the filesystem holding `file`, which isn't measured off
+/// Unix, so every upload is taken to fit.
+#[cfg(not(unix))]
+pub fn available_space<F>(_file: &F) -> io::Result<u64> {
+    Ok(u64::MAX)
+}
+
+/// Reserves disk space for `file`, only possible on Linux.
+#[cfg(not(target_os = "linux"))]
+pub fn preallocate<F>(_file: &F, _size: u64) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "space can only be reserved on Linux",
+    ))
+}
+
+/// Returns `true` if `error` is the filesystem running out of space.
+pub fn is_disk_full(error: &io::Error) -> bool {
+    #[cfg(unix)]
+    let codes = [libc::ENOSPC];
+    // `ERROR_HANDLE_DISK_FULL` and `ERROR_DISK_FULL`
+    #[cfg(windows)]
+    let codes = [39, 112];
+    #[cfg(not(any(unix, windows)))]
+    let codes: [i32; 0] = [];
+    error
+        .raw_os_error()
+        .is_some_and(|code| codes.contains(&code))
+}
+
+/// Creates the directory at `path` with its parents, setting its `mode`
+/// bits if it's new, which only Unix has.
+pub fn create_dir(path: &Path, mode: Option<u32>) -> io::Result<()> {
+    let new = !path.exists();
+    std::fs::create_dir_all(path)?;
+    match mode.filter(|_| new) {
+        #[cfg(unix)]
+        Some(mode) => std::fs::set_permissions(path, Permissions::from_mode(mode)),
+        _ => Ok(()),
+    }
 }
@@ -0,0 +1,154 @@
+//! A server on a port of its own, and just enough of a client to drive it.
+
+#![allow(dead_code)]
+
+use std::{net::SocketAddr, time::Duration};
+
+use ftp_server::{config::Config, FTPServer};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+/// Starts a server with `config`, returning its address.
+pub async fn serve(config: Config) -> SocketAddr {
+    serve_with(FTPServer::from((
+        SocketAddr::from(([127, 0, 0, 1], 0)),
+        config,
+    )))
+    .await
+}
+
+/// Starts `server` on a port of its own, returning its address.
+pub async fn serve_with(mut server: FTPServer) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { server.serve(listener).await });
+    addr
+}
+
+/// Parses `config` as a config file would be, with `{root}` replaced by
+/// `root`.
+pub fn config(root: &std::path::Path, config: &str) -> Config {
+    let config = config.replace("{root}", &root.to_string_lossy());
+    let mut config: Config = toml::from_str(&config).unwrap();
+    config.root = Some(root.to_path_buf());
+    config.prepare().unwrap();
+    config
+}
+
+pub struct Client {
+    control: BufReader<TcpStream>,
+}
+
+impl Client {
+    /// Connects to the server at `addr`, past its greeting.
+    pub async fn connect(addr: SocketAddr) -> Self {
+        let mut client = Self {
+            control: BufReader::new(TcpStream::connect(addr).await.unwrap()),
+        };
+        assert_eq!(client.reply().await.0, 220);
+        client
+    }
+
+    /// Connects and logs in as `user` with `password`.
+    pub async fn login(addr: SocketAddr, user: &str, password: &str) -> Self {
+        let mut client = Self::connect(addr).await;
+        assert_eq!(client.command(&format!("USER {user}")).await.0, 331);
+        assert_eq!(client.command(&format!("PASS {password}")).await.0, 230);
+        client
+    }
+
+    /// Sends `command`, returning the code and text of its first reply.
+    pub async fn command(&mut self, command: &str) -> (u16, String) {
+        self.control
+            .write_all(format!("{command}\r\n").as_bytes())
+            .await
+            .unwrap();
+        self.reply().await
+    }
+
+    /// Sends `command`, returning the code and every line of its first
+    /// reply.
+    pub async fn command_lines(&mut self, command: &str) -> (u16, Vec<String>) {
+        self.control
+            .write_all(format!("{command}\r\n").as_bytes())
+            .await
+            .unwrap();
+        self.reply_lines().await
+    }
+
+    /// The code and last line of the next reply.
+    pub async fn reply(&mut self) -> (u16, String) {
+        let (code, mut lines) = self.reply_lines().await;
+        (code, lines.pop().unwrap_or_default())
+    }
+
+    /// The code and every line of the next reply.
+    pub async fn reply_lines(&mut self) -> (u16, Vec<String>) {
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            let read = tokio::time::timeout(Duration::from_secs(10), async {
+                self.control.read_line(&mut line).await
+            })
+            .await
+            .expect("No reply")
+            .unwrap();
+            assert!(read > 0, "The control connection was closed");
+            let line = line.trim_end().to_string();
+            // Text lines may have a space fourth, but don't start with a code
+            let last = line.len() >= 4
+                && line.as_bytes()[..3].iter().all(u8::is_ascii_digit)
+                && line.as_bytes()[3] == b' ';
+            lines.push(line);
+            if last {
+                return (lines[lines.len() - 1][..3].parse().unwrap(), lines);
+            }
+        }
+    }
+
+    /// Opens a data connection with `PASV`.
+    pub async fn passive(&mut self) -> TcpStream {
+        let (code, reply) = self.command("PASV").await;
+        assert_eq!(code, 227, "{reply}");
+        let start = reply.find('(').unwrap() + 1;
+        let end = reply.find(')').unwrap();
+        let numbers: Vec<u8> = reply[start..end]
+            .split(',')
+            .map(|number| number.trim().parse().unwrap())
+            .collect();
+        let addr = SocketAddr::from((
+            [numbers[0], numbers[1], numbers[2], numbers[3]],
+            u16::from_be_bytes([numbers[4], numbers[5]]),
+        ));
+        TcpStream::connect(addr).await.unwrap()
+    }
+
+    /// Uploads `contents` with `command`, like `STOR name`, returning the
+    /// code of the final reply.
+    pub async fn upload(&mut self, command: &str, contents: &[u8]) -> u16 {
+        let mut data = self.passive().await;
+        let (code, _) = self.command(command).await;
+        if !(100..200).contains(&code) {
+            return code;
+        }
+        // The server may stop reading once it refuses the upload
+        let _ = data.write_all(contents).await;
+        let _ = data.shutdown().await;
+        self.reply().await.0
+    }
+
+    /// Downloads with `command`, like `RETR name`, returning the code of
+    /// the final reply and what was received.
+    pub async fn download(&mut self, command: &str) -> (u16, Vec<u8>) {
+        let mut data = self.passive().await;
+        let (code, _) = self.command(command).await;
+        if !(100..200).contains(&code) {
+            return (code, Vec::new());
+        }
+        let mut contents = Vec::new();
+        data.read_to_end(&mut contents).await.unwrap();
+        (self.reply().await.0, contents)
+    }
+}
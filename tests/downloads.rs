@@ -0,0 +1,22 @@
+//! Downloads of files and of directory archives.
+
+mod common;
+
+use common::{serve, Client};
+
+#[tokio::test]
+async fn directories_are_only_sent_as_archives() {
+    let root = tempfile::tempdir().unwrap();
+    std::fs::create_dir(root.path().join("docs")).unwrap();
+    std::fs::write(root.path().join("docs/readme.txt"), b"hello").unwrap();
+    let config = "[auth]\nallow_any = true\n[data]\narchives = true";
+    let addr = serve(common::config(root.path(), config)).await;
+    let mut client = Client::login(addr, "alice", "secret").await;
+
+    let (code, _) = client.download("RETR docs").await;
+    assert_eq!(code, 550);
+
+    let (code, archive) = client.download("RETR docs.tar").await;
+    assert_eq!(code, 226);
+    assert!(archive.windows(5).any(|window| window == b"hello"));
+}
@@ -0,0 +1,60 @@
+//! `FEAT` and the options of the features it advertises.
+
+mod common;
+
+use common::{serve, Client};
+
+/// A session logged in to a server configured with `config`
+async fn session(config: &str) -> Client {
+    // No file is used, the root only has to be a directory
+    let config = format!("[auth]\nallow_any = true\n{config}");
+    let addr = serve(common::config(&std::env::temp_dir(), &config)).await;
+    Client::login(addr, "alice", "secret").await
+}
+
+/// The keywords of the features `FEAT` advertises
+async fn features(client: &mut Client) -> Vec<String> {
+    let (code, lines) = client.command_lines("FEAT").await;
+    assert_eq!(code, 211);
+    lines
+        .iter()
+        .filter_map(|line| line.strip_prefix(' '))
+        .map(|feature| feature.split(' ').next().unwrap_or_default().to_string())
+        .collect()
+}
+
+#[tokio::test]
+async fn options_of_advertised_features_are_accepted() {
+    let mut client = session("").await;
+    let features = features(&mut client).await;
+
+    for feature in ["UTF8", "MLST", "HASH", "MODE"] {
+        assert!(
+            features.iter().any(|advertised| advertised == feature),
+            "{feature} in {features:?}"
+        );
+    }
+    assert_eq!(client.command("OPTS UTF8 ON").await.0, 200);
+    assert_eq!(client.command("OPTS MLST type;size;").await.0, 200);
+    assert_eq!(client.command("OPTS HASH SHA-256").await.0, 200);
+    assert_eq!(client.command("OPTS MODE Z LEVEL 9").await.0, 200);
+}
+
+#[tokio::test]
+async fn options_of_other_features_are_refused() {
+    let mut client = session("[commands]\ndisabled = [\"MLST\", \"HASH\"]").await;
+    let features = features(&mut client).await;
+
+    assert!(
+        !features.iter().any(|feature| feature == "MLST"),
+        "{features:?}"
+    );
+    assert!(
+        !features.iter().any(|feature| feature == "HASH"),
+        "{features:?}"
+    );
+    assert_eq!(client.command("OPTS MLST type;size;").await.0, 501);
+    assert_eq!(client.command("OPTS HASH SHA-256").await.0, 501);
+    // Never advertised at all
+    assert_eq!(client.command("OPTS XYZZY ON").await.0, 501);
+}
@@ -0,0 +1,116 @@
+//! Logging in with `USER` and `PASS`.
+
+mod common;
+
+use tokio::{io::AsyncReadExt, net::TcpStream};
+
+use common::{config, serve, Client};
+
+/// The code `PASS` gets after `USER`
+async fn login(client: &mut Client, user: &str, password: &str) -> u16 {
+    assert_eq!(client.command(&format!("USER {user}")).await.0, 331);
+    client.command(&format!("PASS {password}")).await.0
+}
+
+#[tokio::test]
+async fn only_configured_users_log_in() {
+    let root = tempfile::tempdir().unwrap();
+    let config = config(
+        root.path(),
+        r#"
+        [[users]]
+        name = "alice"
+        password = "secret"
+        "#,
+    );
+    let addr = serve(config).await;
+    let mut client = Client::connect(addr).await;
+
+    assert_eq!(login(&mut client, "alice", "wrong").await, 530);
+    assert_eq!(login(&mut client, "mallory", "secret").await, 530);
+    assert_eq!(client.command("PWD").await.0, 530);
+    assert_eq!(login(&mut client, "alice", "secret").await, 230);
+}
+
+#[tokio::test]
+async fn no_users_let_no_one_in() {
+    let root = tempfile::tempdir().unwrap();
+    let addr = serve(config(root.path(), "")).await;
+    let mut client = Client::connect(addr).await;
+
+    assert_eq!(login(&mut client, "alice", "secret").await, 530);
+}
+
+#[tokio::test]
+async fn no_users_let_anyone_in_if_allowed() {
+    let root = tempfile::tempdir().unwrap();
+    let config = config(
+        root.path(),
+        r#"
+        [auth]
+        allow_any = true
+        "#,
+    );
+    let addr = serve(config).await;
+    let mut client = Client::connect(addr).await;
+
+    assert_eq!(login(&mut client, "alice", "anything").await, 230);
+}
+
+#[tokio::test]
+async fn passwords_are_the_rest_of_the_line() {
+    let root = tempfile::tempdir().unwrap();
+    let config = config(
+        root.path(),
+        r#"
+        [[users]]
+        name = "alice"
+        password = "correct horse battery"
+
+        [[users]]
+        name = "bob"
+        password = "anonymous"
+        "#,
+    );
+    let addr = serve(config).await;
+    let mut client = Client::connect(addr).await;
+
+    assert_eq!(login(&mut client, "alice", "correct horse").await, 530);
+    assert_eq!(
+        login(&mut client, "alice", "correct horse battery").await,
+        230
+    );
+
+    // A missing password is no password at all
+    let mut client = Client::connect(addr).await;
+    assert_eq!(client.command("USER bob").await.0, 331);
+    assert_eq!(client.command("PASS").await.0, 530);
+}
+
+#[tokio::test]
+async fn banned_clients_are_refused() {
+    let root = tempfile::tempdir().unwrap();
+    let config = config(
+        root.path(),
+        r#"
+        [[users]]
+        name = "alice"
+        password = "secret"
+
+        [throttle]
+        max_failures = 2
+        delay_ms = 0
+        "#,
+    );
+    let addr = serve(config).await;
+    let mut client = Client::connect(addr).await;
+
+    assert_eq!(login(&mut client, "alice", "wrong").await, 530);
+    assert_eq!(login(&mut client, "alice", "wrong").await, 421);
+
+    // Refused before the greeting
+    let mut control = TcpStream::connect(addr).await.unwrap();
+    let mut reply = String::new();
+    control.read_to_string(&mut reply).await.unwrap();
+    assert!(reply.starts_with("421 "), "{reply}");
+}
@@ -0,0 +1,131 @@
+//! Permissions of users, and the `[[acl]]` rules changing them by path.
+
+mod common;
+
+use common::{config, serve, Client};
+
+#[tokio::test]
+async fn read_only_users_cant_upload() {
+    let root = tempfile::tempdir().unwrap();
+    std::fs::write(root.path().join("readme.txt"), "hello\n").unwrap();
+    let config = config(
+        root.path(),
+        r#"
+        [[users]]
+        name = "reader"
+        password = "secret"
+        home = "{root}"
+        permissions = ["list", "download"]
+        "#,
+    );
+    let addr = serve(config).await;
+    let mut client = Client::login(addr, "reader", "secret").await;
+
+    assert_eq!(client.upload("STOR new.txt", b"new\n").await, 550);
+    assert_eq!(client.upload("APPE readme.txt", b"more\n").await, 550);
+    assert!(!root.path().join("new.txt").exists());
+    let (code, contents) = client.download("RETR readme.txt").await;
+    assert_eq!((code, contents.as_slice()), (226, b"hello\n".as_slice()));
+}
+
+#[tokio::test]
+async fn rules_override_users_by_path() {
+    let root = tempfile::tempdir().unwrap();
+    for directory in ["incoming/locked", "private"] {
+        std::fs::create_dir_all(root.path().join(directory)).unwrap();
+    }
+    std::fs::write(root.path().join("private/notes.txt"), "notes\n").unwrap();
+    let config = config(
+        root.path(),
+        r#"
+        [[users]]
+        name = "acme"
+        password = "secret"
+        home = "{root}"
+        permissions = ["list", "download"]
+        groups = ["partners"]
+
+        [[users]]
+        name = "bob"
+        password = "secret"
+        home = "{root}"
+
+        [[acl]]
+        path = "/incoming"
+        groups = ["partners"]
+        allow = ["upload"]
+
+        [[acl]]
+        path = "/incoming/locked"
+        deny = ["upload"]
+
+        [[acl]]
+        path = "/private"
+        users = ["bob"]
+        deny = ["download"]
+        "#,
+    );
+    let addr = serve(config).await;
+
+    let mut acme = Client::login(addr, "acme", "secret").await;
+    assert_eq!(
+        acme.upload("STOR incoming/order.txt", b"order\n").await,
+        226
+    );
+    assert_eq!(acme.upload("STOR order.txt", b"order\n").await, 550);
+    assert_eq!(
+        acme.upload("STOR incoming/locked/order.txt", b"order\n")
+            .await,
+        550
+    );
+    assert_eq!(acme.download("RETR private/notes.txt").await.0, 226);
+
+    let mut bob = Client::login(addr, "bob", "secret").await;
+    assert_eq!(bob.download("RETR private/notes.txt").await.0, 550);
+    assert_eq!(bob.upload("STOR private/todo.txt", b"todo\n").await, 226);
+    assert_eq!(
+        bob.upload("STOR incoming/locked/order.txt", b"order\n")
+            .await,
+        550
+    );
+}
+
+#[tokio::test]
+async fn replacing_files_needs_delete() {
+    let root = tempfile::tempdir().unwrap();
+    std::fs::write(root.path().join("report.txt"), "draft\n").unwrap();
+    let config = config(
+        root.path(),
+        r#"
+        [[users]]
+        name = "dropbox"
+        password = "secret"
+        home = "{root}"
+        permissions = ["list", "upload"]
+
+        [[users]]
+        name = "editor"
+        password = "secret"
+        home = "{root}"
+        permissions = ["list", "upload", "delete"]
+        "#,
+    );
+    let addr = serve(config).await;
+    let report = root.path().join("report.txt");
+
+    let mut dropbox = Client::login(addr, "dropbox", "secret").await;
+    assert_eq!(dropbox.upload("STOR new.txt", b"new\n").await, 226);
+    assert_eq!(dropbox.upload("STOR report.txt", b"final\n").await, 550);
+    assert_eq!(dropbox.command("REST 2").await.0, 350);
+    assert_eq!(dropbox.upload("STOR report.txt", b"final\n").await, 550);
+    assert_eq!(std::fs::read(&report).unwrap(), b"draft\n");
+    // Resuming at the end or appending keeps what's there
+    assert_eq!(dropbox.command("REST 6").await.0, 350);
+    assert_eq!(dropbox.upload("STOR report.txt", b"more\n").await, 226);
+    assert_eq!(dropbox.upload("APPE report.txt", b"end\n").await, 226);
+    assert_eq!(std::fs::read(&report).unwrap(), b"draft\nmore\nend\n");
+
+    let mut editor = Client::login(addr, "editor", "secret").await;
+    assert_eq!(editor.upload("STOR report.txt", b"final\n").await, 226);
+    assert_eq!(std::fs::read(&report).unwrap(), b"final\n");
+}
@@ -0,0 +1,64 @@
+//! Replays the transcripts of `tests/transcripts` against a server keeping
+//! its files in memory, which every one of them expects the same replies
+//! from.
+//!
+//! A transcript recorded with `[record]` can be added as is, once the files
+//! it uses are seeded below.
+
+use std::{net::SocketAddr, path::Path};
+
+use ftp_server::{
+    config::{AuthConfig, Config},
+    storage::Memory,
+    transcript, FTPServer,
+};
+
+/// The files the transcripts were recorded against
+fn files() -> Memory {
+    Memory::default()
+        .with_file("readme.txt", "hello\n")
+        .with_file("docs/guide.txt", "guide\n")
+}
+
+#[tokio::test]
+async fn transcripts_replay() {
+    let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/transcripts");
+    let mut paths: Vec<_> = std::fs::read_dir(&directory)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "ftp"))
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty(), "No transcripts in {directory:?}");
+
+    for path in paths {
+        let transcript = std::fs::read_to_string(&path).unwrap();
+        // Only checked to be a directory, the files are the ones in memory.
+        // The credentials are masked, so anyone is let in
+        let config = Config {
+            root: Some(std::env::temp_dir()),
+            auth: AuthConfig {
+                allow_any: true,
+                ..AuthConfig::default()
+            },
+            ..Config::default()
+        };
+        let mut server = FTPServer::from((SocketAddr::from(([127, 0, 0, 1], 0)), config));
+        server.storage(files());
+
+        let report = transcript::replay_against(&transcript, server)
+            .await
+            .unwrap();
+        assert!(report.replayed > 0, "Nothing replayed from {path:?}");
+        assert!(
+            report.skipped.is_empty(),
+            "{path:?} skipped {:?}",
+            report.skipped
+        );
+        assert!(
+            report.mismatches.is_empty(),
+            "{path:?} mismatched {:?}",
+            report.mismatches
+        );
+    }
+}
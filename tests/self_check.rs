@@ -0,0 +1,36 @@
+//! The self-check against a running server.
+
+mod common;
+
+use common::{config, serve};
+use ftp_server::self_check::self_check;
+
+/// Checks a server configured with `passive`, returning the findings.
+async fn check(passive: &str) -> Vec<String> {
+    let config = config(
+        &std::env::temp_dir(),
+        &format!(
+            r#"
+            [[users]]
+            name = "alice"
+            password = "secret"
+
+            [passive]
+            {passive}
+            "#
+        ),
+    );
+    let addr = serve(config.clone()).await;
+    let findings = self_check(addr, &config, None).await.unwrap();
+    findings.iter().map(ToString::to_string).collect()
+}
+
+#[tokio::test]
+async fn passive_connections_are_reachable() {
+    assert_eq!(check("").await, Vec::<String>::new());
+}
+
+#[tokio::test]
+async fn passive_connections_send_their_token() {
+    assert_eq!(check("data_token = true").await, Vec::<String>::new());
+}
@@ -0,0 +1,85 @@
+//! `STAT` while a transfer runs.
+
+mod common;
+
+use tokio::io::AsyncReadExt;
+
+use common::{serve, Client};
+
+#[tokio::test]
+async fn status_reports_the_transfer_in_progress() {
+    let root = tempfile::tempdir().unwrap();
+    // Too big to fit in the socket buffers, so the transfer waits for it to
+    // be read
+    let contents = vec![b'x'; 64 * 1024 * 1024];
+    std::fs::write(root.path().join("big.bin"), &contents).unwrap();
+    let addr = serve(common::config(root.path(), "[auth]\nallow_any = true")).await;
+    let mut client = Client::login(addr, "alice", "secret").await;
+
+    let (code, lines) = client.command_lines("STAT").await;
+    assert_eq!(code, 211);
+    assert!(
+        lines
+            .iter()
+            .any(|line| line == " No data transfer in progress"),
+        "{lines:?}"
+    );
+
+    let mut data = client.passive().await;
+    assert_eq!(client.command("RETR big.bin").await.0, 125);
+    let (code, lines) = client.command_lines("STAT").await;
+    assert_eq!(code, 211);
+    assert!(
+        lines
+            .iter()
+            .any(|line| line.starts_with(" Sending /big.bin, ")),
+        "{lines:?}"
+    );
+    assert!(
+        lines.iter().any(|line| line == " Data connection is open"),
+        "{lines:?}"
+    );
+
+    let mut received = Vec::new();
+    data.read_to_end(&mut received).await.unwrap();
+    assert_eq!(received.len(), contents.len());
+    assert_eq!(client.reply().await.0, 226);
+
+    let (code, lines) = client.command_lines("STAT").await;
+    assert_eq!(code, 211);
+    assert!(
+        lines
+            .iter()
+            .any(|line| line == " No data transfer in progress"),
+        "{lines:?}"
+    );
+}
+
+#[tokio::test]
+async fn status_reports_the_listing_in_progress() {
+    let root = tempfile::tempdir().unwrap();
+    let many = root.path().join("many");
+    std::fs::create_dir(&many).unwrap();
+    // Listed in more than the socket buffers hold
+    for index in 0..30_000 {
+        let name = format!("{index:0>200}");
+        std::fs::write(many.join(name), b"").unwrap();
+    }
+    let addr = serve(common::config(root.path(), "[auth]\nallow_any = true")).await;
+    let mut client = Client::login(addr, "alice", "secret").await;
+
+    let mut data = client.passive().await;
+    assert_eq!(client.command("LIST many").await.0, 125);
+    let (code, lines) = client.command_lines("STAT").await;
+    assert_eq!(code, 211);
+    assert!(
+        lines
+            .iter()
+            .any(|line| line.starts_with(" Sending /many, ")),
+        "{lines:?}"
+    );
+
+    let mut listing = Vec::new();
+    data.read_to_end(&mut listing).await.unwrap();
+    assert_eq!(client.reply().await.0, 226);
+}
@@ -0,0 +1,292 @@
+//! Uploads refused part way, which must leave nothing behind.
+
+mod common;
+
+use std::{net::SocketAddr, path::Path};
+
+use tokio::{io::AsyncWriteExt, net::TcpStream};
+
+use common::{config, serve, serve_with, Client};
+use ftp_server::{storage::Memory, FTPServer};
+
+/// The names in `directory` of files being uploaded
+fn leftovers(directory: &Path) -> Vec<String> {
+    std::fs::read_dir(directory)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .filter(|name| name.starts_with(".in."))
+        .collect()
+}
+
+#[tokio::test]
+async fn uploads_past_the_quota_leave_nothing_behind() {
+    let root = tempfile::tempdir().unwrap();
+    let home = root.path().join("alice");
+    std::fs::create_dir(&home).unwrap();
+    let config = config(
+        root.path(),
+        r#"
+        [[users]]
+        name = "alice"
+        password = "secret"
+        home = "{root}/alice"
+        quota = 1000
+        "#,
+    );
+    let addr = serve(config).await;
+    let mut client = Client::login(addr, "alice", "secret").await;
+
+    // Refused as it's received
+    let code = client.upload("STOR big.bin", &[0; 4096]).await;
+    assert_eq!(code, 552);
+    assert!(!home.join("big.bin").exists());
+    assert_eq!(leftovers(&home), Vec::<String>::new());
+
+    // Refused from the size announced beforehand, once another session of
+    // the user filled up the home after it was announced
+    let mut other = Client::login(addr, "alice", "secret").await;
+    assert_eq!(client.command("ALLO 900").await.0, 202);
+    assert_eq!(other.upload("STOR small.bin", &[1; 500]).await, 226);
+    let code = client.upload("STOR big.bin", &[0; 900]).await;
+    assert_eq!(code, 552);
+    assert_eq!(leftovers(&home), Vec::<String>::new());
+
+    // Restarting a file doesn't cut it when refused
+    assert_eq!(client.command("ALLO 500").await.0, 202);
+    assert_eq!(other.upload("STOR other.bin", &[2; 350]).await, 226);
+    assert_eq!(client.command("REST 200").await.0, 350);
+    let code = client.upload("STOR small.bin", &[0; 500]).await;
+    assert_eq!(code, 552);
+    assert_eq!(std::fs::read(home.join("small.bin")).unwrap(), [1; 500]);
+
+    assert_eq!(client.upload("STOR fits.bin", &[0; 100]).await, 226);
+    assert_eq!(leftovers(&home), Vec::<String>::new());
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn post_processed_uploads_leave_no_stale_listings() {
+    let root = tempfile::tempdir().unwrap();
+    let incoming = root.path().join("incoming");
+    std::fs::create_dir(&incoming).unwrap();
+    let config = config(
+        root.path(),
+        r#"
+        [[users]]
+        name = "alice"
+        password = "secret"
+
+        [listing]
+        cache_seconds = 60
+
+        [[uploads.mounts]]
+        path = "incoming"
+        actions = [
+            { action = "hook", command = "sh", args = ["-c", "sleep 0.5", "sh"] },
+            { action = "gzip" },
+        ]
+        "#,
+    );
+    let addr = serve(config).await;
+    let mut client = Client::login(addr, "alice", "secret").await;
+
+    assert_eq!(
+        client.upload("STOR incoming/report.txt", b"report").await,
+        226
+    );
+    // Listed, and cached, before it's compressed
+    let (_, listing) = client.download("NLST incoming").await;
+    assert!(String::from_utf8(listing).unwrap().contains("report.txt"));
+    for _ in 0..100 {
+        if !incoming.join("report.txt").exists() {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    assert!(incoming.join("report.txt.gz").exists());
+
+    let (code, listing) = client.download("NLST incoming").await;
+    assert_eq!(code, 226);
+    let listing = String::from_utf8(listing).unwrap();
+    assert!(listing.contains("report.txt.gz"), "{listing}");
+    assert!(
+        !listing.lines().any(|name| name.ends_with("report.txt")),
+        "{listing}"
+    );
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn post_upload_actions_replace_nothing() {
+    let root = tempfile::tempdir().unwrap();
+    let incoming = root.path().join("incoming");
+    std::fs::create_dir(&incoming).unwrap();
+    std::fs::write(incoming.join("report.txt.gz"), b"kept").unwrap();
+    let config = config(
+        root.path(),
+        r#"
+        [[users]]
+        name = "alice"
+        password = "secret"
+
+        [uploads]
+        workers = 1
+
+        [[uploads.mounts]]
+        path = "incoming"
+        actions = [{ action = "gzip" }]
+        "#,
+    );
+    let addr = serve(config).await;
+    let mut client = Client::login(addr, "alice", "secret").await;
+
+    assert_eq!(
+        client.upload("STOR incoming/report.txt", b"report").await,
+        226
+    );
+    assert_eq!(
+        client.upload("STOR incoming/other.txt", b"other").await,
+        226
+    );
+    // Jobs run in order with a single worker
+    wait_for(&incoming.join("other.txt.gz")).await;
+
+    assert_eq!(
+        std::fs::read(incoming.join("report.txt.gz")).unwrap(),
+        b"kept"
+    );
+    assert_eq!(
+        std::fs::read(incoming.join("report.txt")).unwrap(),
+        b"report"
+    );
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn hung_hooks_are_killed() {
+    let root = tempfile::tempdir().unwrap();
+    for directory in ["slow", "fast"] {
+        std::fs::create_dir(root.path().join(directory)).unwrap();
+    }
+    let config = config(
+        root.path(),
+        r#"
+        [[users]]
+        name = "alice"
+        password = "secret"
+
+        [uploads]
+        workers = 1
+        hook_timeout_seconds = 1
+
+        [[uploads.mounts]]
+        path = "slow"
+        actions = [{ action = "hook", command = "sh", args = ["-c", "sleep 60", "sh"] }]
+
+        [[uploads.mounts]]
+        path = "fast"
+        actions = [{ action = "gzip" }]
+        "#,
+    );
+    let addr = serve(config).await;
+    let mut client = Client::login(addr, "alice", "secret").await;
+
+    assert_eq!(client.upload("STOR slow/hangs.txt", b"hangs").await, 226);
+    assert_eq!(client.upload("STOR fast/next.txt", b"next").await, 226);
+    // The only worker is free again once the hook is killed
+    wait_for(&root.path().join("fast/next.txt.gz")).await;
+}
+
+/// Waits for a post-upload action to create `path`, for a few seconds.
+async fn wait_for(path: &Path) {
+    for _ in 0..250 {
+        if path.exists() {
+            return;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    panic!("{path:?} was never created");
+}
+
+#[tokio::test]
+async fn quotas_are_measured_in_the_storage() {
+    // The home only exists in memory
+    let root = tempfile::tempdir().unwrap();
+    let config = config(
+        root.path(),
+        r#"
+        [[users]]
+        name = "alice"
+        password = "secret"
+        home = "{root}/alice"
+        quota = 1000
+        "#,
+    );
+    let mut server = FTPServer::from((SocketAddr::from(([127, 0, 0, 1], 0)), config));
+    server.storage(Memory::default().with_file("old.bin", [1; 500]));
+    let addr = serve_with(server).await;
+    let mut client = Client::login(addr, "alice", "secret").await;
+
+    assert_eq!(client.command("ALLO 600").await.0, 552);
+    assert_eq!(client.upload("STOR big.bin", &[0; 600]).await, 552);
+    // What a refused upload would have replaced is left as it was
+    assert_eq!(client.upload("STOR old.bin", &[0; 600]).await, 552);
+    assert_eq!(client.download("RETR old.bin").await, (226, vec![1; 500]));
+    assert_eq!(client.upload("STOR fits.bin", &[0; 400]).await, 226);
+
+    // Written in place, with nothing moved over it
+    let (code, listing) = client.download("NLST").await;
+    assert_eq!(code, 226);
+    let mut names: Vec<_> = String::from_utf8(listing)
+        .unwrap()
+        .lines()
+        .map(str::to_string)
+        .collect();
+    names.sort();
+    assert_eq!(names, ["fits.bin", "old.bin"]);
+}
+
+/// Starts the upload of `name` by `client`, returning its data connection
+/// once the server receives it.
+async fn start_upload(client: &mut Client, name: &str) -> TcpStream {
+    let data = client.passive().await;
+    assert_eq!(client.command(&format!("STOR {name}")).await.0, 125);
+    loop {
+        let (_, lines) = client.command_lines("STAT").await;
+        if lines.iter().any(|line| line.starts_with(" Receiving ")) {
+            return data;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+}
+
+#[tokio::test]
+async fn concurrent_uploads_share_the_quota() {
+    let root = tempfile::tempdir().unwrap();
+    std::fs::create_dir(root.path().join("alice")).unwrap();
+    let config = config(
+        root.path(),
+        r#"
+        [[users]]
+        name = "alice"
+        password = "secret"
+        home = "{root}/alice"
+        quota = 1000
+        "#,
+    );
+    let addr = serve(config).await;
+    let mut first = Client::login(addr, "alice", "secret").await;
+    let mut second = Client::login(addr, "alice", "secret").await;
+
+    // Both started while the quota was still free
+    let mut first_data = start_upload(&mut first, "first.bin").await;
+    let mut second_data = start_upload(&mut second, "second.bin").await;
+
+    first_data.write_all(&[0; 800]).await.unwrap();
+    first_data.shutdown().await.unwrap();
+    assert_eq!(first.reply().await.0, 226);
+    // The server may stop reading once it refuses the upload
+    let _ = second_data.write_all(&[0; 800]).await;
+    let _ = second_data.shutdown().await;
+    assert_eq!(second.reply().await.0, 552);
+}